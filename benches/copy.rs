@@ -0,0 +1,66 @@
+//! Copying books by the OS itself, against reading and writing them in chunks.
+
+use {
+    criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput},
+    std::{fs, path::Path},
+    sync_kobo_and_workstation::{sync, sync_with_events, SyncOptions},
+    tempfile::TempDir,
+    tokio::runtime::Runtime,
+};
+
+const BOOKS: usize = 16;
+const BOOK_BYTES: usize = 8 * 1024 * 1024;
+
+/// Write books large enough for copying them to outweigh finding them.
+fn write_books(dir: &Path) {
+    let contents: Vec<u8> = (0..BOOK_BYTES).map(|i| (i % 251) as u8).collect();
+    for book in 0..BOOKS {
+        fs::write(dir.join(format!("book-{book}.pdf")), &contents).unwrap();
+    }
+}
+
+fn copying(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let src_dir = TempDir::new().unwrap();
+    write_books(src_dir.path());
+    let src = src_dir.path();
+
+    let mut group = c.benchmark_group("copying");
+    group
+        .sample_size(10)
+        .throughput(Throughput::Bytes((BOOKS * BOOK_BYTES) as u64));
+
+    // Without progress events, nothing needs to look at each byte, so the OS can copy each book.
+    group.bench_function("by the OS", |b| {
+        b.to_async(&runtime).iter_batched(
+            || TempDir::new().unwrap(),
+            |dest| async move {
+                let options = SyncOptions::builder(dest.path()).source(src).build();
+                sync(options).await.unwrap();
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    // Reporting each book's progress means reading and writing it in chunks instead.
+    group.bench_function("in chunks", |b| {
+        b.to_async(&runtime).iter_batched(
+            || TempDir::new().unwrap(),
+            |dest| async move {
+                let options = SyncOptions::builder(dest.path())
+                    .source(src)
+                    .copy_progress_events(true)
+                    .build();
+                let (mut events, syncing) = sync_with_events(options);
+                while events.recv().await.is_some() {}
+                syncing.await.unwrap().unwrap();
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, copying);
+criterion_main!(benches);
@@ -0,0 +1,859 @@
+//! Turning the command line's arguments, and the configuration file's defaults, into the options
+//! to synchronise with.
+
+use {
+    crate::{
+        clear_hash_cache,
+        cli::{
+            build_glob_set, parse_extension, Action, OutputFormat, PartialArgs, Preset, SyncArgs,
+            NAME,
+        },
+        detect_mtp_storage_directory, detect_storage_directory,
+        device::destination_in,
+        is_accessible_dir, mount_by_label,
+        progress::{new_progress_bar, shows_progress_bar},
+        Device, Plan, ReportFormat, RunFailure, SyncOptions,
+    },
+    anyhow::{anyhow, Result},
+    directories::UserDirs,
+    glob::glob,
+    serde::Deserialize,
+    std::{
+        collections::HashSet,
+        env,
+        ffi::OsStr,
+        num::NonZeroU64,
+        path::{Path, PathBuf},
+    },
+    tokio::{
+        fs,
+        io::{self, stdin, AsyncReadExt},
+        task::spawn_blocking,
+    },
+};
+
+fn lookup_home_directory() -> Result<PathBuf> {
+    let dirs =
+        UserDirs::new().ok_or_else(|| anyhow!("failed to read the current home directory"))?;
+
+    let home = dirs.home_dir();
+    Ok(home.to_path_buf())
+}
+
+pub(crate) fn lookup_config_file() -> Result<PathBuf> {
+    let mut path = match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            let mut dir = lookup_home_directory()?;
+            dir.push(".config");
+            dir
+        }
+    };
+    path.push(NAME);
+    path.push("config.toml");
+    Ok(path)
+}
+
+fn lookup_state_directory() -> Result<PathBuf> {
+    let mut path = match env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            let mut dir = lookup_home_directory()?;
+            dir.push(".local");
+            dir.push("state");
+            dir
+        }
+    };
+    path.push(NAME);
+    Ok(path)
+}
+
+fn lookup_audit_log_file() -> Result<PathBuf> {
+    Ok(lookup_state_directory()?.join("history.log"))
+}
+
+fn lookup_history_file() -> Result<PathBuf> {
+    Ok(lookup_state_directory()?.join("runs.json"))
+}
+
+fn lookup_cache_directory() -> Result<PathBuf> {
+    let mut path = match env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            let mut dir = lookup_home_directory()?;
+            dir.push(".cache");
+            dir
+        }
+    };
+    path.push(NAME);
+    Ok(path)
+}
+
+pub(crate) fn lookup_hash_cache_file() -> Result<PathBuf> {
+    Ok(lookup_cache_directory()?.join("hashes.json"))
+}
+
+fn lookup_conversion_cache_directory() -> Result<PathBuf> {
+    Ok(lookup_cache_directory()?.join("conversions"))
+}
+
+/// Windows knows where the documents folder is even if it's been moved, such as into OneDrive.
+#[cfg(windows)]
+fn lookup_default_documents_directories() -> Result<Vec<PathBuf>> {
+    let dirs =
+        UserDirs::new().ok_or_else(|| anyhow!("failed to read the current home directory"))?;
+    let documents = dirs
+        .document_dir()
+        .ok_or_else(|| anyhow!("failed to read the current documents directory"))?;
+    Ok(vec![documents.to_path_buf()])
+}
+
+#[cfg(not(windows))]
+fn lookup_default_documents_directories() -> Result<Vec<PathBuf>> {
+    let home = lookup_home_directory()?;
+
+    let mut documents = PathBuf::new();
+    documents.push(home);
+    documents.push("Documents");
+
+    Ok(vec![documents])
+}
+
+/// Replace a leading `~` with the home directory, as shells do, for paths that weren't given
+/// through one, such as those in the configuration file or quoted to keep a glob pattern intact.
+fn expand_tilde(path: PathBuf) -> Result<PathBuf> {
+    match path.strip_prefix("~") {
+        Ok(rest) => Ok(lookup_home_directory()?.join(rest)),
+        Err(_) => Ok(path),
+    }
+}
+
+fn has_glob_metacharacters(s: &OsStr) -> bool {
+    s.to_str()
+        .map(|s| s.contains(['*', '?', '[']))
+        .unwrap_or(false)
+}
+
+/// The directories that a glob pattern such as `~/Library/*/books` matches, in order. Those inside
+/// another that matched, such as with `~/Library/**`, are left out, as their books are found by
+/// searching that one anyway.
+async fn expand_glob(pattern: &Path) -> Result<Vec<PathBuf>> {
+    let pattern_str = pattern.to_string_lossy().into_owned();
+    let mut paths = spawn_blocking(move || {
+        // Paths that can't be read just don't match anything.
+        glob(&pattern_str).map(|paths| paths.filter_map(Result::ok).collect::<Vec<_>>())
+    })
+    .await?
+    .map_err(|err| anyhow!("invalid glob pattern {}: {err}", pattern.display()))?;
+    paths.sort();
+
+    let mut matched: Vec<PathBuf> = vec![];
+    for path in paths {
+        if !matched.iter().any(|dir| path.starts_with(dir)) && is_accessible_dir(&path).await {
+            matched.push(path);
+        }
+    }
+    Ok(matched)
+}
+
+/// Expand the tildes and glob patterns in the documents directories, so that each directory a
+/// pattern matches is a documents directory of its own. Those matched more than once are only kept
+/// once, and a pattern matching nothing is an error unless `allow_empty`. Paths without patterns
+/// are kept as they are, to be validated like any other.
+async fn expand_documents_directories(
+    dirs: Vec<PathBuf>,
+    allow_empty: bool,
+) -> Result<Vec<PathBuf>> {
+    let mut expanded = vec![];
+    let mut seen = HashSet::new();
+    for dir in dirs {
+        let dir = expand_tilde(dir)?;
+        if !has_glob_metacharacters(dir.as_os_str()) {
+            seen.insert(dir.clone());
+            expanded.push(dir);
+            continue;
+        }
+
+        let matched = expand_glob(&dir).await?;
+        if matched.is_empty() && !allow_empty {
+            return Err(anyhow!(
+                "the documents directory pattern {} matches no directories; pass \
+                --allow-empty-sources to carry on without it",
+                dir.display()
+            ));
+        }
+        for dir in matched {
+            if seen.insert(dir.clone()) {
+                expanded.push(dir);
+            }
+        }
+    }
+    Ok(expanded)
+}
+
+/// Defaults for the command line arguments, read from a TOML file in the user's configuration
+/// directory. Explicit arguments take precedence over these.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) kobo_directory: Option<PathBuf>,
+    pub(crate) documents_directories: Option<Vec<PathBuf>>,
+    pub(crate) dry_run: Option<bool>,
+    pub(crate) extensions: Option<Vec<String>>,
+    pub(crate) device: Option<Device>,
+}
+
+/// Read the paths of the books listed by `--files-from`, or by `--files-from0` if
+/// `nul_separated`, from stdin for `-`.
+async fn read_file_list(path: &Path, nul_separated: bool) -> Result<Vec<PathBuf>> {
+    let contents = if path == Path::new("-") {
+        let mut contents = vec![];
+        stdin().read_to_end(&mut contents).await?;
+        contents
+    } else {
+        fs::read(path).await.map_err(|err| {
+            anyhow!(
+                "could not read the list of files at {}: {err}",
+                path.display()
+            )
+        })?
+    };
+    let contents = String::from_utf8(contents)
+        .map_err(|_| anyhow!("the list of files at {} is not valid UTF-8", path.display()))?;
+
+    let listed: Vec<&str> = if nul_separated {
+        contents
+            .split('\0')
+            .filter(|path| !path.is_empty())
+            .collect()
+    } else {
+        contents
+            .lines()
+            .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+            .collect()
+    };
+    let current_dir = env::current_dir()?;
+    Ok(listed
+        .into_iter()
+        .map(|listed| current_dir.join(listed))
+        .collect())
+}
+
+async fn load_config(path: &Path) -> Result<Config> {
+    let contents = match fs::read_to_string(path).await {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let path_str = path.display();
+    let mut config: Config = toml::from_str(&contents)
+        .map_err(|err| anyhow!("the configuration file at {path_str} is invalid: {err}"))?;
+
+    if let Some(extensions) = config.extensions.take() {
+        let normalised = extensions
+            .iter()
+            .map(|ext| parse_extension(ext))
+            .collect::<Result<_>>()
+            .map_err(|err| {
+                anyhow!("the configuration file at {path_str} has an invalid extension: {err}")
+            })?;
+        config.extensions = Some(normalised);
+    }
+    Ok(config)
+}
+
+pub(crate) async fn config_for(no_config: bool) -> Result<Config> {
+    if no_config {
+        Ok(Config::default())
+    } else {
+        load_config(&lookup_config_file()?).await
+    }
+}
+
+/// Where to look for the device, so that a daemon can look for it again each time it's plugged in.
+#[derive(Clone, Debug)]
+pub(crate) struct VolumeSearch {
+    mtp_device: Option<String>,
+    kobo_directory: Option<PathBuf>,
+    auto_mount: bool,
+
+    /// Whether a volume that `--auto-mount` mounted is to be unmounted once the run finishes.
+    auto_unmount: bool,
+}
+
+/// The arguments once checked and combined with the configuration file's defaults, ready to run
+/// with.
+#[derive(Clone)]
+pub struct Args {
+    pub sync_options: SyncOptions,
+    pub device: Device,
+    pub(crate) volume_search: VolumeSearch,
+
+    /// The volumes of the other devices to synchronise the same books to, one after another.
+    pub extra_destinations: Vec<PathBuf>,
+
+    /// The subdirectory of each device that books go into, if not its root.
+    pub dest_subdir: Option<PathBuf>,
+
+    pub force: bool,
+
+    /// The volume that `--auto-mount` mounted, if it's to be unmounted once the run finishes.
+    pub auto_unmount: Option<PathBuf>,
+
+    pub output: OutputFormat,
+    pub colors: bool,
+    pub watch: bool,
+    pub raw_bytes: bool,
+    pub log_file: Option<PathBuf>,
+    pub report: Option<PathBuf>,
+    pub report_format: ReportFormat,
+    pub plan: bool,
+    pub plan_file: Option<PathBuf>,
+    pub action: Option<Action>,
+}
+
+/// Find the device's volume or, with `--auto-mount`, mount it if it can't be found, yielding it
+/// along with where it was mounted if it was.
+async fn find_volume(search: &VolumeSearch, device: Device) -> Result<(PathBuf, Option<PathBuf>)> {
+    let volume = locate_volume(
+        search.mtp_device.as_deref(),
+        search.kobo_directory.clone(),
+        device,
+        search.auto_mount,
+    )
+    .await;
+    match volume {
+        Err(not_found) if search.auto_mount => {
+            let mount_point = mount_by_label(device).await.map_err(|err| {
+                let not_found = not_found.to_string();
+                RunFailure::Inaccessible(format!(
+                    "{}. Mounting it with udisks2 failed too: {err:#}",
+                    not_found.trim_end_matches('.')
+                ))
+            })?;
+            Ok((mount_point.clone(), Some(mount_point)))
+        }
+        volume => Ok((volume?, None)),
+    }
+}
+
+impl Args {
+    /// The same arguments, but with the device looked for again, as it can be mounted somewhere
+    /// else each time it's plugged in.
+    pub(crate) async fn with_device_found_again(&self) -> Result<Args> {
+        let (volume, auto_mounted) = find_volume(&self.volume_search, self.device).await?;
+        let destination = destination_in(
+            &volume,
+            self.device,
+            !self.force,
+            self.dest_subdir.as_deref(),
+            !self.sync_options.is_dry_run(),
+        )
+        .await?;
+        Ok(Args {
+            sync_options: self.sync_options.with_destination(volume, destination),
+            auto_unmount: auto_mounted.filter(|_| self.volume_search.auto_unmount),
+            ..self.clone()
+        })
+    }
+}
+
+/// Find the device's volume: on the MTP device if one is named, otherwise where it's said to be,
+/// otherwise wherever it's detected to be mounted.
+pub(crate) async fn locate_volume(
+    mtp_device: Option<&str>,
+    kobo_directory: Option<PathBuf>,
+    device: Device,
+    auto_mount: bool,
+) -> Result<PathBuf> {
+    match (mtp_device, kobo_directory) {
+        (Some(name), _) => detect_mtp_storage_directory(name, device).await,
+        (None, Some(dir)) if !auto_mount || is_accessible_dir(&dir).await => Ok(dir),
+        (None, Some(dir)) => Err(RunFailure::Inaccessible(format!(
+            "The Kobo storage directory at {} is not accessible",
+            dir.display()
+        ))
+        .into()),
+        (None, None) => detect_storage_directory(device).await,
+    }
+}
+
+/// Presets replace the configured extensions, as those are only defaults, but not the explicit
+/// ones, which are added to them.
+pub(crate) fn extensions_for(
+    presets: Option<Vec<Preset>>,
+    extensions: Option<Vec<String>>,
+    configured: Option<Vec<String>>,
+    device: Device,
+) -> Vec<String> {
+    match (presets, extensions) {
+        (Some(presets), extensions) => {
+            let mut combined = vec![];
+            let preset_extensions = presets
+                .iter()
+                .flat_map(|preset| preset.extensions())
+                .map(|ext| ext.to_string());
+            for ext in preset_extensions.chain(extensions.into_iter().flatten()) {
+                if !combined.contains(&ext) {
+                    combined.push(ext);
+                }
+            }
+            combined
+        }
+        (None, Some(extensions)) => extensions,
+        (None, None) => configured.unwrap_or_else(|| {
+            device
+                .default_extensions()
+                .iter()
+                .map(|ext| ext.to_string())
+                .collect()
+        }),
+    }
+}
+
+/// Without any extensions, no books would ever be found.
+pub(crate) fn check_extensions(extensions: &[String]) -> Result<()> {
+    if extensions.is_empty() {
+        return Err(anyhow!(
+            "no extensions are configured, so no books would be synchronised"
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) async fn documents_directories_for(
+    dirs: Option<Vec<PathBuf>>,
+    configured: Option<Vec<PathBuf>>,
+    has_calibre_library: bool,
+    allow_empty: bool,
+) -> Result<Vec<PathBuf>> {
+    let dirs = match dirs.or(configured) {
+        Some(dirs) => dirs,
+        // A Calibre library is a source of its own, so isn't joined by the default documents
+        // directories unless they're asked for.
+        None if has_calibre_library => vec![],
+        None => lookup_default_documents_directories().map_err(|err| {
+            anyhow!(
+                "{err}, so there is no default documents directory; give one with \
+                --documents-directories"
+            )
+        })?,
+    };
+    expand_documents_directories(dirs, allow_empty).await
+}
+
+pub(crate) async fn check_documents_directory(dir: &Path) -> Result<()> {
+    let inaccessible = dir.display();
+    if !is_accessible_dir(dir).await {
+        return Err(RunFailure::Inaccessible(format!(
+            "The documents directory at {inaccessible} is not accessible"
+        ))
+        .into());
+    }
+    if let Err(err) = fs::read_dir(dir).await {
+        return Err(RunFailure::Inaccessible(format!(
+            "The documents directory at {inaccessible} is not readable: {err}"
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+pub(crate) async fn check_calibre_library(library: &Path) -> Result<()> {
+    if !is_accessible_dir(library).await {
+        let inaccessible = library.display();
+        return Err(RunFailure::Inaccessible(format!(
+            "The Calibre library at {inaccessible} is not accessible"
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+pub(crate) async fn check_pull_directory(dir: &Path) -> Result<()> {
+    if !is_accessible_dir(dir).await {
+        let inaccessible = dir.display();
+        return Err(RunFailure::Inaccessible(format!(
+            "The directory to pull books into at {inaccessible} is not accessible"
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+pub async fn parse_args(args: PartialArgs) -> Result<Args> {
+    let partial @ PartialArgs {
+        mirror_structure,
+        output,
+        no_progress,
+        bytes: raw_bytes,
+        no_config,
+        sync:
+            SyncArgs {
+                update,
+                verify,
+                max_concurrent_copies,
+                resume,
+                retries,
+                interactive,
+                on_collision,
+                check_free_space,
+                fit_what_fits,
+                delete,
+                eject,
+                collections_from_folders,
+                watch,
+                ..
+            },
+        ..
+    } = args;
+
+    let config = config_for(no_config).await?;
+
+    let dry_run = partial.dry_run || partial.sync.plan || config.dry_run.unwrap_or(false);
+    let audit_log = match partial.sync.audit_log {
+        Some(Some(path)) => Some(path),
+        Some(None) => Some(lookup_audit_log_file()?),
+        None => None,
+    };
+    let device = partial.device.or(config.device).unwrap_or_default();
+    let hash_cache = lookup_hash_cache_file()?;
+    if partial.clear_hash_cache {
+        clear_hash_cache(&hash_cache).await.map_err(|err| {
+            anyhow!(
+                "could not clear the hash cache at {}: {err}",
+                hash_cache.display()
+            )
+        })?;
+    }
+    let hash_cache = (!partial.no_hash_cache).then_some(hash_cache);
+    let conversion_cache = match partial.convert_unsupported {
+        Some(_) if !partial.no_conversion_cache => Some(lookup_conversion_cache_directory()?),
+        _ => None,
+    };
+    let history = if partial.sync.no_history {
+        None
+    } else {
+        Some(lookup_history_file()?)
+    };
+
+    if partial.auto_mount && !cfg!(target_os = "linux") {
+        return Err(anyhow!(
+            "--auto-mount only works on Linux, as it mounts the device with udisks2"
+        ));
+    }
+    let volume_search = VolumeSearch {
+        mtp_device: partial.mtp_device.clone(),
+        kobo_directory: partial.kobo_directory.clone().or(config.kobo_directory),
+        auto_mount: partial.auto_mount,
+        auto_unmount: partial.sync.auto_unmount && !eject,
+    };
+    let (kobo_directory, auto_mounted) = find_volume(&volume_search, device).await?;
+
+    let extensions = extensions_for(
+        partial.preset,
+        partial.extensions,
+        config.extensions,
+        device,
+    );
+    check_extensions(&extensions)?;
+    let documents_directories = documents_directories_for(
+        partial.documents_directories,
+        config.documents_directories,
+        partial.calibre_library.is_some(),
+        partial.allow_empty_sources,
+    )
+    .await?;
+
+    let is_exporting = matches!(partial.action, Some(Action::ExportAnnotations(_)));
+    let is_pruning = matches!(partial.action, Some(Action::PruneDuplicates));
+    if partial.action.is_some() && !partial.sync.extra_destination.is_empty() {
+        return Err(anyhow!("--extra-destination only works when synchronising"));
+    }
+    if is_exporting && device != Device::Kobo {
+        return Err(anyhow!(
+            "exporting annotations only works with Kobos, not a {device:?}"
+        ));
+    }
+    if collections_from_folders && device != Device::Kobo {
+        return Err(anyhow!(
+            "--collections-from-folders only works with Kobos, not a {device:?}"
+        ));
+    }
+    if partial.kepubify.is_some() && device != Device::Kobo {
+        return Err(anyhow!(
+            "--kepubify only works with Kobos, not a {device:?}"
+        ));
+    }
+    if partial.convert_unsupported.is_some() && device != Device::Kobo {
+        return Err(anyhow!(
+            "--convert-unsupported only works with Kobos, not a {device:?}"
+        ));
+    }
+    // Deleting files from something that isn't an e-reader could do real damage, so pruning always
+    // checks for the marker.
+    if is_pruning && partial.force {
+        return Err(anyhow!(
+            "--force can't be used when pruning duplicates, as that deletes books"
+        ));
+    }
+    // Only synchronising puts books into the subdirectory, so nothing else needs it created.
+    let dest_directory = destination_in(
+        &kobo_directory,
+        device,
+        !partial.force,
+        partial.dest_subdir.as_deref(),
+        !dry_run && partial.action.is_none(),
+    )
+    .await?;
+    // Exporting annotations and pruning duplicates don't look at the books in the documents
+    // directories, so don't need them.
+    for dir in documents_directories
+        .iter()
+        .filter(|_| !is_exporting && !is_pruning)
+    {
+        check_documents_directory(dir).await?;
+    }
+    if let Some(library) = partial
+        .calibre_library
+        .as_ref()
+        .filter(|_| !is_exporting && !is_pruning)
+    {
+        check_calibre_library(library).await?;
+    }
+    if let Some(pull_dir) = &partial.sync.pull {
+        check_pull_directory(pull_dir).await?;
+    }
+
+    let files = match (&partial.sync.files_from, &partial.sync.files_from0) {
+        (Some(path), _) => Some((path, false)),
+        (None, Some(path)) => Some((path, true)),
+        (None, None) => None,
+    };
+    let files = match files {
+        Some((path, _)) if interactive && path == Path::new("-") => {
+            return Err(anyhow!(
+                "--interactive can't read answers from stdin while the list of files is read from \
+                it"
+            ));
+        }
+        Some((path, nul_separated)) => Some(read_file_list(path, nul_separated).await?),
+        None => None,
+    };
+
+    // A plan is checked before anything's done, so that a stale one is refused outright.
+    let plan = match &partial.sync.apply {
+        Some(path) => {
+            let plan = Plan::load(path).await?;
+            plan.check_current(&dest_directory).await?;
+            Some(plan)
+        }
+        None => None,
+    };
+
+    let includes = if partial.include.is_empty() {
+        None
+    } else {
+        Some(build_glob_set(&partial.include)?)
+    };
+    let mut builder = SyncOptions::builder(dest_directory)
+        .volume_directory(kobo_directory)
+        .sources(documents_directories)
+        .extensions(extensions)
+        .dry_run(dry_run)
+        .mirror_structure(mirror_structure)
+        .update(update)
+        .verify(verify)
+        .max_concurrent_copies(max_concurrent_copies)
+        .resume(resume)
+        .retries(retries)
+        .copy_timeout(partial.sync.copy_timeout)
+        .limit_rate(partial.sync.limit_rate.and_then(NonZeroU64::new))
+        .copy_buffer_size(partial.sync.copy_buffer_size)
+        .fsync(partial.sync.fsync)
+        .preserve_times(partial.sync.preserve_times)
+        .rename_from_metadata(partial.rename_from_metadata)
+        .include_sidecars(partial.include_sidecars)
+        .interactive(interactive)
+        .files(files)
+        .on_collision(on_collision)
+        .order_by(partial.sync.order_by)
+        .compare(partial.sync.compare)
+        .overwrite(partial.sync.overwrite)
+        .check_free_space(check_free_space)
+        .fit_what_fits(fit_what_fits)
+        .max_total_bytes(partial.sync.max_total_bytes)
+        .kepubify(partial.kepubify)
+        .ebook_convert(partial.convert_unsupported)
+        .convert_extensions(partial.convert_extensions)
+        .conversion_cache(conversion_cache)
+        .manifest(!partial.no_manifest)
+        .excludes(build_glob_set(&partial.exclude)?)
+        .includes(includes)
+        .follow_symlinks(partial.follow_symlinks)
+        .hidden(partial.hidden)
+        .validate(!partial.sync.no_validate)
+        .dedupe_content(partial.dedupe_content)
+        .prefer_formats(partial.sync.prefer_format)
+        .calibre_library(partial.calibre_library)
+        .calibre_tag(partial.calibre_tag)
+        .hash_cache(hash_cache)
+        .history(history)
+        // How far each copy has got is only shown on the progress bar, which daemons don't have.
+        .copy_progress_events(shows_progress_bar(
+            output,
+            no_progress || partial.sync.daemon,
+        ))
+        .syncignore(!partial.no_syncignore)
+        .calibre_aware(partial.calibre_aware)
+        .max_size(partial.max_size)
+        .max_depth(partial.max_depth)
+        .one_file_system(partial.one_file_system)
+        .since(partial.since)
+        .incremental(partial.sync.incremental && !partial.sync.full)
+        .stale_lock_age(partial.sync.stale_lock_age)
+        .delete(delete)
+        .pull(partial.sync.pull)
+        .collections_from_folders(collections_from_folders)
+        .eject(eject)
+        .watch(watch)
+        .fail_fast(partial.sync.fail_fast)
+        .audit_log(audit_log)
+        .audit_log_max_size(partial.sync.audit_log_max_size)
+        // Only synchronising reports its progress, and daemons run unattended.
+        .progress_bar(new_progress_bar(
+            output,
+            no_progress || partial.action.is_some() || partial.sync.daemon,
+        ));
+    for (ext, subdir) in partial.dest_for {
+        builder = builder.route(ext, subdir);
+    }
+    if let Some(plan) = &plan {
+        builder = builder.apply(plan);
+    }
+
+    Ok(Args {
+        sync_options: builder.build(),
+        device,
+        extra_destinations: partial.sync.extra_destination,
+        dest_subdir: partial.dest_subdir,
+        force: partial.force,
+        auto_unmount: auto_mounted.filter(|_| volume_search.auto_unmount),
+        volume_search,
+        output,
+        colors: partial.color.colors(output),
+        watch,
+        raw_bytes,
+        log_file: partial.log_file,
+        report: partial.report,
+        report_format: partial.report_format,
+        plan: partial.sync.plan,
+        plan_file: partial.sync.plan_file,
+        action: partial.action,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, clap::Parser};
+
+    #[tokio::test]
+    async fn expands_glob_patterns_to_the_directories_they_match() {
+        let library = tempfile::TempDir::new().unwrap();
+        for dir in ["fiction/books", "papers/books", "papers/notes"] {
+            std::fs::create_dir_all(library.path().join(dir)).unwrap();
+        }
+        std::fs::write(library.path().join("fiction/books.pdf"), b"").unwrap();
+
+        let matched = expand_glob(&library.path().join("*/books*")).await.unwrap();
+        assert_eq!(
+            matched,
+            [
+                library.path().join("fiction/books"),
+                library.path().join("papers/books")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn leaves_out_directories_inside_others_that_matched() {
+        let library = tempfile::TempDir::new().unwrap();
+        for dir in ["fiction/classics", "papers"] {
+            std::fs::create_dir_all(library.path().join(dir)).unwrap();
+        }
+
+        let matched = expand_glob(&library.path().join("**")).await.unwrap();
+        assert_eq!(
+            matched,
+            [
+                library.path().join("fiction"),
+                library.path().join("papers")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn looks_for_the_device_again_with_the_same_options() {
+        let (volume, src) = (
+            tempfile::TempDir::new().unwrap(),
+            tempfile::TempDir::new().unwrap(),
+        );
+        let marker = volume.path().join(".kobo");
+        std::fs::create_dir(&marker).unwrap();
+        let partial = PartialArgs::try_parse_from([
+            OsStr::new(NAME),
+            OsStr::new("--no-config"),
+            OsStr::new("--no-history"),
+            OsStr::new("--dry-run"),
+            OsStr::new("--kobo-directory"),
+            volume.path().as_os_str(),
+            OsStr::new("--documents-directories"),
+            src.path().as_os_str(),
+        ])
+        .unwrap();
+        let parsed = parse_args(partial).await.unwrap();
+
+        std::fs::remove_dir(&marker).unwrap();
+        let unplugged = parsed.with_device_found_again().await;
+        assert!(matches!(
+            unplugged.err().as_ref().and_then(|err| err.downcast_ref()),
+            Some(RunFailure::Inaccessible(_))
+        ));
+
+        std::fs::create_dir(&marker).unwrap();
+        let replugged = parsed.with_device_found_again().await.unwrap();
+        assert_eq!(replugged.sync_options.destination(), volume.path());
+        assert!(replugged.sync_options.is_dry_run());
+    }
+
+    #[tokio::test]
+    async fn only_creates_the_destination_subdirectory_when_synchronising() {
+        let (volume, src) = (
+            tempfile::TempDir::new().unwrap(),
+            tempfile::TempDir::new().unwrap(),
+        );
+        std::fs::create_dir(volume.path().join(".kobo")).unwrap();
+        let args_with = |action: Option<&str>| {
+            let args = [
+                OsStr::new(NAME),
+                OsStr::new("--no-config"),
+                OsStr::new("--no-history"),
+                OsStr::new("--dest-subdir"),
+                OsStr::new("books"),
+                OsStr::new("--kobo-directory"),
+                volume.path().as_os_str(),
+                OsStr::new("--documents-directories"),
+                src.path().as_os_str(),
+            ];
+            PartialArgs::try_parse_from(args.into_iter().chain(action.map(OsStr::new))).unwrap()
+        };
+
+        for action in ["list", "verify"] {
+            parse_args(args_with(Some(action))).await.unwrap();
+            assert!(!volume.path().join("books").exists());
+        }
+        parse_args(args_with(None)).await.unwrap();
+        assert!(volume.path().join("books").is_dir());
+    }
+}
@@ -0,0 +1,128 @@
+//! Appending a record of each run to a log file, kept to a maximum size by rotating it.
+
+use {
+    crate::{report::BookAction, stats::Counters},
+    std::{
+        ffi::OsString,
+        fmt::Debug,
+        path::{Path, PathBuf},
+        time::SystemTime,
+    },
+    tokio::{
+        fs::{self, File, OpenOptions},
+        io::AsyncWriteExt,
+    },
+    tracing::warn,
+};
+
+/// How large the audit log gets before it's rotated, unless told otherwise.
+pub(crate) const DEFAULT_AUDIT_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// A run's block in the audit log. Failing to write it only warns, as the log is no reason to
+/// fail the run, and after the first failure nothing more is written.
+pub(crate) struct AuditLog {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+/// The older log that a full one is rotated to, such as `history.log.1` for `history.log`. Only
+/// one is kept.
+fn rotated_path_for(path: &Path) -> PathBuf {
+    let mut rotated = OsString::from(path.as_os_str());
+    rotated.push(".1");
+    PathBuf::from(rotated)
+}
+
+async fn open_appending(path: &Path, max_size: u64) -> std::io::Result<File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    let is_full = fs::metadata(path)
+        .await
+        .map(|metadata| max_size <= metadata.len())
+        .unwrap_or(false);
+    if is_full {
+        fs::rename(path, rotated_path_for(path)).await?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+}
+
+impl AuditLog {
+    /// Start a run's block in the log, with when it started and the options in effect. A log that
+    /// has reached the maximum size is rotated first.
+    pub(crate) async fn open(path: PathBuf, max_size: u64, options: &impl Debug) -> AuditLog {
+        let file = match open_appending(&path, max_size).await {
+            Ok(file) => Some(file),
+            Err(err) => {
+                let path_str = path.display();
+                warn!(path = %path_str, "Failed to open the audit log {path_str}: {err}");
+                None
+            }
+        };
+        let mut log = AuditLog { path, file };
+        let started = humantime::format_rfc3339_seconds(SystemTime::now());
+        log.write(&format!(
+            "=== Run started at {started}\nOptions: {options:?}\n"
+        ))
+        .await;
+        log
+    }
+
+    async fn write(&mut self, text: &str) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        if let Err(err) = file.write_all(text.as_bytes()).await {
+            let path_str = self.path.display();
+            warn!(
+                path = %path_str,
+                "Failed to write to the audit log {path_str}, so no more will be written: {err}"
+            );
+            self.file = None;
+        }
+    }
+
+    /// Record what was done with a book.
+    pub(crate) async fn record(&mut self, action: &BookAction) {
+        let BookAction {
+            source,
+            destination,
+            action,
+            bytes,
+            error,
+            ..
+        } = action;
+        let mut line = format!(
+            "{} {} -> {} ({bytes} bytes)",
+            action.as_str(),
+            source.display(),
+            destination.display()
+        );
+        if let Some(error) = error {
+            line.push_str(&format!(": {error}"));
+        }
+        line.push('\n');
+        self.write(&line).await;
+    }
+
+    /// End the run's block with the counters that its summary is made from.
+    pub(crate) async fn finish(mut self, counters: &Counters) {
+        let counters = serde_json::to_string(counters)
+            .unwrap_or_else(|err| format!("(the counters could not be written: {err})"));
+        let finished = humantime::format_rfc3339_seconds(SystemTime::now());
+        self.write(&format!(
+            "Counters: {counters}\n=== Run finished at {finished}\n\n"
+        ))
+        .await;
+        if let Some(file) = &mut self.file {
+            if let Err(err) = file.flush().await {
+                let path_str = self.path.display();
+                warn!(path = %path_str, "Failed to write to the audit log {path_str}: {err}");
+            }
+        }
+    }
+}
@@ -0,0 +1,246 @@
+//! Reading which books to synchronise from a Calibre library's database.
+
+use {
+    crate::{find::FoundBook, metadata::name_from_title_and_author, stats::Statistic, Run},
+    anyhow::{anyhow, Result},
+    rusqlite::{Connection, OpenFlags},
+    std::{
+        collections::HashSet,
+        ffi::OsStr,
+        path::{Path, PathBuf},
+        time::Duration,
+    },
+    tokio::{fs, sync::mpsc::Sender, task::spawn_blocking},
+    tracing::{debug, warn},
+};
+
+const CALIBRE_DATABASE: &str = "metadata.db";
+
+/// The directories Calibre keeps deleted books and notes in, beside the books of a library.
+const CALIBRE_BOOKKEEPING_DIRS: [&str; 2] = [".caltrash", ".calnotes"];
+
+/// The start of the extensions Calibre gives the originals of books it has converted or
+/// polished in place, such as `.original_epub`.
+const CALIBRE_ORIGINAL_PREFIX: &str = "original_";
+
+/// The tables and columns of a Calibre database that books are read from.
+const CALIBRE_TABLES: [(&str, &[&str]); 6] = [
+    ("books", &["id", "title", "path"]),
+    ("data", &["book", "format", "name"]),
+    ("authors", &["id", "name"]),
+    ("books_authors_link", &["id", "book", "author"]),
+    ("tags", &["id", "name"]),
+    ("books_tags_link", &["book", "tag"]),
+];
+
+/// A Calibre library to synchronise books from, as well as or instead of the documents
+/// directories.
+#[derive(Clone, Debug)]
+pub(crate) struct CalibreLibrary {
+    pub(crate) path: PathBuf,
+
+    /// If present, only books with this tag are synchronised.
+    pub(crate) tag: Option<String>,
+}
+
+/// Whether to skip what Calibre keeps beside the books when walking a documents directory: if told
+/// to, or otherwise if the directory is a Calibre library, by having its database at the root.
+pub(crate) async fn is_calibre_aware(dir: &Path, calibre_aware: Option<bool>) -> bool {
+    match calibre_aware {
+        Some(calibre_aware) => calibre_aware,
+        None => {
+            let detected = fs::try_exists(dir.join(CALIBRE_DATABASE))
+                .await
+                .unwrap_or(false);
+            if detected {
+                let dir_str = dir.display();
+                debug!(path = %dir_str, "Skipping Calibre's bookkeeping in the library {dir_str}");
+            }
+            detected
+        }
+    }
+}
+
+/// Whether a directory is one Calibre keeps deleted books or notes in.
+pub(crate) fn is_calibre_bookkeeping(name: &OsStr) -> bool {
+    CALIBRE_BOOKKEEPING_DIRS.iter().any(|dir| name == *dir)
+}
+
+/// Whether a file is the original of a book Calibre has since converted or polished.
+pub(crate) fn is_calibre_original(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| {
+            ext.to_string_lossy()
+                .to_lowercase()
+                .starts_with(CALIBRE_ORIGINAL_PREFIX)
+        })
+        .unwrap_or(false)
+}
+
+/// A format of a book in a Calibre library.
+struct CalibreFormat {
+    book: i64,
+    title: String,
+    author: Option<String>,
+
+    /// The book's directory, relative to the library.
+    dir: String,
+
+    /// The format, such as `EPUB`, which is also the extension of its file.
+    format: String,
+
+    /// The name of its file, without the extension.
+    name: String,
+}
+
+fn check_calibre_schema(db: &Connection) -> Result<()> {
+    for (table, columns) in CALIBRE_TABLES {
+        let mut statement = db.prepare("SELECT name FROM pragma_table_info(?1)")?;
+        let present = statement
+            .query_map([table], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<HashSet<_>>>()?;
+
+        if present.is_empty() {
+            return Err(anyhow!(
+                "the Calibre database has no {table} table, so this version of Calibre is not \
+                supported"
+            ));
+        }
+        let missing: Vec<_> = columns
+            .iter()
+            .filter(|column| !present.contains(**column))
+            .collect();
+        if !missing.is_empty() {
+            return Err(anyhow!(
+                "the {table} table of the Calibre database lacks the columns {missing:?}, so this \
+                version of Calibre is not supported"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Read every format of every book in a Calibre library, or of those with a tag, in the order the
+/// books were added. The database is only ever opened read-only.
+fn read_calibre_formats(db_path: &Path, tag: Option<&str>) -> Result<Vec<CalibreFormat>> {
+    let db = Connection::open_with_flags(
+        db_path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .map_err(|err| {
+        anyhow!(
+            "could not open the Calibre database at {}: {err}",
+            db_path.display()
+        )
+    })?;
+    // Calibre may be writing to it at the same time.
+    db.busy_timeout(Duration::from_secs(1))?;
+    check_calibre_schema(&db)?;
+
+    let mut statement = db.prepare(
+        "SELECT b.id, b.title, \
+            (SELECT a.name FROM books_authors_link l JOIN authors a ON a.id = l.author \
+                WHERE l.book = b.id ORDER BY l.id LIMIT 1), \
+            b.path, d.format, d.name \
+        FROM books b JOIN data d ON d.book = b.id \
+        WHERE ?1 IS NULL OR EXISTS ( \
+            SELECT 1 FROM books_tags_link l JOIN tags t ON t.id = l.tag \
+            WHERE l.book = b.id AND t.name = ?1 COLLATE NOCASE) \
+        ORDER BY b.id, d.format",
+    )?;
+    let formats = statement
+        .query_map([tag], |row| {
+            Ok(CalibreFormat {
+                book: row.get(0)?,
+                title: row.get(1)?,
+                author: row.get(2)?,
+                dir: row.get(3)?,
+                format: row.get(4)?,
+                name: row.get(5)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .map_err(|err| {
+            anyhow!(
+                "could not read the books from the Calibre database at {}: {err}",
+                db_path.display()
+            )
+        })?;
+    Ok(formats)
+}
+
+/// Send along one format of each book in a Calibre library, as if it'd been found, rather than
+/// walking the library's directories, which hold every format of every book along with their
+/// covers and metadata. The format chosen is the first of a book's formats with a matching
+/// extension in the preferred formats, or otherwise the first alphabetically. Each is named on the
+/// destination after the book's title and author in the library.
+pub(crate) async fn find_calibre_books(
+    library: &CalibreLibrary,
+    extensions_to_match: &HashSet<&OsStr>,
+    prefer_formats: &[String],
+    books: &Sender<FoundBook>,
+    stats: &Sender<Statistic>,
+    run: &Run,
+) -> Result<()> {
+    let db_path = library.path.join(CALIBRE_DATABASE);
+    let tag = library.tag.clone();
+    let formats = spawn_blocking(move || read_calibre_formats(&db_path, tag.as_deref())).await??;
+
+    let rank = |format: &CalibreFormat| {
+        let ext = format.format.to_lowercase();
+        let preference = prefer_formats
+            .iter()
+            .position(|preferred| *preferred == ext);
+        (preference.unwrap_or(usize::MAX), ext)
+    };
+    let mut chosen: Vec<&CalibreFormat> = vec![];
+    for format in &formats {
+        let ext = format.format.to_lowercase();
+        if !extensions_to_match.contains(OsStr::new(&ext)) {
+            continue;
+        }
+        match chosen.last_mut() {
+            Some(last) if last.book == format.book => {
+                if rank(format) < rank(last) {
+                    *last = format;
+                }
+            }
+            _ => chosen.push(format),
+        }
+    }
+
+    for format in chosen {
+        if run.is_interrupted() {
+            break;
+        }
+        let ext = format.format.to_lowercase();
+        let dir = library.path.join(&format.dir);
+        let path = dir.join(format!("{}.{ext}", format.name));
+        let path_str = path.display();
+        let len = match fs::metadata(&path).await {
+            Ok(metadata) => metadata.len(),
+            Err(err) => {
+                warn!(
+                    path = %path_str,
+                    "Skipping {path_str}, as the Calibre library lists it but it could not be \
+                    read: {err}"
+                );
+                stats.send(Statistic::WalkFailed).await?;
+                continue;
+            }
+        };
+
+        debug!(path = %path_str, size = len, "Found {path_str} in the Calibre library");
+        stats
+            .send(Statistic::FoundSrcDocument(path.clone(), len))
+            .await?;
+        let name = name_from_title_and_author(&format.title, format.author.as_deref(), &ext);
+        let found = FoundBook {
+            path,
+            root: dir,
+            name: Some(name),
+        };
+        books.send(found).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,1234 @@
+//! The command line tool's arguments, and what it does with them besides synchronising.
+
+pub use crate::{
+    args::{parse_args, Args},
+    daemon::{interrupt_signal, run_daemon},
+    device::destination_in,
+    doctor::{diagnose, Diagnosis},
+    progress::{progress_style, scanning_style},
+    summary::{
+        format_bytes, format_count, format_diagnoses, format_listing, format_plan, format_pruned,
+        format_summary, format_verification,
+    },
+};
+
+use {
+    crate::{
+        AnnotationFormat, CollisionPolicy, Compare, Device, OrderBy, OverwritePolicy, ReportFormat,
+        RunFailure,
+    },
+    anstream::AutoStream,
+    anyhow::{anyhow, Result},
+    clap::{
+        error::ErrorKind, parser::ValueSource, value_parser, ArgMatches, Args as _, Command,
+        CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum,
+    },
+    clap_complete::{generate, Shell},
+    globset::{Glob, GlobSet, GlobSetBuilder},
+    std::{
+        env,
+        num::NonZeroUsize,
+        path::PathBuf,
+        process::ExitCode,
+        time::{Duration, SystemTime},
+    },
+};
+
+/// The tool's name, which its configuration, state, and cache directories are named after.
+pub const NAME: &str = "sync-kobo-and-workstation";
+
+const LONG_ABOUT: &str = "Synchronise books between a workstation and a Kobo e-book reader. In \
+                          practice, this means synchronising a connected Kobo volume with EPUB \
+                          and PDF files in the specified local documents directories. By \
+                          default, the destination Kobo is found by looking for a volume with a \
+                          .kobo directory under the usual automount directories, such as \
+                          /media/user and /run/media/user, and the source is just ~/Documents. \
+                          If these defaults are overridden with explicit values, it will likely \
+                          work on other OSes too.";
+
+/// Named sets of extensions to synchronise, for common kinds of library.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Preset {
+    /// EPUB and PDF.
+    Books,
+
+    /// CBZ and CBR comic book archives.
+    Comics,
+
+    /// AZW3, MOBI, and PDF.
+    Kindle,
+
+    /// Every format of the other presets.
+    All,
+}
+
+impl Preset {
+    pub(crate) fn extensions(self) -> &'static [&'static str] {
+        match self {
+            Preset::Books => &["epub", "pdf"],
+            Preset::Comics => &["cbz", "cbr"],
+            Preset::Kindle => &["azw3", "mobi", "pdf"],
+            Preset::All => &["epub", "pdf", "cbz", "cbr", "azw3", "mobi"],
+        }
+    }
+}
+
+/// How the results of a run are reported.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable progress lines and summary on stdout.
+    #[default]
+    Text,
+
+    /// A single JSON summary object on stdout, with progress lines moved to stderr.
+    Json,
+}
+
+/// When to colour console output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// Only when writing to a terminal, and `NO_COLOR` isn't set.
+    #[default]
+    Auto,
+
+    /// Even when piped.
+    Always,
+
+    /// Never.
+    Never,
+}
+
+impl ColorChoice {
+    /// Whether console output is coloured. Progress lines go wherever the output format puts them,
+    /// so it's that stream that's checked.
+    pub fn colors(self, output: OutputFormat) -> bool {
+        let detected = match output {
+            OutputFormat::Text => AutoStream::choice(&std::io::stdout()),
+            OutputFormat::Json => AutoStream::choice(&std::io::stderr()),
+        };
+        match self {
+            ColorChoice::Auto => detected != anstream::ColorChoice::Never,
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+/// How a run ended, which determines the exit code that scripts can rely on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Everything was synchronised.
+    Succeeded,
+
+    /// The run failed outright, for a reason without a more specific outcome.
+    Failed,
+
+    /// The device or a documents directory was inaccessible, so nothing was done.
+    Inaccessible,
+
+    /// The run finished, or failed fast, but at least one book failed to copy or verify, or a file
+    /// or directory couldn't be read while finding books.
+    CopiesFailed,
+
+    /// The run was interrupted before finishing.
+    Interrupted,
+
+    /// Another run was already synchronising to the destination, so nothing was done.
+    Locked,
+}
+
+impl Outcome {
+    /// Work out the outcome of a run from its result, which is the number of failures.
+    pub fn of(result: &Result<usize>) -> Self {
+        match result {
+            Ok(0) => Outcome::Succeeded,
+            Ok(_) => Outcome::CopiesFailed,
+            Err(err) => match err.downcast_ref::<RunFailure>() {
+                Some(RunFailure::Inaccessible(_)) => Outcome::Inaccessible,
+                Some(RunFailure::Interrupted(_)) => Outcome::Interrupted,
+                Some(RunFailure::Locked(_)) => Outcome::Locked,
+                None => Outcome::Failed,
+            },
+        }
+    }
+}
+
+impl From<Outcome> for ExitCode {
+    fn from(outcome: Outcome) -> Self {
+        ExitCode::from(match outcome {
+            Outcome::Succeeded => 0,
+            Outcome::Failed => 1,
+            Outcome::Inaccessible => 2,
+            Outcome::CopiesFailed => 3,
+            Outcome::Interrupted => 4,
+            Outcome::Locked => 5,
+        })
+    }
+}
+
+/// Something to do other than synchronising books.
+#[derive(Clone, Debug, Subcommand)]
+pub enum Action {
+    /// Synchronise the books, as running without a subcommand does.
+    Sync(Box<SyncArgs>),
+
+    /// Export the highlights and notes made on a Kobo, without synchronising any books. The
+    /// Kobo's database is only ever read.
+    ExportAnnotations(ExportAnnotationsArgs),
+
+    /// List the books in both the documents directories and on the device, those only in the
+    /// documents directories, which would be copied, and those only on the device, which could be
+    /// pulled or deleted. Nothing is changed.
+    List(ListArgs),
+
+    /// Delete all but one of each group of identical books on the device, keeping the one with
+    /// the shortest name, then the oldest. The device's own files under `.kobo` are never
+    /// touched. Honours `--dry-run`.
+    PruneDuplicates,
+
+    /// Check that every book in the documents directories is on the device, where synchronising
+    /// would copy it, and the same size as it, without copying anything. Prints which books are
+    /// missing or differ, and fails if any do.
+    Verify(VerifyArgs),
+
+    /// Check that everything synchronising needs is in place, printing whether each check passed,
+    /// without synchronising anything: that the device is there, looks like the device, and is
+    /// writable, and that the documents directories can be read. Fails if any check does, so that
+    /// scripts can check before synchronising.
+    Doctor,
+
+    /// Write a completion script for a shell to stdout, such as with `completions bash >
+    /// ~/.local/share/bash-completion/completions/sync-kobo-and-workstation`.
+    #[command(hide = true)]
+    Completions(CompletionsArgs),
+}
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct CompletionsArgs {
+    /// The shell to write a completion script for.
+    #[arg(value_enum)]
+    pub shell: Shell,
+}
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct ListArgs {
+    /// Print a line per book for scripts, each prefixed by whether it's in both places (`=`),
+    /// only in the documents directories (`+`), or only on the device (`-`).
+    #[arg(long, default_value_t = false)]
+    pub porcelain: bool,
+}
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct VerifyArgs {
+    /// Whether to compare the contents of the books with their copies too, by reading both. This
+    /// catches copies corrupted without their sizes changing, but takes a while for large
+    /// libraries.
+    #[arg(long, default_value_t = false)]
+    pub hash: bool,
+}
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct ExportAnnotationsArgs {
+    /// Where to export the annotations: a directory to write a Markdown file per book into, or,
+    /// with `--format json`, a single JSON file.
+    pub output: PathBuf,
+
+    /// How to format the exported annotations.
+    #[arg(long, value_enum, default_value_t = AnnotationFormat::Markdown)]
+    pub format: AnnotationFormat,
+}
+
+/// The options that only synchronising takes, which can be given either without a subcommand or
+/// after `sync`.
+#[derive(Clone, Debug, clap::Args)]
+pub struct SyncArgs {
+    /// The volume of another device of the same kind to synchronise the same books to, after the
+    /// first. Can be repeated. Each device gets its own summary, and one failing doesn't stop the
+    /// others being synchronised.
+    #[arg(
+        long,
+        env = "SYNC_EXTRA_DESTINATION",
+        value_name = "DIR",
+        conflicts_with_all = ["watch", "plan", "apply"]
+    )]
+    pub extra_destination: Vec<PathBuf>,
+
+    /// Whether to overwrite books that already exist on the destination when their source is newer
+    /// or differs in size.
+    #[arg(long, env = "SYNC_UPDATE", default_value_t = false)]
+    pub update: bool,
+
+    /// Whether to verify each copy by re-reading it and comparing its BLAKE3 digest against its
+    /// source's, deleting copies that don't match.
+    #[arg(long, env = "SYNC_VERIFY", default_value_t = false)]
+    pub verify: bool,
+
+    /// The maximum number of books to copy at once.
+    #[arg(long, env = "SYNC_MAX_CONCURRENT_COPIES", default_value = "4")]
+    pub max_concurrent_copies: NonZeroUsize,
+
+    /// Whether to resume copies interrupted by a previous run, rather than restarting them, as
+    /// long as their source hasn't been modified since.
+    #[arg(long, env = "SYNC_RESUME", default_value_t = false)]
+    pub resume: bool,
+
+    /// How many times, up to 10, to retry copying a book after a transient I/O error, such as the
+    /// e-reader's USB connection timing out, with an exponential backoff of up to 8 seconds between
+    /// attempts.
+    #[arg(
+        long,
+        env = "SYNC_RETRIES",
+        default_value_t = 2,
+        value_parser = value_parser!(u32).range(0..=10)
+    )]
+    pub retries: u32,
+
+    /// Give up on copying a book after this many seconds, retries included, such as when a flaky
+    /// USB connection leaves it hanging. The rest of the run carries on regardless.
+    #[arg(
+        long,
+        env = "SYNC_COPY_TIMEOUT",
+        value_name = "SECONDS",
+        value_parser = parse_copy_timeout
+    )]
+    pub copy_timeout: Option<Duration>,
+
+    /// Keep the combined rate of all copies under this many bytes per second, given with a binary
+    /// unit suffix such as `5M` if need be, so as not to overwhelm a device that's busy doing
+    /// something else, like indexing. Zero means unlimited, which is the default.
+    #[arg(long, env = "SYNC_LIMIT_RATE", value_name = "RATE", value_parser = parse_size)]
+    pub limit_rate: Option<u64>,
+
+    /// How much of a book to read and then write at a time, given like `--max-size`, which can
+    /// be tuned to suit the device's flash storage. Books are only copied this way when verifying,
+    /// limiting the rate, resuming, or showing each book's progress; otherwise, the OS copies them.
+    #[arg(
+        long,
+        env = "SYNC_COPY_BUFFER_SIZE",
+        value_name = "SIZE",
+        value_parser = parse_buffer_size,
+        default_value = "1M"
+    )]
+    pub copy_buffer_size: NonZeroUsize,
+
+    /// Whether to flush each book to the device before counting it as copied, and the
+    /// directories they were copied into at the end, so that the books the summary counts as
+    /// copied are safe to unplug. FAT devices over USB cache writes for a while otherwise. This
+    /// makes copying slower.
+    #[arg(long, env = "SYNC_FSYNC", default_value_t = false)]
+    pub fsync: bool,
+
+    /// Whether to give copies the modification times of their sources, as the Kobo sorts
+    /// sideloaded books by them. Without this, every book looks new after each sync. Pass
+    /// `--preserve-times=false` to stamp copies with the time they were copied instead.
+    #[arg(
+        long,
+        env = "SYNC_PRESERVE_TIMES",
+        value_name = "BOOL",
+        default_value_t = true,
+        num_args = 0..=1,
+        default_missing_value = "true",
+        action = clap::ArgAction::Set
+    )]
+    pub preserve_times: bool,
+
+    /// Whether to ask before copying or updating each book, answering `y` for yes, `n` for no,
+    /// `a` for yes to all remaining books, or `q` to stop. Combined with `--dry-run`, the answers
+    /// are only reported.
+    #[arg(long, env = "SYNC_INTERACTIVE", default_value_t = false)]
+    pub interactive: bool,
+
+    /// What to do when books from different source paths would be synchronised to the same
+    /// destination.
+    #[arg(long, env = "SYNC_ON_COLLISION", value_enum, default_value_t = CollisionPolicy::Skip)]
+    pub on_collision: CollisionPolicy,
+
+    /// How to tell whether a book already on the destination under the same name is the same
+    /// book: by `name` alone, by `size`, or by `hash`, which reads both in full. Books that differ
+    /// are copied over.
+    #[arg(long, env = "SYNC_COMPARE", value_enum, default_value_t = Compare::Name)]
+    pub compare: Compare,
+
+    /// What to do when a book's destination already exists: `never` overwrite it, overwrite it
+    /// `if-newer` or `if-different` by size or modification time, or `always` overwrite it. Books
+    /// are overwritten through a partial file, so an interrupted overwrite leaves the old one.
+    #[arg(long, env = "SYNC_OVERWRITE", value_enum, default_value_t = OverwritePolicy::Never)]
+    pub overwrite: OverwritePolicy,
+
+    /// The order in which to copy books. Anything but the order they're found in waits for every
+    /// book to be found first, holding them all in memory, so it can't be used when watching.
+    #[arg(
+        long,
+        env = "SYNC_ORDER_BY",
+        value_enum,
+        default_value_t = OrderBy::Discovered,
+        conflicts_with = "watch"
+    )]
+    pub order_by: OrderBy,
+
+    /// Whether to check that all books needing copying fit on the destination before copying any
+    /// of them, aborting if they don't. This waits for all books to be found before copying
+    /// starts.
+    #[arg(long, env = "SYNC_CHECK_FREE_SPACE", default_value_t = false)]
+    pub check_free_space: bool,
+
+    /// Like `--check-free-space`, but copy as many books as will fit, smallest first, rather than
+    /// aborting.
+    #[arg(long, env = "SYNC_FIT_WHAT_FITS", default_value_t = false)]
+    pub fit_what_fits: bool,
+
+    /// Whether to delete books from the destination that no longer exist in any documents
+    /// directory. Only files with a synchronised extension are ever deleted, and books still in a
+    /// documents directory but skipped by `--exclude`, `--include`, `--max-size`, `--since`,
+    /// `--max-depth`, `--one-file-system`, a `.syncignore` file, or for being hidden without
+    /// `--hidden` are left alone.
+    #[arg(long, env = "SYNC_DELETE", default_value_t = false)]
+    pub delete: bool,
+
+    /// A local directory into which to copy books that only exist on the destination, such as
+    /// those sideloaded onto it from another machine. They keep their paths relative to the
+    /// destination.
+    #[arg(long, env = "SYNC_PULL", value_name = "DIR")]
+    pub pull: Option<PathBuf>,
+
+    /// Whether to flush and unmount the device once synchronisation finishes, so that it can be
+    /// unplugged safely. This uses `udisksctl` on Linux and `diskutil` on macOS.
+    #[arg(long, env = "SYNC_EJECT", default_value_t = false)]
+    pub eject: bool,
+
+    /// Whether to unmount the device again once synchronisation finishes, if `--auto-mount`
+    /// mounted it.
+    #[arg(
+        long,
+        env = "SYNC_AUTO_UNMOUNT",
+        default_value_t = false,
+        requires = "auto_mount"
+    )]
+    pub auto_unmount: bool,
+
+    /// Whether to put books into Kobo collections named after the top-level folders of the
+    /// documents directories that they're in, such as `Fiction` for `~/Documents/Fiction/a.epub`.
+    /// The Kobo's database is backed up to `KoboReader.sqlite.sync-backup` first.
+    #[arg(long, env = "SYNC_COLLECTIONS_FROM_FOLDERS", default_value_t = false)]
+    pub collections_from_folders: bool,
+
+    /// Whether to keep running after the initial synchronisation, watching the documents
+    /// directories and synchronising new or modified books as they appear, until Ctrl-C is
+    /// pressed.
+    #[arg(long, env = "SYNC_WATCH", default_value_t = false)]
+    pub watch: bool,
+
+    /// Whether to keep running in the background, synchronising whenever the device is plugged
+    /// in, such as from a user service. The device is looked for every couple of seconds, and is
+    /// synchronised once each time it appears, until Ctrl-C is pressed or SIGTERM is received.
+    #[arg(
+        long,
+        env = "SYNC_DAEMON",
+        default_value_t = false,
+        conflicts_with_all = ["watch", "plan", "apply", "interactive", "files_from", "files_from0"]
+    )]
+    pub daemon: bool,
+
+    /// Whether to only plan the run, working out what it would do as a dry run does, and then
+    /// printing a preview of that grouped by what would be done with each book.
+    #[arg(
+        long,
+        env = "SYNC_PLAN",
+        default_value_t = false,
+        conflicts_with_all = ["apply", "watch"]
+    )]
+    pub plan: bool,
+
+    /// A file to write the plan to as JSON, for `--apply` to carry out later.
+    #[arg(long, env = "SYNC_PLAN_FILE", value_name = "PATH", requires = "plan")]
+    pub plan_file: Option<PathBuf>,
+
+    /// Carry out a plan written by `--plan-file`, copying exactly the books it planned to where it
+    /// planned to copy them. It's refused if any of those books have changed since, or if it was
+    /// made for another destination.
+    #[arg(
+        long,
+        env = "SYNC_APPLY",
+        value_name = "PATH",
+        conflicts_with_all = ["watch", "delete", "pull", "collections_from_folders"]
+    )]
+    pub apply: Option<PathBuf>,
+
+    /// A file listing the books to synchronise, one path per line, rather than finding them in the
+    /// documents directories; `-` reads the list from stdin. Blank lines and those starting with
+    /// `#` are ignored, and relative paths are resolved against the current directory.
+    #[arg(
+        long,
+        env = "SYNC_FILES_FROM",
+        value_name = "PATH",
+        conflicts_with_all = ["files_from0", "watch", "delete", "pull", "apply"]
+    )]
+    pub files_from: Option<PathBuf>,
+
+    /// Like `--files-from`, but with the paths separated by NUL characters, as `find -print0`
+    /// writes them.
+    #[arg(
+        long,
+        env = "SYNC_FILES_FROM0",
+        value_name = "PATH",
+        conflicts_with_all = ["watch", "delete", "pull", "apply"]
+    )]
+    pub files_from0: Option<PathBuf>,
+
+    /// Whether to copy books blindly, rather than skipping those that are empty or obviously
+    /// corrupt, such as EPUBs left half-downloaded.
+    #[arg(long, env = "SYNC_NO_VALIDATE", default_value_t = false)]
+    pub no_validate: bool,
+
+    /// A comma-separated list of extensions to prefer, most preferred first, such as `epub,pdf`.
+    /// When books differ only by extension, such as `dune.epub` and `dune.pdf`, wherever they are
+    /// in the documents directories, only the most preferred is synchronised and the rest are
+    /// counted as superseded. Every book is found before any is copied, so it can't be used when
+    /// watching.
+    #[arg(
+        long,
+        env = "SYNC_PREFER_FORMAT",
+        value_delimiter = ',',
+        value_parser = parse_extension,
+        conflicts_with = "watch"
+    )]
+    pub prefer_format: Vec<String>,
+
+    /// Whether to neither read nor remember the books found by earlier runs, which otherwise lets
+    /// the summary say what changed since the last run to the same destination. They're kept in
+    /// `runs.json` in the XDG state directory, usually `~/.local/state/sync-kobo-and-workstation`.
+    #[arg(long, env = "SYNC_NO_HISTORY", default_value_t = false)]
+    pub no_history: bool,
+
+    /// Whether to stop at the first book that fails to copy or verify, or the first file or
+    /// directory that can't be read while finding books, abandoning the copies in progress and
+    /// removing their partial files. Otherwise, failures are counted and listed at the end.
+    #[arg(long, env = "SYNC_FAIL_FAST", default_value_t = false)]
+    pub fail_fast: bool,
+
+    /// Append a record of each run to this file: when it started, the options in effect, what was
+    /// done with each book, and the final counters. Without a path, it goes to `history.log` in
+    /// the XDG state directory, usually `~/.local/state/sync-kobo-and-workstation`.
+    #[arg(long, env = "SYNC_AUDIT_LOG", value_name = "PATH", num_args = 0..=1)]
+    pub audit_log: Option<Option<PathBuf>>,
+
+    /// Once the audit log reaches this size, given like `--max-size`, move it aside to a file
+    /// with a `.1` suffix and start a new one, so that it doesn't grow without bound.
+    #[arg(
+        long,
+        env = "SYNC_AUDIT_LOG_MAX_SIZE",
+        value_name = "SIZE",
+        value_parser = parse_size,
+        default_value = "10M"
+    )]
+    pub audit_log_max_size: u64,
+
+    /// Copy at most this many bytes of books in a run, given like `--max-size`, deferring the rest
+    /// to a later run. Books already on the destination don't count towards it.
+    #[arg(long, env = "SYNC_MAX_TOTAL_BYTES", value_name = "SIZE", value_parser = parse_size)]
+    pub max_total_bytes: Option<u64>,
+
+    /// Whether to skip books last modified before the last successful run to the destination,
+    /// without even looking for them on it, which each run records at the destination's root.
+    /// Books modified since are synchronised as usual. If no run is recorded, every book is looked
+    /// at.
+    #[arg(
+        long,
+        env = "SYNC_INCREMENTAL",
+        default_value_t = false,
+        conflicts_with_all = ["watch", "delete", "pull", "collections_from_folders"]
+    )]
+    pub incremental: bool,
+
+    /// Whether to look at every book even when `--incremental` is given, such as by the
+    /// environment, after changing which books are synchronised.
+    #[arg(long, env = "SYNC_FULL", default_value_t = false)]
+    pub full: bool,
+
+    /// How long the lock another run keeps on the destination can go untouched before it's taken
+    /// to be left behind by a run that died, and broken with a warning, given like `30m` or `2h`.
+    /// Runs touch their locks every minute while they go.
+    #[arg(
+        long,
+        env = "SYNC_STALE_LOCK_AGE",
+        value_name = "DURATION",
+        default_value = "1h",
+        value_parser = parse_stale_lock_age
+    )]
+    pub stale_lock_age: Duration,
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(name = NAME, about, author, version, long_about = LONG_ABOUT)]
+pub struct PartialArgs {
+    #[command(subcommand)]
+    pub action: Option<Action>,
+    /// The directory of the mounted Kobo storage directory to which to synchronise the books and
+    /// documents.
+    #[arg(long, env = "SYNC_KOBO_DIRECTORY", global = true)]
+    pub kobo_directory: Option<PathBuf>,
+
+    /// Synchronise to an MTP device, such as a newer Kindle, that doesn't mount as mass storage.
+    /// It is found by a part of its name among the devices GVFS has mounted, so mount it first,
+    /// such as with a file manager or `gio mount`.
+    #[arg(
+        long,
+        env = "SYNC_MTP_DEVICE",
+        value_name = "NAME",
+        conflicts_with = "kobo_directory",
+        global = true
+    )]
+    pub mtp_device: Option<String>,
+
+    /// The kind of e-book reader to synchronise to. A Kindle gets Kindle-friendly formats by
+    /// default, synchronised into the `documents` directory of the volume given by
+    /// `--kobo-directory`.
+    #[arg(long, env = "SYNC_DEVICE", value_enum, global = true)]
+    pub device: Option<Device>,
+
+    /// The directory of the documents directories from which to synchronise books and documents.
+    /// Glob patterns, such as `'~/Library/*/books'`, make each directory they match a documents
+    /// directory.
+    #[arg(long, env = "SYNC_DOCUMENTS_DIRECTORIES", global = true)]
+    pub documents_directories: Option<Vec<PathBuf>>,
+
+    /// A Calibre library to synchronise books from, one format of each, as chosen by
+    /// `--prefer-format`, named on the device as `Author - Title.ext`. Its `metadata.db` is only
+    /// read. The default documents directories aren't synchronised too unless they're given.
+    #[arg(long, env = "SYNC_CALIBRE_LIBRARY", value_name = "PATH", global = true)]
+    pub calibre_library: Option<PathBuf>,
+
+    /// Only synchronise the books in the Calibre library with this tag, such as `kobo`.
+    #[arg(
+        long,
+        env = "SYNC_CALIBRE_TAG",
+        value_name = "TAG",
+        requires = "calibre_library",
+        global = true
+    )]
+    pub calibre_tag: Option<String>,
+
+    /// Whether to carry on when a documents directory pattern matches no directories, rather than
+    /// failing.
+    #[arg(
+        long,
+        env = "SYNC_ALLOW_EMPTY_SOURCES",
+        default_value_t = false,
+        global = true
+    )]
+    pub allow_empty_sources: bool,
+
+    /// Whether to dry run, documenting what would happen rather than doing it.
+    #[arg(long, env = "SYNC_DRY_RUN", default_value_t = false, global = true)]
+    pub dry_run: bool,
+
+    /// Whether to recreate the layout of the documents directories on the destination, rather than
+    /// flattening all books into the destination's root.
+    #[arg(
+        long,
+        env = "SYNC_MIRROR_STRUCTURE",
+        default_value_t = false,
+        global = true
+    )]
+    pub mirror_structure: bool,
+
+    /// Whether to name EPUBs on the device after the titles and authors in their metadata, as
+    /// `Author - Title.epub`, rather than after their files. Books whose metadata can't be read keep
+    /// their names, and books with the same title and author are told apart by a short hash of
+    /// their contents, as with `--on-collision suffix`.
+    #[arg(
+        long,
+        env = "SYNC_RENAME_FROM_METADATA",
+        default_value_t = false,
+        global = true
+    )]
+    pub rename_from_metadata: bool,
+
+    /// Whether to copy the covers and metadata files next to each book copied, such as `a.jpg`,
+    /// `a.opf`, and `a.pdf.jpg` for `a.pdf`, which some firmware picks up. They're named after the
+    /// book on the destination, and are left alone if already there, like books.
+    #[arg(
+        long,
+        env = "SYNC_INCLUDE_SIDECARS",
+        default_value_t = false,
+        global = true
+    )]
+    pub include_sidecars: bool,
+
+    /// A subdirectory of the device to synchronise books into rather than its root, such as
+    /// `Books`, which is created if it's missing. It must be a relative path without `..`, so that
+    /// it stays on the device.
+    #[arg(
+        long,
+        env = "SYNC_DEST_SUBDIR",
+        value_name = "SUBDIR",
+        value_parser = parse_subdir,
+        global = true
+    )]
+    pub dest_subdir: Option<PathBuf>,
+
+    /// Put books with an extension into a subdirectory of the destination rather than its root,
+    /// given as `EXT=SUBDIR`, such as `pdf=PDFs`. Can be repeated. The subdirectory is created when
+    /// first needed.
+    #[arg(
+        long,
+        env = "SYNC_DEST_FOR",
+        value_name = "EXT=SUBDIR",
+        value_parser = parse_route,
+        global = true
+    )]
+    pub dest_for: Vec<(String, PathBuf)>,
+
+    /// Whether to convert EPUBs to Kobo's KEPUB format while copying them, with the `kepubify`
+    /// program at the given path or, if no path is given, on the `PATH`. Converted books are named
+    /// `<name>.kepub.epub`. Books that fail to convert are copied as-is instead.
+    #[arg(
+        long,
+        env = "SYNC_KEPUBIFY",
+        value_name = "PATH",
+        num_args = 0..=1,
+        default_missing_value = "kepubify",
+        global = true
+    )]
+    pub kepubify: Option<PathBuf>,
+
+    /// Whether to convert books in formats the device can't read to EPUBs while copying them,
+    /// with Calibre's `ebook-convert` program at the given path or, if no path is given, on the
+    /// `PATH`. Converted books are named `<name>.epub`. Books that fail to convert are reported and
+    /// not copied.
+    #[arg(
+        long,
+        env = "SYNC_CONVERT_UNSUPPORTED",
+        value_name = "PATH",
+        num_args = 0..=1,
+        default_missing_value = "ebook-convert",
+        global = true
+    )]
+    pub convert_unsupported: Option<PathBuf>,
+
+    /// A comma-separated list of the extensions of the books to convert with
+    /// `--convert-unsupported`, which are synchronised as well as the other extensions.
+    #[arg(
+        long,
+        env = "SYNC_CONVERT_EXTENSIONS",
+        value_delimiter = ',',
+        value_parser = parse_extension,
+        default_value = "mobi,fb2",
+        global = true
+    )]
+    pub convert_extensions: Vec<String>,
+
+    /// Whether to convert every book afresh, rather than taking conversions of books converted by
+    /// earlier runs from the conversion cache, which is kept under `$XDG_CACHE_HOME`, or `~/.cache`
+    /// if that isn't set.
+    #[arg(
+        long,
+        env = "SYNC_NO_CONVERSION_CACHE",
+        default_value_t = false,
+        global = true
+    )]
+    pub no_conversion_cache: bool,
+
+    /// Whether to neither use nor update the `.sync-manifest.json` file at the root of the
+    /// destination, which records what earlier runs synchronised so that they needn't be checked
+    /// on the device again.
+    #[arg(long, env = "SYNC_NO_MANIFEST", default_value_t = false, global = true)]
+    pub no_manifest: bool,
+
+    /// Whether to mount the device with udisks2 if it's plugged in but not mounted, finding it by
+    /// its volume's label. This only works on Linux.
+    #[arg(
+        long,
+        env = "SYNC_AUTO_MOUNT",
+        default_value_t = false,
+        conflicts_with = "mtp_device",
+        global = true
+    )]
+    pub auto_mount: bool,
+
+    /// Whether to disable the progress bar, printing plain progress lines even on a terminal.
+    #[arg(long, env = "SYNC_NO_PROGRESS", default_value_t = false, global = true)]
+    pub no_progress: bool,
+
+    /// Whether to print sizes in the summary as plain numbers of bytes rather than in
+    /// human-readable units, for scripts to parse.
+    #[arg(long, env = "SYNC_BYTES", default_value_t = false, global = true)]
+    pub bytes: bool,
+
+    /// A file to which to also write everything logged, without colours. Set `RUST_LOG`, such as
+    /// to `debug`, to log more.
+    #[arg(long, env = "SYNC_LOG_FILE", global = true)]
+    pub log_file: Option<PathBuf>,
+
+    /// A file to write a report to of what was done with each book considered, including its
+    /// source and destination paths, its size, and how long copying it took. It is written even if
+    /// some books failed to copy.
+    #[arg(long, env = "SYNC_REPORT", value_name = "PATH", global = true)]
+    pub report: Option<PathBuf>,
+
+    /// The format of the `--report` file.
+    #[arg(
+        long,
+        env = "SYNC_REPORT_FORMAT",
+        value_enum,
+        default_value_t = ReportFormat::Json,
+        requires = "report",
+        global = true
+    )]
+    pub report_format: ReportFormat,
+
+    /// Whether to descend into symlinked directories within the documents directories. Each
+    /// directory is only walked once, however many symlinks lead to it, so symlink cycles are
+    /// safe.
+    #[arg(
+        long,
+        env = "SYNC_FOLLOW_SYMLINKS",
+        default_value_t = false,
+        global = true
+    )]
+    pub follow_symlinks: bool,
+
+    /// Whether to include hidden files in the documents directories, and descend into hidden
+    /// directories, which are otherwise skipped.
+    #[arg(long, env = "SYNC_HIDDEN", default_value_t = false, global = true)]
+    pub hidden: bool,
+
+    /// Whether to only synchronise one of the books with the same contents, however they're
+    /// named, such as the same EPUB downloaded from different places. Every book is hashed to tell,
+    /// which takes a while for large libraries.
+    #[arg(
+        long,
+        env = "SYNC_DEDUPE_CONTENT",
+        default_value_t = false,
+        global = true
+    )]
+    pub dedupe_content: bool,
+
+    /// Whether to hash every book afresh, rather than taking the digests of those unchanged since
+    /// an earlier run from the hash cache, which is kept under `$XDG_CACHE_HOME`, or `~/.cache` if
+    /// that isn't set.
+    #[arg(
+        long,
+        env = "SYNC_NO_HASH_CACHE",
+        default_value_t = false,
+        global = true
+    )]
+    pub no_hash_cache: bool,
+
+    /// Whether to empty the hash cache before the run, which then fills it again.
+    #[arg(
+        long,
+        env = "SYNC_CLEAR_HASH_CACHE",
+        default_value_t = false,
+        global = true
+    )]
+    pub clear_hash_cache: bool,
+
+    /// Whether to ignore the `.syncignore` files in the documents directories, which otherwise
+    /// exclude the books and directories beneath them that their glob patterns match, like
+    /// `.gitignore` files.
+    #[arg(
+        long,
+        env = "SYNC_NO_SYNCIGNORE",
+        default_value_t = false,
+        global = true
+    )]
+    pub no_syncignore: bool,
+
+    /// Whether to skip Calibre's bookkeeping when walking the documents directories: its
+    /// `.caltrash` and `.calnotes` directories, the `.original_*` files it keeps of converted
+    /// books, and EPUBs with the KEPUB converted from them beside them. Unless given, this is done
+    /// in each documents directory with a Calibre `metadata.db` at its root.
+    #[arg(
+        long,
+        env = "SYNC_CALIBRE_AWARE",
+        value_name = "BOOL",
+        num_args = 0..=1,
+        default_missing_value = "true",
+        action = clap::ArgAction::Set,
+        global = true
+    )]
+    pub calibre_aware: Option<bool>,
+
+    /// Whether to synchronise to a destination even if it doesn't have the device's marker
+    /// directory, such as `.kobo` on a Kobo or `system` on a Kindle, which otherwise suggests that
+    /// it's a mount point left behind after the device was unplugged.
+    #[arg(long, env = "SYNC_FORCE", default_value_t = false, global = true)]
+    pub force: bool,
+
+    /// Whether to ignore the configuration file at
+    /// `$XDG_CONFIG_HOME/sync-kobo-and-workstation/config.toml`.
+    #[arg(long, env = "SYNC_NO_CONFIG", default_value_t = false, global = true)]
+    pub no_config: bool,
+
+    /// A glob pattern, matched against paths relative to their documents directory, of books to
+    /// skip. Can be repeated.
+    #[arg(long, env = "SYNC_EXCLUDE", value_parser = parse_glob, global = true)]
+    pub exclude: Vec<Glob>,
+
+    /// A glob pattern, matched against paths relative to their documents directory, of books to
+    /// synchronise. Can be repeated. If given, only matching books are synchronised, although
+    /// `--exclude` still takes precedence.
+    #[arg(long, env = "SYNC_INCLUDE", value_parser = parse_glob, global = true)]
+    pub include: Vec<Glob>,
+
+    /// How many directories deep to look for books beneath each documents directory, with 0 only
+    /// looking at the files directly in them.
+    #[arg(long, env = "SYNC_MAX_DEPTH", value_name = "N", global = true)]
+    pub max_depth: Option<usize>,
+
+    /// Whether to skip directories on other filesystems than their documents directory, such as
+    /// bind mounts or network drives mounted beneath it.
+    #[arg(
+        long,
+        env = "SYNC_ONE_FILE_SYSTEM",
+        default_value_t = false,
+        global = true
+    )]
+    pub one_file_system: bool,
+
+    /// Skip books larger than this size, given in bytes or with a binary unit suffix such as
+    /// `200M` or `1.5G`.
+    #[arg(
+        long,
+        env = "SYNC_MAX_SIZE",
+        value_name = "SIZE",
+        value_parser = parse_size,
+        global = true
+    )]
+    pub max_size: Option<u64>,
+
+    /// Skip books last modified before this time, given as an RFC 3339 date or timestamp such as
+    /// `2024-01-01` or `2024-01-01T09:00:00Z`, or as a number of days or hours ago such as `30d` or
+    /// `12h`.
+    #[arg(long, env = "SYNC_SINCE", value_name = "TIME", value_parser = parse_since, global = true)]
+    pub since: Option<SystemTime>,
+
+    /// How to report the results of the run.
+    #[arg(
+        long,
+        env = "SYNC_OUTPUT",
+        value_enum,
+        default_value_t = OutputFormat::Text,
+        global = true
+    )]
+    pub output: OutputFormat,
+
+    /// When to colour the console output: `auto` colours it on a terminal unless `NO_COLOR` is
+    /// set, and JSON output is never coloured.
+    #[arg(long, env = "SYNC_COLOR", value_enum, default_value_t = ColorChoice::Auto, global = true)]
+    pub color: ColorChoice,
+
+    /// A comma-separated list of file extensions to synchronise, replacing the built-in set of
+    /// EPUB and PDF.
+    #[arg(
+        long,
+        env = "SYNC_EXTENSIONS",
+        value_delimiter = ',',
+        value_parser = parse_extension,
+        global = true
+    )]
+    pub extensions: Option<Vec<String>>,
+
+    /// A comma-separated list of named sets of extensions to synchronise instead of the built-in
+    /// set: `books`, `comics`, `kindle`, or `all`. Any `--extensions` are added to them.
+    #[arg(
+        long,
+        env = "SYNC_PRESET",
+        value_enum,
+        value_delimiter = ',',
+        global = true
+    )]
+    pub preset: Option<Vec<Preset>>,
+
+    #[command(flatten)]
+    pub sync: SyncArgs,
+}
+
+fn parse_glob(s: &str) -> Result<Glob> {
+    Glob::new(s).map_err(|err| anyhow!("invalid glob pattern: {err}"))
+}
+
+pub(crate) fn build_glob_set(globs: &[Glob]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(glob.clone());
+    }
+    Ok(builder.build()?)
+}
+
+fn parse_buffer_size(s: &str) -> Result<NonZeroUsize> {
+    usize::try_from(parse_size(s)?)
+        .ok()
+        .and_then(NonZeroUsize::new)
+        .ok_or_else(|| anyhow!("a buffer size must be at least one byte"))
+}
+
+/// Parse a size such as `500`, `200M`, or `1.5GiB` into bytes. Units are binary, so `1K` is 1024
+/// bytes, matching how sizes are shown in the summary.
+fn parse_size(s: &str) -> Result<u64> {
+    let trimmed = s.trim();
+    let lowercase = trimmed.to_lowercase();
+    let without_bytes = lowercase
+        .strip_suffix("ib")
+        .or_else(|| lowercase.strip_suffix('b'))
+        .unwrap_or(&lowercase);
+
+    let (number, multiplier) = match without_bytes.char_indices().last() {
+        Some((i, unit)) if unit.is_ascii_alphabetic() => {
+            let exponent = match unit {
+                'k' => 1,
+                'm' => 2,
+                'g' => 3,
+                't' => 4,
+                _ => return Err(anyhow!("unknown size unit in {trimmed}")),
+            };
+            (&without_bytes[..i], 1024u64.pow(exponent))
+        }
+        _ => (without_bytes, 1),
+    };
+
+    let number: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| anyhow!("invalid size {trimmed}; expected something like 200M or 1.5G"))?;
+    if !number.is_finite() || number < 0.0 {
+        return Err(anyhow!("a size must be a non-negative number"));
+    }
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Parse a cutoff time, either as an RFC 3339 date or timestamp, or as a whole number of days or
+/// hours before now. Bare dates are taken as midnight UTC.
+fn parse_since(s: &str) -> Result<SystemTime> {
+    let trimmed = s.trim();
+
+    let relative_unit = trimmed
+        .strip_suffix('d')
+        .map(|n| (n, 24 * 60 * 60))
+        .or_else(|| trimmed.strip_suffix('h').map(|n| (n, 60 * 60)));
+    if let Some((number, unit_secs)) = relative_unit {
+        if !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()) {
+            let ago = number
+                .parse::<u64>()
+                .ok()
+                .and_then(|n| n.checked_mul(unit_secs))
+                .map(Duration::from_secs)
+                .ok_or_else(|| anyhow!("{trimmed} is too far in the past"))?;
+            return SystemTime::now()
+                .checked_sub(ago)
+                .ok_or_else(|| anyhow!("{trimmed} is too far in the past"));
+        }
+    }
+
+    let invalid = || {
+        anyhow!(
+            "invalid time {trimmed}; expected an RFC 3339 date or timestamp such as 2024-01-01, \
+            or a relative time such as 30d or 12h"
+        )
+    };
+
+    let is_date_only = trimmed.len() == "YYYY-MM-DD".len() && !trimmed.contains(['T', 't', ' ']);
+    if is_date_only {
+        return humantime::parse_rfc3339(&format!("{trimmed}T00:00:00Z")).map_err(|_| invalid());
+    }
+
+    // `humantime` only understands UTC timestamps, so other offsets are applied separately.
+    let offset_at = trimmed.len().saturating_sub("+HH:MM".len());
+    let (utc, offset) = match trimmed.get(offset_at..).map(str::as_bytes) {
+        Some([sign @ (b'+' | b'-'), h1, h2, b':', m1, m2])
+            if [h1, h2, m1, m2].iter().all(|b| b.is_ascii_digit()) =>
+        {
+            let digit = |b: &u8| u64::from(b - b'0');
+            let secs = (digit(h1) * 10 + digit(h2)) * 60 * 60 + (digit(m1) * 10 + digit(m2)) * 60;
+            let utc = format!("{}Z", &trimmed[..offset_at]);
+            (utc, Some((*sign == b'+', Duration::from_secs(secs))))
+        }
+        _ => (trimmed.to_owned(), None),
+    };
+    let time = humantime::parse_rfc3339(&utc).map_err(|_| invalid())?;
+    match offset {
+        // A local time ahead of UTC is an earlier instant.
+        Some((true, offset)) => time.checked_sub(offset).ok_or_else(invalid),
+        Some((false, offset)) => time.checked_add(offset).ok_or_else(invalid),
+        None => Ok(time),
+    }
+}
+
+fn parse_copy_timeout(s: &str) -> Result<Duration> {
+    let secs: u64 = s
+        .parse()
+        .map_err(|_| anyhow!("expected a whole number of seconds, like 300"))?;
+    if secs == 0 {
+        return Err(anyhow!("the timeout must be at least a second"));
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+fn parse_stale_lock_age(s: &str) -> Result<Duration> {
+    let age =
+        humantime::parse_duration(s).map_err(|_| anyhow!("expected a duration, like 30m or 2h"))?;
+    if age < Duration::from_secs(1) {
+        return Err(anyhow!("the age must be at least a second"));
+    }
+    Ok(age)
+}
+
+fn parse_subdir(s: &str) -> Result<PathBuf> {
+    let subdir = PathBuf::from(s);
+    let is_within_dest = subdir
+        .components()
+        .all(|component| matches!(component, std::path::Component::Normal(_)));
+    if subdir.as_os_str().is_empty() || !is_within_dest {
+        return Err(anyhow!(
+            "the subdirectory must be a relative path within the destination, without `..`"
+        ));
+    }
+    Ok(subdir)
+}
+
+fn parse_route(s: &str) -> Result<(String, PathBuf)> {
+    let (ext, subdir) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected EXT=SUBDIR, such as pdf=PDFs"))?;
+    Ok((parse_extension(ext)?, parse_subdir(subdir)?))
+}
+
+pub(crate) fn parse_extension(s: &str) -> Result<String> {
+    let normalised = s.trim_start_matches('.').to_lowercase();
+    if normalised.is_empty() {
+        Err(anyhow!(
+            "an extension must contain at least one non-dot character"
+        ))
+    } else {
+        Ok(normalised)
+    }
+}
+
+/// The completion script for a shell.
+pub fn completions_for(shell: Shell) -> String {
+    let mut script = vec![];
+    generate(shell, &mut PartialArgs::command(), NAME, &mut script);
+    String::from_utf8_lossy(&script).into_owned()
+}
+
+/// The environment variables that arguments were taken from, along with their values.
+fn environment_sources(command: &Command, matches: &ArgMatches) -> Vec<(String, String)> {
+    command
+        .get_arguments()
+        .filter(|arg| matches.value_source(arg.get_id().as_str()) == Some(ValueSource::EnvVariable))
+        .filter_map(|arg| {
+            let variable = arg.get_env()?;
+            let value = env::var_os(variable)?;
+            Some((
+                variable.to_string_lossy().into_owned(),
+                value.to_string_lossy().into_owned(),
+            ))
+        })
+        .collect()
+}
+
+/// Parse the arguments, yielding them along with the environment variables that any were taken
+/// from. Flags take precedence over environment variables. The documents directories are given by
+/// a flag per directory, but by a single environment variable, so it's split like `PATH`.
+pub fn parse_partial_args() -> (PartialArgs, Vec<(String, String)>) {
+    let mut command = PartialArgs::command();
+    let matches = command.clone().get_matches();
+    let mut args = PartialArgs::from_arg_matches(&matches)
+        .unwrap_or_else(|err| err.format(&mut command).exit());
+
+    // Synchronising is the same with or without its subcommand, but its options are taken from
+    // before it or after it, not both.
+    if let Some(Action::Sync(sync)) = &args.action {
+        let sync = (**sync).clone();
+        let before = SyncArgs::augment_args(Command::new(NAME))
+            .get_arguments()
+            .find(|arg| {
+                matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+            })
+            .and_then(|arg| arg.get_long().map(str::to_owned));
+        if let Some(before) = before {
+            command
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    format!("`--{before}` must come after `sync`, not before it"),
+                )
+                .exit();
+        }
+        args.sync = sync;
+        args.action = None;
+    }
+
+    let documents_directories_source = matches.value_source("documents_directories");
+    if documents_directories_source == Some(ValueSource::EnvVariable) {
+        args.documents_directories = args.documents_directories.map(|dirs| {
+            dirs.iter()
+                .flat_map(|dir| env::split_paths(dir.as_os_str()).collect::<Vec<_>>())
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .collect()
+        });
+    }
+    (args, environment_sources(&command, &matches))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_results_to_outcomes() {
+        assert_eq!(Outcome::of(&Ok(0)), Outcome::Succeeded);
+        assert_eq!(Outcome::of(&Ok(3)), Outcome::CopiesFailed);
+        assert_eq!(Outcome::of(&Err(anyhow!("no config"))), Outcome::Failed);
+
+        let failures = [
+            (
+                RunFailure::Inaccessible("gone".into()),
+                Outcome::Inaccessible,
+            ),
+            (
+                RunFailure::Interrupted("stopped".into()),
+                Outcome::Interrupted,
+            ),
+            (RunFailure::Locked("held".into()), Outcome::Locked),
+        ];
+        for (failure, outcome) in failures {
+            // Failures keep their outcomes through any context added on the way up.
+            let result = Err(anyhow::Error::from(failure).context("while synchronising"));
+            assert_eq!(Outcome::of(&result), outcome);
+        }
+    }
+
+    #[test]
+    fn maps_outcomes_to_distinct_exit_codes() {
+        let codes = [
+            (Outcome::Succeeded, 0),
+            (Outcome::Failed, 1),
+            (Outcome::Inaccessible, 2),
+            (Outcome::CopiesFailed, 3),
+            (Outcome::Interrupted, 4),
+            (Outcome::Locked, 5),
+        ];
+        for (outcome, code) in codes {
+            assert_eq!(ExitCode::from(outcome), ExitCode::from(code));
+        }
+    }
+
+    #[test]
+    fn completes_options_and_subcommands_in_every_shell() {
+        for &shell in Shell::value_variants() {
+            let script = completions_for(shell);
+            assert!(script.contains("kobo-directory"), "{shell} lacks an option");
+            assert!(
+                script.contains("prune-duplicates"),
+                "{shell} lacks a subcommand"
+            );
+        }
+    }
+
+    #[test]
+    fn completes_the_values_of_options_with_a_fixed_set() {
+        let script = completions_for(Shell::Bash);
+        assert!(script.contains(r#"compgen -W "text json""#));
+    }
+}
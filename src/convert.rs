@@ -0,0 +1,221 @@
+//! Converting books the device can't read to EPUBs with Calibre's `ebook-convert` on the way to it.
+
+use {
+    crate::{
+        copy::{copy_through_partial, hash_file, to_hex, CopyKind, CopyOptions, CopyTask},
+        hash_cache::HashCache,
+        kepub::{is_epub, is_kepub_conversion},
+        report::record_failure,
+        stats::Statistic,
+        Run,
+    },
+    anyhow::{anyhow, Result},
+    std::{
+        env,
+        ffi::OsStr,
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    },
+    tokio::{
+        fs,
+        process::Command,
+        sync::{mpsc::Sender, Semaphore},
+        task::spawn,
+    },
+    tracing::{debug, error},
+};
+
+/// The extensions of the books converted to EPUBs when converting is asked for, unless told
+/// otherwise.
+pub(crate) const DEFAULT_CONVERT_EXTENSIONS: &[&str] = &["mobi", "fb2"];
+
+/// Which books to convert to EPUBs, and how.
+#[derive(Debug)]
+pub(crate) struct Conversion {
+    /// The `ebook-convert` program to convert them with.
+    pub(crate) program: PathBuf,
+
+    /// The extensions of the books to convert, lowercase and without a leading dot.
+    pub(crate) extensions: Vec<String>,
+
+    /// Where conversions are kept between runs, named after the digests of the books they were
+    /// converted from, if anywhere.
+    pub(crate) cache: Option<PathBuf>,
+}
+
+impl Conversion {
+    /// Whether a book is in one of the formats to convert.
+    pub(crate) fn converts(&self, path: &Path) -> bool {
+        path.extension()
+            .and_then(OsStr::to_str)
+            .map(|ext| self.extensions.contains(&ext.to_lowercase()))
+            .unwrap_or(false)
+    }
+}
+
+/// Whether a destination is an EPUB converted from a book in another format.
+pub(crate) fn is_epub_conversion(src_path: &Path, dest_path: &Path) -> bool {
+    !is_epub(src_path) && is_epub(dest_path)
+}
+
+/// Whether a destination was converted from its source in any way, in which case their sizes and
+/// contents never match.
+pub(crate) fn is_conversion(src_path: &Path, dest_path: &Path) -> bool {
+    is_kepub_conversion(src_path, dest_path) || is_epub_conversion(src_path, dest_path)
+}
+
+/// A file in a directory to convert a book into. It has to end in `.epub`, as that's how
+/// `ebook-convert` knows what to convert it to.
+fn conversion_path(dir: &Path) -> PathBuf {
+    static CONVERSIONS: AtomicUsize = AtomicUsize::new(0);
+
+    let id = CONVERSIONS.fetch_add(1, Ordering::Relaxed);
+    dir.join(format!(
+        "{}-{}-{id}.epub",
+        env!("CARGO_PKG_NAME"),
+        std::process::id()
+    ))
+}
+
+/// A book converted to an EPUB, and whether it's only a temporary file rather than one kept in the
+/// cache.
+struct Converted {
+    path: PathBuf,
+    temporary: bool,
+}
+
+async fn run_ebook_convert(program: &Path, src_path: &Path, converted: &Path) -> Result<()> {
+    let output = Command::new(program)
+        .arg(src_path)
+        .arg(converted)
+        .output()
+        .await
+        .map_err(|err| anyhow!("could not run {}: {err}", program.display()))?;
+
+    if !output.status.success() {
+        let _ = fs::remove_file(converted).await;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow!(
+            "ebook-convert failed with {}: {}",
+            output.status,
+            stderr.trim()
+        ));
+    }
+    if !fs::try_exists(converted).await? {
+        return Err(anyhow!(
+            "ebook-convert did not write {}",
+            converted.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Convert a book to an EPUB, or take its conversion from the cache if an earlier run already
+/// converted the same contents. New conversions are written beside where they're cached and
+/// renamed into place once complete, so that one cut short is never taken for a whole one.
+async fn convert_to_epub(
+    conversion: &Conversion,
+    src_path: &Path,
+    hash_cache: Option<&HashCache>,
+) -> Result<Converted> {
+    let Some(cache) = &conversion.cache else {
+        let path = conversion_path(&env::temp_dir());
+        run_ebook_convert(&conversion.program, src_path, &path).await?;
+        return Ok(Converted {
+            path,
+            temporary: true,
+        });
+    };
+
+    let cached = cache.join(format!(
+        "{}.epub",
+        to_hex(&hash_file(src_path, hash_cache).await?)
+    ));
+    if fs::try_exists(&cached).await? {
+        let (src_str, cached_str) = (src_path.display(), cached.display());
+        debug!(path = %src_str, "Using the conversion of {src_str} cached at {cached_str}");
+        return Ok(Converted {
+            path: cached,
+            temporary: false,
+        });
+    }
+
+    fs::create_dir_all(cache).await?;
+    let partial = conversion_path(cache);
+    run_ebook_convert(&conversion.program, src_path, &partial).await?;
+    if let Err(err) = fs::rename(&partial, &cached).await {
+        let _ = fs::remove_file(&partial).await;
+        return Err(err.into());
+    }
+    Ok(Converted {
+        path: cached,
+        temporary: false,
+    })
+}
+
+/// Convert a book to an EPUB and copy that to its destination. A book that fails to convert is
+/// reported and counted as failed by the task itself, so that it doesn't abort the others.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn convert_and_copy(
+    src_path: &Path,
+    dest_path: &Path,
+    kind: CopyKind,
+    options: &CopyOptions,
+    conversion: Arc<Conversion>,
+    copy_permits: &Arc<Semaphore>,
+    stats: &Sender<Statistic>,
+    run: &Run,
+) -> Result<CopyTask> {
+    let (src_path, dest_path) = (src_path.to_path_buf(), dest_path.to_path_buf());
+    let (options, copy_permits, stats) = (options.clone(), copy_permits.clone(), stats.clone());
+    let run = run.clone();
+    Ok(spawn(async move {
+        // Conversions are bounded by the same permits as copies.
+        let converting = {
+            let _permit = copy_permits.acquire().await?;
+            convert_to_epub(&conversion, &src_path, run.hash_cache()).await
+        };
+        let (src_str, dest_str) = (src_path.display(), dest_path.display());
+
+        let converted = match converting {
+            Ok(converted) => converted,
+            Err(err) => {
+                error!(
+                    path = %src_str,
+                    dest = %dest_str,
+                    "Failed to convert {src_str} with ebook-convert, so it was not copied: {err:#}"
+                );
+                stats.send(Statistic::ConversionFailed).await?;
+                let failed = format!("ebook-convert failed: {err:#}");
+                record_failure(&stats, &src_path, &dest_path, failed, Duration::ZERO).await?;
+                run.advance_progress();
+                return Ok(None);
+            }
+        };
+        debug!(path = %src_str, dest = %dest_str, "Converted {src_str} with ebook-convert");
+
+        let copying = copy_through_partial(
+            &converted.path,
+            &src_path,
+            &dest_path,
+            kind,
+            &options,
+            &copy_permits,
+            &stats,
+            &run,
+        )
+        .await;
+        let copied = match copying {
+            Ok(copy_task) => copy_task.await?,
+            Err(err) => Err(err),
+        };
+        if converted.temporary {
+            let _ = fs::remove_file(&converted.path).await;
+        }
+        copied
+    }))
+}
@@ -0,0 +1,1016 @@
+//! Copying books to the destination safely, verifiably, and resumably.
+
+use {
+    crate::{
+        convert::{convert_and_copy, is_conversion, is_epub_conversion, Conversion},
+        hash_cache::HashCache,
+        kepub::{copy_or_convert, is_kepub_conversion},
+        report::{record_action, record_failure, Action},
+        stats::Statistic,
+        synchronise::{CollisionPolicy, Compare, OrderBy, OverwritePolicy},
+        Run,
+    },
+    anyhow::{Error, Result},
+    blake3::{Hash, Hasher},
+    std::{
+        collections::HashMap,
+        num::{NonZeroU64, NonZeroUsize},
+        path::{Path, PathBuf},
+        sync::Arc,
+        time::{Duration, Instant},
+    },
+    tokio::{
+        fs::{self, File},
+        io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom},
+        select,
+        sync::{mpsc::Sender, Mutex, Semaphore},
+        task::{spawn, spawn_blocking, JoinHandle},
+        time::{sleep, sleep_until, timeout},
+    },
+    tracing::{debug, error, info, warn},
+};
+
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+/// How much of a book to read and write at a time, unless told otherwise.
+pub(crate) const DEFAULT_COPY_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// How many bytes of a book to copy between reports of how far its copy has got.
+const PROGRESS_REPORT_INTERVAL: u64 = 1024 * 1024;
+
+const RETRY_BASE_BACKOFF: Duration = Duration::from_millis(500);
+
+/// The longest to wait between attempts at a copy, however many retries there are.
+const RETRY_MAX_BACKOFF: Duration = Duration::from_secs(8);
+
+/// Why a book was not copied across. Only a destination that already exists is an expected,
+/// benign outcome; everything else is a genuine failure that should be surfaced to the user.
+#[derive(Debug)]
+pub(crate) enum CopyError {
+    AlreadyExists,
+    Failed(Error),
+}
+
+impl From<Error> for CopyError {
+    fn from(err: Error) -> Self {
+        CopyError::Failed(err)
+    }
+}
+
+impl From<io::Error> for CopyError {
+    fn from(err: io::Error) -> Self {
+        CopyError::Failed(err.into())
+    }
+}
+
+/// Options controlling how found books are synchronised to the destination.
+#[derive(Clone, Debug)]
+pub(crate) struct CopyOptions {
+    pub(crate) dry_run: bool,
+    pub(crate) mirror_structure: bool,
+    pub(crate) update: bool,
+    pub(crate) verify: bool,
+    pub(crate) max_concurrent_copies: NonZeroUsize,
+
+    /// Whether to continue interrupted copies from where their partial files left off.
+    pub(crate) resume: bool,
+
+    /// How many times to retry a copy that fails with a transient device error.
+    pub(crate) retries: u32,
+
+    /// How long to let a copy run, retries included, before giving up on it, if at all.
+    pub(crate) copy_timeout: Option<Duration>,
+
+    /// Whether to ask before copying or updating each book.
+    pub(crate) interactive: bool,
+
+    /// What to do when books from different source paths would be synchronised to the same
+    /// destination.
+    pub(crate) on_collision: CollisionPolicy,
+
+    /// The order in which to copy the books found.
+    pub(crate) order_by: OrderBy,
+
+    /// How to tell whether a book already on the destination under the same name is the same book.
+    pub(crate) compare: Compare,
+
+    /// What to do when a book's destination already exists.
+    pub(crate) overwrite: OverwritePolicy,
+
+    /// The `kepubify` program to convert EPUBs to KEPUBs with, if they should be. It's shared, so
+    /// that these options stay cheap to clone into every copy.
+    pub(crate) kepubify: Option<Arc<Path>>,
+
+    /// Which books to convert to EPUBs with `ebook-convert`, if any should be. Shared, like
+    /// `kepubify`.
+    pub(crate) conversion: Option<Arc<Conversion>>,
+
+    /// Whether to check that the books needing copying fit on the destination before copying any
+    /// of them, which means waiting for all books to be found first.
+    pub(crate) check_free_space: bool,
+
+    /// When the books don't all fit, whether to copy as many as will fit, smallest first, rather
+    /// than aborting. Implies `check_free_space`.
+    pub(crate) fit_what_fits: bool,
+
+    /// The most bytes of books to copy in a run, after which the rest are deferred to a later one.
+    /// Books already on the destination don't count towards it.
+    pub(crate) max_total_bytes: Option<u64>,
+
+    /// Subdirectories of the destination to put books into by their extension, rather than its root.
+    /// Extensions are lowercase and without a leading dot. Shared, like `kepubify`.
+    pub(crate) routes: Arc<[(String, PathBuf)]>,
+
+    /// Whether to skip books recorded as already synchronised in the destination's manifest, and
+    /// to record the books synchronised by this run in it.
+    pub(crate) manifest: bool,
+
+    /// How many bytes of a book to read and then write at a time, when it isn't left to the OS to
+    /// copy.
+    pub(crate) copy_buffer_size: NonZeroUsize,
+
+    /// Whether to flush each copy to the device before it counts as copied, rather than leaving
+    /// the OS to write it out whenever it likes, and to flush the directories copied into once
+    /// the run's copies are done.
+    pub(crate) fsync: bool,
+
+    /// The limit on the combined rate of all copies, if there is one. It's shared between every
+    /// copy of the run.
+    pub(crate) rate_limiter: Option<Arc<RateLimiter>>,
+
+    /// Whether to give copies the modification times of their sources, rather than the time they
+    /// were copied, as devices like the Kobo sort sideloaded books by it.
+    pub(crate) preserve_times: bool,
+
+    /// Whether to name EPUBs on the destination after the titles and authors in their metadata.
+    pub(crate) rename_from_metadata: bool,
+
+    /// If applying a plan, where it copies each of its books to, which is used rather than
+    /// working that out again. Shared, like `kepubify`.
+    pub(crate) planned: Option<Arc<HashMap<PathBuf, PathBuf>>>,
+
+    /// Whether to report how far each copy has got, which is only worth the cost of copying in
+    /// chunks, rather than leaving the OS to copy each book in one go, when something is listening
+    /// for the run's events.
+    pub(crate) report_progress: bool,
+
+    /// Whether to copy the covers and metadata files alongside books, such as `a.jpg` for `a.pdf`,
+    /// with the books copied.
+    pub(crate) include_sidecars: bool,
+}
+
+/// Where to report how far a copy has got.
+struct CopyProgress<'a> {
+    stats: &'a Sender<Statistic>,
+    source: &'a Path,
+    destination: &'a Path,
+
+    /// The size of the book.
+    total: u64,
+}
+
+impl CopyProgress<'_> {
+    /// Report how many bytes of the book have been copied so far. A copy shouldn't wait on its own
+    /// progress reports, so any that there's no room for are dropped.
+    fn report(&self, bytes: u64) {
+        let _ = self.stats.try_send(Statistic::CopyProgressed {
+            source: self.source.to_path_buf(),
+            destination: self.destination.to_path_buf(),
+            bytes,
+            total: self.total,
+        });
+    }
+}
+
+/// Keeps the combined transfer rate of concurrent copies under a limit, by having each copy
+/// reserve time for every chunk it writes and wait until its reservation comes round.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    bytes_per_second: u64,
+
+    /// When the reservations made so far will have been used up.
+    next_free: Mutex<tokio::time::Instant>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(bytes_per_second: NonZeroU64) -> Self {
+        RateLimiter {
+            bytes_per_second: bytes_per_second.get(),
+            next_free: Mutex::new(tokio::time::Instant::now()),
+        }
+    }
+
+    /// Wait until this many bytes can be written without exceeding the limit.
+    async fn reserve(&self, bytes: usize) {
+        let start = {
+            let mut next_free = self.next_free.lock().await;
+            // Time spent idle isn't saved up, so copies can't burst above the limit after a lull.
+            let start = (*next_free).max(tokio::time::Instant::now());
+            *next_free =
+                start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_second as f64);
+            start
+        };
+        sleep_until(start).await;
+    }
+}
+
+/// Copy a source book to its destination a buffer's worth at a time, yielding the number of bytes
+/// written and, if `verify` is set, a BLAKE3 digest of the source as it was read. Reads can fill
+/// less than the buffer before the end of the book, so only what each read yields is written.
+///
+/// If `resume_from` is non-zero, the destination is assumed to already hold that many bytes of the
+/// source, and only the rest is copied after it.
+async fn copy_book(
+    src: &mut File,
+    dest: &mut File,
+    verify: bool,
+    resume_from: u64,
+    buffer_size: NonZeroUsize,
+    rate_limiter: Option<&RateLimiter>,
+    progress: Option<&CopyProgress<'_>>,
+) -> io::Result<(u64, Option<Hash>)> {
+    dest.seek(SeekFrom::Start(resume_from)).await?;
+
+    let mut hasher = verify.then(Hasher::new);
+    let mut buf = vec![0; buffer_size.get()];
+
+    match &mut hasher {
+        // The digest must cover the whole source, so the part that was already copied is read and
+        // hashed without being written again.
+        Some(hasher) => {
+            let mut to_skip = resume_from;
+            while 0 < to_skip {
+                let len = buf.len().min(to_skip.try_into().unwrap_or(usize::MAX));
+                let read = src.read(&mut buf[..len]).await?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&buf[..read]);
+                to_skip -= read as u64;
+            }
+        }
+        None => {
+            src.seek(SeekFrom::Start(resume_from)).await?;
+        }
+    }
+
+    let mut written = 0;
+    let mut last_reported = 0;
+    loop {
+        let read = src.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        if let Some(hasher) = &mut hasher {
+            hasher.update(&buf[..read]);
+        }
+        if let Some(rate_limiter) = rate_limiter {
+            rate_limiter.reserve(read).await;
+        }
+        dest.write_all(&buf[..read]).await?;
+        written += read as u64;
+        if let Some(progress) = progress {
+            if PROGRESS_REPORT_INTERVAL <= written - last_reported {
+                progress.report(resume_from + written);
+                last_reported = written;
+            }
+        }
+    }
+    dest.flush().await?;
+    Ok((written, hasher.as_ref().map(Hasher::finalize)))
+}
+
+pub(crate) fn to_hex(digest: &Hash) -> String {
+    digest.to_hex().to_string()
+}
+
+/// Hash a file, or take its digest from the run's hash cache, if it has one, when the file is
+/// unchanged since it was cached.
+pub(crate) async fn hash_file(path: &Path, hash_cache: Option<&HashCache>) -> io::Result<Hash> {
+    let Some(hash_cache) = hash_cache else {
+        return hash_contents(path).await;
+    };
+    let cached = hash_cache.cached_digest(path).await;
+    if let Some((_, Some(digest))) = cached {
+        return Ok(digest);
+    }
+    let digest = hash_contents(path).await?;
+    if let Some((key, _)) = cached {
+        hash_cache.cache_digest(key, &digest);
+    }
+    Ok(digest)
+}
+
+/// Hash a file by reading the whole of it, regardless of the hash cache.
+pub(crate) async fn hash_contents(path: &Path) -> io::Result<Hash> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Hasher::new();
+    let mut buf = vec![0; HASH_BUFFER_SIZE];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hasher.finalize())
+}
+
+/// Re-read a freshly written file and compare it against the digest of its source, deleting it
+/// and reporting the failure on a mismatch. Yields whether the file was intact.
+async fn verify_or_discard(
+    written_path: &Path,
+    expected: Option<Hash>,
+    src_str: &str,
+    stats: &Sender<Statistic>,
+) -> Result<bool> {
+    let Some(expected) = expected else {
+        return Ok(true);
+    };
+    // What was written must be read back, rather than trusting a cached digest.
+    if hash_contents(written_path).await? == expected {
+        return Ok(true);
+    }
+
+    fs::remove_file(written_path).await?;
+    let written_str = written_path.display();
+    warn!(
+        path = %written_str,
+        "Verification of {written_str} failed after copying it from {src_str}; it was deleted."
+    );
+    stats.send(Statistic::VerificationFailed).await?;
+    Ok(false)
+}
+
+/// A book that a copy task put on the destination.
+#[derive(Debug)]
+pub(crate) struct CopiedBook {
+    /// Where the book ended up, which can differ from where it was meant to go if its conversion
+    /// failed.
+    pub(crate) dest_path: PathBuf,
+
+    /// The BLAKE3 digest of the book as written, if it was verified.
+    pub(crate) digest: Option<Hash>,
+}
+
+/// A running copy, yielding the copied book if it succeeded. Failures are reported and counted by
+/// the task itself.
+pub(crate) type CopyTask = JoinHandle<Result<Option<CopiedBook>>>;
+
+/// Wait for a copy task to finish. Should it fail in a way it couldn't report itself, such as by
+/// panicking, it's reported and counted here instead, so that one bad copy doesn't abandon the
+/// others.
+pub(crate) async fn await_copy(
+    task: CopyTask,
+    stats: &Sender<Statistic>,
+    run: &Run,
+) -> Result<Option<CopiedBook>> {
+    match task.await.map_err(Error::from).and_then(|copied| copied) {
+        Ok(copied) => Ok(copied),
+        Err(err) => {
+            error!("A copy failed: {err:#}");
+            stats.send(Statistic::CopyFailed).await?;
+            run.advance_progress();
+            Ok(None)
+        }
+    }
+}
+
+/// Whether the overwrite policy says to copy over a book's existing destination. Converted books
+/// never match their source's size, so only their times are compared.
+pub(crate) async fn overwrites(
+    src_path: &Path,
+    dest_path: &Path,
+    policy: OverwritePolicy,
+) -> io::Result<bool> {
+    let (src, dest) = match policy {
+        OverwritePolicy::Never => return Ok(false),
+        OverwritePolicy::Always => return Ok(true),
+        OverwritePolicy::IfNewer | OverwritePolicy::IfDifferent => (
+            fs::metadata(src_path).await?,
+            fs::metadata(dest_path).await?,
+        ),
+    };
+    let (src_modified, dest_modified) = (src.modified().ok(), dest.modified().ok());
+    Ok(match policy {
+        OverwritePolicy::IfNewer => matches!(
+            (src_modified, dest_modified),
+            (Some(src_modified), Some(dest_modified)) if dest_modified < src_modified
+        ),
+        _ => {
+            (src.len() != dest.len() && !is_conversion(src_path, dest_path))
+                || src_modified != dest_modified
+        }
+    })
+}
+
+/// Copy a book to its destination if it doesn't exist yet or, if it does, if the overwrite policy
+/// says to, in which case it's replaced through a partial file like an update. Pulled books never
+/// overwrite anything.
+pub(crate) async fn copy_with_policy(
+    src_path: &Path,
+    dest_path: &Path,
+    mut kind: CopyKind,
+    options: &CopyOptions,
+    copy_permits: &Arc<Semaphore>,
+    stats: &Sender<Statistic>,
+    run: &Run,
+) -> Result<CopyTask, CopyError> {
+    // This is checked even when dry-running, so that a dry run previews what a real one would do.
+    if fs::try_exists(dest_path).await? {
+        let policy = match kind {
+            CopyKind::Pull => OverwritePolicy::Never,
+            CopyKind::New | CopyKind::Update => options.overwrite,
+        };
+        let (src, dest) = (src_path.display(), dest_path.display());
+        let policy_name = policy.as_str();
+        if !overwrites(src_path, dest_path, policy).await? {
+            if policy != OverwritePolicy::Never {
+                debug!(
+                    path = %src,
+                    dest = %dest,
+                    policy = policy_name,
+                    "Not overwriting {dest} with {src}, as the overwrite policy is {policy_name}"
+                );
+            }
+            return Err(CopyError::AlreadyExists);
+        }
+        info!(
+            path = %src,
+            dest = %dest,
+            policy = policy_name,
+            "Overwriting {dest} with {src}, as the overwrite policy is {policy_name}"
+        );
+        kind = CopyKind::Update;
+    }
+
+    if options.dry_run {
+        let (src, dest) = (src_path.display(), dest_path.display());
+        if let CopyKind::Pull = kind {
+            info!(
+                path = %src,
+                dest = %dest,
+                "Dry-running; would otherwise pull {src} to {dest}"
+            );
+        } else if let CopyKind::Update = kind {
+            info!(
+                path = %src,
+                dest = %dest,
+                "Dry-running; would otherwise overwrite {dest} with {src}"
+            );
+        } else if options.kepubify.is_some() && is_kepub_conversion(src_path, dest_path) {
+            info!(
+                path = %src,
+                dest = %dest,
+                "Dry-running; would otherwise convert {src} to {dest} with kepubify"
+            );
+        } else if options.conversion.is_some() && is_epub_conversion(src_path, dest_path) {
+            info!(
+                path = %src,
+                dest = %dest,
+                "Dry-running; would otherwise convert {src} to {dest} with ebook-convert"
+            );
+        } else {
+            info!(
+                path = %src,
+                dest = %dest,
+                "Dry-running; would otherwise copy {src} to {dest}"
+            );
+        }
+        let len = fs::metadata(src_path).await?.len();
+        let statistic = match kind {
+            CopyKind::Pull => Statistic::Pulled(len),
+            CopyKind::New => Statistic::WouldCopy(len),
+            CopyKind::Update => Statistic::WouldUpdate(len),
+        };
+        stats.send(statistic).await.map_err(Error::from)?;
+        record_action(
+            stats,
+            src_path,
+            dest_path,
+            Action::DryRun,
+            len,
+            Duration::ZERO,
+        )
+        .await?;
+        run.advance_progress();
+        Ok(spawn(async { Ok(None) }))
+    } else {
+        let copy_task = match &options.conversion {
+            Some(conversion) if is_epub_conversion(src_path, dest_path) => {
+                let (conversion, permits) = (conversion.clone(), copy_permits);
+                convert_and_copy(
+                    src_path, dest_path, kind, options, conversion, permits, stats, run,
+                )
+                .await?
+            }
+            _ => {
+                copy_or_convert(src_path, dest_path, kind, options, copy_permits, stats, run)
+                    .await?
+            }
+        };
+        Ok(copy_task)
+    }
+}
+
+async fn report_out_of_space(src_str: &str, stats: &Sender<Statistic>, run: &Run) -> Result<()> {
+    warn!(
+        path = %src_str,
+        "The destination ran out of space while copying {src_str}; the partial copy was removed, \
+        so as not to leave it full."
+    );
+    stats.send(Statistic::OutOfSpace).await?;
+    run.advance_progress();
+    Ok(())
+}
+
+/// Whether the destination copy of a book is stale, i.e. its source is newer or its size differs.
+/// Converted books never match their source's size, so only their times are compared.
+pub(crate) async fn is_outdated(src_path: &Path, dest_path: &Path) -> Result<bool> {
+    let (src, dest) = (
+        fs::metadata(src_path).await?,
+        fs::metadata(dest_path).await?,
+    );
+
+    if src.len() != dest.len() && !is_conversion(src_path, dest_path) {
+        return Ok(true);
+    }
+    match (src.modified(), dest.modified()) {
+        (Ok(src_modified), Ok(dest_modified)) => Ok(dest_modified < src_modified),
+        _ => Ok(false),
+    }
+}
+
+/// Deal with the partial file of a copy that didn't finish, returning how to say so in the log.
+/// It's kept for `--resume` to continue, if given, and removed otherwise.
+async fn settle_partial(partial_path: &Path, resume: bool) -> &'static str {
+    if resume {
+        "the partial copy was kept for --resume"
+    } else {
+        let _ = fs::remove_file(partial_path).await;
+        "the partial copy was removed"
+    }
+}
+
+pub(crate) fn partial_path_for(dest_path: &Path) -> PathBuf {
+    let mut name = dest_path.file_name().unwrap_or_default().to_owned();
+    name.push(".sync-partial");
+    dest_path.with_file_name(name)
+}
+
+/// How far through a previous, interrupted copy of the source a partial file got, if it can be
+/// resumed. It can't be if the source was modified after the partial file was last written to,
+/// since the bytes already copied may then be stale.
+async fn resumable_offset(src_path: &Path, partial_path: &Path) -> Option<u64> {
+    let partial = fs::metadata(partial_path).await.ok()?;
+    let src = fs::metadata(src_path).await.ok()?;
+
+    let is_unchanged = match (src.modified(), partial.modified()) {
+        (Ok(src_modified), Ok(partial_modified)) => src_modified <= partial_modified,
+        _ => false,
+    };
+    (is_unchanged && 0 < partial.len() && partial.len() < src.len()).then_some(partial.len())
+}
+
+/// Whether a copy puts a new book on the destination, replaces an outdated one, or pulls one from
+/// the destination back to the workstation.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum CopyKind {
+    New,
+    Update,
+    Pull,
+}
+
+/// Whether an I/O error plausibly comes from transient trouble with the device, such as a flaky
+/// USB connection, rather than from something that retrying won't fix.
+fn is_transient(err: &io::Error) -> bool {
+    // `EIO` has no `io::ErrorKind` of its own.
+    #[cfg(unix)]
+    const EIO: i32 = 5;
+    #[cfg(unix)]
+    if err.raw_os_error() == Some(EIO) {
+        return true;
+    }
+
+    matches!(
+        err.kind(),
+        io::ErrorKind::TimedOut
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+/// Give a copied book the modification time of its source.
+pub(crate) async fn preserve_modified_time(src_path: &Path, dest_path: &Path) -> io::Result<()> {
+    let modified = fs::metadata(src_path).await?.modified()?;
+    let dest_path = dest_path.to_path_buf();
+    spawn_blocking(move || {
+        std::fs::File::options()
+            .write(true)
+            .open(dest_path)?
+            .set_modified(modified)
+    })
+    .await?
+}
+
+/// Flush the entries of the books moved into a directory to the device, which is only needed as
+/// well as flushing the books themselves on filesystems such as FAT, where the directory records
+/// which books exist and when they were modified. Some can't flush directories, such as some
+/// FAT mounts, and Windows can't open them as files, so failing to only warns.
+pub(crate) async fn sync_directory(dir: &Path) {
+    #[cfg(not(windows))]
+    let synced = async { File::open(dir).await?.sync_all().await }.await;
+    #[cfg(windows)]
+    let synced: io::Result<()> = Err(io::Error::other("Windows can't flush directories"));
+
+    let dir_str = dir.display();
+    match synced {
+        Ok(()) => debug!(path = %dir_str, "Flushed {dir_str} to the device"),
+        Err(err) => warn!(
+            path = %dir_str,
+            "Failed to flush {dir_str} to the device, so the books copied into it may not be \
+            there yet; eject the device before unplugging it: {err}"
+        ),
+    }
+}
+
+/// Copy a whole book into its partial file the OS's own way, which can offload it to the kernel or
+/// the filesystem, such as with `copy_file_range` on Linux or `clonefile` on macOS, rather than
+/// reading and writing every byte here. That replaces any partial file already there, as creating
+/// one for the copy does anyway. Nothing is yielded if the destination's filesystem refused to take
+/// the source's permissions, which the OS copies too, such as a FAT one mounted without `quiet`,
+/// so that the book can be copied by reading and writing it instead.
+async fn copy_by_os(src_path: &Path, partial_path: &Path) -> io::Result<Option<u64>> {
+    match fs::copy(src_path, partial_path).await {
+        Ok(written) => Ok(Some(written)),
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            let path_str = partial_path.display();
+            debug!(
+                path = %path_str,
+                "Copying into {path_str} by reading and writing it instead, as it could not be \
+                copied the OS's way: {err}"
+            );
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Make one attempt at copying a book into its partial file, opening both afresh so that a retry
+/// doesn't reuse a handle left in a bad state by a device error.
+async fn attempt_copy(
+    src_path: &Path,
+    partial_path: &Path,
+    src_len: u64,
+    resume_from: Option<u64>,
+    &CopyOptions {
+        verify,
+        copy_buffer_size,
+        ref rate_limiter,
+        fsync,
+        ..
+    }: &CopyOptions,
+    progress: Option<&CopyProgress<'_>>,
+) -> io::Result<(u64, Option<Hash>)> {
+    // Only a whole copy that needn't look at each byte can be left to the OS.
+    let is_plain = resume_from.is_none() && !verify && rate_limiter.is_none() && progress.is_none();
+    let copied_by_os = if is_plain {
+        copy_by_os(src_path, partial_path).await?
+    } else {
+        None
+    };
+    let copied = match copied_by_os {
+        Some(written) => (written, None),
+        None => {
+            let mut src = File::open(src_path).await?;
+            let mut partial = match resume_from {
+                Some(offset) => {
+                    let partial = fs::OpenOptions::new()
+                        .write(true)
+                        .open(partial_path)
+                        .await?;
+                    partial.set_len(offset).await?;
+                    partial
+                }
+                None => File::create(partial_path).await?,
+            };
+            copy_book(
+                &mut src,
+                &mut partial,
+                verify,
+                resume_from.unwrap_or(0),
+                copy_buffer_size,
+                rate_limiter.as_deref(),
+                progress,
+            )
+            .await?
+        }
+    };
+    if fsync {
+        // Windows only flushes files opened for writing.
+        let partial = fs::OpenOptions::new()
+            .write(true)
+            .open(partial_path)
+            .await?;
+        partial.sync_all().await?;
+    }
+    let partial_len = fs::metadata(partial_path).await?.len();
+    if partial_len != src_len {
+        return Err(io::Error::other(format!(
+            "the copy is {partial_len} bytes long but its source is {src_len} bytes long"
+        )));
+    }
+    Ok(copied)
+}
+
+/// Copy a book to a temporary file alongside its destination, only renaming it to the final name
+/// once the copy has fully succeeded. That way, an interrupted or failed copy never leaves a
+/// truncated book behind to be mistaken for a complete one on later runs.
+///
+/// Transient device errors are retried up to `retries` times with an exponential backoff. A book
+/// that still fails is reported and counted as failed by the copy task itself, so that it doesn't
+/// abort the other copies.
+///
+/// The book is reported as `src_name`, which only differs from `src_path` when copying a
+/// temporary file converted from it.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn copy_through_partial(
+    src_path: &Path,
+    src_name: &Path,
+    dest_path: &Path,
+    kind: CopyKind,
+    options: &CopyOptions,
+    copy_permits: &Arc<Semaphore>,
+    stats: &Sender<Statistic>,
+    run: &Run,
+) -> Result<CopyTask> {
+    let &CopyOptions {
+        resume,
+        retries,
+        copy_timeout,
+        preserve_times,
+        report_progress,
+        ..
+    } = options;
+    // The permit is taken before opening either file, so that it bounds open file handles as well
+    // as concurrent copies. It is released when the copy task finishes.
+    let permit = copy_permits.clone().acquire_owned().await?;
+
+    let src_len = fs::metadata(src_path).await?.len();
+    let partial_path = partial_path_for(dest_path);
+    let resume_from = if resume {
+        let offset = resumable_offset(src_path, &partial_path).await;
+        // A partial file that can't be resumed is stale, whatever it holds.
+        if offset.is_none() && fs::remove_file(&partial_path).await.is_ok() {
+            let partial_str = partial_path.display();
+            debug!(
+                path = %partial_str,
+                "Removed {partial_str}, as it can't be resumed from"
+            );
+        }
+        offset
+    } else {
+        None
+    };
+
+    let src_path = src_path.to_path_buf();
+    let src_name = src_name.to_path_buf();
+    let src_str = src_name.display().to_string();
+    let dest_path = dest_path.to_path_buf();
+    let options = options.clone();
+    let stats = stats.clone();
+    let run = run.clone();
+
+    if let Some(offset) = resume_from {
+        info!(
+            path = %src_str,
+            offset, "Resuming the copy of {src_str} from byte {offset} of {src_len}"
+        );
+    }
+
+    Ok(spawn(async move {
+        let _permit = permit;
+        let dest_str = dest_path.display();
+
+        stats
+            .send(Statistic::CopyStarted {
+                source: src_name.clone(),
+                destination: dest_path.clone(),
+            })
+            .await?;
+        let progress = report_progress.then_some(CopyProgress {
+            stats: &stats,
+            source: &src_name,
+            destination: &dest_path,
+            total: src_len,
+        });
+
+        let started = Instant::now();
+        let copying = async {
+            let mut attempt = 0;
+            loop {
+                attempt += 1;
+                let attempt_copy = attempt_copy(
+                    &src_path,
+                    &partial_path,
+                    src_len,
+                    resume_from,
+                    &options,
+                    progress.as_ref(),
+                );
+                let err = match attempt_copy.await {
+                    Ok(copied) => return Ok(Some(copied)),
+                    Err(err) => err,
+                };
+
+                if is_transient(&err) && attempt <= retries {
+                    let backoff = 2u32
+                        .checked_pow(attempt - 1)
+                        .and_then(|factor| RETRY_BASE_BACKOFF.checked_mul(factor))
+                        .map_or(RETRY_MAX_BACKOFF, |backoff| backoff.min(RETRY_MAX_BACKOFF));
+                    warn!(
+                        path = %src_str,
+                        attempt,
+                        "Copying {src_str} failed on attempt {attempt}: {err}; retrying in \
+                        {backoff:?}"
+                    );
+                    sleep(backoff).await;
+                    continue;
+                }
+
+                // Keeping the partial file of a book that filled the destination would leave it
+                // full, so it's never kept.
+                let out_of_space = err.kind() == io::ErrorKind::StorageFull;
+                let partial = settle_partial(&partial_path, resume && !out_of_space).await;
+                let elapsed = started.elapsed();
+                let failed = if out_of_space {
+                    "the destination ran out of space".to_owned()
+                } else {
+                    format!("{err}, after {attempt} attempts")
+                };
+                record_failure(&stats, &src_name, &dest_path, failed, elapsed).await?;
+                if out_of_space {
+                    report_out_of_space(&src_str, &stats, &run).await?;
+                } else {
+                    error!(
+                        path = %src_str,
+                        dest = %dest_str,
+                        "Failed to copy {src_str} to {dest_str} after {attempt} attempts: {err}; \
+                        {partial}."
+                    );
+                    stats.send(Statistic::CopyFailed).await?;
+                    run.advance_progress();
+                }
+                return Ok::<_, Error>(None);
+            }
+        };
+
+        // Once another book has failed when failing fast, there's no point finishing this one.
+        let copying = async {
+            select! {
+                copied = copying => copied,
+                () = run.failed_fast() => {
+                    let partial = settle_partial(&partial_path, resume).await;
+                    info!(
+                        path = %src_str,
+                        dest = %dest_str,
+                        "Abandoned copying {src_str} to {dest_str}, as another book failed; \
+                        {partial}."
+                    );
+                    stats.send(Statistic::Cancelled).await?;
+                    run.advance_progress();
+                    Ok(None)
+                }
+            }
+        };
+
+        let copied = match copy_timeout {
+            Some(limit) => match timeout(limit, copying).await {
+                Ok(copied) => copied?,
+                Err(_) => {
+                    let partial = settle_partial(&partial_path, resume).await;
+                    error!(
+                        path = %src_str,
+                        dest = %dest_str,
+                        "Copying {src_str} to {dest_str} timed out after {limit:?}, so the device \
+                        may have stopped responding; {partial}."
+                    );
+                    stats.send(Statistic::TimedOut).await?;
+                    let elapsed = started.elapsed();
+                    let failed = format!("timed out after {limit:?}");
+                    record_failure(&stats, &src_name, &dest_path, failed, elapsed).await?;
+                    run.advance_progress();
+                    return Ok(None);
+                }
+            },
+            None => copying.await?,
+        };
+        let Some((written, digest)) = copied else {
+            return Ok(None);
+        };
+
+        if !verify_or_discard(&partial_path, digest, &src_str, &stats).await? {
+            let elapsed = started.elapsed();
+            let failed = "the copy did not match it when read back, so it was deleted";
+            record_failure(&stats, &src_name, &dest_path, failed, elapsed).await?;
+            run.advance_progress();
+            return Ok(None);
+        }
+        // Something else may have put a book at the destination while this one was being copied,
+        // which only an update may replace.
+        let collided = match kind {
+            CopyKind::Update => false,
+            CopyKind::New | CopyKind::Pull => fs::try_exists(&dest_path).await.unwrap_or(true),
+        };
+        if collided {
+            let _ = fs::remove_file(&partial_path).await;
+            warn!(
+                path = %src_str,
+                dest = %dest_str,
+                outcome = "skipped",
+                "Not moving the copy of {src_str} into place, as {dest_str} appeared while it \
+                was being copied"
+            );
+            stats.send(Statistic::skipped_existing(false)).await?;
+            let skipped = Action::SkippedExisting;
+            record_action(&stats, &src_name, &dest_path, skipped, 0, started.elapsed()).await?;
+            run.advance_progress();
+            return Ok(None);
+        }
+        if let Err(err) = fs::rename(&partial_path, &dest_path).await {
+            error!(
+                path = %src_str,
+                dest = %dest_str,
+                "Failed to move the copy of {src_str} into place at {dest_str}: {err}"
+            );
+            stats.send(Statistic::CopyFailed).await?;
+            let elapsed = started.elapsed();
+            let failed = format!("could not move the copy into place: {err}");
+            record_failure(&stats, &src_name, &dest_path, failed, elapsed).await?;
+            run.advance_progress();
+            return Ok(None);
+        }
+        if preserve_times {
+            if let Err(err) = preserve_modified_time(&src_name, &dest_path).await {
+                warn!(
+                    path = %src_str,
+                    dest = %dest_str,
+                    "Failed to give {dest_str} the modification time of {src_str}: {err}"
+                );
+            }
+        }
+        match (kind, resume_from) {
+            (CopyKind::New, None) => {
+                info!(
+                    path = %src_str,
+                    dest = %dest_str,
+                    outcome = "copied",
+                    "Copied {src_str} to {dest_str}"
+                )
+            }
+            (CopyKind::New, Some(offset)) => info!(
+                path = %src_str,
+                dest = %dest_str,
+                outcome = "copied",
+                "Copied {src_str} to {dest_str}, resuming from byte {offset}"
+            ),
+            (CopyKind::Update, None) => {
+                info!(
+                    path = %src_str,
+                    dest = %dest_str,
+                    outcome = "copied",
+                    "Updated {dest_str} from {src_str}"
+                )
+            }
+            (CopyKind::Update, Some(offset)) => info!(
+                path = %src_str,
+                dest = %dest_str,
+                outcome = "copied",
+                "Updated {dest_str} from {src_str}, resuming from byte {offset}"
+            ),
+            (CopyKind::Pull, _) => info!(
+                path = %src_str,
+                dest = %dest_str,
+                outcome = "copied",
+                "Pulled {src_str} to {dest_str}"
+            ),
+        }
+        debug!(
+            path = %src_str,
+            bytes = written,
+            elapsed_ms = started.elapsed().as_millis(),
+            "Finished copying {src_str}"
+        );
+        let (statistic, action) = match kind {
+            CopyKind::New => (Statistic::Copied(written), Action::Copied),
+            CopyKind::Update => (Statistic::Updated(written), Action::Updated),
+            CopyKind::Pull => (Statistic::Pulled(written), Action::Pulled),
+        };
+        let elapsed = started.elapsed();
+        record_action(&stats, &src_name, &dest_path, action, written, elapsed).await?;
+        stats.send(statistic).await?;
+        run.advance_progress();
+        Ok(Some(CopiedBook { dest_path, digest }))
+    }))
+}
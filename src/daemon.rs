@@ -0,0 +1,141 @@
+//! Synchronising whenever the device is plugged in, until interrupted.
+
+use {
+    crate::{
+        args::{lookup_hash_cache_file, parse_args, Args},
+        clear_hash_cache,
+        cli::{Outcome, PartialArgs},
+        is_accessible_dir, RunFailure,
+    },
+    anyhow::{anyhow, Result},
+    std::{future::Future, time::Duration},
+    tokio::{select, signal::ctrl_c, time::sleep},
+    tracing::{debug, error, info},
+};
+
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Wait for Ctrl-C, or on Unix, for SIGTERM too.
+pub async fn interrupt_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal(SignalKind::terminate())?;
+        select! {
+            interrupted = ctrl_c() => interrupted?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    ctrl_c().await?;
+    Ok(())
+}
+
+/// Wait for a while, unless interrupted first, yielding whether it was.
+async fn sleep_unless_interrupted(duration: Duration) -> Result<bool> {
+    select! {
+        _ = sleep(duration) => Ok(false),
+        signalled = interrupt_signal() => signalled.map(|()| true),
+    }
+}
+
+/// How often a daemon looks for the device to be plugged in, and then unplugged.
+const DAEMON_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long a daemon waits after the device appears before synchronising to it, as a device that
+/// was just plugged in can take a moment to finish mounting.
+const DAEMON_SETTLE_TIME: Duration = Duration::from_secs(3);
+
+/// Synchronise with `synchronise` whenever the device is plugged in, until interrupted. Each time
+/// the device appears it's synchronised once, and then it must be unplugged before it's
+/// synchronised again, so that a device left plugged in isn't synchronised over and over.
+pub async fn run_daemon<Synchronising>(
+    mut args: PartialArgs,
+    mut synchronise: impl FnMut(Args) -> Synchronising,
+) -> Result<usize>
+where
+    Synchronising: Future<Output = Result<usize>>,
+{
+    // The hash cache is only cleared once, rather than before every run.
+    if args.clear_hash_cache {
+        let hash_cache = lookup_hash_cache_file()?;
+        clear_hash_cache(&hash_cache).await.map_err(|err| {
+            anyhow!(
+                "could not clear the hash cache at {}: {err}",
+                hash_cache.display()
+            )
+        })?;
+        args.clear_hash_cache = false;
+    }
+
+    info!("Waiting for the device to be plugged in; press Ctrl-C to stop");
+    // The arguments are only parsed once the device is first found, after which it's just looked
+    // for again each time.
+    let mut first_found: Option<Args> = None;
+    loop {
+        let parsed = loop {
+            let found = match &first_found {
+                None => parse_args(args.clone()).await,
+                Some(first_found) => first_found.with_device_found_again().await,
+            };
+            match found {
+                Ok(parsed) => break parsed,
+                Err(err)
+                    if matches!(
+                        err.downcast_ref::<RunFailure>(),
+                        Some(RunFailure::Inaccessible(_))
+                    ) =>
+                {
+                    debug!("Still waiting for the device: {err:#}");
+                    if sleep_unless_interrupted(DAEMON_POLL_INTERVAL).await? {
+                        info!("Stopping, as interrupted while waiting for the device");
+                        return Ok(0);
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+        };
+
+        if first_found.is_none() {
+            first_found = Some(parsed.clone());
+        }
+        let volume = parsed.sync_options.volume_directory().to_path_buf();
+        let volume_str = volume.display().to_string();
+        info!(path = %volume_str, "Found {volume_str}; synchronising to it shortly");
+        if sleep_unless_interrupted(DAEMON_SETTLE_TIME).await? {
+            info!("Stopping, as interrupted before synchronising");
+            return Ok(0);
+        }
+
+        let result = synchronise(parsed).await;
+        let outcome = Outcome::of(&result);
+        match &result {
+            Ok(failures) => info!(
+                path = %volume_str,
+                ?outcome,
+                failures,
+                "Finished synchronising to {volume_str}"
+            ),
+            Err(err) => error!(
+                path = %volume_str,
+                ?outcome,
+                "Failed to synchronise to {volume_str}: {err:#}"
+            ),
+        }
+        if outcome == Outcome::Interrupted {
+            return result;
+        }
+
+        info!(path = %volume_str, "Waiting for {volume_str} to be unplugged");
+        while is_accessible_dir(&volume).await {
+            if sleep_unless_interrupted(DAEMON_POLL_INTERVAL).await? {
+                info!("Stopping, as interrupted while waiting for the device to be unplugged");
+                return Ok(0);
+            }
+        }
+        info!(
+            path = %volume_str,
+            "{volume_str} was unplugged; waiting for the device to be plugged in again"
+        );
+    }
+}
@@ -0,0 +1,588 @@
+//! Finding, recognising, and ejecting the e-book readers to synchronise with.
+
+use {
+    crate::RunFailure,
+    anyhow::{anyhow, Result},
+    clap::ValueEnum,
+    serde::Deserialize,
+    std::{
+        env,
+        path::{Path, PathBuf},
+    },
+    tokio::{
+        fs::{self, File},
+        process::Command,
+        task::spawn_blocking,
+    },
+    tracing::info,
+};
+
+#[cfg(not(any(windows, target_os = "macos")))]
+use whoami::username;
+
+#[cfg(target_os = "linux")]
+use {
+    std::collections::HashMap,
+    zbus::{
+        zvariant::{OwnedObjectPath, Value},
+        Connection, Proxy,
+    },
+};
+
+const DEFAULT_EXTENSIONS_TO_SYNCHRONISE: [&str; 2] = ["epub", "pdf"];
+const DEFAULT_KINDLE_EXTENSIONS_TO_SYNCHRONISE: [&str; 4] = ["azw3", "mobi", "kfx", "pdf"];
+
+pub async fn is_accessible_dir(path: &Path) -> bool {
+    fs::metadata(path)
+        .await
+        .map(|m| m.is_dir())
+        .unwrap_or(false)
+}
+
+/// The kind of e-book reader being synchronised to, which determines the formats it can read and
+/// the layout of its storage.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Device {
+    #[default]
+    Kobo,
+    Kindle,
+}
+
+impl Device {
+    /// What the device is called when telling the user about it.
+    pub fn name(self) -> &'static str {
+        match self {
+            Device::Kobo => "Kobo",
+            Device::Kindle => "Kindle",
+        }
+    }
+
+    pub fn default_extensions(self) -> &'static [&'static str] {
+        match self {
+            Device::Kobo => &DEFAULT_EXTENSIONS_TO_SYNCHRONISE,
+            Device::Kindle => &DEFAULT_KINDLE_EXTENSIONS_TO_SYNCHRONISE,
+        }
+    }
+
+    /// The subdirectory of the mounted volume into which books are synchronised.
+    pub fn books_subdirectory(self) -> Option<&'static str> {
+        match self {
+            Device::Kobo => None,
+            Device::Kindle => Some("documents"),
+        }
+    }
+
+    /// A directory in the root of the mounted volume that identifies it as this kind of device.
+    pub fn marker(self) -> &'static str {
+        match self {
+            Device::Kobo => ".kobo",
+            Device::Kindle => "system",
+        }
+    }
+
+    /// The label that the device gives its volume, which it's usually mounted under.
+    pub fn volume_label(self) -> &'static str {
+        match self {
+            Device::Kobo => "KOBOeReader",
+            Device::Kindle => "Kindle",
+        }
+    }
+}
+
+/// The directories under which removable volumes are typically mounted: by udisks2 on Debian-likes
+/// and Fedora-likes respectively, and by older automounters.
+#[cfg(not(any(windows, target_os = "macos")))]
+fn candidate_mount_roots() -> Vec<PathBuf> {
+    let user = username();
+    vec![
+        Path::new("/media").join(&user),
+        Path::new("/run/media").join(&user),
+        PathBuf::from("/media"),
+    ]
+}
+
+/// macOS mounts every volume under `/Volumes`.
+#[cfg(target_os = "macos")]
+fn candidate_mount_roots() -> Vec<PathBuf> {
+    vec![PathBuf::from("/Volumes")]
+}
+
+/// The volumes that the device might be mounted as, along with where they were looked for, to
+/// report if none of them turn out to be it.
+#[cfg(not(windows))]
+async fn candidate_volumes() -> (Vec<PathBuf>, String) {
+    let roots = candidate_mount_roots();
+    let mut volumes = vec![];
+    for root in &roots {
+        let Ok(mut entries) = fs::read_dir(root).await else {
+            continue;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            volumes.push(entry.path());
+        }
+    }
+
+    let checked = roots
+        .iter()
+        .map(|root| root.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    (volumes, format!("the volumes under {checked}"))
+}
+
+/// Windows mounts each volume as a drive of its own rather than under a directory, so the root of
+/// every available drive is a candidate.
+#[cfg(windows)]
+async fn candidate_volumes() -> (Vec<PathBuf>, String) {
+    let mut drives = vec![];
+    for letter in 'A'..='Z' {
+        let root = PathBuf::from(format!("{letter}:\\"));
+        if is_accessible_dir(&root).await {
+            drives.push(root);
+        }
+    }
+
+    let checked = drives
+        .iter()
+        .map(|drive| drive.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    (drives, format!("the drives {checked}"))
+}
+
+/// Find the mounted volume of the device by looking for its marker directory in each candidate
+/// volume, failing if there is not exactly one.
+pub async fn detect_storage_directory(device: Device) -> Result<PathBuf> {
+    let (volumes, checked) = candidate_volumes().await;
+    let marker = device.marker();
+
+    let mut found = vec![];
+    for path in volumes {
+        if is_accessible_dir(&path.join(marker)).await && !found.contains(&path) {
+            found.push(path);
+        }
+    }
+
+    // Something else with the marker directory, such as a backup of the device, is less likely to
+    // be the device than the volume with its label.
+    if 1 < found.len() {
+        let label = device.volume_label();
+        let labelled = found
+            .iter()
+            .filter(|path| path.file_name().is_some_and(|name| name == label))
+            .cloned()
+            .collect::<Vec<_>>();
+        if labelled.len() == 1 {
+            found = labelled;
+        }
+    }
+
+    match found.len() {
+        1 => Ok(found.remove(0)),
+        0 => Err(RunFailure::Inaccessible(format!(
+            "Could not find a mounted {device:?}; looked for a {marker} directory in {checked}. \
+            Pass --kobo-directory if it is mounted elsewhere."
+        ))
+        .into()),
+        _ => {
+            let candidates = found
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            Err(anyhow!(
+                "Found several mounted volumes that look like a {device:?}: {candidates}. Pass \
+                --kobo-directory to choose one."
+            ))
+        }
+    }
+}
+
+/// The directory under which GVFS exposes the devices it mounts, MTP ones included, as ordinary
+/// directories for other programs to use.
+fn gvfs_mount_root() -> Result<PathBuf> {
+    env::var_os("XDG_RUNTIME_DIR")
+        .map(|dir| PathBuf::from(dir).join("gvfs"))
+        .ok_or_else(|| anyhow!("XDG_RUNTIME_DIR is not set, so GVFS mounts cannot be found"))
+}
+
+const GVFS_MTP_PREFIX: &str = "mtp:host=";
+
+/// Find the storage of an MTP device, such as a newer Kindle, which never mounts as mass storage.
+/// It must first be mounted through GVFS, such as by a file manager or `gio mount`, which exposes
+/// it as a directory that the rest of the synchronisation can treat like any other volume. The
+/// device is matched by a case-insensitive part of its name; if it has several storages, such as
+/// internal storage and an SD card, the one containing the device's marker is chosen.
+pub async fn detect_mtp_storage_directory(name: &str, device: Device) -> Result<PathBuf> {
+    let root = gvfs_mount_root()?;
+    let wanted = name.to_lowercase();
+
+    let mut hosts = vec![];
+    if let Ok(mut mounts) = fs::read_dir(&root).await {
+        while let Ok(Some(mount)) = mounts.next_entry().await {
+            let file_name = mount.file_name();
+            let Some(host) = file_name
+                .to_str()
+                .and_then(|n| n.strip_prefix(GVFS_MTP_PREFIX))
+            else {
+                continue;
+            };
+            if host.to_lowercase().contains(&wanted) {
+                hosts.push(mount.path());
+            }
+        }
+    }
+    let host = match hosts.len() {
+        1 => hosts.remove(0),
+        0 => {
+            return Err(RunFailure::Inaccessible(format!(
+                "Could not find an MTP device named like {name} under {}; mount it first, such \
+                as with `gio mount`, or check its name with `gio mount --list`.",
+                root.display()
+            ))
+            .into())
+        }
+        _ => {
+            let candidates = hosts
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            return Err(anyhow!(
+                "Found several MTP devices named like {name}: {candidates}. Pass more of the \
+                name to --mtp-device to choose one."
+            ));
+        }
+    };
+
+    let mut storages = vec![];
+    let mut entries = fs::read_dir(&host).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if is_accessible_dir(&entry.path()).await {
+            storages.push(entry.path());
+        }
+    }
+    if storages.len() > 1 {
+        let mut marked = vec![];
+        for storage in &storages {
+            if is_accessible_dir(&storage.join(device.marker())).await {
+                marked.push(storage.clone());
+            }
+        }
+        storages = marked;
+    }
+    match storages.len() {
+        1 => Ok(storages.remove(0)),
+        0 => Err(RunFailure::Inaccessible(format!(
+            "The MTP device at {} has no storage that looks like a {device:?}'s",
+            host.display()
+        ))
+        .into()),
+        _ => Err(anyhow!(
+            "The MTP device at {} has several storages that look like a {device:?}'s; pass \
+            --kobo-directory with the one to use.",
+            host.display()
+        )),
+    }
+}
+
+/// The GVFS URI of the MTP device that a directory is on, if it is on one at all.
+fn gvfs_mtp_uri(volume: &Path) -> Option<String> {
+    volume.ancestors().find_map(|dir| {
+        let host = dir.file_name()?.to_str()?.strip_prefix(GVFS_MTP_PREFIX)?;
+        Some(format!("mtp://{host}/"))
+    })
+}
+
+/// A filesystem in the mount table.
+#[cfg(target_os = "linux")]
+struct Mount {
+    device: String,
+    mount_point: PathBuf,
+    fs_type: String,
+}
+
+/// Read the mount table.
+#[cfg(target_os = "linux")]
+async fn read_mounts() -> Result<Vec<Mount>> {
+    let mounts = fs::read_to_string("/proc/mounts").await?;
+
+    // The table escapes spaces and the like in octal, as `\040`.
+    let unescape = |field: &str| {
+        field
+            .replace("\\040", " ")
+            .replace("\\011", "\t")
+            .replace("\\012", "\n")
+            .replace("\\134", "\\")
+    };
+    Ok(mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            Some(Mount {
+                device: unescape(fields.next()?),
+                mount_point: PathBuf::from(unescape(fields.next()?)),
+                fs_type: unescape(fields.next()?),
+            })
+        })
+        .collect())
+}
+
+/// Find the block device mounted at a directory, from the mount table.
+#[cfg(target_os = "linux")]
+async fn mounted_device(mount_point: &Path) -> Result<String> {
+    let mount_point = fs::canonicalize(mount_point).await?;
+    read_mounts()
+        .await?
+        .into_iter()
+        .find(|mount| mount.mount_point == mount_point)
+        .map(|mount| mount.device)
+        .ok_or_else(|| anyhow!("{} is not a mount point", mount_point.display()))
+}
+
+pub(crate) async fn check_volume(volume: &Path) -> Result<()> {
+    if !is_accessible_dir(volume).await {
+        let inaccessible = volume.display();
+        return Err(RunFailure::Inaccessible(format!(
+            "The Kobo storage directory at {inaccessible} is not accessible"
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Without the device's marker directory, a mount point left behind after the device was
+/// unplugged would have a whole library copied onto the root filesystem.
+pub(crate) async fn check_marker(volume: &Path, device: Device) -> Result<()> {
+    let marker = device.marker();
+    if !is_accessible_dir(&volume.join(marker)).await {
+        let path_str = volume.display();
+        return Err(RunFailure::Inaccessible(format!(
+            "The storage directory at {path_str} has no {marker} directory, so it does not look \
+            like a mounted {device:?}; pass --force to synchronise to it anyway"
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// The directory in a device's volume that books are synchronised into.
+pub(crate) async fn books_directory_in(volume: &Path, device: Device) -> Result<PathBuf> {
+    match device.books_subdirectory() {
+        Some(subdirectory) => {
+            let dest_directory = volume.join(subdirectory);
+            if !is_accessible_dir(&dest_directory).await {
+                let path_str = dest_directory.display();
+                return Err(RunFailure::Inaccessible(format!(
+                    "The destination directory at {path_str} is not accessible"
+                ))
+                .into());
+            }
+            Ok(dest_directory)
+        }
+        None => Ok(volume.to_path_buf()),
+    }
+}
+
+/// Check that a device's volume is accessible and, unless told otherwise, that it has the device's
+/// marker directory, yielding the directory in it that books are synchronised into.
+///
+/// Books go into a subdirectory of that if one is given, which is created if it's missing, unless
+/// the run isn't to change anything.
+pub async fn destination_in(
+    volume: &Path,
+    device: Device,
+    require_marker: bool,
+    subdir: Option<&Path>,
+    create_subdir: bool,
+) -> Result<PathBuf> {
+    check_volume(volume).await?;
+    if require_marker {
+        check_marker(volume, device).await?;
+    }
+    let dest_directory = books_directory_in(volume, device).await?;
+    let Some(subdir) = subdir else {
+        return Ok(dest_directory);
+    };
+
+    let dest_directory = dest_directory.join(subdir);
+    if create_subdir && !is_accessible_dir(&dest_directory).await {
+        let path_str = dest_directory.display();
+        fs::create_dir_all(&dest_directory).await.map_err(|err| {
+            RunFailure::Inaccessible(format!(
+                "The destination directory at {path_str} could not be created: {err}"
+            ))
+        })?;
+        info!(dest = %path_str, "Created the destination directory {path_str}");
+    }
+    Ok(dest_directory)
+}
+
+/// The type of the filesystem that a directory is on, such as `vfat`, from the mount table. The
+/// last filesystem mounted over it is the one that counts.
+#[cfg(target_os = "linux")]
+pub async fn filesystem_type(dir: &Path) -> Result<String> {
+    let dir = fs::canonicalize(dir).await?;
+    read_mounts()
+        .await?
+        .into_iter()
+        .filter(|mount| dir.starts_with(&mount.mount_point))
+        .max_by_key(|mount| mount.mount_point.components().count())
+        .map(|mount| mount.fs_type)
+        .ok_or_else(|| anyhow!("{} is on no mounted filesystem", dir.display()))
+}
+
+/// The mount table is read from `/proc/mounts`, which is Linux-only.
+#[cfg(not(target_os = "linux"))]
+pub async fn filesystem_type(_dir: &Path) -> Result<String> {
+    Err(anyhow!("filesystem types are only known on Linux"))
+}
+
+/// How many bytes are free for this user on the filesystem that a directory is on.
+pub async fn available_space(dir: &Path) -> Result<u64> {
+    let dir = dir.to_path_buf();
+    Ok(spawn_blocking(move || fs2::available_space(dir)).await??)
+}
+
+async fn run_unmount_command(command: &mut Command) -> Result<()> {
+    let program = command.as_std().get_program().to_owned();
+    let output = command
+        .output()
+        .await
+        .map_err(|err| anyhow!("could not run {}: {err}", program.to_string_lossy()))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Err(anyhow!(
+            "unmounting failed with {}: {}",
+            output.status,
+            stderr.trim()
+        ))
+    }
+}
+
+#[cfg(target_os = "linux")]
+const UDISKS2: &str = "org.freedesktop.UDisks2";
+
+/// Mount the device's volume with udisks2 over D-Bus, found by its label, yielding where it was
+/// mounted. This is for when the device is plugged in but nothing has mounted it.
+#[cfg(target_os = "linux")]
+pub async fn mount_by_label(device: Device) -> Result<PathBuf> {
+    let label = device.volume_label();
+    let connection = Connection::system()
+        .await
+        .map_err(|err| anyhow!("could not connect to the system bus to reach udisks2: {err}"))?;
+    let no_options = HashMap::<&str, Value>::new();
+
+    let manager = Proxy::new(
+        &connection,
+        UDISKS2,
+        "/org/freedesktop/UDisks2/Manager",
+        "org.freedesktop.UDisks2.Manager",
+    )
+    .await?;
+    let spec = HashMap::from([("label", Value::from(label))]);
+    let block_devices: Vec<OwnedObjectPath> = manager
+        .call("ResolveDevice", &(spec, &no_options))
+        .await
+        .map_err(|err| {
+            anyhow!("udisks2 could not look for a block device labelled {label}: {err}")
+        })?;
+    let Some(block_device) = block_devices.first() else {
+        return Err(anyhow!("no block device is labelled {label}"));
+    };
+
+    let filesystem = Proxy::new(
+        &connection,
+        UDISKS2,
+        block_device.as_ref(),
+        "org.freedesktop.UDisks2.Filesystem",
+    )
+    .await?;
+    let mount_point: String = filesystem
+        .call("Mount", &(&no_options,))
+        .await
+        .map_err(|err| anyhow!("mounting {label} failed: {err}"))?;
+    Ok(PathBuf::from(mount_point))
+}
+
+/// Only udisks2 is supported for mounting, which is Linux-only.
+#[cfg(not(target_os = "linux"))]
+pub async fn mount_by_label(_device: Device) -> Result<PathBuf> {
+    Err(anyhow!("mounting devices is only supported on Linux"))
+}
+
+/// Flush everything written to the device and then unmount it, so that it can be unplugged
+/// safely. FAT volumes in particular lose data if pulled out with writes still cached.
+pub async fn eject(volume: &Path) -> Result<()> {
+    // GVFS writes through to MTP devices as each file is closed, so there's nothing to flush.
+    if let Some(uri) = gvfs_mtp_uri(volume) {
+        return run_unmount_command(Command::new("gio").args(["mount", "--unmount", &uri])).await;
+    }
+
+    File::open(volume).await?.sync_all().await?;
+
+    #[cfg(target_os = "linux")]
+    {
+        let device = mounted_device(volume).await?;
+        run_unmount_command(Command::new("udisksctl").args(["unmount", "-b", &device])).await
+    }
+    #[cfg(target_os = "macos")]
+    {
+        run_unmount_command(Command::new("diskutil").arg("unmount").arg(volume)).await
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    {
+        Err(anyhow!("ejecting is not supported on this OS"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn synchronises_into_a_volume_with_the_devices_marker() {
+        let volume = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir(volume.path().join(".kobo")).unwrap();
+
+        let dest = destination_in(volume.path(), Device::Kobo, true, None, false).await;
+        assert_eq!(dest.unwrap(), volume.path());
+    }
+
+    #[tokio::test]
+    async fn refuses_a_volume_without_the_devices_marker() {
+        let volume = tempfile::TempDir::new().unwrap();
+        // A Kindle's marker doesn't make it look like a Kobo.
+        std::fs::create_dir(volume.path().join("system")).unwrap();
+
+        let err = destination_in(volume.path(), Device::Kobo, true, None, false)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<RunFailure>(),
+            Some(RunFailure::Inaccessible(msg)) if msg.contains("no .kobo directory")
+        ));
+    }
+
+    #[tokio::test]
+    async fn synchronises_into_a_volume_without_the_marker_when_forced() {
+        let volume = tempfile::TempDir::new().unwrap();
+
+        let dest = destination_in(volume.path(), Device::Kobo, false, None, false).await;
+        assert_eq!(dest.unwrap(), volume.path());
+    }
+
+    #[tokio::test]
+    async fn synchronises_into_a_kindles_documents_directory() {
+        let volume = tempfile::TempDir::new().unwrap();
+        for dir in ["system", "documents"] {
+            std::fs::create_dir(volume.path().join(dir)).unwrap();
+        }
+
+        let dest = destination_in(volume.path(), Device::Kindle, true, None, false).await;
+        assert_eq!(dest.unwrap(), volume.path().join("documents"));
+    }
+}
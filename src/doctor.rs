@@ -0,0 +1,210 @@
+//! Checking that everything synchronising needs is in place, without synchronising.
+
+use {
+    crate::{
+        args::{
+            check_calibre_library, check_documents_directory, check_extensions,
+            check_pull_directory, config_for, documents_directories_for, extensions_for,
+            locate_volume, lookup_config_file, Config,
+        },
+        available_space,
+        cli::{PartialArgs, NAME},
+        device::{books_directory_in, check_marker, check_volume},
+        filesystem_type, is_accessible_dir,
+        summary::format_bytes,
+        Device,
+    },
+    anyhow::{anyhow, Result},
+    serde::Serialize,
+    std::path::Path,
+    tokio::fs,
+};
+
+/// Check that books can be written to a directory, by creating a file in it and deleting it again.
+async fn check_writable(dir: &Path) -> Result<()> {
+    let probe = dir.join(format!(".{NAME}-probe-{}", std::process::id()));
+    let path_str = dir.display();
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .await
+        .map_err(|err| anyhow!("The destination directory at {path_str} is not writable: {err}"))?;
+    fs::remove_file(&probe).await.map_err(|err| {
+        anyhow!(
+            "The destination directory at {path_str} let a file be created but not deleted: {err}"
+        )
+    })
+}
+
+/// Whether one of the doctor's checks passed, and what it found.
+#[derive(Debug, Serialize)]
+pub struct Diagnosis {
+    pub passed: bool,
+    pub message: String,
+}
+
+impl Diagnosis {
+    fn of(checked: Result<String>) -> Diagnosis {
+        match checked {
+            Ok(message) => Diagnosis {
+                passed: true,
+                message,
+            },
+            Err(err) => Diagnosis {
+                passed: false,
+                message: format!("{err:#}"),
+            },
+        }
+    }
+}
+
+/// Run the doctor's checks on a device's volume, which are those a run does before synchronising to
+/// it and then some.
+async fn diagnose_destination(
+    volume: &Path,
+    device: Device,
+    force: bool,
+    subdir: Option<&Path>,
+    diagnoses: &mut Vec<Diagnosis>,
+) {
+    let volume_str = volume.display();
+    let accessible = check_volume(volume).await;
+    let is_accessible = accessible.is_ok();
+    let accessible = accessible
+        .map(|()| format!("The {device:?} storage directory at {volume_str} is accessible"));
+    diagnoses.push(Diagnosis::of(accessible));
+    if !is_accessible {
+        return;
+    }
+    if !force {
+        let marker = device.marker();
+        let marked = check_marker(volume, device).await.map(|()| {
+            format!(
+                "The storage directory at {volume_str} has a {marker} directory, so it looks like \
+                a mounted {device:?}"
+            )
+        });
+        diagnoses.push(Diagnosis::of(marked));
+    }
+
+    let books_directory = match books_directory_in(volume, device).await {
+        Ok(books_directory) => books_directory,
+        Err(err) => {
+            diagnoses.push(Diagnosis::of(Err(err)));
+            return;
+        }
+    };
+    // A missing subdirectory is only created once synchronising, so it's the directory it would be
+    // created in that has to be writable.
+    let dest_directory = match subdir {
+        Some(subdir) if is_accessible_dir(&books_directory.join(subdir)).await => {
+            books_directory.join(subdir)
+        }
+        _ => books_directory,
+    };
+    let dest_str = dest_directory.display();
+    let writable = check_writable(&dest_directory)
+        .await
+        .map(|()| format!("The destination directory at {dest_str} is writable"));
+    diagnoses.push(Diagnosis::of(writable));
+
+    let free = available_space(&dest_directory).await.map_err(|err| {
+        anyhow!(
+            "The free space on the destination directory at {dest_str} could not be read: {err}"
+        )
+    });
+    let filesystem = match filesystem_type(&dest_directory).await {
+        Ok(fs_type) => format!("its filesystem is {fs_type}"),
+        Err(_) => "its filesystem's type is unknown".to_owned(),
+    };
+    let free = free.map(|free| {
+        let free = format_bytes(free, false);
+        format!("The destination directory at {dest_str} has {free} free, and {filesystem}")
+    });
+    diagnoses.push(Diagnosis::of(free));
+}
+
+/// Run the checks that synchronising depends on, through the same code as a run does them, yielding
+/// whether each passed. Unlike a run, every check is made, rather than stopping at the first to
+/// fail, and nothing is changed, so devices aren't mounted.
+pub async fn diagnose(args: PartialArgs) -> Result<Vec<Diagnosis>> {
+    let mut diagnoses = vec![];
+    let config = match config_for(args.no_config).await {
+        Ok(config) => {
+            if !args.no_config {
+                let path = lookup_config_file()?;
+                let path_str = path.display();
+                diagnoses.push(Diagnosis::of(Ok(format!(
+                    "The configuration at {path_str} is valid, if there is one"
+                ))));
+            }
+            config
+        }
+        Err(err) => {
+            diagnoses.push(Diagnosis::of(Err(err)));
+            Config::default()
+        }
+    };
+    let device = args.device.or(config.device).unwrap_or_default();
+
+    let volume = locate_volume(
+        args.mtp_device.as_deref(),
+        args.kobo_directory.or(config.kobo_directory),
+        device,
+        false,
+    )
+    .await;
+    let subdir = args.dest_subdir.as_deref();
+    match volume {
+        Ok(volume) => {
+            diagnose_destination(&volume, device, args.force, subdir, &mut diagnoses).await
+        }
+        Err(err) => diagnoses.push(Diagnosis::of(Err(err))),
+    }
+    for volume in &args.sync.extra_destination {
+        diagnose_destination(volume, device, args.force, subdir, &mut diagnoses).await;
+    }
+
+    let documents_directories = documents_directories_for(
+        args.documents_directories,
+        config.documents_directories,
+        args.calibre_library.is_some(),
+        args.allow_empty_sources,
+    )
+    .await;
+    match documents_directories {
+        Ok(dirs) => {
+            for dir in dirs {
+                let dir_str = dir.display();
+                let readable = check_documents_directory(&dir)
+                    .await
+                    .map(|()| format!("The documents directory at {dir_str} is readable"));
+                diagnoses.push(Diagnosis::of(readable));
+            }
+        }
+        Err(err) => diagnoses.push(Diagnosis::of(Err(err))),
+    }
+    if let Some(library) = &args.calibre_library {
+        let library_str = library.display();
+        let accessible = check_calibre_library(library)
+            .await
+            .map(|()| format!("The Calibre library at {library_str} is accessible"));
+        diagnoses.push(Diagnosis::of(accessible));
+    }
+    if let Some(pull_dir) = &args.sync.pull {
+        let pull_str = pull_dir.display();
+        let accessible = check_pull_directory(pull_dir)
+            .await
+            .map(|()| format!("The directory to pull books into at {pull_str} is accessible"));
+        diagnoses.push(Diagnosis::of(accessible));
+    }
+
+    let extensions = extensions_for(args.preset, args.extensions, config.extensions, device);
+    let configured = check_extensions(&extensions).map(|()| {
+        let extensions = extensions.join(", ");
+        format!("Books with these extensions are synchronised: {extensions}")
+    });
+    diagnoses.push(Diagnosis::of(configured));
+    Ok(diagnoses)
+}
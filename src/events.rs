@@ -0,0 +1,162 @@
+//! Telling library consumers what's happening to each book as a run goes.
+
+use {
+    crate::{
+        report::{Action, BookAction},
+        stats::{Counters, Statistic},
+    },
+    std::path::PathBuf,
+};
+
+/// Something that happened to a book during a run, as sent by [`sync_with_events`]. Sizes are in
+/// bytes.
+///
+/// [`sync_with_events`]: crate::sync_with_events
+#[derive(Clone, Debug)]
+pub enum SyncEvent {
+    /// A book was found in the documents directories.
+    Found { path: PathBuf, bytes: u64 },
+
+    /// How many files have been examined while finding books, including those that weren't books
+    /// or were excluded, and how many books have been found. This is sent every so often while
+    /// finding books.
+    Scanning {
+        files_examined: usize,
+        books_found: usize,
+    },
+
+    /// Finished finding books, having examined this many files. Books are still found afterwards
+    /// when watching.
+    ScanFinished {
+        files_examined: usize,
+        books_found: usize,
+    },
+
+    /// A book started being copied, either across to the destination or, when pulling, back.
+    CopyStarted {
+        source: PathBuf,
+        destination: PathBuf,
+    },
+
+    /// How much of a book has been copied so far, out of its `total` size. This is sent every
+    /// mebibyte or so rather than for every write, and may be dropped if events are piling up.
+    CopyProgress {
+        source: PathBuf,
+        destination: PathBuf,
+        bytes: u64,
+        total: u64,
+    },
+
+    /// A book finished being copied. It replaced an outdated copy if `update` is set.
+    Copied {
+        source: PathBuf,
+        destination: PathBuf,
+        bytes: u64,
+        update: bool,
+    },
+
+    /// A book was left alone, as it was already on the destination, as it was deferred to a later
+    /// run or, if dry-running, as nothing is copied.
+    Skipped {
+        source: PathBuf,
+        destination: PathBuf,
+    },
+
+    /// A book failed to copy.
+    Failed {
+        source: PathBuf,
+        destination: PathBuf,
+
+        /// Why it failed, if known.
+        error: Option<String>,
+    },
+}
+
+impl SyncEvent {
+    /// The event for how finding books is going, or how it went if it's finished.
+    pub(crate) fn scanned(counters: &Counters, finished: bool) -> SyncEvent {
+        let files_examined = counters.found
+            + counters.scanned_non_matching
+            + counters.excluded
+            + counters.excluded_by_size
+            + counters.excluded_as_too_old
+            + counters.unchanged_since_last_sync
+            + counters.skipped_invalid;
+        let books_found = counters.found;
+        if finished {
+            SyncEvent::ScanFinished {
+                files_examined,
+                books_found,
+            }
+        } else {
+            SyncEvent::Scanning {
+                files_examined,
+                books_found,
+            }
+        }
+    }
+
+    /// The event that a statistic amounts to, if any.
+    pub(crate) fn of(stat: &Statistic) -> Option<SyncEvent> {
+        let event = match stat {
+            Statistic::FoundSrcDocument(path, bytes) => SyncEvent::Found {
+                path: path.clone(),
+                bytes: *bytes,
+            },
+            Statistic::CopyStarted {
+                source,
+                destination,
+            } => SyncEvent::CopyStarted {
+                source: source.clone(),
+                destination: destination.clone(),
+            },
+            Statistic::CopyProgressed {
+                source,
+                destination,
+                bytes,
+                total,
+            } => SyncEvent::CopyProgress {
+                source: source.clone(),
+                destination: destination.clone(),
+                bytes: *bytes,
+                total: *total,
+            },
+            Statistic::Acted(BookAction {
+                source,
+                destination,
+                action,
+                bytes,
+                error,
+                ..
+            }) => {
+                let source = source.clone();
+                let destination = destination.clone();
+                match action {
+                    Action::Copied | Action::Updated | Action::Pulled => SyncEvent::Copied {
+                        source,
+                        destination,
+                        bytes: *bytes,
+                        update: *action == Action::Updated,
+                    },
+                    Action::SkippedExisting
+                    | Action::DryRun
+                    | Action::Deferred
+                    | Action::Healthy
+                    | Action::Missing
+                    | Action::Mismatched
+                    | Action::Corrupted => SyncEvent::Skipped {
+                        source,
+                        destination,
+                    },
+                    Action::Failed => SyncEvent::Failed {
+                        source,
+                        destination,
+                        error: error.clone(),
+                    },
+                }
+            }
+            _ => return None,
+        };
+        Some(event)
+    }
+}
@@ -0,0 +1,1130 @@
+//! Finding books in the documents directories, once or continually.
+
+use {
+    crate::{
+        calibre::{
+            find_calibre_books, is_calibre_aware, is_calibre_bookkeeping, is_calibre_original,
+            CalibreLibrary,
+        },
+        copy::hash_file,
+        kepub::{is_convertible_to_kepub, is_epub, kepub_path_for},
+        stats::Statistic,
+        syncignore::SyncIgnore,
+        Run, FOUND_BOOKS_CHANNEL_BOUND,
+    },
+    anyhow::{Error, Result},
+    async_walkdir::{Filtering, WalkDir},
+    globset::GlobSet,
+    notify::{EventKind, RecursiveMode, Watcher},
+    std::{
+        collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+        ffi::{OsStr, OsString},
+        io,
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        thread::available_parallelism,
+        time::{Duration, Instant, SystemTime},
+    },
+    tokio::{
+        fs::{self, File},
+        io::AsyncReadExt,
+        select,
+        sync::mpsc::{channel, unbounded_channel, Receiver, Sender, UnboundedSender},
+        task::{spawn, JoinHandle},
+        time::interval,
+    },
+    tokio_stream::StreamExt,
+    tracing::{debug, info, warn},
+};
+
+/// How long a watched book must go unchanged before it's considered fully written.
+const WATCH_SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// How many files that aren't books are walked past before they're counted, so that the count
+/// keeps up during long walks without sending a statistic for every file.
+const NON_MATCHING_BATCH_SIZE: usize = 256;
+
+/// Options controlling which files in the documents directories are considered books to
+/// synchronise.
+#[derive(Clone, Debug)]
+pub(crate) struct FindOptions {
+    pub(crate) excludes: GlobSet,
+
+    /// If present, only books matching one of these are synchronised. Excludes take precedence.
+    pub(crate) includes: Option<GlobSet>,
+
+    /// Whether to descend into symlinked directories.
+    pub(crate) follow_symlinks: bool,
+
+    /// Whether to include hidden files, and descend into hidden directories.
+    pub(crate) hidden: bool,
+
+    /// If present, books larger than this many bytes are skipped.
+    pub(crate) max_size: Option<u64>,
+
+    /// If present, books last modified before this are skipped.
+    pub(crate) since: Option<SystemTime>,
+
+    /// If present, when the last successful run to the destination started. Books last modified
+    /// before it are skipped without being looked for on the destination.
+    pub(crate) last_sync: Option<SystemTime>,
+
+    /// Whether to skip books that are empty or otherwise obviously corrupt.
+    pub(crate) validate: bool,
+
+    /// Whether to skip books with the same contents as one already found.
+    pub(crate) dedupe_content: bool,
+
+    /// If present, a Calibre library to synchronise books from too.
+    pub(crate) calibre: Option<CalibreLibrary>,
+
+    /// If not empty, the extensions to prefer, most preferred first, when books differ only by
+    /// extension. Only the most preferred of them is synchronised.
+    pub(crate) prefer_formats: Vec<String>,
+
+    /// Whether to skip what the `.syncignore` files in the documents directories say to.
+    pub(crate) syncignore: bool,
+
+    /// Whether to skip Calibre's bookkeeping in the documents directories, such as its trash and
+    /// the originals of converted books, and EPUBs beside their KEPUBs. If absent, this is decided
+    /// for each documents directory by whether it's a Calibre library.
+    pub(crate) calibre_aware: Option<bool>,
+
+    /// If present, how many directories deep to look for books beneath each documents directory,
+    /// with 0 only looking at the files directly in it.
+    pub(crate) max_depth: Option<usize>,
+
+    /// Whether to skip directories on other filesystems than their documents directory, such as
+    /// mounts beneath it.
+    pub(crate) one_file_system: bool,
+}
+
+impl FindOptions {
+    fn is_filtered_out(&self, relative: &Path) -> bool {
+        self.excludes.is_match(relative)
+            || self
+                .includes
+                .as_ref()
+                .map(|includes| !includes.is_match(relative))
+                .unwrap_or(false)
+    }
+
+    fn is_too_large(&self, len: u64) -> bool {
+        self.max_size.map(|max| max < len).unwrap_or(false)
+    }
+
+    /// Whether a book was last modified before the cutoff. Books whose modification times can't be
+    /// read are never considered too old.
+    fn is_too_old(&self, metadata: &std::fs::Metadata) -> bool {
+        match (self.since, metadata.modified()) {
+            (Some(since), Ok(modified)) => modified < since,
+            _ => false,
+        }
+    }
+
+    /// Whether a book was last modified before the last successful run, so that it's already on
+    /// the destination as it is.
+    fn is_unchanged_since_last_sync(&self, metadata: &std::fs::Metadata) -> bool {
+        match (self.last_sync, metadata.modified()) {
+            (Some(last_sync), Ok(modified)) => modified < last_sync,
+            _ => false,
+        }
+    }
+}
+
+/// Whether a directory, relative to its documents directory, is too deep for its contents to be
+/// looked at.
+fn is_too_deep(relative_dir: &Path, max_depth: Option<usize>) -> bool {
+    max_depth
+        .map(|max| max < relative_dir.components().count())
+        .unwrap_or(false)
+}
+
+/// The device ID of the filesystem that a file or directory is on.
+#[cfg(unix)]
+fn device_of(metadata: &std::fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.dev())
+}
+
+/// Windows has no stable equivalent of a device ID, so every directory is walked.
+#[cfg(not(unix))]
+fn device_of(_metadata: &std::fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// Every EPUB is a ZIP archive, which starts with a local file header.
+const ZIP_LOCAL_FILE_HEADER: [u8; 4] = *b"PK\x03\x04";
+
+/// Why a book looks corrupt, if it does. Only the first few bytes are read, so this catches the
+/// likes of failed downloads rather than subtler damage.
+async fn invalidity(path: &Path, len: u64) -> io::Result<Option<&'static str>> {
+    if len == 0 {
+        return Ok(Some("it is empty"));
+    }
+    if !is_epub(path) {
+        return Ok(None);
+    }
+
+    let mut header = [0; ZIP_LOCAL_FILE_HEADER.len()];
+    match File::open(path).await?.read_exact(&mut header).await {
+        Ok(_) if header == ZIP_LOCAL_FILE_HEADER => Ok(None),
+        Ok(_) => Ok(Some("it is not a ZIP archive, as EPUBs must be")),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            Ok(Some("it is too short to be an EPUB"))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Whether a book should be skipped for looking corrupt, reporting why if so. Books that can't be
+/// read are left for the copy to fail on.
+async fn is_invalid(path: &Path, len: u64, stats: &Sender<Statistic>) -> Result<bool> {
+    match invalidity(path, len).await {
+        Ok(Some(reason)) => {
+            warn!(
+                path = %path.display(),
+                "Skipping {}, as it looks corrupt: {reason}",
+                path.display()
+            );
+            stats.send(Statistic::SkippedInvalid).await?;
+            Ok(true)
+        }
+        Ok(None) | Err(_) => Ok(false),
+    }
+}
+
+/// A book found in one of the documents directories, alongside the documents directory under which
+/// it was found.
+#[derive(Debug)]
+pub(crate) struct FoundBook {
+    pub(crate) path: PathBuf,
+    pub(crate) root: PathBuf,
+
+    /// What to name the book on the destination, if not after its own name, such as after its
+    /// title and author in a Calibre library.
+    pub(crate) name: Option<OsString>,
+}
+
+pub(crate) fn has_matching_extension(path: &Path, extensions_to_match: &HashSet<&OsStr>) -> bool {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .map(|ext| extensions_to_match.contains(OsStr::new(&ext.to_lowercase())))
+        .unwrap_or(false)
+}
+
+/// Pass along a book that's in a documents directory but was filtered out, if anything wants to
+/// know, so that deleting stale books leaves its copy on the destination alone.
+fn keep_book(kept: Option<&UnboundedSender<FoundBook>>, path: &Path, root: &Path) -> Result<()> {
+    if let Some(kept) = kept {
+        let found = FoundBook {
+            path: path.to_path_buf(),
+            root: root.to_path_buf(),
+            name: None,
+        };
+        kept.send(found)?;
+    }
+    Ok(())
+}
+
+/// Keep the books that were left unsearched, such as for being too deep or ignored by a
+/// `.syncignore` file, along with those beneath them, as they were never found but are still in
+/// the documents directory.
+async fn keep_unsearched_books(
+    unsearched: &[PathBuf],
+    extensions_to_match: &HashSet<&OsStr>,
+    kept: &UnboundedSender<FoundBook>,
+    root: &Path,
+) -> Result<()> {
+    for path in unsearched {
+        if fs::metadata(path)
+            .await
+            .is_ok_and(|metadata| metadata.is_file())
+        {
+            if has_matching_extension(path, extensions_to_match) {
+                keep_book(Some(kept), path, root)?;
+            }
+            continue;
+        }
+        let mut entries = WalkDir::new(path);
+        while let Some(entry) = entries.next().await {
+            // What can't be read can't be told from what's gone, so deleting it is left to runs
+            // that can.
+            let Ok(entry) = entry else {
+                continue;
+            };
+            let path = entry.path();
+            if has_matching_extension(&path, extensions_to_match) {
+                keep_book(Some(kept), &path, root)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Find the books in the documents directories, walking each one concurrently so that a slow one,
+/// such as a network mount, doesn't hold up the others. Those of the Calibre library, if there is
+/// one, are read from its database meanwhile. Books that are filtered out are sent to `kept`, if
+/// given, rather than just being skipped.
+pub(crate) async fn find_books(
+    dirs: &[PathBuf],
+    extensions_to_match: &HashSet<&OsStr>,
+    options: &FindOptions,
+    books: &Sender<FoundBook>,
+    kept: Option<&UnboundedSender<FoundBook>>,
+    stats: &Sender<Statistic>,
+    run: &Run,
+) -> Result<()> {
+    let extensions: Arc<[OsString]> = extensions_to_match
+        .iter()
+        .map(|ext| ext.to_os_string())
+        .collect();
+    let options = Arc::new(options.clone());
+    let syncignore = options.syncignore.then(|| Arc::new(SyncIgnore::default()));
+
+    let walkers: Vec<JoinHandle<Result<()>>> = dirs
+        .iter()
+        .map(|dir| {
+            let dir = dir.clone();
+            let extensions = extensions.clone();
+            let options = options.clone();
+            let syncignore = syncignore.clone();
+            let books = books.clone();
+            let kept = kept.cloned();
+            let stats = stats.clone();
+            let run = run.clone();
+            spawn(async move {
+                let extensions_to_match = extensions.iter().map(OsString::as_os_str).collect();
+                walk_documents_directory(
+                    &dir,
+                    &extensions_to_match,
+                    &options,
+                    syncignore,
+                    &books,
+                    kept.as_ref(),
+                    &stats,
+                    &run,
+                )
+                .await
+            })
+        })
+        .collect();
+
+    let mut result = match &options.calibre {
+        Some(library) => {
+            let prefer_formats = &options.prefer_formats;
+            find_calibre_books(
+                library,
+                extensions_to_match,
+                prefer_formats,
+                books,
+                stats,
+                run,
+            )
+            .await
+        }
+        None => Ok(()),
+    };
+
+    // Every walker is waited for, even after one fails, so that none is left sending books once
+    // this returns.
+    for walker in walkers {
+        let walked = walker.await.map_err(Error::from).and_then(|walked| walked);
+        if result.is_ok() {
+            result = walked;
+        }
+    }
+    result
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn walk_documents_directory(
+    dir: &Path,
+    extensions_to_match: &HashSet<&OsStr>,
+    options: &FindOptions,
+    syncignore: Option<Arc<SyncIgnore>>,
+    books: &Sender<FoundBook>,
+    kept: Option<&UnboundedSender<FoundBook>>,
+    stats: &Sender<Statistic>,
+    run: &Run,
+) -> Result<()> {
+    let started = Instant::now();
+    debug!(path = %dir.display(), "Walking documents directory {}", dir.display());
+
+    // Symlinked directories are walked separately through the symlink, so that the books in
+    // them appear to be under the documents directory. The canonical paths of what's been
+    // walked are kept to avoid walking anything twice, which also stops symlink cycles.
+    let mut to_walk = vec![dir.to_path_buf()];
+    let mut walked = vec![fs::canonicalize(dir).await?];
+    let pruned = Arc::new(AtomicUsize::new(0));
+    // Only tracked when the books beneath them must be kept.
+    let unsearched = Arc::new(Mutex::new(vec![]));
+
+    // Files that aren't books are counted in batches rather than one by one, as there can be far
+    // more of them than books.
+    let mut non_matching = 0;
+    let mut directories = 0;
+
+    let root_device = if options.one_file_system {
+        let device = fs::metadata(dir).await.ok().as_ref().and_then(device_of);
+        if device.is_none() {
+            debug!(
+                path = %dir.display(),
+                "Searching every filesystem beneath {}, as which one it's on could not be \
+                determined",
+                dir.display()
+            );
+        }
+        device
+    } else {
+        None
+    };
+    let calibre_aware = is_calibre_aware(dir, options.calibre_aware).await;
+
+    while let Some(walking) = to_walk.pop() {
+        directories += 1;
+        let mut entries = WalkDir::new(&walking);
+        if !options.hidden
+            || calibre_aware
+            || syncignore.is_some()
+            || options.max_depth.is_some()
+            || root_device.is_some()
+        {
+            let hidden = options.hidden;
+            let max_depth = options.max_depth;
+            let pruned = pruned.clone();
+            let syncignore = syncignore.clone();
+            let root = dir.to_path_buf();
+            let stats = stats.clone();
+            let unsearched = kept.map(|_| unsearched.clone());
+            entries = entries.filter(move |entry| {
+                let pruned = pruned.clone();
+                let syncignore = syncignore.clone();
+                let root = root.clone();
+                let stats = stats.clone();
+                let unsearched = unsearched.clone();
+                let leave_unsearched = move |path: PathBuf| {
+                    if let Some(unsearched) = &unsearched {
+                        unsearched.lock().unwrap().push(path);
+                    }
+                    Filtering::IgnoreDir
+                };
+                async move {
+                    // Calibre's trash and notes are skipped even when hidden files aren't.
+                    if calibre_aware && is_calibre_bookkeeping(&entry.file_name()) {
+                        let path = entry.path();
+                        debug!(
+                            path = %path.display(),
+                            "Not searching {}, as it's Calibre's bookkeeping",
+                            path.display()
+                        );
+                        return Filtering::IgnoreDir;
+                    }
+                    if !hidden && is_hidden(&entry.file_name()) {
+                        pruned.fetch_add(1, Ordering::Relaxed);
+                        return leave_unsearched(entry.path());
+                    }
+                    if max_depth.is_some() {
+                        let path = entry.path();
+                        let relative = path.strip_prefix(&root).unwrap_or(&path);
+                        let is_dir = entry
+                            .file_type()
+                            .await
+                            .map(|file_type| file_type.is_dir())
+                            .unwrap_or(false);
+                        if is_dir && is_too_deep(relative, max_depth) {
+                            debug!(
+                                path = %path.display(),
+                                "Not searching {}, as it's deeper than the maximum depth",
+                                path.display()
+                            );
+                            // This only fails once the run is ending anyway.
+                            let _ = stats.send(Statistic::PrunedByDepth).await;
+                            return leave_unsearched(path);
+                        }
+                    }
+                    if let Some(root_device) = root_device {
+                        let path = entry.path();
+                        let is_dir = entry
+                            .file_type()
+                            .await
+                            .map(|file_type| file_type.is_dir())
+                            .unwrap_or(false);
+                        if is_dir {
+                            match entry.metadata().await.ok().as_ref().and_then(device_of) {
+                                Some(device) if device != root_device => {
+                                    debug!(
+                                        path = %path.display(),
+                                        "Not searching {}, as it's on another filesystem",
+                                        path.display()
+                                    );
+                                    return leave_unsearched(path);
+                                }
+                                Some(_) => {}
+                                None => debug!(
+                                    path = %path.display(),
+                                    "Searching {}, as which filesystem it's on could not be \
+                                    determined",
+                                    path.display()
+                                ),
+                            }
+                        }
+                    }
+                    // This is checked before the extension, so that ignored directories aren't
+                    // walked at all.
+                    if let Some(syncignore) = syncignore {
+                        let path = entry.path();
+                        let is_dir = entry
+                            .file_type()
+                            .await
+                            .map(|file_type| file_type.is_dir())
+                            .unwrap_or(false);
+                        if syncignore.is_ignored(&root, &path, is_dir).await {
+                            debug!(
+                                path = %path.display(),
+                                "Excluded {} by a .syncignore file",
+                                path.display()
+                            );
+                            // This only fails once the run is ending anyway.
+                            let _ = stats.send(Statistic::Excluded).await;
+                            return leave_unsearched(path);
+                        }
+                    }
+                    Filtering::Continue
+                }
+            });
+        }
+
+        loop {
+            if run.is_interrupted() {
+                debug!(path = %dir.display(), "Stopped walking {}", dir.display());
+                stats
+                    .send(Statistic::ScannedNonMatching(non_matching))
+                    .await?;
+                stats
+                    .send(Statistic::TraversedDirectories(directories))
+                    .await?;
+                return Ok(());
+            }
+            let entry = match entries.next().await {
+                Some(Ok(entry)) => entry,
+                Some(Err(err)) => {
+                    warn!(
+                        path = %dir.display(),
+                        "Skipping part of {}, as it could not be read: {err}",
+                        dir.display()
+                    );
+                    stats.send(Statistic::WalkFailed).await?;
+                    continue;
+                }
+                None => break,
+            };
+            let path = entry.path();
+
+            let file_type = match entry.file_type().await {
+                Ok(file_type) => file_type,
+                Err(err) => {
+                    warn!(
+                        path = %path.display(),
+                        "Skipping {}, as its type could not be read: {err}",
+                        path.display()
+                    );
+                    stats.send(Statistic::WalkFailed).await?;
+                    continue;
+                }
+            };
+            let mut is_file = file_type.is_file();
+            if file_type.is_dir() {
+                directories += 1;
+            }
+            if file_type.is_symlink() {
+                let target = match fs::metadata(&path).await {
+                    Ok(target) => target,
+                    Err(err) => {
+                        warn!(
+                            path = %path.display(),
+                            "Skipping the broken symlink {}: {err}",
+                            path.display()
+                        );
+                        continue;
+                    }
+                };
+                if target.is_dir() {
+                    let relative = path.strip_prefix(dir).unwrap_or(&path);
+                    let is_elsewhere = root_device
+                        .zip(device_of(&target))
+                        .map(|(root_device, device)| root_device != device)
+                        .unwrap_or(false);
+                    if options.follow_symlinks && is_too_deep(relative, options.max_depth) {
+                        debug!(
+                            path = %path.display(),
+                            "Not following {}, as it's deeper than the maximum depth",
+                            path.display()
+                        );
+                        stats.send(Statistic::PrunedByDepth).await?;
+                        if kept.is_some() {
+                            unsearched.lock().unwrap().push(path.clone());
+                        }
+                    } else if options.follow_symlinks && is_elsewhere {
+                        debug!(
+                            path = %path.display(),
+                            "Not following {}, as it leads to another filesystem",
+                            path.display()
+                        );
+                        if kept.is_some() {
+                            unsearched.lock().unwrap().push(path.clone());
+                        }
+                    } else if options.follow_symlinks {
+                        let canonical = match fs::canonicalize(&path).await {
+                            Ok(canonical) => canonical,
+                            Err(err) => {
+                                warn!(
+                                    path = %path.display(),
+                                    "Not following {}, as where it leads could not be read: {err}",
+                                    path.display()
+                                );
+                                stats.send(Statistic::WalkFailed).await?;
+                                continue;
+                            }
+                        };
+                        if walked.iter().any(|seen| canonical.starts_with(seen)) {
+                            debug!(
+                                path = %path.display(),
+                                "Not following {}, as it leads somewhere already walked",
+                                path.display()
+                            );
+                        } else {
+                            walked.push(canonical);
+                            to_walk.push(path.clone());
+                        }
+                    }
+                    continue;
+                }
+                is_file = target.is_file();
+            }
+
+            if !has_matching_extension(&path, extensions_to_match)
+                || (calibre_aware && is_calibre_original(&path))
+            {
+                if is_file {
+                    non_matching += 1;
+                }
+                if non_matching == NON_MATCHING_BATCH_SIZE {
+                    stats
+                        .send(Statistic::ScannedNonMatching(non_matching))
+                        .await?;
+                    non_matching = 0;
+                }
+            } else {
+                let relative = path.strip_prefix(dir).unwrap_or(&path);
+                if options.is_filtered_out(relative) {
+                    debug!(path = %path.display(), "Excluded {}", path.display());
+                    stats.send(Statistic::Excluded).await?;
+                    keep_book(kept, &path, dir)?;
+                    continue;
+                }
+                // Calibre can keep both an EPUB and the KEPUB converted from it for the same book,
+                // in which case only the KEPUB is synchronised, whichever is walked past first.
+                if calibre_aware
+                    && is_convertible_to_kepub(&path)
+                    && fs::try_exists(kepub_path_for(&path)).await.unwrap_or(false)
+                {
+                    debug!(
+                        path = %path.display(),
+                        "Not synchronising {}, as it's superseded by the KEPUB beside it",
+                        path.display()
+                    );
+                    stats.send(Statistic::Superseded).await?;
+                    continue;
+                }
+
+                // Symlinked books are sized, and later copied, by their targets.
+                let metadata = match fs::metadata(&path).await {
+                    Ok(metadata) => metadata,
+                    Err(err) => {
+                        warn!(
+                            path = %path.display(),
+                            "Skipping {}, as its metadata could not be read: {err}",
+                            path.display()
+                        );
+                        stats.send(Statistic::WalkFailed).await?;
+                        continue;
+                    }
+                };
+                let len = metadata.len();
+                if options.is_too_large(len) {
+                    debug!(
+                        path = %path.display(),
+                        size = len,
+                        "Excluded {} for being too large",
+                        path.display()
+                    );
+                    stats.send(Statistic::ExcludedBySize).await?;
+                    keep_book(kept, &path, dir)?;
+                    continue;
+                }
+                if options.is_too_old(&metadata) {
+                    debug!(
+                        path = %path.display(),
+                        "Excluded {} for being modified before the cutoff",
+                        path.display()
+                    );
+                    stats.send(Statistic::ExcludedAsTooOld).await?;
+                    keep_book(kept, &path, dir)?;
+                    continue;
+                }
+                if options.is_unchanged_since_last_sync(&metadata) {
+                    debug!(
+                        path = %path.display(),
+                        "Skipping {}, as it's unchanged since the last run",
+                        path.display()
+                    );
+                    stats.send(Statistic::UnchangedSinceLastSync).await?;
+                    continue;
+                }
+                if options.validate && is_invalid(&path, len, stats).await? {
+                    continue;
+                }
+                debug!(path = %path.display(), size = len, "Found {}", path.display());
+                stats
+                    .send(Statistic::FoundSrcDocument(path.clone(), len))
+                    .await?;
+
+                let found = FoundBook {
+                    path: path.to_path_buf(),
+                    root: dir.to_path_buf(),
+                    name: None,
+                };
+                books.send(found).await?;
+            }
+        }
+
+        stats
+            .send(Statistic::ScannedNonMatching(non_matching))
+            .await?;
+        stats
+            .send(Statistic::TraversedDirectories(directories))
+            .await?;
+        non_matching = 0;
+        directories = 0;
+    }
+    if let Some(kept) = kept {
+        let unsearched = std::mem::take(&mut *unsearched.lock().unwrap());
+        keep_unsearched_books(&unsearched, extensions_to_match, kept, dir).await?;
+    }
+    debug!(
+        path = %dir.display(),
+        elapsed_ms = started.elapsed().as_millis(),
+        "Finished walking {}",
+        dir.display()
+    );
+    let pruned = pruned.load(Ordering::Relaxed);
+    if 0 < pruned {
+        debug!(
+            path = %dir.display(),
+            pruned,
+            "Skipped {pruned} hidden files and directories in {}; pass --hidden to include them",
+            dir.display()
+        );
+    }
+    Ok(())
+}
+
+/// Send along the books that a plan copies, as if they'd been found, rather than looking for any
+/// others.
+pub(crate) async fn find_planned_books(
+    planned: &HashMap<PathBuf, PathBuf>,
+    books: &Sender<FoundBook>,
+    stats: &Sender<Statistic>,
+    run: &Run,
+) -> Result<()> {
+    let mut paths: Vec<&PathBuf> = planned.keys().collect();
+    paths.sort();
+    for path in paths {
+        if run.is_interrupted() {
+            break;
+        }
+        let len = fs::metadata(path).await?.len();
+        stats
+            .send(Statistic::FoundSrcDocument(path.clone(), len))
+            .await?;
+        let found = FoundBook {
+            path: path.clone(),
+            root: path.parent().unwrap_or(path).to_path_buf(),
+            name: None,
+        };
+        books.send(found).await?;
+    }
+    Ok(())
+}
+
+/// Send along the books in a list of files, as if they'd been found, rather than walking the
+/// documents directories. Each must exist and have a matching extension; those that don't are
+/// skipped, as are any listed more than once. Books under a documents directory count as found in
+/// it, and the rest as found in their own directories.
+pub(crate) async fn find_listed_books(
+    files: &[PathBuf],
+    dirs: &[PathBuf],
+    extensions_to_match: &HashSet<&OsStr>,
+    books: &Sender<FoundBook>,
+    stats: &Sender<Statistic>,
+    run: &Run,
+) -> Result<()> {
+    let mut listed = HashSet::new();
+    for path in files {
+        if run.is_interrupted() {
+            break;
+        }
+        let path_str = path.display();
+        if !listed.insert(path) {
+            debug!(path = %path_str, "Skipping {path_str}, as it was already listed");
+            continue;
+        }
+        let metadata = match fs::metadata(path).await {
+            Ok(metadata) if metadata.is_file() => metadata,
+            Ok(_) => {
+                warn!(path = %path_str, "Skipping {path_str}, as it is not a file");
+                stats.send(Statistic::WalkFailed).await?;
+                continue;
+            }
+            Err(err) => {
+                warn!(path = %path_str, "Skipping {path_str}, as it could not be read: {err}");
+                stats.send(Statistic::WalkFailed).await?;
+                continue;
+            }
+        };
+        if !has_matching_extension(path, extensions_to_match) {
+            warn!(
+                path = %path_str,
+                "Skipping {path_str}, as it does not have a matching extension"
+            );
+            stats.send(Statistic::ScannedNonMatching(1)).await?;
+            continue;
+        }
+
+        let len = metadata.len();
+        debug!(path = %path_str, size = len, "Found {path_str}");
+        stats
+            .send(Statistic::FoundSrcDocument(path.clone(), len))
+            .await?;
+        let root = dirs
+            .iter()
+            .find(|dir| path.starts_with(dir))
+            .map(PathBuf::as_path)
+            .or_else(|| path.parent())
+            .unwrap_or(path);
+        let found = FoundBook {
+            path: path.clone(),
+            root: root.to_path_buf(),
+            name: None,
+        };
+        books.send(found).await?;
+    }
+    Ok(())
+}
+
+/// Pass along only the first of the found books with each content, reporting the rest as
+/// duplicates of it. Books are hashed concurrently, as hashing a large library takes a while, but
+/// are still passed along in the order they were found, so that the same one is kept each run.
+pub(crate) fn dedupe_books(
+    mut books: Receiver<FoundBook>,
+    stats: Sender<Statistic>,
+    run: Run,
+) -> (Receiver<FoundBook>, JoinHandle<Result<()>>) {
+    let (deduped_tx, deduped_rx) = channel(FOUND_BOOKS_CHANNEL_BOUND);
+    let concurrency = available_parallelism().map(usize::from).unwrap_or(1);
+
+    let deduping = spawn(async move {
+        let mut hashing = VecDeque::new();
+        let mut seen: HashMap<_, PathBuf> = HashMap::new();
+        loop {
+            // Take more books while there's room to hash them, but don't wait for more while
+            // there are hashed ones to pass along, as a watch can go a long time between books.
+            let found = if hashing.is_empty() {
+                books.recv().await
+            } else if hashing.len() < concurrency {
+                books.try_recv().ok()
+            } else {
+                None
+            };
+            if let Some(found) = found {
+                let run = run.clone();
+                hashing.push_back(spawn(async move {
+                    let digest = hash_file(&found.path, run.hash_cache()).await;
+                    (found, digest)
+                }));
+                continue;
+            }
+            let Some(hashed) = hashing.pop_front() else {
+                break;
+            };
+
+            let (found, digest) = hashed.await?;
+            if run.is_interrupted() {
+                continue;
+            }
+            let src_str = found.path.display();
+            let digest = match digest {
+                Ok(digest) => digest,
+                Err(err) => {
+                    warn!(
+                        path = %src_str,
+                        "Failed to hash {src_str}, so it can't be checked for duplicates: {err}"
+                    );
+                    deduped_tx.send(found).await?;
+                    continue;
+                }
+            };
+            match seen.entry(digest) {
+                // The same book is found again when it's modified while watching.
+                Entry::Occupied(first) if *first.get() != found.path => {
+                    let first_str = first.get().display();
+                    info!(
+                        path = %src_str,
+                        "Not synchronising {src_str}, as it's a duplicate of {first_str}"
+                    );
+                    stats.send(Statistic::Duplicate).await?;
+                    run.advance_progress();
+                }
+                Entry::Occupied(_) => deduped_tx.send(found).await?,
+                Entry::Vacant(entry) => {
+                    entry.insert(found.path.clone());
+                    deduped_tx.send(found).await?;
+                }
+            }
+        }
+        Ok(())
+    });
+    (deduped_rx, deduping)
+}
+
+/// Pass along only the most preferred format of the books that differ only by extension, such as
+/// `dune.epub` rather than `dune.pdf` when EPUBs are preferred, counting the others as superseded.
+/// Books are matched by name wherever they are in the documents directories, as they're usually
+/// flattened side by side on the destination. Books with an extension that isn't in the
+/// preferences are always passed along. A preferred format may be found after the others, so
+/// every book is held until the finder is done, which it never is when watching.
+pub(crate) fn prefer_formats(
+    mut books: Receiver<FoundBook>,
+    formats: Vec<String>,
+    stats: Sender<Statistic>,
+    run: Run,
+) -> (Receiver<FoundBook>, JoinHandle<Result<()>>) {
+    let (preferred_tx, preferred_rx) = channel(FOUND_BOOKS_CHANNEL_BOUND);
+    let rank = move |path: &Path| {
+        let ext = path.extension()?.to_str()?.to_lowercase();
+        formats.iter().position(|format| *format == ext)
+    };
+
+    let preferring = spawn(async move {
+        let mut found = vec![];
+        while let Some(book) = books.recv().await {
+            found.push(book);
+        }
+
+        // The most preferred format of each title, keyed by its name without the extension.
+        let mut preferred = HashMap::<OsString, (usize, PathBuf)>::new();
+        for book in &found {
+            let (Some(rank), Some(title)) = (rank(&book.path), book.path.file_stem()) else {
+                continue;
+            };
+            match preferred.entry(title.to_owned()) {
+                Entry::Occupied(mut best) if rank < best.get().0 => {
+                    best.insert((rank, book.path.clone()));
+                }
+                Entry::Occupied(_) => {}
+                Entry::Vacant(entry) => {
+                    entry.insert((rank, book.path.clone()));
+                }
+            }
+        }
+
+        for book in found {
+            let best = rank(&book.path)
+                .and_then(|_| preferred.get(book.path.file_stem()?))
+                .map(|(_, best)| best)
+                .filter(|best| **best != book.path);
+            match best {
+                Some(best) => {
+                    let (src_str, best_str) = (book.path.display(), best.display());
+                    info!(
+                        path = %src_str,
+                        "Not synchronising {src_str}, as it's superseded by the preferred format \
+                        {best_str}"
+                    );
+                    stats.send(Statistic::Superseded).await?;
+                    run.advance_progress();
+                }
+                None => preferred_tx.send(book).await?,
+            }
+        }
+        Ok(())
+    });
+    (preferred_rx, preferring)
+}
+
+/// After the initial pass, keep watching the documents directories for books being created or
+/// modified, and send them along to be synchronised too. A book is only sent once it has stopped
+/// changing for a while, so that one still being downloaded isn't copied half-written. This runs
+/// until Ctrl-C is pressed.
+pub(crate) async fn watch_books(
+    dirs: &[PathBuf],
+    extensions_to_match: &HashSet<&OsStr>,
+    options: &FindOptions,
+    books: Sender<FoundBook>,
+    stats: Sender<Statistic>,
+    run: &Run,
+) -> Result<()> {
+    let (events_tx, mut events) = unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        // This only fails once the receiver is gone, when the watcher is about to be dropped too.
+        let _ = events_tx.send(event);
+    })?;
+    // Events come with absolute paths, so they are matched against the canonical form of each
+    // documents directory but reported relative to the directory as it was given.
+    let mut roots = vec![];
+    for dir in dirs {
+        watcher.watch(dir, RecursiveMode::Recursive)?;
+        let calibre_aware = is_calibre_aware(dir, options.calibre_aware).await;
+        roots.push((fs::canonicalize(dir).await?, dir, calibre_aware));
+    }
+    let syncignore = options.syncignore.then(SyncIgnore::default);
+
+    let dirs_str = dirs
+        .iter()
+        .map(|dir| dir.display().to_string())
+        .collect::<Vec<_>>()
+        .join(" and ");
+    info!("Watching {dirs_str} for new books; press Ctrl-C to stop.");
+
+    let mut changing = HashMap::new();
+    let mut ticks = interval(WATCH_SETTLE_TIME / 4);
+    loop {
+        select! {
+            () = run.interrupted() => break,
+            event = events.recv() => {
+                let Some(event) = event else { break };
+                let event: notify::Event = event?;
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    for path in event.paths {
+                        if has_matching_extension(&path, extensions_to_match) {
+                            changing.insert(path, Instant::now());
+                        }
+                    }
+                }
+            }
+            _ = ticks.tick() => {
+                let settled: Vec<PathBuf> = changing
+                    .iter()
+                    .filter(|(_, changed)| WATCH_SETTLE_TIME <= changed.elapsed())
+                    .map(|(path, _)| path.clone())
+                    .collect();
+                for path in settled {
+                    changing.remove(&path);
+
+                    let Some((relative, root, calibre_aware)) = roots
+                        .iter()
+                        .find_map(|(canonical, dir, calibre_aware)| {
+                            Some((path.strip_prefix(canonical).ok()?, dir, *calibre_aware))
+                        })
+                    else {
+                        continue;
+                    };
+                    if !options.hidden && relative.iter().any(is_hidden) {
+                        continue;
+                    }
+                    if calibre_aware
+                        && (relative.iter().any(is_calibre_bookkeeping) || is_calibre_original(&path))
+                    {
+                        continue;
+                    }
+                    if is_too_deep(relative.parent().unwrap_or(relative), options.max_depth) {
+                        continue;
+                    }
+                    let metadata = match fs::metadata(&path).await {
+                        Ok(metadata) if metadata.is_file() => metadata,
+                        _ => continue,
+                    };
+                    if options.one_file_system {
+                        let root_device = fs::metadata(root).await.ok().as_ref().and_then(device_of);
+                        let is_elsewhere = root_device
+                            .zip(device_of(&metadata))
+                            .map(|(root_device, device)| root_device != device)
+                            .unwrap_or(false);
+                        if is_elsewhere {
+                            continue;
+                        }
+                    }
+                    let len = metadata.len();
+                    let is_syncignored = match &syncignore {
+                        Some(syncignore) => {
+                            syncignore.is_ignored(root, &root.join(relative), false).await
+                        }
+                        None => false,
+                    };
+                    if is_syncignored || options.is_filtered_out(relative) {
+                        stats.send(Statistic::Excluded).await?;
+                        continue;
+                    }
+                    if options.is_too_large(len) {
+                        stats.send(Statistic::ExcludedBySize).await?;
+                        continue;
+                    }
+                    if options.is_too_old(&metadata) {
+                        stats.send(Statistic::ExcludedAsTooOld).await?;
+                        continue;
+                    }
+                    if options.validate && is_invalid(&path, len, &stats).await? {
+                        continue;
+                    }
+
+                    debug!(path = %path.display(), size = len, "Noticed {}", path.display());
+                    stats.send(Statistic::FoundSrcDocument(path.clone(), len)).await?;
+                    let found = FoundBook {
+                        path: root.join(relative),
+                        root: root.to_path_buf(),
+                        name: None,
+                    };
+                    books.send(found).await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether a file or directory is hidden, by the Unix convention of a leading dot.
+pub(crate) fn is_hidden(name: &OsStr) -> bool {
+    name.to_string_lossy().starts_with('.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_extensions_whatever_their_case() {
+        let extensions = HashSet::from([OsStr::new("epub"), OsStr::new("pdf")]);
+        for name in [
+            "dune.epub",
+            "DUNE.EPUB",
+            "Dune.EpUb",
+            "dune.PDF",
+            "dune.Pdf",
+        ] {
+            assert!(
+                has_matching_extension(Path::new(name), &extensions),
+                "{name}"
+            );
+        }
+        for name in ["dune.mobi", "DUNE.TXT", "epub", "dune", "dune.epub.part"] {
+            assert!(
+                !has_matching_extension(Path::new(name), &extensions),
+                "{name}"
+            );
+        }
+    }
+}
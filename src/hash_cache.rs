@@ -0,0 +1,191 @@
+//! Remembering the digests of books between runs, so that unchanged ones needn't be hashed again.
+
+use {
+    crate::copy::{partial_path_for, to_hex},
+    anyhow::Result,
+    blake3::Hash,
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+        sync::Mutex,
+        time::SystemTime,
+    },
+    tokio::{
+        fs::{self, File},
+        io::{self, AsyncWriteExt},
+    },
+    tracing::{debug, warn},
+};
+
+/// The digests of files hashed by earlier runs, kept in a file of its own outside of the
+/// destination, as the books on the workstation are hashed far more often than those on the
+/// device. Each run loads its own, which everything hashing books during the run goes through.
+#[derive(Debug)]
+pub(crate) struct HashCache {
+    path: PathBuf,
+    digests: Mutex<Digests>,
+}
+
+/// The contents of a hash cache's file.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Digests {
+    /// Digests keyed by the canonical paths of the files they're of.
+    digests: HashMap<String, CachedDigest>,
+
+    /// Whether anything was added since it was loaded, and so whether it needs saving.
+    #[serde(skip)]
+    changed: bool,
+}
+
+/// The digest of a file as it was when hashed. It's only used again while the file's size and
+/// modification time are the same.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct CachedDigest {
+    size: u64,
+    modified_ns: u64,
+
+    /// Caches written before digests were BLAKE3 ones lack this, so their digests are recomputed.
+    #[serde(default)]
+    blake3: String,
+}
+
+/// What a file's digest is cached under, and what it must still look like for the digest to be
+/// used.
+pub(crate) struct CacheKey {
+    path: String,
+    size: u64,
+    modified_ns: u64,
+}
+
+impl HashCache {
+    /// Load the cache at a path. A missing cache is just empty, as is a corrupt one, which is
+    /// warned about and rebuilt as books are hashed.
+    pub(crate) async fn load(path: &Path) -> Self {
+        let path_str = path.display();
+        let digests = match fs::read(path).await {
+            Ok(contents) => match serde_json::from_slice::<Digests>(&contents) {
+                Ok(digests) => digests,
+                Err(err) => {
+                    warn!(path = %path_str, "Discarding the corrupt hash cache {path_str}: {err}");
+                    Digests {
+                        changed: true,
+                        ..Digests::default()
+                    }
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Digests::default(),
+            Err(err) => {
+                warn!(path = %path_str, "Ignoring the unreadable hash cache {path_str}: {err}");
+                Digests::default()
+            }
+        };
+        HashCache {
+            path: path.to_path_buf(),
+            digests: Mutex::new(digests),
+        }
+    }
+
+    /// Save the cache, if anything was added to it since it was loaded or last saved. It's written
+    /// to a partial file first and renamed into place, like the manifest. Failing to save it only
+    /// warns, as the next run can hash the books again.
+    pub(crate) async fn save(&self) {
+        let (json, count) = {
+            let Ok(mut digests) = self.digests.lock() else {
+                return;
+            };
+            if !digests.changed {
+                return;
+            }
+            digests.changed = false;
+            (serde_json::to_vec(&*digests), digests.digests.len())
+        };
+        let path_str = self.path.display();
+        let written = match json {
+            Ok(json) => self.write(&json).await,
+            Err(err) => Err(err.into()),
+        };
+        match written {
+            Ok(()) => debug!(path = %path_str, digests = count, "Saved the hash cache"),
+            Err(err) => warn!(
+                path = %path_str,
+                "Failed to save the hash cache {path_str}, so the next run will hash every book \
+                again: {err:#}"
+            ),
+        }
+    }
+
+    async fn write(&self, json: &[u8]) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let partial_path = partial_path_for(&self.path);
+        let mut partial = File::create(&partial_path).await?;
+        partial.write_all(json).await?;
+        partial.sync_all().await?;
+        drop(partial);
+
+        if let Err(err) = fs::rename(&partial_path, &self.path).await {
+            let _ = fs::remove_file(&partial_path).await;
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    /// What a file's digest would be cached under, along with the digest if it's already cached.
+    pub(crate) async fn cached_digest(&self, path: &Path) -> Option<(CacheKey, Option<Hash>)> {
+        let metadata = fs::metadata(path).await.ok()?;
+        let modified = metadata.modified().ok()?;
+        let since_epoch = modified.duration_since(SystemTime::UNIX_EPOCH).ok()?;
+        let key = CacheKey {
+            path: fs::canonicalize(path).await.ok()?.to_str()?.to_owned(),
+            size: metadata.len(),
+            modified_ns: u64::try_from(since_epoch.as_nanos()).ok()?,
+        };
+
+        let digest = self
+            .digests
+            .lock()
+            .ok()?
+            .digests
+            .get(&key.path)
+            .filter(|cached| cached.size == key.size && cached.modified_ns == key.modified_ns)
+            .and_then(|cached| Hash::from_hex(&cached.blake3).ok());
+        Some((key, digest))
+    }
+
+    /// Cache the digest of a file that was just hashed.
+    pub(crate) fn cache_digest(&self, key: CacheKey, digest: &Hash) {
+        let Ok(mut digests) = self.digests.lock() else {
+            return;
+        };
+        let CacheKey {
+            path,
+            size,
+            modified_ns,
+        } = key;
+        let cached = CachedDigest {
+            size,
+            modified_ns,
+            blake3: to_hex(digest),
+        };
+        digests.digests.insert(path, cached);
+        digests.changed = true;
+    }
+}
+
+/// Load the cache at a path for a run to use, if it uses one.
+pub(crate) async fn load_hash_cache(path: Option<&Path>) -> Option<HashCache> {
+    match path {
+        Some(path) => Some(HashCache::load(path).await),
+        None => None,
+    }
+}
+
+/// Delete the cache at a path, so that every book is hashed afresh.
+pub async fn clear_hash_cache(path: &Path) -> Result<()> {
+    match fs::remove_file(path).await {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
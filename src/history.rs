@@ -0,0 +1,151 @@
+//! Remembering the books seen by earlier runs, so that a run's summary can say what changed since.
+
+use {
+    crate::copy::partial_path_for,
+    anyhow::Result,
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::{HashMap, HashSet},
+        path::{Path, PathBuf},
+        time::SystemTime,
+    },
+    tokio::{
+        fs::{self, File},
+        io::{self, AsyncWriteExt},
+    },
+    tracing::{debug, warn},
+};
+
+/// The last run to each destination, keyed by the destination's path.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct HistoryState {
+    runs: HashMap<String, PastRun>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct PastRun {
+    /// When the run finished, in RFC 3339 format.
+    finished_at: String,
+
+    /// The paths of the books found in the documents directories.
+    books: HashSet<String>,
+}
+
+/// How a run's books compare with those of the last run to the same destination.
+#[derive(Clone, Debug, Serialize)]
+pub struct SinceLastRun {
+    /// When the last run finished, in RFC 3339 format.
+    pub last_run: String,
+
+    /// How many of the books found weren't found by the last run.
+    pub new_sources: usize,
+
+    /// How many of the books found by the last run weren't found by this one.
+    pub disappeared_sources: usize,
+
+    /// How many of the books copied or updated weren't found by the last run.
+    pub copied_new: usize,
+}
+
+/// The history of runs, kept in a state file outside of the destination. Failing to read or write
+/// it only warns, as it's no reason to fail the run.
+pub(crate) struct History {
+    path: PathBuf,
+    destination: String,
+    state: HistoryState,
+
+    /// Whether this run is to be remembered, which dry runs aren't.
+    record: bool,
+}
+
+fn book_key(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+impl History {
+    /// Load the history at a path for a run to a destination. A missing history is just empty, as
+    /// is a corrupt one, which is warned about and replaced once the run finishes.
+    pub(crate) async fn load(path: PathBuf, destination: &Path, record: bool) -> History {
+        let path_str = path.display();
+        let state = match fs::read(&path).await {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|err| {
+                warn!(path = %path_str, "Ignoring the corrupt history {path_str}: {err}");
+                HistoryState::default()
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HistoryState::default(),
+            Err(err) => {
+                warn!(path = %path_str, "Ignoring the unreadable history {path_str}: {err}");
+                HistoryState::default()
+            }
+        };
+        History {
+            path,
+            destination: book_key(destination),
+            state,
+            record,
+        }
+    }
+
+    async fn write(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let partial_path = partial_path_for(&self.path);
+        let json = serde_json::to_vec(&self.state)?;
+        let mut partial = File::create(&partial_path).await?;
+        partial.write_all(&json).await?;
+        partial.sync_all().await?;
+        drop(partial);
+
+        if let Err(err) = fs::rename(&partial_path, &self.path).await {
+            let _ = fs::remove_file(&partial_path).await;
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    /// Compare the books found and copied by this run with those found by the last one to the
+    /// same destination, if there was one, and then remember this run in its place. A run that
+    /// didn't finish finding books isn't remembered, as the books it didn't find would look like
+    /// they had disappeared.
+    pub(crate) async fn finish(
+        mut self,
+        found: impl Iterator<Item = &PathBuf>,
+        copied: &[&Path],
+        found_every_book: bool,
+    ) -> Option<SinceLastRun> {
+        let found = found.map(|path| book_key(path)).collect::<HashSet<_>>();
+        let since_last_run = self.state.runs.get(&self.destination).map(|last| {
+            let new_sources = found.difference(&last.books).count();
+            let disappeared_sources = last.books.difference(&found).count();
+            let copied_new = copied
+                .iter()
+                .filter(|source| !last.books.contains(&book_key(source)))
+                .count();
+            SinceLastRun {
+                last_run: last.finished_at.clone(),
+                new_sources,
+                disappeared_sources,
+                copied_new,
+            }
+        });
+
+        if self.record && found_every_book {
+            let run = PastRun {
+                finished_at: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+                books: found,
+            };
+            self.state.runs.insert(self.destination.clone(), run);
+            let path_str = self.path.display();
+            match self.write().await {
+                Ok(()) => debug!(path = %path_str, "Saved the history"),
+                Err(err) => warn!(
+                    path = %path_str,
+                    "Failed to save the history {path_str}, so the next run can't say what \
+                    changed since this one: {err:#}"
+                ),
+            }
+        }
+        since_last_run
+    }
+}
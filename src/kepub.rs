@@ -0,0 +1,217 @@
+//! Converting EPUBs to Kobo's KEPUB format on the way to the device.
+
+use {
+    crate::{
+        copy::{copy_through_partial, CopyKind, CopyOptions, CopyTask},
+        report::{record_action, Action},
+        stats::Statistic,
+        Run,
+    },
+    anyhow::{anyhow, Result},
+    std::{
+        env,
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    },
+    tokio::{
+        fs,
+        process::Command,
+        sync::{mpsc::Sender, Semaphore},
+        task::spawn,
+    },
+    tracing::{debug, info, warn},
+};
+
+pub(crate) const KEPUB_SUFFIX: &str = ".kepub.epub";
+
+pub(crate) fn is_kepub(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| {
+            name.to_string_lossy()
+                .to_lowercase()
+                .ends_with(KEPUB_SUFFIX)
+        })
+        .unwrap_or(false)
+}
+
+/// Whether a book can be converted to a KEPUB, which only plain EPUBs can.
+pub(crate) fn is_epub(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| ext.eq_ignore_ascii_case("epub"))
+        .unwrap_or(false)
+}
+
+pub(crate) fn is_convertible_to_kepub(path: &Path) -> bool {
+    is_epub(path) && !is_kepub(path)
+}
+
+/// Whether a destination is a KEPUB converted from its source, rather than a plain copy.
+pub(crate) fn is_kepub_conversion(src_path: &Path, dest_path: &Path) -> bool {
+    is_convertible_to_kepub(src_path) && is_kepub(dest_path)
+}
+
+/// Name the EPUB that a KEPUB would have been converted from, such as `a.epub` for `a.kepub.epub`.
+pub(crate) fn plain_path_for_kepub(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    let stem = name.get(..name.len().checked_sub(KEPUB_SUFFIX.len())?)?;
+    Some(path.with_file_name(format!("{stem}.epub")))
+}
+
+/// Where to copy a book that fails to convert instead: where its KEPUB would have gone, but
+/// under its own extension, such as `a.epub` for `a.kepub.epub`.
+pub(crate) fn plain_dest_for(src_path: &Path, kepub_dest: &Path) -> PathBuf {
+    let plain = plain_path_for_kepub(kepub_dest).unwrap_or_else(|| kepub_dest.to_path_buf());
+    match src_path.extension() {
+        Some(extension) => plain.with_extension(extension),
+        None => plain,
+    }
+}
+
+/// Name a book as the KEPUB converted from it, such as `a.kepub.epub` for `a.epub`.
+pub(crate) fn kepub_path_for(path: &Path) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_owned();
+    name.push(KEPUB_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// A temporary file to convert a book into before it's copied to the destination.
+fn conversion_path() -> PathBuf {
+    static CONVERSIONS: AtomicUsize = AtomicUsize::new(0);
+
+    let id = CONVERSIONS.fetch_add(1, Ordering::Relaxed);
+    env::temp_dir().join(format!(
+        "{}-{}-{id}{KEPUB_SUFFIX}",
+        env!("CARGO_PKG_NAME"),
+        std::process::id()
+    ))
+}
+
+/// Convert an EPUB to a KEPUB with the `kepubify` program, yielding the path of the converted book.
+async fn convert_to_kepub(kepubify: &Path, src_path: &Path) -> Result<PathBuf> {
+    let converted = conversion_path();
+    let output = Command::new(kepubify)
+        .arg("--output")
+        .arg(&converted)
+        .arg(src_path)
+        .output()
+        .await
+        .map_err(|err| anyhow!("could not run {}: {err}", kepubify.display()))?;
+
+    if !output.status.success() {
+        let _ = fs::remove_file(&converted).await;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow!(
+            "kepubify failed with {}: {}",
+            output.status,
+            stderr.trim()
+        ));
+    }
+    if !fs::try_exists(&converted).await? {
+        return Err(anyhow!("kepubify did not write {}", converted.display()));
+    }
+    Ok(converted)
+}
+
+/// Copy a book to its destination, converting it to a KEPUB on the way if the destination is
+/// one. If the conversion fails, the book is copied as-is alongside where the KEPUB would have
+/// gone instead.
+pub(crate) async fn copy_or_convert(
+    src_path: &Path,
+    dest_path: &Path,
+    kind: CopyKind,
+    options: &CopyOptions,
+    copy_permits: &Arc<Semaphore>,
+    stats: &Sender<Statistic>,
+    run: &Run,
+) -> Result<CopyTask> {
+    let kepubify = match &options.kepubify {
+        Some(kepubify) if is_kepub_conversion(src_path, dest_path) => kepubify.clone(),
+        _ => {
+            return copy_through_partial(
+                src_path,
+                src_path,
+                dest_path,
+                kind,
+                options,
+                copy_permits,
+                stats,
+                run,
+            )
+            .await
+        }
+    };
+
+    let (src_path, dest_path) = (src_path.to_path_buf(), dest_path.to_path_buf());
+    let (options, copy_permits, stats) = (options.clone(), copy_permits.clone(), stats.clone());
+    let run = run.clone();
+    Ok(spawn(async move {
+        // Conversions are bounded by the same permits as copies.
+        let converting = {
+            let _permit = copy_permits.acquire().await?;
+            convert_to_kepub(&kepubify, &src_path).await
+        };
+        let (src_str, dest_str) = (src_path.display(), dest_path.display());
+
+        match converting {
+            Ok(converted) => {
+                debug!(path = %src_str, dest = %dest_str, "Converted {src_str} with kepubify");
+                let copying = copy_through_partial(
+                    &converted,
+                    &src_path,
+                    &dest_path,
+                    kind,
+                    &options,
+                    &copy_permits,
+                    &stats,
+                    &run,
+                )
+                .await;
+                let copied = match copying {
+                    Ok(copy_task) => copy_task.await?,
+                    Err(err) => Err(err),
+                };
+                let _ = fs::remove_file(&converted).await;
+                copied
+            }
+            Err(err) => {
+                let plain_dest = plain_dest_for(&src_path, &dest_path);
+                warn!(
+                    path = %src_str,
+                    "Failed to convert {src_str} with kepubify, so copying it as-is instead: {err:#}"
+                );
+                if fs::try_exists(&plain_dest).await? {
+                    let plain_str = plain_dest.display();
+                    info!(
+                        path = %src_str,
+                        dest = %plain_str,
+                        "Book {plain_str} already exists on the destination; will not copy across."
+                    );
+                    stats
+                        .send(Statistic::NotCopiedBecauseAlreadyExistedAtDest)
+                        .await?;
+                    let skipped = Action::SkippedExisting;
+                    record_action(&stats, &src_path, &plain_dest, skipped, 0, Duration::ZERO)
+                        .await?;
+                    run.advance_progress();
+                    return Ok(None);
+                }
+                copy_through_partial(
+                    &src_path,
+                    &src_path,
+                    &plain_dest,
+                    CopyKind::New,
+                    &options,
+                    &copy_permits,
+                    &stats,
+                    &run,
+                )
+                .await?
+                .await?
+            }
+        }
+    }))
+}
@@ -0,0 +1,403 @@
+//! Reading from and writing to a Kobo's database, for collections and annotations.
+
+use {
+    crate::{device::Device, RunFailure},
+    anyhow::{anyhow, Result},
+    clap::ValueEnum,
+    rusqlite::{Connection, OpenFlags},
+    serde::Serialize,
+    std::{
+        collections::{HashMap, HashSet},
+        path::{Path, PathBuf},
+        time::Duration,
+    },
+    tokio::{fs, task::spawn_blocking},
+    tracing::{debug, info},
+};
+
+/// The tables and columns of the Kobo database that collections are made from. They vary between
+/// firmware versions, so they are checked before anything is written.
+const KOBO_COLLECTION_TABLES: [(&str, &[&str]); 2] = [
+    (
+        "Shelf",
+        &[
+            "CreationDate",
+            "Id",
+            "InternalName",
+            "LastModified",
+            "Name",
+            "Type",
+            "_IsDeleted",
+            "_IsVisible",
+            "_IsSynced",
+        ],
+    ),
+    (
+        "ShelfContent",
+        &[
+            "ShelfName",
+            "ContentId",
+            "DateModified",
+            "_IsDeleted",
+            "_IsSynced",
+        ],
+    ),
+];
+
+const KOBO_DATABASE: &str = "KoboReader.sqlite";
+
+/// The tables and columns of the Kobo database that annotations are read from.
+const KOBO_ANNOTATION_TABLES: [(&str, &[&str]); 2] = [
+    (
+        "Bookmark",
+        &[
+            "VolumeID",
+            "ContentID",
+            "Text",
+            "Annotation",
+            "DateCreated",
+            "ChapterProgress",
+        ],
+    ),
+    (
+        "content",
+        &["ContentID", "ContentType", "Title", "Attribution"],
+    ),
+];
+
+/// A highlight or note made on a Kobo.
+#[derive(Debug, Serialize)]
+pub(crate) struct Annotation {
+    chapter: Option<String>,
+
+    /// The highlighted text, if any; notes can also be made without highlighting anything.
+    text: Option<String>,
+    note: Option<String>,
+    created: Option<String>,
+}
+
+/// A book's annotations, in reading order.
+#[derive(Debug, Serialize)]
+pub(crate) struct AnnotatedBook {
+    content_id: String,
+    title: Option<String>,
+    author: Option<String>,
+    annotations: Vec<Annotation>,
+}
+
+impl AnnotatedBook {
+    /// A name for the book, falling back to its file name when the database lacks its title.
+    fn name(&self) -> String {
+        match &self.title {
+            Some(title) => title.clone(),
+            None => {
+                let path = self.content_id.trim_end_matches('/');
+                path.rsplit('/').next().unwrap_or(path).to_owned()
+            }
+        }
+    }
+
+    fn to_markdown(&self) -> String {
+        let mut markdown = format!("# {}\n", self.name());
+        if let Some(author) = &self.author {
+            markdown.push_str(&format!("\n*{author}*\n"));
+        }
+
+        let mut chapter = None;
+        for annotation in &self.annotations {
+            if annotation.chapter.is_some() && annotation.chapter != chapter {
+                chapter = annotation.chapter.clone();
+                markdown.push_str(&format!(
+                    "\n## {}\n",
+                    chapter.as_deref().unwrap_or_default()
+                ));
+            }
+            if let Some(text) = &annotation.text {
+                markdown.push('\n');
+                for line in text.trim().lines() {
+                    markdown.push_str(&format!("> {line}\n"));
+                }
+            }
+            if let Some(note) = &annotation.note {
+                markdown.push_str(&format!("\n{}\n", note.trim()));
+            }
+        }
+        markdown
+    }
+}
+
+/// Read all highlights and notes from a Kobo database, grouped by book. Books without any are left
+/// out. The database is only ever opened read-only.
+fn read_kobo_annotations(db_path: &Path) -> Result<Vec<AnnotatedBook>> {
+    let reconnect = "the device may need to be ejected and reconnected";
+    let db = Connection::open_with_flags(
+        db_path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .map_err(|err| {
+        anyhow!(
+            "could not open the Kobo database at {}: {err}; {reconnect}",
+            db_path.display()
+        )
+    })?;
+    db.busy_timeout(Duration::from_secs(1))?;
+
+    let read = || -> rusqlite::Result<Vec<AnnotatedBook>> {
+        let mut statement = db.prepare(
+            "SELECT b.VolumeID, \
+                (SELECT Title FROM content WHERE ContentID = b.VolumeID AND ContentType = 6), \
+                (SELECT Attribution FROM content WHERE ContentID = b.VolumeID AND ContentType = 6), \
+                (SELECT Title FROM content WHERE ContentID = b.ContentID LIMIT 1), \
+                NULLIF(TRIM(b.Text), ''), NULLIF(TRIM(b.Annotation), ''), b.DateCreated \
+            FROM Bookmark b \
+            WHERE NULLIF(TRIM(b.Text), '') IS NOT NULL OR NULLIF(TRIM(b.Annotation), '') IS NOT NULL \
+            ORDER BY b.VolumeID, b.ChapterProgress, b.DateCreated",
+        )?;
+        let mut rows = statement.query([])?;
+
+        let mut books: Vec<AnnotatedBook> = vec![];
+        while let Some(row) = rows.next()? {
+            let content_id: String = row.get(0)?;
+            let annotation = Annotation {
+                chapter: row.get(3)?,
+                text: row.get(4)?,
+                note: row.get(5)?,
+                created: row.get(6)?,
+            };
+            match books.last_mut() {
+                Some(book) if book.content_id == content_id => book.annotations.push(annotation),
+                _ => books.push(AnnotatedBook {
+                    content_id,
+                    title: row.get(1)?,
+                    author: row.get(2)?,
+                    annotations: vec![annotation],
+                }),
+            }
+        }
+        Ok(books)
+    };
+
+    let is_locked = |err: &rusqlite::Error| {
+        matches!(
+            err.sqlite_error_code(),
+            Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked)
+        )
+    };
+    let locked = || anyhow!("the Kobo database is locked by something else; {reconnect}");
+
+    if let Err(err) = check_kobo_schema(&db, &KOBO_ANNOTATION_TABLES, "exporting annotations") {
+        return match err.downcast_ref::<rusqlite::Error>() {
+            Some(sqlite_err) if is_locked(sqlite_err) => Err(locked()),
+            _ => Err(err),
+        };
+    }
+    read().map_err(|err| {
+        if is_locked(&err) {
+            locked()
+        } else {
+            anyhow!("could not read annotations from the Kobo database: {err}")
+        }
+    })
+}
+
+/// Make a book's name safe to use as a file name.
+fn sanitise_file_name(name: &str) -> String {
+    let sanitised: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let trimmed = sanitised.trim().trim_start_matches('.');
+    if trimmed.is_empty() {
+        "Untitled".to_owned()
+    } else {
+        trimmed.to_owned()
+    }
+}
+
+/// Export the highlights and notes on a Kobo, either as a Markdown file per book in a directory or
+/// as a single JSON file.
+pub async fn export_annotations(
+    volume_dir: &Path,
+    output: &Path,
+    format: AnnotationFormat,
+) -> Result<()> {
+    let db_path = volume_dir.join(Device::Kobo.marker()).join(KOBO_DATABASE);
+    if !fs::try_exists(&db_path).await.unwrap_or(false) {
+        return Err(RunFailure::Inaccessible(format!(
+            "There is no Kobo database at {}; the device may need to be reconnected",
+            db_path.display()
+        ))
+        .into());
+    }
+    let books = spawn_blocking(move || read_kobo_annotations(&db_path)).await??;
+
+    match format {
+        AnnotationFormat::Json => {
+            let mut json = serde_json::to_vec_pretty(&books)?;
+            json.push(b'\n');
+            fs::write(output, json).await?;
+            let output_str = output.display();
+            info!(
+                path = %output_str,
+                "Exported the annotations of {} books to {output_str}",
+                books.len()
+            );
+        }
+        AnnotationFormat::Markdown => {
+            fs::create_dir_all(output).await?;
+            let mut used_names = HashSet::new();
+            for book in &books {
+                let name = sanitise_file_name(&book.name());
+                let mut file_name = format!("{name}.md");
+                let mut n = 2;
+                while !used_names.insert(file_name.to_lowercase()) {
+                    file_name = format!("{name} ({n}).md");
+                    n += 1;
+                }
+
+                let path = output.join(&file_name);
+                fs::write(&path, book.to_markdown()).await?;
+                debug!(path = %path.display(), "Exported {}", path.display());
+            }
+            let output_str = output.display();
+            info!(
+                path = %output_str,
+                "Exported the annotations of {} books to {output_str}",
+                books.len()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn check_kobo_schema(db: &Connection, tables: &[(&str, &[&str])], purpose: &str) -> Result<()> {
+    for (table, columns) in tables {
+        let mut statement = db.prepare("SELECT name FROM pragma_table_info(?1)")?;
+        let present = statement
+            .query_map([table], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<HashSet<_>>>()?;
+
+        if present.is_empty() {
+            return Err(anyhow!(
+                "the Kobo database has no {table} table, so this firmware version is not \
+                supported for {purpose}"
+            ));
+        }
+        let missing: Vec<_> = columns
+            .iter()
+            .filter(|column| !present.contains(**column))
+            .collect();
+        if !missing.is_empty() {
+            return Err(anyhow!(
+                "the {table} table of the Kobo database lacks the columns {missing:?}, so this \
+                firmware version is not supported for {purpose}"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Add books to collections in the Kobo database, creating those that don't exist yet. Each
+/// assignment is of a book's content ID to the name of its collection. The database is backed up
+/// alongside itself beforehand.
+fn add_to_kobo_collections(db_path: &Path, assignments: &[(String, String)]) -> Result<()> {
+    let mut db = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+    check_kobo_schema(&db, &KOBO_COLLECTION_TABLES, "creating collections")?;
+
+    let mut backup_path = db_path.as_os_str().to_owned();
+    backup_path.push(".sync-backup");
+    std::fs::copy(db_path, &backup_path)?;
+
+    let transaction = db.transaction()?;
+    for (content_id, collection) in assignments {
+        transaction.execute(
+            "INSERT INTO Shelf (CreationDate, Id, InternalName, LastModified, Name, Type, \
+                _IsDeleted, _IsVisible, _IsSynced) \
+            SELECT strftime('%Y-%m-%dT%H:%M:%SZ', 'now'), ?1, ?1, \
+                strftime('%Y-%m-%dT%H:%M:%SZ', 'now'), ?1, 'UserTag', 'false', 'true', 'false' \
+            WHERE NOT EXISTS (SELECT 1 FROM Shelf WHERE Name = ?1)",
+            [collection],
+        )?;
+        transaction.execute(
+            "UPDATE Shelf SET _IsDeleted = 'false', _IsVisible = 'true' WHERE Name = ?1",
+            [collection],
+        )?;
+        transaction.execute(
+            "INSERT INTO ShelfContent (ShelfName, ContentId, DateModified, _IsDeleted, _IsSynced) \
+            SELECT ?1, ?2, strftime('%Y-%m-%dT%H:%M:%SZ', 'now'), 'false', 'false' \
+            WHERE NOT EXISTS (SELECT 1 FROM ShelfContent WHERE ShelfName = ?1 AND ContentId = ?2)",
+            [collection, content_id],
+        )?;
+        transaction.execute(
+            "UPDATE ShelfContent SET _IsDeleted = 'false' WHERE ShelfName = ?1 AND ContentId = ?2",
+            [collection, content_id],
+        )?;
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Put the synchronised books into Kobo collections named after the top-level folders of the
+/// documents directories that they came from. Books directly inside a documents directory are
+/// left out of any collection. This covers books that were already on the device too, so that
+/// turning this on later still organises them.
+pub(crate) async fn create_collections_from_folders(
+    volume_dir: &Path,
+    synchronised: &HashMap<PathBuf, PathBuf>,
+    dry_run: bool,
+) -> Result<()> {
+    let mut assignments = vec![];
+    for (dest_path, relative) in synchronised {
+        let mut components = relative.components();
+        let (Some(folder), Some(_)) = (components.next(), components.next()) else {
+            continue;
+        };
+        if !dry_run && !fs::try_exists(dest_path).await.unwrap_or(false) {
+            continue;
+        }
+        let Ok(on_device) = dest_path.strip_prefix(volume_dir) else {
+            continue;
+        };
+
+        let collection = folder.as_os_str().to_string_lossy().into_owned();
+        let content_id = format!("file:///mnt/onboard/{}", on_device.to_string_lossy());
+        if dry_run {
+            info!(
+                path = %dest_path.display(),
+                collection, "Dry-running; would otherwise add {} to the {collection} collection",
+                dest_path.display()
+            );
+        } else {
+            debug!(
+                path = %dest_path.display(),
+                collection, "Adding {} to the {collection} collection",
+                dest_path.display()
+            );
+        }
+        assignments.push((content_id, collection));
+    }
+    if dry_run || assignments.is_empty() {
+        return Ok(());
+    }
+
+    let db_path = volume_dir.join(Device::Kobo.marker()).join(KOBO_DATABASE);
+    let count = assignments.len();
+    spawn_blocking(move || add_to_kobo_collections(&db_path, &assignments)).await??;
+    info!("Added {count} books to collections named after their folders");
+    Ok(())
+}
+
+/// The format to export annotations in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum AnnotationFormat {
+    /// A Markdown file per book.
+    #[default]
+    Markdown,
+
+    /// A single JSON file of all books.
+    Json,
+}
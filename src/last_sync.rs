@@ -0,0 +1,55 @@
+//! Recording when the last successful run to a destination was on it, for incremental runs.
+
+use {
+    crate::copy::partial_path_for,
+    anyhow::Result,
+    std::{path::Path, time::SystemTime},
+    tokio::{
+        fs::{self, File},
+        io::AsyncWriteExt,
+    },
+    tracing::debug,
+};
+
+const LAST_SYNC_NAME: &str = ".sync-last-run";
+
+/// Read when the last successful run to a destination started. A missing or unparsable record is
+/// just absent, so that the run falls back to looking at every book.
+pub(crate) async fn read_last_sync(dest_dir: &Path) -> Option<SystemTime> {
+    let path = dest_dir.join(LAST_SYNC_NAME);
+    let path_str = path.display();
+    let contents = match fs::read_to_string(&path).await {
+        Ok(contents) => contents,
+        Err(err) => {
+            debug!(path = %path_str, "Looking at every book, as {path_str} could not be read: {err}");
+            return None;
+        }
+    };
+    match humantime::parse_rfc3339(contents.trim()) {
+        Ok(last_sync) => Some(last_sync),
+        Err(err) => {
+            debug!(path = %path_str, "Looking at every book, as {path_str} is unparsable: {err}");
+            None
+        }
+    }
+}
+
+/// Record on a destination that a run to it succeeded. The time recorded is when the run started
+/// rather than finished, so that books changed while it was running are looked at by the next.
+pub(crate) async fn record_last_sync(dest_dir: &Path, started: SystemTime) -> Result<()> {
+    let path = dest_dir.join(LAST_SYNC_NAME);
+    let partial_path = partial_path_for(&path);
+    let stamp = humantime::format_rfc3339_nanos(started).to_string();
+    let mut partial = File::create(&partial_path).await?;
+    partial.write_all(stamp.as_bytes()).await?;
+    partial.write_all(b"\n").await?;
+    partial.sync_all().await?;
+    drop(partial);
+
+    if let Err(err) = fs::rename(&partial_path, &path).await {
+        let _ = fs::remove_file(&partial_path).await;
+        return Err(err.into());
+    }
+    debug!(path = %path.display(), "Recorded the run in {}", path.display());
+    Ok(())
+}
@@ -0,0 +1,1515 @@
+// The use of Tokio is probably slower than using blocking calls directly, due to the lack of truly
+// asynchronous filesystem I/O APIs on some OSes. That said, using it means a threadpool doesn't
+// need to be imported or written, and perhaps Tokio will, one day, transparently support the likes
+// of `io_uring` for their filesystem APIs.
+
+#![forbid(unsafe_code)]
+
+//! Synchronise books between a workstation and an e-book reader, such as a Kobo or a Kindle.
+//!
+//! Build a [`SyncOptions`] with [`SyncOptions::builder`] and pass it to [`sync`], which yields a
+//! [`SyncReport`] of what happened. To follow each book as the run goes, use [`sync_with_events`]
+//! instead. The command line tool's arguments, and what it does with them, are in [`cli`].
+
+mod args;
+mod audit;
+mod calibre;
+pub mod cli;
+mod convert;
+mod copy;
+mod daemon;
+mod device;
+mod doctor;
+mod events;
+mod find;
+mod hash_cache;
+mod history;
+mod kepub;
+mod kobo;
+mod last_sync;
+mod lock;
+mod manifest;
+mod metadata;
+mod plan;
+mod progress;
+mod prune;
+mod report;
+mod sidecars;
+mod stats;
+mod summary;
+mod synchronise;
+mod syncignore;
+
+pub use {
+    device::{
+        available_space, detect_mtp_storage_directory, detect_storage_directory, eject,
+        filesystem_type, is_accessible_dir, mount_by_label, Device,
+    },
+    events::SyncEvent,
+    hash_cache::clear_hash_cache,
+    history::SinceLastRun,
+    kobo::{export_annotations, AnnotationFormat},
+    lock::remove_held_locks,
+    plan::{Plan, PlannedCopy},
+    prune::{PruneReport, PrunedDuplicate},
+    report::{write_report, Action, BookAction, ReportFormat},
+    stats::{Counters, LargeBook, SyncReport, Timings},
+    synchronise::{Collision, CollisionPolicy, Compare, Listing, OrderBy, OverwritePolicy},
+};
+
+use {
+    crate::{
+        audit::{AuditLog, DEFAULT_AUDIT_LOG_MAX_SIZE},
+        calibre::CalibreLibrary,
+        convert::{Conversion, DEFAULT_CONVERT_EXTENSIONS},
+        copy::{sync_directory, CopyOptions, RateLimiter, DEFAULT_COPY_BUFFER_SIZE},
+        find::{
+            dedupe_books, find_books, find_listed_books, find_planned_books, prefer_formats,
+            watch_books, FindOptions, FoundBook,
+        },
+        hash_cache::{load_hash_cache, HashCache},
+        history::History,
+        kobo::create_collections_from_folders,
+        last_sync::{read_last_sync, record_last_sync},
+        lock::{lock_destination, DEFAULT_STALE_LOCK_AGE},
+        manifest::Manifest,
+        stats::{collect_stats, Statistic},
+        synchronise::{
+            delete_stale_books, keep_books, list_books, pull_books, sync_books, verify_books,
+        },
+    },
+    anyhow::{Error, Result},
+    globset::GlobSet,
+    indicatif::ProgressBar,
+    std::{
+        collections::{HashMap, HashSet},
+        ffi::OsStr,
+        io::Write,
+        num::{NonZeroU64, NonZeroUsize},
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::{Duration, SystemTime},
+    },
+    tokio::{
+        select,
+        sync::{
+            mpsc::{channel, unbounded_channel, Receiver, Sender},
+            Notify,
+        },
+        task::{spawn, JoinHandle},
+    },
+    tracing::{error, info, warn},
+};
+
+const FOUND_BOOKS_CHANNEL_BOUND: usize = 128;
+const STATISTICS_CHANNEL_BOUND: usize = 128;
+const EVENTS_CHANNEL_BOUND: usize = 128;
+
+static PROGRESS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Write progress messages and prompts to stderr rather than stdout, such as when stdout has been
+/// claimed by a machine-readable output format.
+pub fn write_progress_to_stderr() {
+    PROGRESS_TO_STDERR.store(true, Ordering::Relaxed);
+}
+
+/// Where progress messages and prompts are written. This is stdout unless
+/// [`write_progress_to_stderr`] was called.
+pub fn progress_output() -> Box<dyn Write> {
+    if PROGRESS_TO_STDERR.load(Ordering::Relaxed) {
+        Box::new(std::io::stderr())
+    } else {
+        Box::new(std::io::stdout())
+    }
+}
+
+/// Something that happens at most once, and a way to wait for it.
+#[derive(Debug, Default)]
+struct Signal {
+    raised: AtomicBool,
+    notify: Notify,
+}
+
+impl Signal {
+    fn raise(&self) {
+        self.raised.store(true, Ordering::Relaxed);
+        self.notify.notify_waiters();
+    }
+
+    fn is_raised(&self) -> bool {
+        self.raised.load(Ordering::Relaxed)
+    }
+
+    /// Wait until the signal is raised.
+    async fn raised(&self) {
+        let notified = self.notify.notified();
+        tokio::pin!(notified);
+        // Registering before checking means a signal raised in between can't be missed.
+        notified.as_mut().enable();
+        if !self.is_raised() {
+            notified.await;
+        }
+    }
+}
+
+/// Asks runs to stop early, such as when Ctrl-C is pressed. Runs are given one with
+/// [`SyncOptionsBuilder::interrupter`], and every run given the same one is interrupted by it
+/// together. It stays interrupted, so runs started with it afterwards stop straight away.
+#[derive(Clone, Debug, Default)]
+pub struct Interrupter(Arc<Signal>);
+
+impl Interrupter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask the runs to stop early. No more books are found or queued for copying, the copies
+    /// already in progress are finished, and each run then ends with a report of what it did,
+    /// which says it was interrupted. Watching stops too, though as that is the normal way to end
+    /// a watch, its report doesn't count as interrupted.
+    pub fn interrupt(&self) {
+        self.0.raise();
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.0.is_raised()
+    }
+}
+
+/// The state of a single run, shared by everything taking part in it, so that runs going on at
+/// the same time don't interfere with each other.
+#[derive(Clone, Debug)]
+pub(crate) struct Run(Arc<RunState>);
+
+#[derive(Debug)]
+struct RunState {
+    interrupter: Interrupter,
+    failed_fast: Signal,
+    progress_bar: Option<ProgressBar>,
+    hash_cache: Option<HashCache>,
+}
+
+impl Run {
+    fn new(options: &SyncOptions, hash_cache: Option<HashCache>) -> Self {
+        Run(Arc::new(RunState {
+            interrupter: options.interrupter.clone(),
+            failed_fast: Signal::default(),
+            progress_bar: options.progress_bar.clone(),
+            hash_cache,
+        }))
+    }
+
+    /// Whether the run was interrupted, or failed fast, which stops it the same way.
+    pub(crate) fn is_interrupted(&self) -> bool {
+        self.0.interrupter.is_interrupted() || self.has_failed_fast()
+    }
+
+    /// Wait until the run is interrupted, or fails fast.
+    pub(crate) async fn interrupted(&self) {
+        select! {
+            () = self.0.interrupter.0.raised() => {}
+            () = self.failed_fast() => {}
+        }
+    }
+
+    /// Stop the run at its first failure, when failing fast. It stops like an interruption, except
+    /// that the copies in progress are abandoned too, and the rest of the run is skipped.
+    pub(crate) fn fail_fast(&self) {
+        self.0.failed_fast.raise();
+    }
+
+    pub(crate) fn has_failed_fast(&self) -> bool {
+        self.0.failed_fast.is_raised()
+    }
+
+    /// Wait until the run fails fast.
+    pub(crate) async fn failed_fast(&self) {
+        self.0.failed_fast.raised().await;
+    }
+
+    /// The bar showing the run's progress, if there is one, which is lengthened as books are found
+    /// and advanced as they are dealt with.
+    pub(crate) fn progress_bar(&self) -> Option<&ProgressBar> {
+        self.0.progress_bar.as_ref()
+    }
+
+    /// Mark one found book as dealt with on the progress bar, if there is one.
+    pub(crate) fn advance_progress(&self) {
+        if let Some(bar) = self.progress_bar() {
+            bar.inc(1);
+        }
+    }
+
+    /// The cache the run hashes books through, if it uses one.
+    pub(crate) fn hash_cache(&self) -> Option<&HashCache> {
+        self.0.hash_cache.as_ref()
+    }
+
+    /// Save the run's hash cache, if it uses one.
+    pub(crate) async fn save_hash_cache(&self) {
+        if let Some(hash_cache) = self.hash_cache() {
+            hash_cache.save().await;
+        }
+    }
+}
+
+/// A failure that ends a run with its own exit code, rather than the generic one.
+#[derive(Debug)]
+pub enum RunFailure {
+    /// The device or a documents directory couldn't be found or accessed.
+    Inaccessible(String),
+
+    /// The run was stopped before it could finish, such as with Ctrl-C.
+    Interrupted(String),
+
+    /// Another run was already synchronising to the destination.
+    Locked(String),
+}
+
+impl std::fmt::Display for RunFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RunFailure::Inaccessible(msg)
+            | RunFailure::Interrupted(msg)
+            | RunFailure::Locked(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for RunFailure {}
+
+/// What to synchronise, where to, and how. Build one with [`SyncOptions::builder`].
+#[derive(Clone, Debug)]
+pub struct SyncOptions {
+    /// The root of the device's volume, which for some devices is not where books go.
+    volume_directory: PathBuf,
+    destination: PathBuf,
+    sources: Vec<PathBuf>,
+    extensions: Vec<String>,
+    files: Option<Vec<PathBuf>>,
+    copy: CopyOptions,
+    find: FindOptions,
+    delete: bool,
+    pull: Option<PathBuf>,
+    collections_from_folders: bool,
+    eject: bool,
+    watch: bool,
+    incremental: bool,
+    stale_lock_age: Duration,
+    fail_fast: bool,
+    audit_log: Option<PathBuf>,
+    audit_log_max_size: u64,
+    hash_cache: Option<PathBuf>,
+    history: Option<PathBuf>,
+    copy_progress_events: bool,
+    interrupter: Interrupter,
+    progress_bar: Option<ProgressBar>,
+}
+
+impl SyncOptions {
+    /// Start building options to synchronise books into the destination directory.
+    pub fn builder(destination: impl Into<PathBuf>) -> SyncOptionsBuilder {
+        SyncOptionsBuilder::new(destination.into())
+    }
+
+    /// The documents directories that books are synchronised from.
+    pub fn sources(&self) -> &[PathBuf] {
+        &self.sources
+    }
+
+    /// The extensions of the books that are synchronised, without leading dots.
+    pub fn extensions(&self) -> &[String] {
+        &self.extensions
+    }
+
+    /// The Calibre library that books are synchronised from, if there is one.
+    pub fn calibre_library(&self) -> Option<&Path> {
+        self.find
+            .calibre
+            .as_ref()
+            .map(|library| library.path.as_path())
+    }
+
+    /// The directory that books are synchronised into.
+    pub fn destination(&self) -> &Path {
+        &self.destination
+    }
+
+    /// The root of the device's volume.
+    pub fn volume_directory(&self) -> &Path {
+        &self.volume_directory
+    }
+
+    /// The same options, but synchronising to another device's volume, and the directory in it
+    /// that books go into.
+    pub fn with_destination(&self, volume_directory: PathBuf, destination: PathBuf) -> SyncOptions {
+        SyncOptions {
+            volume_directory,
+            destination,
+            ..self.clone()
+        }
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.copy.dry_run
+    }
+
+    pub fn fails_fast(&self) -> bool {
+        self.fail_fast
+    }
+
+    /// Whether books no longer in the documents directories are deleted from the destination.
+    pub fn deletes(&self) -> bool {
+        self.delete
+    }
+
+    /// Whether books only on the destination are pulled back into a local directory.
+    pub fn pulls(&self) -> bool {
+        self.pull.is_some()
+    }
+
+    /// Whether books are only looked at if modified since the last run.
+    pub fn is_incremental(&self) -> bool {
+        self.incremental
+    }
+
+    /// Whether a maximum depth stops the documents directories being searched all the way down.
+    pub fn limits_depth(&self) -> bool {
+        self.find.max_depth.is_some()
+    }
+
+    /// Whether books larger than a maximum size are left out.
+    pub fn limits_size(&self) -> bool {
+        self.find.max_size.is_some()
+    }
+
+    /// Whether books modified before a cutoff are left out.
+    pub fn has_cutoff(&self) -> bool {
+        self.find.since.is_some()
+    }
+
+    /// Whether books with the same contents as others are left out.
+    pub fn dedupes_content(&self) -> bool {
+        self.find.dedupe_content
+    }
+
+    /// Whether only the preferred format of each title is synchronised.
+    pub fn prefers_formats(&self) -> bool {
+        !self.find.prefer_formats.is_empty()
+    }
+
+    /// Whether a run copies at most so many bytes, deferring the rest to later runs.
+    pub fn limits_total_bytes(&self) -> bool {
+        self.copy.max_total_bytes.is_some()
+    }
+
+    /// Whether books are converted on the way to the destination.
+    pub fn converts(&self) -> bool {
+        self.copy.kepubify.is_some() || self.copy.conversion.is_some()
+    }
+
+    /// Whether covers and metadata files are copied alongside books.
+    pub fn includes_sidecars(&self) -> bool {
+        self.copy.include_sidecars
+    }
+
+    /// What interrupts runs with these options.
+    pub fn interrupter(&self) -> &Interrupter {
+        &self.interrupter
+    }
+
+    /// The bar that runs with these options show their progress on, if any.
+    pub fn progress_bar(&self) -> Option<&ProgressBar> {
+        self.progress_bar.as_ref()
+    }
+}
+
+/// Builds [`SyncOptions`]. Everything but the destination has a default: no sources, EPUBs and
+/// PDFs, and the same behaviour as the command line without any flags.
+#[derive(Debug)]
+pub struct SyncOptionsBuilder {
+    volume_directory: Option<PathBuf>,
+    destination: PathBuf,
+    sources: Vec<PathBuf>,
+    extensions: Vec<String>,
+    files: Option<Vec<PathBuf>>,
+    copy: CopyOptions,
+    kepubify: Option<PathBuf>,
+    ebook_convert: Option<PathBuf>,
+    convert_extensions: Vec<String>,
+    conversion_cache: Option<PathBuf>,
+    calibre_library: Option<PathBuf>,
+    calibre_tag: Option<String>,
+    routes: Vec<(String, PathBuf)>,
+    limit_rate: Option<NonZeroU64>,
+    plan: Option<HashMap<PathBuf, PathBuf>>,
+    find: FindOptions,
+    delete: bool,
+    pull: Option<PathBuf>,
+    collections_from_folders: bool,
+    eject: bool,
+    watch: bool,
+    incremental: bool,
+    stale_lock_age: Duration,
+    fail_fast: bool,
+    audit_log: Option<PathBuf>,
+    audit_log_max_size: u64,
+    hash_cache: Option<PathBuf>,
+    history: Option<PathBuf>,
+    copy_progress_events: bool,
+    interrupter: Interrupter,
+    progress_bar: Option<ProgressBar>,
+}
+
+impl SyncOptionsBuilder {
+    fn new(destination: PathBuf) -> Self {
+        SyncOptionsBuilder {
+            volume_directory: None,
+            destination,
+            sources: vec![],
+            extensions: Device::Kobo
+                .default_extensions()
+                .iter()
+                .map(|ext| ext.to_string())
+                .collect(),
+            files: None,
+            copy: CopyOptions {
+                dry_run: false,
+                mirror_structure: false,
+                update: false,
+                verify: false,
+                max_concurrent_copies: NonZeroUsize::new(4).expect("4 should be non-zero"),
+                resume: false,
+                retries: 2,
+                copy_timeout: None,
+                copy_buffer_size: NonZeroUsize::new(DEFAULT_COPY_BUFFER_SIZE)
+                    .expect("the default copy buffer size should be non-zero"),
+                fsync: false,
+                rate_limiter: None,
+                preserve_times: true,
+                rename_from_metadata: false,
+                planned: None,
+                interactive: false,
+                on_collision: CollisionPolicy::default(),
+                order_by: OrderBy::default(),
+                compare: Compare::default(),
+                overwrite: OverwritePolicy::default(),
+                kepubify: None,
+                conversion: None,
+                check_free_space: false,
+                fit_what_fits: false,
+                max_total_bytes: None,
+                routes: Arc::new([]),
+                manifest: true,
+                report_progress: false,
+                include_sidecars: false,
+            },
+            kepubify: None,
+            ebook_convert: None,
+            convert_extensions: DEFAULT_CONVERT_EXTENSIONS
+                .iter()
+                .map(|ext| ext.to_string())
+                .collect(),
+            conversion_cache: None,
+            calibre_library: None,
+            calibre_tag: None,
+            routes: vec![],
+            limit_rate: None,
+            plan: None,
+            find: FindOptions {
+                excludes: GlobSet::empty(),
+                includes: None,
+                follow_symlinks: false,
+                hidden: false,
+                max_size: None,
+                since: None,
+                last_sync: None,
+                validate: true,
+                dedupe_content: false,
+                calibre: None,
+                prefer_formats: vec![],
+                syncignore: true,
+                calibre_aware: None,
+                max_depth: None,
+                one_file_system: false,
+            },
+            delete: false,
+            pull: None,
+            collections_from_folders: false,
+            eject: false,
+            watch: false,
+            incremental: false,
+            stale_lock_age: DEFAULT_STALE_LOCK_AGE,
+            fail_fast: false,
+            audit_log: None,
+            audit_log_max_size: DEFAULT_AUDIT_LOG_MAX_SIZE,
+            hash_cache: None,
+            history: None,
+            copy_progress_events: true,
+            interrupter: Interrupter::default(),
+            progress_bar: None,
+        }
+    }
+
+    /// The root of the device's volume, if books go into a subdirectory of it rather than its
+    /// root. Defaults to the destination.
+    pub fn volume_directory(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.volume_directory = Some(dir.into());
+        self
+    }
+
+    /// Add a documents directory to synchronise books from.
+    pub fn source(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.sources.push(dir.into());
+        self
+    }
+
+    /// Add documents directories to synchronise books from.
+    pub fn sources(mut self, dirs: impl IntoIterator<Item = impl Into<PathBuf>>) -> Self {
+        self.sources.extend(dirs.into_iter().map(Into::into));
+        self
+    }
+
+    /// The extensions of the books to synchronise, without leading dots, replacing the defaults.
+    pub fn extensions(mut self, extensions: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.extensions = extensions.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Synchronise just these books, rather than finding them in the documents directories.
+    /// Watching would find others, so it isn't done.
+    pub fn files(mut self, files: Option<Vec<PathBuf>>) -> Self {
+        self.files = files;
+        self
+    }
+
+    /// Report what would be done without changing anything.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.copy.dry_run = dry_run;
+        self
+    }
+
+    /// Keep the directory structure of the documents directories on the destination.
+    pub fn mirror_structure(mut self, mirror_structure: bool) -> Self {
+        self.copy.mirror_structure = mirror_structure;
+        self
+    }
+
+    /// Replace books on the destination whose sources are newer or a different size.
+    pub fn update(mut self, update: bool) -> Self {
+        self.copy.update = update;
+        self
+    }
+
+    /// Read each copy back and compare its checksum with its source's.
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.copy.verify = verify;
+        self
+    }
+
+    pub fn max_concurrent_copies(mut self, max: NonZeroUsize) -> Self {
+        self.copy.max_concurrent_copies = max;
+        self
+    }
+
+    /// Continue interrupted copies from where their partial files left off.
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.copy.resume = resume;
+        self
+    }
+
+    /// How many times to retry a copy that fails with a transient device error.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.copy.retries = retries;
+        self
+    }
+
+    /// Give up on a copy that takes longer than this, retries included.
+    pub fn copy_timeout(mut self, limit: Option<Duration>) -> Self {
+        self.copy.copy_timeout = limit;
+        self
+    }
+
+    /// Read and write books this many bytes at a time, when they aren't left to the OS to copy.
+    /// Defaults to 1 MiB.
+    pub fn copy_buffer_size(mut self, size: NonZeroUsize) -> Self {
+        self.copy.copy_buffer_size = size;
+        self
+    }
+
+    /// Flush each book to the device before counting it as copied, and the directories they were
+    /// copied into once the copies are done, so that the books copied are safe to unplug. That's
+    /// slower, as the OS can't cache the writes.
+    pub fn fsync(mut self, fsync: bool) -> Self {
+        self.copy.fsync = fsync;
+        self
+    }
+
+    /// Keep the combined rate of all copies under this many bytes per second.
+    pub fn limit_rate(mut self, bytes_per_second: Option<NonZeroU64>) -> Self {
+        self.limit_rate = bytes_per_second;
+        self
+    }
+
+    /// Give copies the modification times of their sources, rather than the time they were copied.
+    pub fn preserve_times(mut self, preserve_times: bool) -> Self {
+        self.copy.preserve_times = preserve_times;
+        self
+    }
+
+    /// Name EPUBs on the destination as `Author - Title.epub`, from their metadata.
+    pub fn rename_from_metadata(mut self, rename_from_metadata: bool) -> Self {
+        self.copy.rename_from_metadata = rename_from_metadata;
+        self
+    }
+
+    /// Ask on the terminal before copying or updating each book.
+    pub fn interactive(mut self, interactive: bool) -> Self {
+        self.copy.interactive = interactive;
+        self
+    }
+
+    pub fn on_collision(mut self, policy: CollisionPolicy) -> Self {
+        self.copy.on_collision = policy;
+        self
+    }
+
+    /// Copy books in this order, rather than as they're found. Other orders wait for every book to
+    /// be found first, so they're ignored when watching, which never stops finding books.
+    pub fn order_by(mut self, order_by: OrderBy) -> Self {
+        self.copy.order_by = order_by;
+        self
+    }
+
+    /// Compare books already on the destination under the same name by their sizes or contents,
+    /// copying over those that differ, rather than leaving every one alone.
+    pub fn compare(mut self, compare: Compare) -> Self {
+        self.copy.compare = compare;
+        self
+    }
+
+    /// Copy over books whose destinations already exist as the policy says, rather than leaving
+    /// them alone. Updating and comparing still apply to those it leaves alone.
+    pub fn overwrite(mut self, policy: OverwritePolicy) -> Self {
+        self.copy.overwrite = policy;
+        self
+    }
+
+    /// Convert EPUBs to KEPUBs on the way with the given `kepubify` program.
+    pub fn kepubify(mut self, program: Option<PathBuf>) -> Self {
+        self.kepubify = program;
+        self
+    }
+
+    /// Convert books in formats the device can't read to EPUBs on the way with the given
+    /// `ebook-convert` program from Calibre. They're named on the destination after the book, but
+    /// with an `.epub` extension.
+    pub fn ebook_convert(mut self, program: Option<PathBuf>) -> Self {
+        self.ebook_convert = program;
+        self
+    }
+
+    /// The extensions of the books to convert with `ebook-convert`, lowercase and without leading
+    /// dots, which are synchronised as well as the other extensions. Defaults to MOBI and FB2.
+    pub fn convert_extensions(
+        mut self,
+        extensions: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.convert_extensions = extensions.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Keep the conversions made by `ebook-convert` in this directory, so that later runs needn't
+    /// convert the same books again.
+    pub fn conversion_cache(mut self, dir: Option<PathBuf>) -> Self {
+        self.conversion_cache = dir;
+        self
+    }
+
+    /// Check that the books needing copying fit on the destination before copying any of them.
+    pub fn check_free_space(mut self, check_free_space: bool) -> Self {
+        self.copy.check_free_space = check_free_space;
+        self
+    }
+
+    /// When the books don't all fit, copy as many as will, smallest first, rather than failing.
+    pub fn fit_what_fits(mut self, fit_what_fits: bool) -> Self {
+        self.copy.fit_what_fits = fit_what_fits;
+        self
+    }
+
+    /// Copy at most this many bytes of books, deferring the rest to a later run.
+    pub fn max_total_bytes(mut self, max_total_bytes: Option<u64>) -> Self {
+        self.copy.max_total_bytes = max_total_bytes;
+        self
+    }
+
+    /// Put books with the given lowercase extension into a subdirectory of the destination.
+    pub fn route(mut self, extension: impl Into<String>, subdirectory: impl Into<PathBuf>) -> Self {
+        self.routes.push((extension.into(), subdirectory.into()));
+        self
+    }
+
+    /// Keep a manifest of synchronised books on the destination, to skip them on later runs.
+    pub fn manifest(mut self, manifest: bool) -> Self {
+        self.copy.manifest = manifest;
+        self
+    }
+
+    /// Skip books whose paths relative to their documents directories match.
+    pub fn excludes(mut self, excludes: GlobSet) -> Self {
+        self.find.excludes = excludes;
+        self
+    }
+
+    /// Only synchronise books whose paths relative to their documents directories match.
+    pub fn includes(mut self, includes: Option<GlobSet>) -> Self {
+        self.find.includes = includes;
+        self
+    }
+
+    pub fn follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.find.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    /// Include hidden files, and descend into hidden directories.
+    pub fn hidden(mut self, hidden: bool) -> Self {
+        self.find.hidden = hidden;
+        self
+    }
+
+    /// Skip books larger than this many bytes.
+    pub fn max_size(mut self, max_size: Option<u64>) -> Self {
+        self.find.max_size = max_size;
+        self
+    }
+
+    /// Only look this many directories deep for books beneath each documents directory, with 0
+    /// only looking at the files directly in them.
+    pub fn max_depth(mut self, max_depth: Option<usize>) -> Self {
+        self.find.max_depth = max_depth;
+        self
+    }
+
+    /// Don't look for books in directories on other filesystems than their documents directory,
+    /// such as mounts beneath it. This has no effect on Windows.
+    pub fn one_file_system(mut self, one_file_system: bool) -> Self {
+        self.find.one_file_system = one_file_system;
+        self
+    }
+
+    /// Skip books last modified before this.
+    pub fn since(mut self, since: Option<SystemTime>) -> Self {
+        self.find.since = since;
+        self
+    }
+
+    /// Skip books that are empty or otherwise obviously corrupt, such as EPUBs that aren't ZIP
+    /// archives.
+    pub fn validate(mut self, validate: bool) -> Self {
+        self.find.validate = validate;
+        self
+    }
+
+    /// Skip what `.syncignore` files in the documents directories say to, as `.gitignore` files do.
+    /// This is the default.
+    pub fn syncignore(mut self, syncignore: bool) -> Self {
+        self.find.syncignore = syncignore;
+        self
+    }
+
+    /// Skip Calibre's bookkeeping in the documents directories: its trash and notes, the originals
+    /// of books it has converted, and EPUBs beside the KEPUBs converted from them. If `None`, which
+    /// is the default, this is done in the documents directories that are Calibre libraries.
+    pub fn calibre_aware(mut self, calibre_aware: Option<bool>) -> Self {
+        self.find.calibre_aware = calibre_aware;
+        self
+    }
+
+    /// Only synchronise one of the books with the same contents, however they're named.
+    pub fn dedupe_content(mut self, dedupe_content: bool) -> Self {
+        self.find.dedupe_content = dedupe_content;
+        self
+    }
+
+    /// Synchronise one format of each book in the Calibre library at this path too, chosen by the
+    /// preferred formats and named on the destination after its title and author in the library.
+    /// Its database is only read, never written to.
+    pub fn calibre_library(mut self, library: Option<PathBuf>) -> Self {
+        self.calibre_library = library;
+        self
+    }
+
+    /// Only synchronise the books in the Calibre library with this tag.
+    pub fn calibre_tag(mut self, tag: Option<String>) -> Self {
+        self.calibre_tag = tag;
+        self
+    }
+
+    /// When books differ only by extension, such as `dune.epub` and `dune.pdf`, only synchronise
+    /// the one whose extension comes first in these, wherever they are in the documents
+    /// directories. Books with other extensions are synchronised as usual. Every book is found
+    /// before any is copied, so this isn't done when watching.
+    pub fn prefer_formats(mut self, formats: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.find.prefer_formats = formats.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Delete books from the destination that no longer exist in the documents directories. Books
+    /// that are still there but filtered out, such as by the excludes, are left alone.
+    pub fn delete(mut self, delete: bool) -> Self {
+        self.delete = delete;
+        self
+    }
+
+    /// Copy books that only exist on the destination back into this directory.
+    pub fn pull(mut self, dir: Option<PathBuf>) -> Self {
+        self.pull = dir;
+        self
+    }
+
+    /// Put books on a Kobo into collections named after their top-level folders.
+    pub fn collections_from_folders(mut self, collections_from_folders: bool) -> Self {
+        self.collections_from_folders = collections_from_folders;
+        self
+    }
+
+    /// Flush and unmount the device once synchronisation finishes.
+    pub fn eject(mut self, eject: bool) -> Self {
+        self.eject = eject;
+        self
+    }
+
+    /// Copy exactly the books that a plan does, to where it copies them, rather than looking for
+    /// books to synchronise. Check that the plan is still current first, with
+    /// [`Plan::check_current`].
+    pub fn apply(mut self, plan: &Plan) -> Self {
+        self.plan = Some(plan.destinations());
+        // Only the outdated books that were planned to be updated can be found.
+        self.copy.update = true;
+        self
+    }
+
+    /// With each book copied, copy the covers and metadata files next to it too, such as `a.jpg`,
+    /// `a.opf`, and `a.pdf.jpg` for `a.pdf`.
+    pub fn include_sidecars(mut self, include_sidecars: bool) -> Self {
+        self.copy.include_sidecars = include_sidecars;
+        self
+    }
+
+    /// Break the lock on the destination left by another run once it has gone untouched for this
+    /// long, taking it to have died. Runs keep their locks touched while they go, so this can be
+    /// far shorter than a run. It's an hour by default.
+    pub fn stale_lock_age(mut self, stale_lock_age: Duration) -> Self {
+        self.stale_lock_age = stale_lock_age;
+        self
+    }
+
+    /// Keep synchronising new and modified books until interrupted with Ctrl-C.
+    pub fn watch(mut self, watch: bool) -> Self {
+        self.watch = watch;
+        self
+    }
+
+    /// Skip books last modified before the last successful run to the destination started,
+    /// without looking for them on it. Every book is looked at if no run is recorded on the
+    /// destination, as well as when deleting stale books, pulling books, creating collections from
+    /// folders, or watching, which all go by every book in the documents directories.
+    pub fn incremental(mut self, incremental: bool) -> Self {
+        self.incremental = incremental;
+        self
+    }
+
+    /// Stop the run at the first book that fails to copy or verify, or the first file or directory
+    /// that can't be read while finding books, abandoning the copies in progress. Otherwise, the
+    /// run carries on past failures, counting them in its report.
+    pub fn fail_fast(mut self, fail_fast: bool) -> Self {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    /// Append a record of the run to this file: when it started, the options in effect, what was
+    /// done with each book, and the counters its summary is made from.
+    pub fn audit_log(mut self, audit_log: Option<PathBuf>) -> Self {
+        self.audit_log = audit_log;
+        self
+    }
+
+    /// Once the audit log reaches this many bytes, move it aside to a file named after it with a
+    /// `.1` suffix, replacing any already there, and start a new one. Defaults to 10 MiB.
+    pub fn audit_log_max_size(mut self, max_size: u64) -> Self {
+        self.audit_log_max_size = max_size;
+        self
+    }
+
+    /// Keep the digests of hashed books in this file between runs, so that books unchanged since
+    /// are not read again when comparing or deduplicating them.
+    pub fn hash_cache(mut self, path: Option<PathBuf>) -> Self {
+        self.hash_cache = path;
+        self
+    }
+
+    /// Remember the books found by each run to a destination in this file, so that the next run's
+    /// report can say what changed since, in [`Counters::since_last_run`]. Dry runs only read it,
+    /// and it isn't used at all when synchronising just some books or applying a plan.
+    pub fn history(mut self, path: Option<PathBuf>) -> Self {
+        self.history = path;
+        self
+    }
+
+    /// Send [`SyncEvent::CopyProgress`] events as books are copied, when synchronising with
+    /// [`sync_with_events`]. This is the default, but without them, the OS can copy each book in
+    /// one go, which is quicker, unless verifying or limiting the rate.
+    pub fn copy_progress_events(mut self, copy_progress_events: bool) -> Self {
+        self.copy_progress_events = copy_progress_events;
+        self
+    }
+
+    /// What interrupts runs with these options, so that they can be stopped early. Defaults to one
+    /// of their own, which [`SyncOptions::interrupter`] yields.
+    pub fn interrupter(mut self, interrupter: Interrupter) -> Self {
+        self.interrupter = interrupter;
+        self
+    }
+
+    /// Show the progress of runs on a bar, which is lengthened as books are found and advanced as
+    /// they're dealt with.
+    pub fn progress_bar(mut self, bar: Option<ProgressBar>) -> Self {
+        self.progress_bar = bar;
+        self
+    }
+
+    pub fn build(mut self) -> SyncOptions {
+        // The books to convert have to be found to be converted.
+        if self.ebook_convert.is_some() {
+            for ext in &self.convert_extensions {
+                if !self.extensions.contains(ext) {
+                    self.extensions.push(ext.clone());
+                }
+            }
+        }
+        self.find.calibre = self.calibre_library.map(|path| CalibreLibrary {
+            path,
+            tag: self.calibre_tag,
+        });
+        let conversion = self.ebook_convert.map(|program| Conversion {
+            program,
+            extensions: self.convert_extensions,
+            cache: self.conversion_cache,
+        });
+
+        // The copy options are cloned into every task, so these are shared to keep them cheap to
+        // clone.
+        let copy = CopyOptions {
+            kepubify: self.kepubify.map(Arc::from),
+            conversion: conversion.map(Arc::new),
+            routes: self.routes.into(),
+            rate_limiter: self
+                .limit_rate
+                .map(|limit| Arc::new(RateLimiter::new(limit))),
+            planned: self.plan.map(Arc::new),
+            ..self.copy
+        };
+        SyncOptions {
+            volume_directory: self
+                .volume_directory
+                .unwrap_or_else(|| self.destination.clone()),
+            destination: self.destination,
+            sources: self.sources,
+            extensions: self.extensions,
+            files: self.files,
+            copy,
+            find: self.find,
+            delete: self.delete,
+            pull: self.pull,
+            collections_from_folders: self.collections_from_folders,
+            eject: self.eject,
+            watch: self.watch,
+            incremental: self.incremental,
+            stale_lock_age: self.stale_lock_age,
+            fail_fast: self.fail_fast,
+            audit_log: self.audit_log,
+            audit_log_max_size: self.audit_log_max_size,
+            hash_cache: self.hash_cache,
+            history: self.history,
+            copy_progress_events: self.copy_progress_events,
+            interrupter: self.interrupter,
+            progress_bar: self.progress_bar,
+        }
+    }
+}
+
+/// Compare the books in the documents directories with those on the destination, as the options
+/// say, without changing either.
+pub async fn list(options: SyncOptions) -> Result<Listing> {
+    let run = Run::new(&options, None);
+    let SyncOptions {
+        destination,
+        sources,
+        extensions,
+        copy,
+        find,
+        ..
+    } = options;
+
+    let (books_tx, books_rx) = channel::<FoundBook>(FOUND_BOOKS_CHANNEL_BOUND);
+    let (stats_tx, mut stats_rx) = channel::<Statistic>(STATISTICS_CHANNEL_BOUND);
+
+    // Only the books found matter here, not the statistics of finding them.
+    spawn(async move { while stats_rx.recv().await.is_some() {} });
+
+    let finding = {
+        let extensions = extensions.clone();
+        spawn(async move {
+            let extensions: HashSet<&OsStr> = extensions.iter().map(OsStr::new).collect();
+            find_books(
+                &sources,
+                &extensions,
+                &find,
+                &books_tx,
+                None,
+                &stats_tx,
+                &run,
+            )
+            .await
+        })
+    };
+    let extensions: HashSet<&OsStr> = extensions.iter().map(OsStr::new).collect();
+    let listing = list_books(&destination, &extensions, &copy, books_rx).await?;
+    finding.await??;
+    Ok(listing)
+}
+
+/// Check that every book in the documents directories is on the destination, where synchronising
+/// would have copied it, and that its copy is the same size as it and, when comparing digests, has
+/// the same contents, without changing either. The report counts the books found to be healthy,
+/// missing, mismatched, or corrupted, with an action for each.
+pub async fn verify(options: SyncOptions, compare_digests: bool) -> Result<SyncReport> {
+    let hash_cache = load_hash_cache(options.hash_cache.as_deref()).await;
+    let run = Run::new(&options, hash_cache);
+    let SyncOptions {
+        destination,
+        sources,
+        extensions,
+        copy,
+        find,
+        ..
+    } = options;
+
+    let (books_tx, books_rx) = channel::<FoundBook>(FOUND_BOOKS_CHANNEL_BOUND);
+    let (stats_tx, stats_rx) = channel::<Statistic>(STATISTICS_CHANNEL_BOUND);
+    let stats_collection = spawn(collect_stats(
+        true,
+        false,
+        stats_rx,
+        None,
+        None,
+        None,
+        run.clone(),
+    ));
+
+    let finding = {
+        let stats_tx = stats_tx.clone();
+        let run = run.clone();
+        spawn(async move {
+            let extensions: HashSet<&OsStr> = extensions.iter().map(OsStr::new).collect();
+            find_books(
+                &sources,
+                &extensions,
+                &find,
+                &books_tx,
+                None,
+                &stats_tx,
+                &run,
+            )
+            .await?;
+            stats_tx.send(Statistic::FindingFinished).await?;
+            Ok::<(), Error>(())
+        })
+    };
+    verify_books(
+        &destination,
+        &copy,
+        compare_digests,
+        books_rx,
+        stats_tx.clone(),
+        &run,
+    )
+    .await?;
+    finding.await??;
+    run.save_hash_cache().await;
+    drop(stats_tx);
+    stats_collection.await?
+}
+
+/// Delete all but one of each group of identical books on the destination, reporting each one
+/// deleted. The documents directories aren't looked at.
+pub async fn prune_duplicates(options: SyncOptions) -> Result<PruneReport> {
+    let SyncOptions {
+        destination,
+        extensions,
+        copy,
+        hash_cache,
+        stale_lock_age,
+        ..
+    } = options;
+
+    let _lock = if copy.dry_run {
+        None
+    } else {
+        Some(lock_destination(&destination, stale_lock_age).await?)
+    };
+    let hash_cache = load_hash_cache(hash_cache.as_deref()).await;
+    let mut manifest = if copy.manifest {
+        Manifest::load(&destination).await
+    } else {
+        Manifest::default()
+    };
+    let extensions: HashSet<&OsStr> = extensions.iter().map(OsStr::new).collect();
+    let report = prune::prune_duplicates(
+        &destination,
+        &extensions,
+        copy.dry_run,
+        &mut manifest,
+        hash_cache.as_ref(),
+    )
+    .await?;
+    if let Some(hash_cache) = &hash_cache {
+        hash_cache.save().await;
+    }
+
+    if copy.manifest && !copy.dry_run && !report.deleted.is_empty() {
+        if let Err(err) = manifest.save(&destination).await {
+            warn!("Failed to save the manifest, so the next run will check every book: {err:#}");
+        }
+    }
+    Ok(report)
+}
+
+/// Synchronise books as the options say, reporting what happened.
+pub async fn sync(options: SyncOptions) -> Result<SyncReport> {
+    sync_reporting_events(options, None).await
+}
+
+/// Synchronise books as the options say in the background, sending an event for each thing that
+/// happens to a book along the way. The run waits for each event to be received, so they should be
+/// received promptly; if the receiver is dropped, the run carries on without sending any more.
+pub fn sync_with_events(
+    mut options: SyncOptions,
+) -> (Receiver<SyncEvent>, JoinHandle<Result<SyncReport>>) {
+    let (events_tx, events_rx) = channel(EVENTS_CHANNEL_BOUND);
+    options.copy.report_progress = options.copy_progress_events;
+    let syncing = spawn(sync_reporting_events(options, Some(events_tx)));
+    (events_rx, syncing)
+}
+
+async fn sync_reporting_events(
+    options: SyncOptions,
+    events: Option<Sender<SyncEvent>>,
+) -> Result<SyncReport> {
+    let started = SystemTime::now();
+    // Dry runs leave the destination alone, so needn't keep other runs away from it.
+    let lock = if options.copy.dry_run {
+        None
+    } else {
+        Some(lock_destination(&options.destination, options.stale_lock_age).await?)
+    };
+    let audit = match options.audit_log.clone() {
+        Some(path) => Some(AuditLog::open(path, options.audit_log_max_size, &options).await),
+        None => None,
+    };
+    let hash_cache = load_hash_cache(options.hash_cache.as_deref()).await;
+    let run = Run::new(&options, hash_cache);
+    // Only some books are looked at, so the others would look like they had disappeared.
+    let is_partial = options.files.is_some() || options.copy.planned.is_some();
+    let goes_by_every_book = options.delete
+        || options.pull.is_some()
+        || options.collections_from_folders
+        || options.watch;
+    let last_sync = if options.incremental && !is_partial && !goes_by_every_book {
+        read_last_sync(&options.destination).await
+    } else {
+        None
+    };
+    if let Some(last_sync) = last_sync {
+        let last_sync = humantime::format_rfc3339_seconds(last_sync);
+        info!("Only looking at books modified since the last run at {last_sync}");
+    }
+    // Books skipped for being unchanged would look like they had disappeared, too.
+    let history = match options
+        .history
+        .clone()
+        .filter(|_| !is_partial && last_sync.is_none())
+    {
+        Some(path) => Some(History::load(path, &options.destination, !options.copy.dry_run).await),
+        None => None,
+    };
+
+    let SyncOptions {
+        volume_directory,
+        destination: dest_directory,
+        sources: documents_directories,
+        extensions,
+        files,
+        copy: mut sync_options,
+        find: mut find_options,
+        delete,
+        pull,
+        collections_from_folders,
+        eject: eject_volume,
+        watch,
+        fail_fast,
+        ..
+    } = options;
+
+    find_options.last_sync = last_sync;
+
+    // Other orders wait for the finder to finish, which it never does when watching.
+    if watch {
+        sync_options.order_by = OrderBy::Discovered;
+    }
+
+    let (book_path_tx, mut book_path_rx) = channel::<FoundBook>(FOUND_BOOKS_CHANNEL_BOUND);
+    let (stats_tx, stats_rx) = channel::<Statistic>(STATISTICS_CHANNEL_BOUND);
+
+    let stats_collection = spawn(collect_stats(
+        sync_options.dry_run,
+        fail_fast,
+        stats_rx,
+        events,
+        audit,
+        history,
+        run.clone(),
+    ));
+
+    let documents_directories_ptr = Arc::new(documents_directories);
+    let extensions_ptr = Arc::new(extensions);
+
+    // Preferring one format waits for every book to be found, so it's only done when asked, and
+    // not when watching, as that never finishes finding them.
+    let mut preferring = None;
+    if !find_options.prefer_formats.is_empty() && !watch {
+        let formats = find_options.prefer_formats.clone();
+        let (preferred_rx, task) =
+            prefer_formats(book_path_rx, formats, stats_tx.clone(), run.clone());
+        book_path_rx = preferred_rx;
+        preferring = Some(task);
+    }
+
+    // Hashing every book is slow for large libraries, so it's only done when asked.
+    let mut deduping = None;
+    if find_options.dedupe_content {
+        let (deduped_rx, task) = dedupe_books(book_path_rx, stats_tx.clone(), run.clone());
+        book_path_rx = deduped_rx;
+        deduping = Some(task);
+    }
+
+    // Books that are filtered out, such as by `--exclude` or for being deeper than `--max-depth`,
+    // are still in the documents directories, so their copies mustn't be deleted as stale.
+    let (kept_tx, kept_rx) = if delete {
+        let (kept_tx, kept_rx) = unbounded_channel();
+        (Some(kept_tx), Some(kept_rx))
+    } else {
+        (None, None)
+    };
+
+    let book_finding = {
+        let stats_tx = stats_tx.clone();
+        let extensions_ptr = extensions_ptr.clone();
+        let planned = sync_options.planned.clone();
+        let run = run.clone();
+        spawn(async move {
+            if let Some(planned) = planned {
+                find_planned_books(&planned, &book_path_tx, &stats_tx, &run).await?;
+                stats_tx.send(Statistic::FindingFinished).await?;
+                return Ok(());
+            }
+
+            let extensions: HashSet<&OsStr> = extensions_ptr.iter().map(OsStr::new).collect();
+            if let Some(files) = files {
+                find_listed_books(
+                    &files,
+                    &documents_directories_ptr,
+                    &extensions,
+                    &book_path_tx,
+                    &stats_tx,
+                    &run,
+                )
+                .await?;
+                stats_tx.send(Statistic::FindingFinished).await?;
+                return Ok(());
+            }
+
+            find_books(
+                &(*documents_directories_ptr)[..],
+                &extensions,
+                &find_options,
+                &book_path_tx,
+                kept_tx.as_ref(),
+                &stats_tx,
+                &run,
+            )
+            .await?;
+            drop(kept_tx);
+            stats_tx.send(Statistic::FindingFinished).await?;
+
+            if watch {
+                watch_books(
+                    &(*documents_directories_ptr)[..],
+                    &extensions,
+                    &find_options,
+                    book_path_tx,
+                    stats_tx,
+                    &run,
+                )
+                .await?;
+            }
+            Ok::<(), Error>(())
+        })
+    };
+
+    let mut manifest = if sync_options.manifest {
+        Manifest::load(&dest_directory).await
+    } else {
+        Manifest::default()
+    };
+    let mut synchronised = sync_books(
+        &dest_directory,
+        &sync_options,
+        &mut manifest,
+        book_path_rx,
+        stats_tx.clone(),
+        &run,
+    )
+    .await?;
+    stats_tx.send(Statistic::CopyingFinished).await?;
+    book_finding.await??;
+    if let Some(preferring) = preferring {
+        preferring.await??;
+    }
+    if let Some(deduping) = deduping {
+        deduping.await??;
+    }
+    if let Some(kept_rx) = kept_rx {
+        keep_books(&dest_directory, &sync_options, kept_rx, &mut synchronised).await;
+    }
+
+    // Not every book was seen, so anything that goes by what's missing, like deleting stale books,
+    // would go wrong. What was copied is still recorded in the manifest, though.
+    let interrupted = run.is_interrupted();
+    if interrupted && (pull.is_some() || delete || collections_from_folders || eject_volume) {
+        if run.has_failed_fast() {
+            info!("Skipping the rest of the run, as a book failed");
+        } else {
+            info!("Skipping the rest of the run, as it was interrupted");
+        }
+    }
+
+    // This must happen before deletion, which would otherwise delete the very books to pull.
+    if let Some(pull_dir) = pull.as_ref().filter(|_| !interrupted) {
+        let extensions: HashSet<&OsStr> = extensions_ptr.iter().map(OsStr::new).collect();
+        pull_books(
+            &dest_directory,
+            pull_dir,
+            &extensions,
+            &synchronised,
+            &sync_options,
+            &stats_tx,
+            &run,
+        )
+        .await?;
+    }
+
+    if delete && !interrupted {
+        let extensions: HashSet<&OsStr> = extensions_ptr.iter().map(OsStr::new).collect();
+        delete_stale_books(
+            &dest_directory,
+            &extensions,
+            &synchronised,
+            sync_options.dry_run,
+            sync_options.mirror_structure,
+            stats_tx.clone(),
+        )
+        .await?;
+
+        // Anything that wasn't synchronised has just been deleted.
+        manifest
+            .books
+            .retain(|key, _| synchronised.contains_key(&dest_directory.join(key)));
+    }
+
+    if sync_options.manifest && !sync_options.dry_run {
+        if let Err(err) = manifest.save(&dest_directory).await {
+            warn!("Failed to save the manifest, so the next run will check every book: {err:#}");
+        }
+    }
+    run.save_hash_cache().await;
+
+    // The manifest is flushed along with the books, as it's in the destination.
+    if sync_options.fsync && !sync_options.dry_run {
+        let mut dirs = synchronised
+            .keys()
+            .filter_map(|dest| dest.parent())
+            .collect::<HashSet<_>>();
+        dirs.insert(&dest_directory);
+        for dir in dirs {
+            sync_directory(dir).await;
+        }
+    }
+
+    if collections_from_folders && !interrupted {
+        create_collections_from_folders(&volume_directory, &synchronised, sync_options.dry_run)
+            .await?;
+    }
+
+    drop(stats_tx);
+
+    let mut report = stats_collection.await??;
+    report.interrupted = interrupted && !watch && !run.has_failed_fast();
+
+    // A book left behind could be older than the recorded run, so an incremental run wouldn't look
+    // at it again until it changed.
+    let Counters {
+        wont_fit,
+        deferred,
+        declined,
+        cancelled,
+        ..
+    } = report.counters;
+    let left_behind = report.failures() + wont_fit + deferred + declined + cancelled;
+    if !is_partial && !watch && !sync_options.dry_run && !interrupted && left_behind == 0 {
+        if let Err(err) = record_last_sync(&dest_directory, started).await {
+            warn!(
+                "Failed to record the run on the destination, so the next incremental run will \
+                look at every book: {err:#}"
+            );
+        }
+    }
+
+    // The lock is on the device, so it has to go before the device does.
+    drop(lock);
+
+    if eject_volume && !interrupted {
+        let volume_str = volume_directory.display();
+        if sync_options.dry_run {
+            info!("Dry-running; would otherwise eject {volume_str}");
+        } else {
+            let ejected = match eject(&volume_directory).await {
+                Ok(()) => {
+                    info!("Ejected {volume_str}");
+                    true
+                }
+                Err(err) => {
+                    error!("Failed to eject {volume_str}: {err:#}");
+                    false
+                }
+            };
+            report.counters.ejected = Some(ejected);
+        }
+    }
+    Ok(report)
+}
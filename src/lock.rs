@@ -0,0 +1,193 @@
+//! Stopping two runs from synchronising to the same destination at once.
+
+use {
+    crate::RunFailure,
+    anyhow::{anyhow, Result},
+    serde::{Deserialize, Serialize},
+    std::{
+        io,
+        path::{Path, PathBuf},
+        sync::Mutex,
+        time::{Duration, SystemTime},
+    },
+    tokio::{
+        fs::{self, OpenOptions},
+        io::AsyncWriteExt,
+        task::{spawn, JoinHandle},
+        time::interval,
+    },
+    tracing::{debug, warn},
+};
+
+const LOCK_NAME: &str = ".sync-kobo.lock";
+
+/// How long a lock can go untouched before it's taken to be left behind by a run that died, unless
+/// told otherwise.
+pub(crate) const DEFAULT_STALE_LOCK_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// The longest a lock goes without being touched while its run is going, so that long runs, such
+/// as those watching for new books, never look stale.
+const LOCK_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The locks held by the runs going in this process, so that they can be removed even if the runs
+/// are abandoned rather than finishing, such as when interrupted twice.
+static HELD_LOCKS: Mutex<Vec<PathBuf>> = Mutex::new(vec![]);
+
+/// Who holds a lock, as written inside it.
+#[derive(Debug, Deserialize, Serialize)]
+struct LockHolder {
+    pid: u32,
+    host: String,
+
+    /// When the lock was taken, in RFC 3339 format.
+    since: String,
+}
+
+impl LockHolder {
+    fn of_this_run() -> LockHolder {
+        LockHolder {
+            pid: std::process::id(),
+            host: whoami::fallible::hostname().unwrap_or_else(|_| "an unknown host".to_owned()),
+            since: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "process {} on {}, since {}",
+            self.pid, self.host, self.since
+        )
+    }
+}
+
+/// A lock on a destination, held until dropped, at which point it's removed.
+pub(crate) struct DestinationLock {
+    path: PathBuf,
+    refreshing: JoinHandle<()>,
+}
+
+impl Drop for DestinationLock {
+    fn drop(&mut self) {
+        self.refreshing.abort();
+        if let Ok(mut held) = HELD_LOCKS.lock() {
+            held.retain(|path| *path != self.path);
+        }
+        remove_lock(&self.path);
+    }
+}
+
+fn remove_lock(path: &Path) {
+    let path_str = path.display();
+    match std::fs::remove_file(path) {
+        Ok(()) => debug!(path = %path_str, "Removed the lock {path_str}"),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => warn!(path = %path_str, "Failed to remove the lock {path_str}: {err}"),
+    }
+}
+
+/// Remove the locks on the destinations of any runs that are still going, which must only be done
+/// once they're being abandoned, such as just before exiting.
+pub fn remove_held_locks() {
+    let held = match HELD_LOCKS.lock() {
+        Ok(mut held) => std::mem::take(&mut *held),
+        Err(_) => return,
+    };
+    for path in held {
+        remove_lock(&path);
+    }
+}
+
+/// Keep touching a lock while its run is going, as long as it's still this run's.
+async fn refresh_lock(path: PathBuf, contents: Vec<u8>, stale_age: Duration) {
+    let period = LOCK_REFRESH_INTERVAL.min(stale_age / 4);
+    let mut ticks = interval(period.max(Duration::from_secs(1)));
+    // The first tick is immediate, and the lock has only just been written.
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        match fs::read(&path).await {
+            Ok(held) if held == contents => {
+                if let Err(err) = fs::write(&path, &contents).await {
+                    let path_str = path.display();
+                    warn!(path = %path_str, "Failed to refresh the lock {path_str}: {err}");
+                }
+            }
+            _ => {
+                let path_str = path.display();
+                warn!(path = %path_str, "The lock {path_str} was broken by another run");
+                return;
+            }
+        }
+    }
+}
+
+/// Take the lock on a destination, which is a file at its root created only if it isn't there
+/// already, naming the process holding it. A lock untouched for longer than the stale age is
+/// taken to be left behind by a run that died, so it's broken with a warning; otherwise, the run
+/// fails, saying who holds it.
+pub(crate) async fn lock_destination(
+    dest_dir: &Path,
+    stale_age: Duration,
+) -> Result<DestinationLock> {
+    let path = dest_dir.join(LOCK_NAME);
+    let path_str = path.display();
+    let contents = serde_json::to_vec(&LockHolder::of_this_run())?;
+
+    loop {
+        let created = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await;
+        match created {
+            Ok(mut file) => {
+                file.write_all(&contents).await?;
+                file.sync_all().await?;
+                debug!(path = %path_str, "Took the lock {path_str}");
+                let refreshing = spawn(refresh_lock(path.clone(), contents, stale_age));
+                if let Ok(mut held) = HELD_LOCKS.lock() {
+                    held.push(path.clone());
+                }
+                return Ok(DestinationLock { path, refreshing });
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                let holder = match fs::read(&path).await {
+                    Ok(held) => serde_json::from_slice::<LockHolder>(&held)
+                        .map(|holder| holder.describe())
+                        .unwrap_or_else(|_| "an unknown run".to_owned()),
+                    // It was removed in the meantime, so try again.
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                    Err(err) => return Err(anyhow!("could not read the lock {path_str}: {err}")),
+                };
+                let age = fs::metadata(&path)
+                    .await
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+                    .and_then(|modified| modified.elapsed().ok())
+                    .unwrap_or_default();
+
+                if age < stale_age {
+                    let dest_str = dest_dir.display();
+                    return Err(RunFailure::Locked(format!(
+                        "another run is already synchronising to {dest_str}: {holder}; if it has \
+                        stopped, delete {path_str} or wait for the lock to go stale"
+                    ))
+                    .into());
+                }
+                let age = humantime::format_duration(Duration::from_secs(age.as_secs()));
+                warn!(
+                    path = %path_str,
+                    "Breaking the stale lock {path_str}, held by {holder} but untouched for {age}"
+                );
+                match fs::remove_file(&path).await {
+                    Ok(()) => {}
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                    Err(err) => {
+                        return Err(anyhow!("could not break the stale lock {path_str}: {err}"))
+                    }
+                }
+            }
+            Err(err) => return Err(anyhow!("could not take the lock {path_str}: {err}")),
+        }
+    }
+}
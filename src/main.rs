@@ -6,340 +6,727 @@
 #![forbid(unsafe_code)]
 
 use {
-    anyhow::{anyhow, Error, Result},
-    async_walkdir::WalkDir,
-    clap::Parser,
-    directories::UserDirs,
+    anstyle::{AnsiColor, Style},
+    anyhow::{anyhow, Result},
+    clap_complete::Shell,
+    indicatif::ProgressBar,
     std::{
-        collections::HashSet,
         ffi::OsStr,
-        path::{Path, PathBuf},
-        sync::Arc,
+        io::Write,
+        path::Path,
+        process::ExitCode,
+        sync::{Mutex, OnceLock},
+        time::Duration,
     },
     tokio::{
         self,
-        fs::{self, File},
-        io::{self, stdout, AsyncWriteExt},
-        sync::mpsc::{channel, Receiver, Sender},
-        task::{spawn, JoinHandle},
+        io::{stdout, AsyncWriteExt},
+        runtime::Runtime,
+        select,
+        sync::{
+            mpsc::{error::SendError, unbounded_channel, Receiver, UnboundedSender},
+            oneshot,
+        },
+        task::{spawn_blocking, JoinHandle},
     },
-    tokio_stream::StreamExt,
-    whoami::username,
+    tracing::{
+        debug, error,
+        field::{Field, Visit},
+        info, Event, Level, Subscriber,
+    },
+    tracing_subscriber::{
+        fmt::{
+            self,
+            format::{self, FormatEvent, FormatFields},
+            FmtContext,
+        },
+        layer::SubscriberExt,
+        registry::LookupSpan,
+        util::SubscriberInitExt,
+        EnvFilter,
+    },
+};
+
+use sync_kobo_and_workstation::{
+    cli::{
+        completions_for, destination_in, diagnose, format_bytes, format_count, format_diagnoses,
+        format_listing, format_plan, format_pruned, format_summary, format_verification,
+        interrupt_signal, parse_args, parse_partial_args, progress_style, run_daemon,
+        scanning_style, Action, Args, Outcome, OutputFormat, PartialArgs,
+    },
+    eject, export_annotations, list, progress_output, prune_duplicates, remove_held_locks,
+    sync_with_events, verify, write_progress_to_stderr, write_report, Counters, Plan, RunFailure,
+    SyncEvent, SyncOptions, SyncReport,
 };
 
-const NAME: &str = "sync-kobo-and-workstation";
+/// How often the spinner turns while scanning the documents directories.
+const SPINNER_TICK: Duration = Duration::from_millis(100);
+
+/// What the printer is sent.
+enum Printed {
+    /// A line of output, such as a log event.
+    Line(String),
+
+    /// Everything sent before this has been printed.
+    Flushed(oneshot::Sender<()>),
+}
 
-const LONG_ABOUT: &str = "Synchronise books between a workstation and a Kobo e-book reader. In \
-                          practice, this means synchronising a connected Kobo volume with EPUB \
-                          and PDF files in the specified local documents directories. The \
-                          defaults are Linux-specific, assuming a udisks2-style automount \
-                          directory such as /var/media/user/KOBOeReader for the destination Kobo \
-                          and defaulting to just ~/Documents for the source. However, if these \
-                          defaults are overridden with explicit values, it will likely work on \
-                          other OSes too.";
+/// The printer task owns console output for the whole run, printing lines one at a time in the
+/// order they were sent, so that those from concurrent copies can't be spliced into each other.
+static PRINTER: Mutex<Option<UnboundedSender<Printed>>> = Mutex::new(None);
 
-const EXTENSIONS_TO_SYNCHRONISE: [&str; 2] = ["epub", ".pdf"];
+/// The bar showing the run's progress, which output is printed above while it's being drawn.
+static PROGRESS_BAR: OnceLock<ProgressBar> = OnceLock::new();
 
-const FOUND_BOOKS_CHANNEL_BOUND: usize = 128;
-const STATISTICS_CHANNEL_BOUND: usize = 128;
+fn progress_bar() -> Option<&'static ProgressBar> {
+    PROGRESS_BAR.get()
+}
 
-macro_rules! println_async {
-    ($fmt:literal $(, $elem:expr )* $(,)?) => {
-        {
-            let msg = format!($fmt, $( $elem, )*);
-            stdout().write_all(msg.as_bytes()).await?;
-            stdout().write_all(b"\n")
+/// Print a line now, above the progress bar if one is being drawn so that the two don't
+/// interleave.
+fn write_line(line: &str) {
+    match progress_bar() {
+        Some(bar) if !bar.is_finished() => bar.println(line),
+        _ => {
+            // There is nowhere left to report a failure to print a diagnostic.
+            let _ = writeln!(progress_output(), "{line}");
         }
-    };
+    }
 }
 
-#[derive(Debug)]
-enum Statistic {
-    FoundSrcDocument,
-    NotCopiedBecauseAlreadyExistedAtDest,
-    Copied,
+/// Print a line through the printer, or straight away if it isn't running, such as after it's
+/// stopped.
+fn print_line(line: String) {
+    let printer = PRINTER.lock().ok().and_then(|printer| printer.clone());
+    let unsent = match printer {
+        Some(printer) => match printer.send(Printed::Line(line)) {
+            Ok(()) => return,
+            Err(SendError(unsent)) => unsent,
+        },
+        None => Printed::Line(line),
+    };
+    if let Printed::Line(line) = unsent {
+        write_line(&line);
+    }
 }
 
-async fn is_accessible_dir(path: &Path) -> bool {
-    fs::metadata(path)
-        .await
-        .map(|m| m.is_dir())
-        .unwrap_or(false)
+/// Start the printer. Printing blocks, so it has a thread of its own rather than holding up the
+/// runtime's.
+fn start_printer() -> JoinHandle<()> {
+    let (printer, mut printing) = unbounded_channel();
+    if let Ok(mut slot) = PRINTER.lock() {
+        *slot = Some(printer);
+    }
+    spawn_blocking(move || {
+        while let Some(printed) = printing.blocking_recv() {
+            match printed {
+                Printed::Line(line) => write_line(&line),
+                Printed::Flushed(flushed) => {
+                    let _ = flushed.send(());
+                }
+            }
+        }
+    })
 }
 
-fn lookup_default_kobo_storage_directory() -> PathBuf {
-    let mut buf = PathBuf::new();
-    buf.push("/media");
-    buf.push(username());
-    buf.push("KOBOeReader");
-    buf
+/// Wait for everything sent to the printer so far to be printed.
+async fn flush_printer() {
+    let printer = PRINTER.lock().ok().and_then(|printer| printer.clone());
+    if let Some(printer) = printer {
+        let (flushed, printed) = oneshot::channel();
+        if printer.send(Printed::Flushed(flushed)).is_ok() {
+            let _ = printed.await;
+        }
+    }
 }
 
-fn lookup_home_directory() -> Result<PathBuf> {
-    let dirs =
-        UserDirs::new().ok_or_else(|| anyhow!("failed to read the current home directory"))?;
+/// Stop the printer once it's printed everything it was sent. Anything printed after this is
+/// printed straight away.
+async fn stop_printer(printer: JoinHandle<()>) {
+    if let Ok(mut slot) = PRINTER.lock() {
+        slot.take();
+    }
+    let _ = printer.await;
+}
 
-    let home = dirs.home_dir();
-    Ok(home.to_path_buf())
+/// Write the results of a command, such as its summary, to stdout after any lines still waiting
+/// to be printed. Everything that isn't a diagnostic goes out through here.
+async fn print_out(text: &str) -> Result<()> {
+    flush_printer().await;
+    let mut out = stdout();
+    out.write_all(text.as_bytes()).await?;
+    out.flush().await?;
+    Ok(())
 }
 
-fn lookup_default_documents_directories() -> Result<Vec<PathBuf>> {
-    let home = lookup_home_directory()?;
+/// Buffers a single formatted log event, and sends it to the printer once complete.
+#[derive(Default)]
+struct ConsoleWriter(Vec<u8>);
 
-    let mut documents = PathBuf::new();
-    documents.push(home);
-    documents.push("Documents");
+impl Write for ConsoleWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
 
-    Ok(vec![documents])
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
-async fn find_books(
-    dirs: &[PathBuf],
-    extensions_to_match: &HashSet<&OsStr>,
-    books: Sender<PathBuf>,
-    stats: Sender<Statistic>,
-) -> Result<()> {
-    for dir in dirs {
-        let mut entries = WalkDir::new(dir);
-        loop {
-            match entries.next().await {
-                Some(Ok(entry)) => {
-                    let path = entry.path();
-                    if let Some(ext) = path.extension() {
-                        if extensions_to_match.contains(&ext) {
-                            stats.send(Statistic::FoundSrcDocument).await?;
-
-                            let path_buf = path.to_path_buf();
-                            books.send(path_buf).await?;
-                        }
-                    }
-                }
-                Some(Err(err)) => Err(anyhow!(err))?,
-                None => break,
-            }
-        }
+impl Drop for ConsoleWriter {
+    fn drop(&mut self) {
+        let msg = String::from_utf8_lossy(&self.0);
+        print_line(msg.trim_end_matches('\n').to_owned());
     }
-    Ok(())
 }
 
-fn path_str(path: &Path) -> Result<&str> {
-    path.to_str()
-        .ok_or_else(|| anyhow!("could not decode a path to UTF-8"))
+/// Formats log events for the console. Events at the info level and above are printed as just
+/// their message, which is what the user has always seen; more detailed levels also get their
+/// level and fields, as they're for debugging.
+///
+/// When colouring, errors are red and warnings yellow, and events with an `outcome` field of
+/// `copied` are green and `skipped` dimmed.
+struct ConsoleFormat {
+    detailed: format::Format<format::Full, ()>,
 }
 
-async fn copy_to_non_existant(
-    src_path: &Path,
-    dest_path: &Path,
-    dry_run: bool,
-) -> Result<JoinHandle<Result<()>>> {
-    if dry_run {
-        let (src, dest) = (path_str(src_path)?, path_str(dest_path)?);
-        println_async!("Dry-running; would otherwise copy {src} to {dest}").await?;
-        Ok(spawn(async { Ok(()) }))
-    } else {
-        let mut src = File::open(src_path).await?;
-
-        let mut dest = fs::OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(dest_path)
-            .await?;
+impl<S, N> FormatEvent<S, N> for ConsoleFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: format::Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        if Level::INFO < *event.metadata().level() {
+            return self.detailed.format_event(ctx, writer, event);
+        }
 
-        let src_str = path_str(src_path)?.to_owned();
-        let dest_str = path_str(dest_path)?.to_owned();
+        let style = if writer.has_ansi_escapes() {
+            style_of(event)
+        } else {
+            Style::new()
+        };
+        write!(writer, "{}", style.render())?;
+        let mut visitor = MessageVisitor {
+            writer: &mut writer,
+            result: Ok(()),
+        };
+        event.record(&mut visitor);
+        visitor.result?;
+        writeln!(writer, "{}", style.render_reset())
+    }
+}
 
-        Ok(spawn(async move {
-            io::copy(&mut src, &mut dest).await?;
-            println_async!("Copied {src_str} to {dest_str}").await?;
-            Ok(())
-        }))
+/// The colour of an event's line on the console.
+fn style_of(event: &Event<'_>) -> Style {
+    match *event.metadata().level() {
+        Level::ERROR => return AnsiColor::Red.on_default(),
+        Level::WARN => return AnsiColor::Yellow.on_default(),
+        _ => {}
+    }
+    let mut visitor = OutcomeVisitor(None);
+    event.record(&mut visitor);
+    match visitor.0.as_deref() {
+        Some("copied") => AnsiColor::Green.on_default(),
+        Some("skipped") => Style::new().dimmed(),
+        _ => Style::new(),
     }
 }
 
-async fn sync_books(
-    dest_dir: &Path,
-    dry_run: bool,
-    mut books_to_sync: Receiver<PathBuf>,
-    stats: Sender<Statistic>,
-) -> Result<()> {
-    let mut copy_tasks = vec![];
+/// Finds the `outcome` field of an event, which says what was done with a book.
+struct OutcomeVisitor(Option<String>);
 
-    while let Some(book) = books_to_sync.recv().await {
-        let mut dest_path = PathBuf::new();
-        dest_path.push(dest_dir);
+impl Visit for OutcomeVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "outcome" {
+            self.0 = Some(value.to_owned());
+        }
+    }
 
-        if let Some(book_name) = book.file_name() {
-            dest_path.push(book_name);
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+}
 
-            if let Ok(copy_task) = copy_to_non_existant(&book, &dest_path, dry_run).await {
-                copy_tasks.push(copy_task);
-                stats.send(Statistic::Copied).await?;
-            } else {
-                let dest_str = path_str(&dest_path)?;
-                println_async!(
-                    "Book {dest_str} already exists on the destination; will not copy across."
-                )
-                .await?;
-                stats
-                    .send(Statistic::NotCopiedBecauseAlreadyExistedAtDest)
-                    .await?;
-            }
+/// Writes out just the message of an event, skipping its other fields.
+struct MessageVisitor<'a, 'b> {
+    writer: &'a mut format::Writer<'b>,
+    result: std::fmt::Result,
+}
+
+impl Visit for MessageVisitor<'_, '_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.result = write!(self.writer, "{value:?}");
         }
     }
+}
 
-    for task in copy_tasks {
-        task.await??;
-    }
+/// Set up logging to the console and, if given, a log file. `RUST_LOG` controls what is logged,
+/// defaulting to the info level, or just warnings and errors if `quiet`. The log file is never
+/// coloured.
+fn init_logging(colors: bool, log_file: Option<&Path>, quiet: bool) -> Result<()> {
+    let default_level = if quiet { "warn" } else { "info" };
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_level));
+
+    let console = fmt::layer()
+        .with_ansi(colors)
+        .event_format(ConsoleFormat {
+            detailed: format::Format::default()
+                .without_time()
+                .with_target(false)
+                .with_ansi(colors),
+        })
+        .with_writer(ConsoleWriter::default);
+
+    let file = match log_file {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|err| {
+                    anyhow!("could not open the log file at {}: {err}", path.display())
+                })?;
+            Some(fmt::layer().with_ansi(false).with_writer(Mutex::new(file)))
+        }
+        None => None,
+    };
 
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(console)
+        .with(file)
+        .init();
     Ok(())
 }
 
-async fn collect_stats(dest_dirs: &[PathBuf], mut stats: Receiver<Statistic>) -> Result<()> {
-    let mut found_src_documents: usize = 0;
-    let mut not_copied: usize = 0;
-    let mut copied: usize = 0;
+/// Write a completion script for a shell, generated from the arguments so that it keeps up with
+/// them. Options and subcommands are completed, as are the values of those that take paths or
+/// one of a fixed set.
+async fn write_completions(shell: Shell) -> Result<()> {
+    let script = completions_for(shell);
+    print_out(&script).await?;
+    Ok(())
+}
 
-    while let Some(stat) = stats.recv().await {
-        use Statistic::*;
-        match stat {
-            FoundSrcDocument => {
-                found_src_documents += 1;
+/// Show how a run is going from its events: the book being copied on the progress bar, if there is
+/// one, and, when watching, a running tally after each copy, as a watch can run for hours before
+/// it's summarised.
+async fn follow_events(mut events: Receiver<SyncEvent>, watch: bool, raw_bytes: bool) {
+    let (mut copied, mut updated, mut failed) = (0, 0, 0);
+    let mut copying = None;
+    let mut scanning = false;
+    while let Some(event) = events.recv().await {
+        match event {
+            SyncEvent::Scanning {
+                files_examined,
+                books_found,
+            } => {
+                if let Some(bar) = progress_bar() {
+                    bar.set_prefix(format!(
+                        "{} files examined, {} books found",
+                        format_count(files_examined),
+                        format_count(books_found)
+                    ));
+                    if !scanning {
+                        bar.set_style(scanning_style());
+                        bar.enable_steady_tick(SPINNER_TICK);
+                        scanning = true;
+                    }
+                }
             }
-            NotCopiedBecauseAlreadyExistedAtDest => {
-                not_copied += 1;
+            SyncEvent::ScanFinished { .. } => {
+                if let Some(bar) = progress_bar().filter(|_| scanning) {
+                    bar.disable_steady_tick();
+                    bar.set_prefix("");
+                    bar.set_style(progress_style());
+                    scanning = false;
+                }
+            }
+            SyncEvent::CopyStarted { source, .. } => {
+                if let Some(bar) = progress_bar() {
+                    let name = source
+                        .file_name()
+                        .unwrap_or(OsStr::new(""))
+                        .to_string_lossy();
+                    bar.set_message(format!("(copying {name})"));
+                }
+                copying = Some(source);
             }
-            Copied => {
-                copied += 1;
+            SyncEvent::CopyProgress {
+                source,
+                bytes,
+                total,
+                ..
+            } => {
+                if let Some(bar) = progress_bar() {
+                    let name = source
+                        .file_name()
+                        .unwrap_or(OsStr::new(""))
+                        .to_string_lossy();
+                    let percent = (bytes * 100).checked_div(total).unwrap_or(100);
+                    let (bytes, total) = (
+                        format_bytes(bytes, raw_bytes),
+                        format_bytes(total, raw_bytes),
+                    );
+                    bar.set_message(format!("(copying {name}, {bytes} of {total}, {percent}%)"));
+                }
+                copying = Some(source);
+            }
+            SyncEvent::Copied { source, update, .. } => {
+                if update {
+                    updated += 1;
+                } else {
+                    copied += 1;
+                }
+                if watch {
+                    info!("So far: {copied} copied, {updated} updated, {failed} failed");
+                }
+                if copying.as_ref() == Some(&source) {
+                    if let Some(bar) = progress_bar() {
+                        bar.set_message("");
+                    }
+                }
+            }
+            SyncEvent::Failed { source, .. } => {
+                failed += 1;
+                if copying.as_ref() == Some(&source) {
+                    if let Some(bar) = progress_bar() {
+                        bar.set_message("");
+                    }
+                }
             }
+            SyncEvent::Found { .. } | SyncEvent::Skipped { .. } => {}
         }
     }
-
-    let len = dest_dirs.len();
-    let dest_str: String =
-        dest_dirs
-            .iter()
-            .zip(1..)
-            .try_fold(String::new(), |mut s, (dir, i)| {
-                s.push_str(path_str(dir)?);
-                if i < len {
-                    s.push_str(" and ");
-                }
-                Ok::<String, Error>(s)
-            })?;
-
-    println_async!(
-        "\n\
-        Found documents in documents directory at {dest_str}: {found_src_documents}\n\
-        Books not copied because they already exist on the destination Kobo: {not_copied}\n\
-        Book copied: {copied}"
-    )
-    .await?;
-
-    Ok(())
 }
 
-#[derive(Debug, Parser)]
-#[command(name = NAME, about, author, version, long_about = LONG_ABOUT)]
-struct PartialArgs {
-    /// The directory of the mounted Kobo storage directory to which to synchronise the books and
-    /// documents.
-    #[arg(long)]
-    kobo_directory: Option<PathBuf>,
-
-    /// The directory of the documents directories from which to synchronise books and documents.
-    #[arg(long)]
-    documents_directories: Option<Vec<PathBuf>>,
-
-    /// Whether to dry run, documenting what would happen rather than doing it.
-    #[arg(long, default_value_t = false)]
-    dry_run: bool,
+/// Synchronise books, stopping gracefully at the first interruption. That lets the copies in
+/// progress finish, so that the summary covers everything that was done, but a second stops at
+/// once for those who can't wait.
+async fn sync_until_interrupted(
+    options: &SyncOptions,
+    watch: bool,
+    raw_bytes: bool,
+) -> Result<SyncReport> {
+    let (events, syncing) = sync_with_events(options.clone());
+    let following = tokio::spawn(follow_events(events, watch, raw_bytes));
+    tokio::pin!(syncing);
+    let report = select! {
+        result = &mut syncing => result??,
+        signalled = interrupt_signal() => {
+            signalled?;
+            if !watch {
+                info!(
+                    "Interrupted; finishing the copies in progress. Interrupt again to stop at \
+                    once."
+                );
+            }
+            options.interrupter().interrupt();
+            select! {
+                result = &mut syncing => result??,
+                signalled = interrupt_signal() => {
+                    signalled?;
+                    if let Some(bar) = progress_bar() {
+                        bar.finish_and_clear();
+                    }
+                    return Err(RunFailure::Interrupted(
+                        "interrupted again; any books being copied were left as partial files"
+                            .to_owned(),
+                    )
+                    .into());
+                }
+            }
+        }
+    };
+    following.await?;
+    Ok(report)
 }
 
-struct Args {
-    kobo_directory: PathBuf,
-    documents_directories: Vec<PathBuf>,
-    dry_run: bool,
+/// Run the doctor's checks and print whether each passed, failing if any didn't.
+async fn run_doctor(args: PartialArgs) -> Result<usize> {
+    let (output, colors) = (args.output, args.color.colors(args.output));
+    let diagnoses = diagnose(args).await?;
+    print_out(&format_diagnoses(&diagnoses, output, colors)?).await?;
+    let failed = diagnoses
+        .iter()
+        .filter(|diagnosis| !diagnosis.passed)
+        .count();
+    if 0 < failed {
+        return Err(anyhow!("{failed} of the {} checks failed", diagnoses.len()));
+    }
+    Ok(0)
 }
 
-async fn parse_args() -> Result<Args> {
-    let partial @ PartialArgs { dry_run, .. } = PartialArgs::parse();
-
-    let kobo_directory = partial
-        .kobo_directory
-        .unwrap_or_else(lookup_default_kobo_storage_directory);
-
-    let documents_directories = partial.documents_directories.unwrap_or_else(|| {
-        lookup_default_documents_directories().expect(
-            "failed to lookup the default documents directory while yielding a default \
-                    value for that missing argument",
-        )
-    });
-
-    if !is_accessible_dir(&kobo_directory).await {
-        let inaccessible = kobo_directory.to_str().ok_or_else(|| {
-            anyhow!("could not decode Kobo directory path as UTF-8 while reporting its absense")
-        })?;
-        return Err(anyhow!(
-            "The Kobo storage directory at {inaccessible} is not accessible"
-        ));
+/// Run the tool, yielding the number of books that failed to copy or verify.
+async fn run() -> Result<usize> {
+    let (args, environment) = parse_partial_args();
+    // Completions are generated from the arguments alone, so mustn't need a device to be found.
+    if let Some(Action::Completions(completions)) = &args.action {
+        write_completions(completions.shell).await?;
+        return Ok(0);
     }
-    for dir in &documents_directories {
-        if !is_accessible_dir(dir).await {
-            let inaccessible = dir.to_str().ok_or_else(|| {
-                anyhow!(
-                    "could not a decode documents directory path as UTF-8 while reporting its \
-                        absence",
-                )
-            })?;
-            return Err(anyhow!(
-                "The documents directory at {inaccessible} is not accessible"
-            ));
+
+    // A daemon starts before the device is plugged in, so it can't wait for it to be found before
+    // logging, and doesn't show progress bars, running unattended.
+    if args.sync.daemon {
+        if args.action.is_some() {
+            return Err(anyhow!("--daemon only works when synchronising"));
+        }
+        if args.output == OutputFormat::Json {
+            write_progress_to_stderr();
         }
+        init_logging(
+            args.color.colors(args.output),
+            args.log_file.as_deref(),
+            false,
+        )?;
+        for (variable, value) in &environment {
+            debug!(variable, value, "Taking an argument from the environment");
+        }
+        return run_daemon(args, synchronise).await;
+    }
+    // The doctor reports what a run would stop at rather than stopping at it.
+    if let Some(Action::Doctor) = &args.action {
+        init_logging(
+            args.color.colors(args.output),
+            args.log_file.as_deref(),
+            false,
+        )?;
+        return run_doctor(args).await;
     }
 
-    Ok(Args {
-        kobo_directory,
-        documents_directories,
-        dry_run,
-    })
+    let args = parse_args(args).await?;
+    if args.output == OutputFormat::Json {
+        write_progress_to_stderr();
+    }
+    if let Some(bar) = args.sync_options.progress_bar() {
+        PROGRESS_BAR
+            .set(bar.clone())
+            .expect("the progress bar should only be set up once");
+    }
+    // A plan's preview replaces the line per book that dry runs otherwise log.
+    init_logging(args.colors, args.log_file.as_deref(), args.plan)?;
+    for (variable, value) in &environment {
+        debug!(variable, value, "Taking an argument from the environment");
+    }
+    synchronise(args).await
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
+/// Do what the parsed arguments say to, yielding the number of books that failed to copy or
+/// verify.
+async fn synchronise(args: Args) -> Result<usize> {
     let Args {
-        dry_run,
-        kobo_directory,
-        documents_directories,
-    } = parse_args().await?;
-
-    let extensions: HashSet<&OsStr> = EXTENSIONS_TO_SYNCHRONISE.iter().map(OsStr::new).collect();
-
-    let (book_path_tx, book_path_rx) = channel::<PathBuf>(FOUND_BOOKS_CHANNEL_BOUND);
-    let (stats_tx, stats_rx) = channel::<Statistic>(STATISTICS_CHANNEL_BOUND);
+        sync_options,
+        device,
+        extra_destinations,
+        dest_subdir,
+        force,
+        auto_unmount,
+        output,
+        colors,
+        watch,
+        raw_bytes,
+        report: report_path,
+        report_format,
+        plan,
+        plan_file,
+        action,
+        ..
+    } = args;
+
+    debug!(options = ?sync_options, "Running with these options");
+    debug!(
+        extensions = ?sync_options.extensions(),
+        "Synchronising books with these extensions"
+    );
+
+    if let Some(Action::ExportAnnotations(export)) = &action {
+        export_annotations(
+            sync_options.volume_directory(),
+            &export.output,
+            export.format,
+        )
+        .await?;
+        return Ok(0);
+    }
+    if let Some(Action::List(list_args)) = &action {
+        let listing = list(sync_options).await?;
+        print_out(&format_listing(&listing, list_args.porcelain)).await?;
+        return Ok(0);
+    }
+    if let Some(Action::Verify(verify_args)) = &action {
+        let report = verify(sync_options, verify_args.hash).await?;
+        print_out(&format_verification(
+            &report,
+            verify_args.hash,
+            output,
+            colors,
+        )?)
+        .await?;
+        if let Some(report_path) = &report_path {
+            write_report(&report.actions, report_path, report_format).await?;
+        }
+        let Counters {
+            missing,
+            mismatched,
+            corrupted,
+            ..
+        } = report.counters;
+        return Ok(report.failures() + missing + mismatched + corrupted);
+    }
+    if let Some(Action::PruneDuplicates) = &action {
+        let dry_run = sync_options.is_dry_run();
+        let report = prune_duplicates(sync_options).await?;
+        print_out(&format_pruned(&report, dry_run, output, raw_bytes)?).await?;
+        return Ok(0);
+    }
 
-    let documents_directories_ptr = Arc::new(documents_directories);
+    // Each device is synchronised in turn, so that one failing, such as by being unplugged midway,
+    // doesn't stop the rest from being synchronised.
+    let is_fanning_out = !extra_destinations.is_empty();
+    let mut destinations = vec![(
+        sync_options.volume_directory().to_path_buf(),
+        Ok(sync_options.clone()),
+    )];
+    for volume in &extra_destinations {
+        let create_subdir = !sync_options.is_dry_run();
+        let subdir = dest_subdir.as_deref();
+        let destination = destination_in(volume, device, !force, subdir, create_subdir).await;
+        let options = destination
+            .map(|destination| sync_options.with_destination(volume.clone(), destination));
+        destinations.push((volume.clone(), options));
+    }
+    let destination_count = destinations.len();
+
+    let mut actions = vec![];
+    let mut failures = 0;
+    let mut failed_destinations = vec![];
+    for (volume, options) in destinations {
+        if is_fanning_out && output == OutputFormat::Text {
+            let heading = format!("\nDestination: {}\n", volume.display());
+            print_out(&heading).await?;
+        }
+        let synchronised = match options {
+            Ok(options) => sync_until_interrupted(&options, watch, raw_bytes)
+                .await
+                .map(|report| (options, report)),
+            Err(err) => Err(err),
+        };
+        let (options, report) = match synchronised {
+            Ok(synchronised) => synchronised,
+            // An interruption stops the whole run, not just this destination, as does any failure
+            // when failing fast.
+            Err(err)
+                if !is_fanning_out
+                    || sync_options.fails_fast()
+                    || matches!(err.downcast_ref(), Some(RunFailure::Interrupted(_))) =>
+            {
+                return Err(err)
+            }
+            Err(err) => {
+                let volume_str = volume.display();
+                error!(path = %volume_str, "Failed to synchronise to {volume_str}: {err:#}");
+                failed_destinations.push(err);
+                continue;
+            }
+        };
 
-    let stats_collection = {
-        let documents_directories_ptr = documents_directories_ptr.clone();
-        spawn(async move { collect_stats(&(*documents_directories_ptr)[..], stats_rx).await })
-    };
+        if plan {
+            let plan = Plan::from_dry_run(options.destination(), &report).await;
+            print_out(&format_plan(&plan, output, raw_bytes)?).await?;
+            if let Some(plan_file) = &plan_file {
+                plan.write(plan_file).await?;
+            }
+        } else {
+            // The summary is the result of the run rather than a diagnostic, so it always goes to
+            // stdout, regardless of the log level.
+            print_out(&format_summary(
+                &options, &report, device, output, raw_bytes, colors,
+            )?)
+            .await?;
+        }
+        failures += report.failures();
+        actions.extend(report.actions);
 
-    let book_finding = {
-        let stats_tx = stats_tx.clone();
-        spawn(async move {
-            find_books(
-                &(*documents_directories_ptr)[..],
-                &extensions,
-                book_path_tx,
-                stats_tx,
+        if report.interrupted {
+            if let Some(report_path) = &report_path {
+                write_report(&actions, report_path, report_format).await?;
+            }
+            return Err(RunFailure::Interrupted(
+                "interrupted; the books that were not yet being copied were skipped".to_owned(),
             )
-            .await
-        })
-    };
+            .into());
+        }
+        if options.fails_fast() && 0 < failures {
+            if is_fanning_out {
+                info!("Skipping the remaining destinations, as a book failed");
+            }
+            break;
+        }
+    }
+    if let Some(report_path) = &report_path {
+        write_report(&actions, report_path, report_format).await?;
+    }
+    if let Some(volume) = &auto_unmount {
+        let volume_str = volume.display();
+        match eject(volume).await {
+            Ok(()) => info!("Unmounted {volume_str}, which was mounted for this run"),
+            Err(err) => error!("Failed to unmount {volume_str}: {err:#}"),
+        }
+    }
 
-    sync_books(&kobo_directory, dry_run, book_path_rx, stats_tx).await?;
-    book_finding.await??;
-    stats_collection.await??;
+    // The exit code still says what went wrong, as long as every failure was the same kind.
+    match failed_destinations.as_slice() {
+        [] => Ok(failures),
+        failed => {
+            let message = format!(
+                "failed to synchronise {} of the {destination_count} destinations",
+                failed.len()
+            );
+            let are_all = |is_kind: fn(&RunFailure) -> bool| {
+                failed
+                    .iter()
+                    .all(|err| err.downcast_ref::<RunFailure>().is_some_and(is_kind))
+            };
+            if are_all(|failure| matches!(failure, RunFailure::Inaccessible(_))) {
+                Err(RunFailure::Inaccessible(message).into())
+            } else if are_all(|failure| matches!(failure, RunFailure::Locked(_))) {
+                Err(RunFailure::Locked(message).into())
+            } else {
+                Err(anyhow!(message))
+            }
+        }
+    }
+}
 
-    Ok(())
+fn main() -> ExitCode {
+    let runtime = Runtime::new().expect("the Tokio runtime should start");
+    let result = runtime.block_on(async {
+        let printer = start_printer();
+        let result = run().await;
+        stop_printer(printer).await;
+        result
+    });
+    // A copy that timed out can leave a thread stuck reading from a hung device, which would stop
+    // the process from ever exiting if the runtime waited for it.
+    runtime.shutdown_background();
+    // Abandoned runs, such as those interrupted twice, don't get to remove their own locks.
+    remove_held_locks();
+
+    match &result {
+        Ok(0) => {}
+        Ok(failed) => {
+            eprintln!(
+                "Error: {failed} books failed to convert, copy, or verify, or could not be read"
+            )
+        }
+        Err(err) => eprintln!("Error: {err:?}"),
+    }
+    Outcome::of(&result).into()
 }
@@ -0,0 +1,163 @@
+//! The record of synchronised books kept on the destination, to skip checking them.
+
+use {
+    anyhow::Result,
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+        time::SystemTime,
+    },
+    tokio::{
+        fs::{self, File},
+        io::{self, AsyncWriteExt},
+    },
+    tracing::{debug, warn},
+};
+
+use crate::copy::{partial_path_for, CopyOptions};
+
+const MANIFEST_NAME: &str = ".sync-manifest.json";
+
+/// A record, kept at the destination's root, of the books a previous run synchronised. A source
+/// book matching its entry is known to be on the destination already, so it can be skipped without
+/// touching the device at all, which is much faster than checking a whole library over USB.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct Manifest {
+    /// Entries keyed by their paths relative to the destination directory.
+    pub(crate) books: HashMap<String, ManifestEntry>,
+}
+
+/// What a synchronised book looked like. The size and modification time are of the source it was
+/// synchronised from, as that's what later runs compare against.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct ManifestEntry {
+    size: u64,
+    modified_ns: u64,
+
+    /// The BLAKE3 digest of the book as written, if it was verified when copied.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) blake3: Option<String>,
+
+    /// The source the book was synchronised from, to tell it apart from other books with the same
+    /// name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<PathBuf>,
+}
+
+impl ManifestEntry {
+    /// Describe a source book, or yield nothing if its modification time isn't available.
+    fn of(metadata: &std::fs::Metadata) -> Option<Self> {
+        let modified = metadata.modified().ok()?;
+        let since_epoch = modified.duration_since(SystemTime::UNIX_EPOCH).ok()?;
+        Some(ManifestEntry {
+            size: metadata.len(),
+            modified_ns: u64::try_from(since_epoch.as_nanos()).ok()?,
+            blake3: None,
+            source: None,
+        })
+    }
+
+    fn matches(&self, other: &ManifestEntry) -> bool {
+        self.size == other.size && self.modified_ns == other.modified_ns
+    }
+}
+
+impl Manifest {
+    /// Load the manifest from a destination. A missing manifest is just empty, as is a corrupt one,
+    /// which is warned about; either way, books fall back to being checked on the device.
+    pub(crate) async fn load(dest_dir: &Path) -> Self {
+        let path = dest_dir.join(MANIFEST_NAME);
+        let contents = match fs::read(&path).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Manifest::default(),
+            Err(err) => {
+                warn!(path = %path.display(), "Ignoring unreadable {}: {err}", path.display());
+                return Manifest::default();
+            }
+        };
+        match serde_json::from_slice(&contents) {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                warn!(path = %path.display(), "Ignoring corrupt {}: {err}", path.display());
+                Manifest::default()
+            }
+        }
+    }
+
+    /// Save the manifest to a destination. It's written to a partial file first and renamed into
+    /// place, so that an interrupted write leaves the previous manifest intact.
+    pub(crate) async fn save(&self, dest_dir: &Path) -> Result<()> {
+        let path = dest_dir.join(MANIFEST_NAME);
+        let partial_path = partial_path_for(&path);
+
+        let json = serde_json::to_vec(self)?;
+        let mut partial = File::create(&partial_path).await?;
+        partial.write_all(&json).await?;
+        partial.sync_all().await?;
+        drop(partial);
+
+        if let Err(err) = fs::rename(&partial_path, &path).await {
+            let _ = fs::remove_file(&partial_path).await;
+            return Err(err.into());
+        }
+        debug!(path = %path.display(), books = self.books.len(), "Saved the manifest");
+        Ok(())
+    }
+
+    fn key(dest_dir: &Path, dest_path: &Path) -> Option<String> {
+        Some(dest_path.strip_prefix(dest_dir).ok()?.to_str()?.to_owned())
+    }
+
+    /// Whether a book's destination is recorded as synchronised from the source as it is now.
+    pub(crate) fn is_current(
+        &self,
+        dest_dir: &Path,
+        dest_path: &Path,
+        src: Option<&ManifestEntry>,
+    ) -> bool {
+        let recorded = Manifest::key(dest_dir, dest_path).and_then(|key| self.books.get(&key));
+        match (recorded, src) {
+            (Some(recorded), Some(src)) => recorded.matches(src),
+            _ => false,
+        }
+    }
+
+    /// The source that a book on the destination was synchronised from, if it's recorded.
+    pub(crate) fn source_of(&self, dest_dir: &Path, dest_path: &Path) -> Option<&Path> {
+        let key = Manifest::key(dest_dir, dest_path)?;
+        self.books.get(&key)?.source.as_deref()
+    }
+
+    pub(crate) fn record(&mut self, dest_dir: &Path, dest_path: &Path, entry: ManifestEntry) {
+        if let Some(key) = Manifest::key(dest_dir, dest_path) {
+            self.books.insert(key, entry);
+        }
+    }
+
+    /// Forget a book that's been deleted from the destination.
+    pub(crate) fn forget(&mut self, dest_dir: &Path, dest_path: &Path) {
+        if let Some(key) = Manifest::key(dest_dir, dest_path) {
+            self.books.remove(&key);
+        }
+    }
+}
+
+/// Describe a source book for the manifest, if it's in use.
+pub(crate) async fn manifest_entry_for(
+    src_path: &Path,
+    options: &CopyOptions,
+) -> Option<ManifestEntry> {
+    if options.manifest {
+        let entry = ManifestEntry::of(&fs::metadata(src_path).await.ok()?)?;
+        let source = fs::canonicalize(src_path)
+            .await
+            .unwrap_or_else(|_| src_path.to_path_buf());
+        Some(ManifestEntry {
+            source: Some(source),
+            ..entry
+        })
+    } else {
+        None
+    }
+}
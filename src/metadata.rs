@@ -0,0 +1,160 @@
+//! Reading the titles and authors embedded in EPUBs.
+
+use {
+    crate::kepub::{is_kepub, KEPUB_SUFFIX},
+    anyhow::{anyhow, Result},
+    std::{ffi::OsString, path::Path},
+    tokio::process::Command,
+};
+
+/// The longest, in characters, that a name made from metadata may be before its extension. This
+/// leaves room for the likes of collision suffixes and `.sync-partial` within the 255 characters
+/// that FAT allows.
+const MAX_NAME_LEN: usize = 200;
+
+/// Read a file out of a ZIP archive, which is what an EPUB is, with `unzip`.
+async fn read_archived_file(archive: &Path, name: &str) -> Result<String> {
+    let output = Command::new("unzip")
+        .arg("-p")
+        .arg(archive)
+        .arg(name)
+        .output()
+        .await
+        .map_err(|err| anyhow!("could not run unzip: {err}"))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "unzip failed to read {name} from it with {}",
+            output.status
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Replace XML's predefined entities and character references with the characters they stand for.
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find(';') else {
+            break;
+        };
+        let entity = &rest[1..end];
+        let replacement = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        match replacement {
+            Some(c) => {
+                unescaped.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+/// Each tag with this name, from just after its name onwards. This is nowhere near a full XML
+/// parser, but package documents are simple enough not to need one.
+fn tags<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let open = format!("<{name}");
+    xml.match_indices(&open)
+        .map(|(start, _)| &xml[start + open.len()..])
+        // Don't mistake the likes of `<rootfiles>` for `<rootfile>`.
+        .filter(|after| after.starts_with(|c: char| c == '>' || c == '/' || c.is_whitespace()))
+        .collect()
+}
+
+/// The value of an attribute of the tag that a string is in.
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let tag = &tag[..tag.find('>')?];
+    let start = tag.find(&format!("{name}="))? + name.len() + 1;
+    let quote = tag[start..]
+        .chars()
+        .next()
+        .filter(|c| *c == '"' || *c == '\'')?;
+    let value = &tag[start + 1..];
+    Some(&value[..value.find(quote)?])
+}
+
+/// The text of the first element with this name that has any, with its whitespace collapsed.
+fn element_text(xml: &str, name: &str) -> Option<String> {
+    let close = format!("</{name}>");
+    tags(xml, name).into_iter().find_map(|after| {
+        let tag_end = after.find('>')?;
+        if after[..tag_end].ends_with('/') {
+            return None;
+        }
+        let content = &after[tag_end + 1..];
+        let text = unescape(&content[..content.find(&close)?])
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        Some(text).filter(|text| !text.is_empty())
+    })
+}
+
+/// The title of an EPUB, and its first author if it names any, from the package document that
+/// its container points to.
+async fn epub_title_and_author(path: &Path) -> Result<(String, Option<String>)> {
+    let container = read_archived_file(path, "META-INF/container.xml").await?;
+    let package_path = tags(&container, "rootfile")
+        .into_iter()
+        .find_map(|tag| attribute(tag, "full-path"))
+        .ok_or_else(|| anyhow!("its container doesn't say where its package document is"))?;
+    let package = read_archived_file(path, &unescape(package_path)).await?;
+
+    let title = element_text(&package, "dc:title")
+        .ok_or_else(|| anyhow!("its package document has no title"))?;
+    Ok((title, element_text(&package, "dc:creator")))
+}
+
+/// Name a book after its title and author, as `Author - Title.epub`, or just `Title.epub` if it
+/// has no author. The extension is given without its leading dot, such as `kepub.epub` for a
+/// KEPUB. The name may not yet be valid on the destination.
+pub(crate) fn name_from_title_and_author(
+    title: &str,
+    author: Option<&str>,
+    extension: &str,
+) -> OsString {
+    let stem = match author {
+        Some(author) => format!("{author} - {title}"),
+        None => title.to_owned(),
+    };
+    let stem: String = stem.chars().take(MAX_NAME_LEN).collect();
+    format!("{}.{extension}", stem.trim_end()).into()
+}
+
+/// Name an EPUB after its metadata, as `Author - Title.epub`, or just `Title.epub` if it names no
+/// author. Its extension is kept as it was, including that of a KEPUB.
+pub(crate) async fn name_from_metadata(path: &Path) -> Result<OsString> {
+    let (title, author) = epub_title_and_author(path).await?;
+    let extension = if is_kepub(path) {
+        KEPUB_SUFFIX.trim_start_matches('.').to_owned()
+    } else {
+        path.extension()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    };
+    Ok(name_from_title_and_author(
+        &title,
+        author.as_deref(),
+        &extension,
+    ))
+}
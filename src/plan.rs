@@ -0,0 +1,135 @@
+//! Previewing what a run would do, and later doing exactly that.
+
+use {
+    crate::{report::Action, stats::SyncReport, synchronise::Collision},
+    anyhow::{anyhow, Result},
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+        time::SystemTime,
+    },
+    tokio::fs,
+};
+
+/// A book that a plan copies, along with what its source was like when the plan was made.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlannedCopy {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub bytes: u64,
+
+    /// Whether the copy replaces an outdated book, rather than adding a new one.
+    pub update: bool,
+
+    /// When the source was last modified, if that could be told.
+    pub modified: Option<SystemTime>,
+}
+
+/// What a run would do, worked out by dry-running it.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Plan {
+    /// The directory that the books would be synchronised into.
+    pub destination: PathBuf,
+
+    pub copies: Vec<PlannedCopy>,
+
+    /// The source paths of the books that are already on the destination, sorted.
+    pub present: Vec<PathBuf>,
+
+    pub collisions: Vec<Collision>,
+}
+
+impl Plan {
+    /// Make a plan from the report of a dry run into a destination.
+    pub async fn from_dry_run(destination: &Path, report: &SyncReport) -> Plan {
+        let mut copies = vec![];
+        let mut present = vec![];
+        for action in report.actions.iter().cloned() {
+            match action.action {
+                Action::DryRun => {
+                    let update = fs::try_exists(&action.destination).await.unwrap_or(false);
+                    let modified = fs::metadata(&action.source)
+                        .await
+                        .and_then(|metadata| metadata.modified())
+                        .ok();
+                    copies.push(PlannedCopy {
+                        source: action.source,
+                        destination: action.destination,
+                        bytes: action.bytes,
+                        update,
+                        modified,
+                    });
+                }
+                Action::SkippedExisting => present.push(action.source),
+                _ => {}
+            }
+        }
+        copies.sort_by(|a, b| a.source.cmp(&b.source));
+        present.sort();
+
+        Plan {
+            destination: destination.to_path_buf(),
+            copies,
+            present,
+            collisions: report.counters.collisions.clone(),
+        }
+    }
+
+    pub async fn load(path: &Path) -> Result<Plan> {
+        let path_str = path.display();
+        let contents = fs::read_to_string(path)
+            .await
+            .map_err(|err| anyhow!("could not read the plan at {path_str}: {err}"))?;
+        serde_json::from_str(&contents)
+            .map_err(|err| anyhow!("the plan at {path_str} is invalid: {err}"))
+    }
+
+    pub async fn write(&self, path: &Path) -> Result<()> {
+        let mut json = serde_json::to_string_pretty(self)?;
+        json.push('\n');
+        fs::write(path, json).await?;
+        Ok(())
+    }
+
+    /// Check that the plan can still be applied as it was made: into the same destination, and
+    /// from sources that haven't changed since.
+    pub async fn check_current(&self, destination: &Path) -> Result<()> {
+        if destination != self.destination {
+            return Err(anyhow!(
+                "the plan was made for {}, not {}",
+                self.destination.display(),
+                destination.display()
+            ));
+        }
+
+        let mut changed = vec![];
+        for copy in &self.copies {
+            let is_unchanged = match fs::metadata(&copy.source).await {
+                Ok(metadata) => {
+                    metadata.len() == copy.bytes && metadata.modified().ok() == copy.modified
+                }
+                Err(_) => false,
+            };
+            if !is_unchanged {
+                changed.push(copy.source.display().to_string());
+            }
+        }
+        if changed.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "{} changed since the plan was made; make a new plan",
+                changed.join(", ")
+            ))
+        }
+    }
+
+    /// Where each source that the plan copies is copied to.
+    pub(crate) fn destinations(&self) -> HashMap<PathBuf, PathBuf> {
+        self.copies
+            .iter()
+            .map(|copy| (copy.source.clone(), copy.destination.clone()))
+            .collect()
+    }
+}
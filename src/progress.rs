@@ -0,0 +1,43 @@
+//! The progress bar shown while books are found and copied.
+
+use {
+    crate::cli::OutputFormat,
+    indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle},
+    std::io::IsTerminal,
+};
+
+/// How the progress bar looks once books are being found.
+pub fn progress_style() -> ProgressStyle {
+    ProgressStyle::with_template("{bar:40} {pos}/{len} books {msg}")
+        .expect("the progress bar template should be valid")
+}
+
+/// How the progress bar looks while the documents directories are still being walked, which can
+/// take a while on network drives before many books are found.
+pub fn scanning_style() -> ProgressStyle {
+    ProgressStyle::with_template("{spinner} Scanning… {prefix} {msg}")
+        .expect("the scanning template should be valid")
+}
+
+/// Whether there's to be a progress bar, which there isn't if it was disabled or wouldn't be
+/// written to a terminal.
+pub(crate) fn shows_progress_bar(output: OutputFormat, no_progress: bool) -> bool {
+    let is_terminal = match output {
+        OutputFormat::Text => std::io::stdout().is_terminal(),
+        OutputFormat::Json => std::io::stderr().is_terminal(),
+    };
+    !no_progress && is_terminal
+}
+
+/// Make the progress bar, unless there's not to be one.
+pub(crate) fn new_progress_bar(output: OutputFormat, no_progress: bool) -> Option<ProgressBar> {
+    if !shows_progress_bar(output, no_progress) {
+        return None;
+    }
+
+    let target = match output {
+        OutputFormat::Text => ProgressDrawTarget::stdout(),
+        OutputFormat::Json => ProgressDrawTarget::stderr(),
+    };
+    Some(ProgressBar::with_draw_target(Some(0), target).with_style(progress_style()))
+}
@@ -0,0 +1,153 @@
+//! Finding and removing books that are on the destination more than once.
+
+use {
+    crate::{
+        copy::hash_file,
+        find::{has_matching_extension, is_hidden},
+        hash_cache::HashCache,
+        manifest::Manifest,
+    },
+    anyhow::{anyhow, Result},
+    async_walkdir::{Filtering, WalkDir},
+    serde::Serialize,
+    std::{
+        collections::{HashMap, HashSet},
+        ffi::OsStr,
+        path::{Path, PathBuf},
+        time::SystemTime,
+    },
+    tokio::fs,
+    tokio_stream::StreamExt,
+    tracing::{info, warn},
+};
+
+/// A book deleted for being identical to another on the destination.
+#[derive(Debug, Serialize)]
+pub struct PrunedDuplicate {
+    pub path: PathBuf,
+
+    /// The identical book that was kept instead.
+    pub kept: PathBuf,
+
+    pub bytes: u64,
+}
+
+/// What pruning the duplicates on a destination did, or would do if dry-running.
+#[derive(Debug, Default, Serialize)]
+pub struct PruneReport {
+    pub deleted: Vec<PrunedDuplicate>,
+    pub bytes_freed: u64,
+}
+
+/// A book on the destination that might have duplicates.
+struct Candidate {
+    path: PathBuf,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Candidate {
+    /// Of identical books, the shortest name is kept, as copies tend to gain suffixes such as
+    /// `(1)` or ` - Copy`, and then the oldest, as the original.
+    fn keep_order(&self) -> (usize, Option<SystemTime>, &Path) {
+        let name_len = self
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy().chars().count())
+            .unwrap_or(0);
+        (name_len, self.modified, &self.path)
+    }
+}
+
+/// Delete all but one of each group of identical books on the destination. Books are only hashed
+/// if another has the same size, as hashing a whole device over USB is slow. The device's own
+/// files, such as those under `.kobo`, are hidden and so never considered.
+pub(crate) async fn prune_duplicates(
+    dest_dir: &Path,
+    extensions_to_match: &HashSet<&OsStr>,
+    dry_run: bool,
+    manifest: &mut Manifest,
+    hash_cache: Option<&HashCache>,
+) -> Result<PruneReport> {
+    let mut entries = WalkDir::new(dest_dir).filter(|entry| async move {
+        if is_hidden(&entry.file_name()) {
+            Filtering::IgnoreDir
+        } else {
+            Filtering::Continue
+        }
+    });
+
+    let mut by_size: HashMap<u64, Vec<Candidate>> = HashMap::new();
+    while let Some(entry) = entries.next().await {
+        let entry = entry.map_err(|err| anyhow!(err))?;
+        let path = entry.path();
+        if !entry.file_type().await?.is_file()
+            || !has_matching_extension(&path, extensions_to_match)
+        {
+            continue;
+        }
+        let metadata = entry.metadata().await?;
+        // Empty files are all identical, but aren't really the same book.
+        if metadata.len() == 0 {
+            continue;
+        }
+        let candidate = Candidate {
+            path,
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        };
+        by_size.entry(candidate.len).or_default().push(candidate);
+    }
+
+    let mut report = PruneReport::default();
+    for (_, same_size) in by_size.into_iter().filter(|(_, books)| 1 < books.len()) {
+        let mut by_digest = HashMap::new();
+        for candidate in same_size {
+            match hash_file(&candidate.path, hash_cache).await {
+                Ok(digest) => by_digest
+                    .entry(digest)
+                    .or_insert_with(Vec::new)
+                    .push(candidate),
+                Err(err) => {
+                    let path_str = candidate.path.display();
+                    warn!(
+                        path = %path_str,
+                        "Failed to hash {path_str}, so it can't be checked for duplicates: {err}"
+                    );
+                }
+            }
+        }
+
+        for (_, mut identical) in by_digest.into_iter().filter(|(_, books)| 1 < books.len()) {
+            identical.sort_by(|a, b| a.keep_order().cmp(&b.keep_order()));
+            let kept = identical.remove(0);
+            let kept_str = kept.path.display();
+
+            for duplicate in identical {
+                let path_str = duplicate.path.display();
+                if dry_run {
+                    info!(
+                        path = %path_str,
+                        "Dry-running; would otherwise delete {path_str}, as it's identical to \
+                        {kept_str}"
+                    );
+                } else {
+                    fs::remove_file(&duplicate.path).await?;
+                    manifest.forget(dest_dir, &duplicate.path);
+                    info!(
+                        path = %path_str,
+                        "Deleted {path_str}, as it's identical to {kept_str}"
+                    );
+                }
+                report.bytes_freed += duplicate.len;
+                report.deleted.push(PrunedDuplicate {
+                    path: duplicate.path,
+                    kept: kept.path.clone(),
+                    bytes: duplicate.len,
+                });
+            }
+        }
+    }
+    report.deleted.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(report)
+}
@@ -0,0 +1,191 @@
+//! The per-book record of a run, written out for auditing.
+
+use {
+    crate::{copy::partial_path_for, stats::Statistic},
+    anyhow::Result,
+    clap::ValueEnum,
+    serde::{Serialize, Serializer},
+    std::{
+        fmt::{Display, Write as _},
+        path::{Path, PathBuf},
+        time::Duration,
+    },
+    tokio::{
+        fs::{self, File},
+        io::AsyncWriteExt,
+        sync::mpsc::Sender,
+    },
+    tracing::debug,
+};
+
+/// What was done with a book.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Copied,
+    Updated,
+    Pulled,
+    SkippedExisting,
+    Failed,
+    DryRun,
+
+    /// Not copied, as copying it would have gone over the most bytes to copy in a run.
+    Deferred,
+
+    /// Verified to be on the destination intact.
+    Healthy,
+
+    /// Verified to be missing from the destination.
+    Missing,
+
+    /// Verified to be on the destination, but with a different size.
+    Mismatched,
+
+    /// Verified to be on the destination with the same size, but with different contents, or
+    /// couldn't be read back.
+    Corrupted,
+}
+
+impl Action {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Action::Copied => "copied",
+            Action::Updated => "updated",
+            Action::Pulled => "pulled",
+            Action::SkippedExisting => "skipped-existing",
+            Action::Failed => "failed",
+            Action::DryRun => "dry-run",
+            Action::Deferred => "deferred",
+            Action::Healthy => "healthy",
+            Action::Missing => "missing",
+            Action::Mismatched => "mismatched",
+            Action::Corrupted => "corrupted",
+        }
+    }
+}
+
+impl Serialize for Action {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// A record of what was done with one book during a run. Sizes are in bytes.
+#[derive(Clone, Debug, Serialize)]
+pub struct BookAction {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub action: Action,
+    pub bytes: u64,
+
+    /// How long copying took, or zero if nothing was copied.
+    pub duration_ms: u64,
+
+    /// Why the book failed, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The format to write a run's report in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+    /// A JSON array of actions.
+    #[default]
+    Json,
+
+    /// A CSV file with a row per action, for spreadsheets.
+    Csv,
+}
+
+/// Record what was done with a book in the run's report.
+pub(crate) async fn record_action(
+    stats: &Sender<Statistic>,
+    source: &Path,
+    destination: &Path,
+    action: Action,
+    bytes: u64,
+    duration: Duration,
+) -> Result<()> {
+    let action = BookAction {
+        source: source.to_path_buf(),
+        destination: destination.to_path_buf(),
+        action,
+        bytes,
+        duration_ms: duration.as_millis().try_into().unwrap_or(u64::MAX),
+        error: None,
+    };
+    stats.send(Statistic::Acted(action)).await?;
+    Ok(())
+}
+
+/// Record in the run's report that a book failed, along with why, so that the failures can be
+/// listed together once the run finishes rather than only among the rest of its log.
+pub(crate) async fn record_failure(
+    stats: &Sender<Statistic>,
+    source: &Path,
+    destination: &Path,
+    error: impl Display,
+    duration: Duration,
+) -> Result<()> {
+    let action = BookAction {
+        source: source.to_path_buf(),
+        destination: destination.to_path_buf(),
+        action: Action::Failed,
+        bytes: 0,
+        duration_ms: duration.as_millis().try_into().unwrap_or(u64::MAX),
+        error: Some(error.to_string()),
+    };
+    stats.send(Statistic::Acted(action)).await?;
+    Ok(())
+}
+
+/// Quote a CSV field if it needs to be, doubling any quotes inside it.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn to_csv(actions: &[BookAction]) -> String {
+    let mut csv = String::from("source,destination,action,bytes,duration_ms,error\n");
+    for action in actions {
+        let _ = writeln!(
+            csv,
+            "{},{},{},{},{},{}",
+            csv_field(&action.source.to_string_lossy()),
+            csv_field(&action.destination.to_string_lossy()),
+            action.action.as_str(),
+            action.bytes,
+            action.duration_ms,
+            csv_field(action.error.as_deref().unwrap_or_default()),
+        );
+    }
+    csv
+}
+
+/// Write the actions of a run to a report file, replacing it only once fully written so that an
+/// earlier report is never left truncated.
+pub async fn write_report(actions: &[BookAction], path: &Path, format: ReportFormat) -> Result<()> {
+    let contents = match format {
+        ReportFormat::Json => {
+            let mut json = serde_json::to_vec_pretty(actions)?;
+            json.push(b'\n');
+            json
+        }
+        ReportFormat::Csv => to_csv(actions).into_bytes(),
+    };
+
+    let partial_path = partial_path_for(path);
+    let mut partial = File::create(&partial_path).await?;
+    partial.write_all(&contents).await?;
+    partial.sync_all().await?;
+    drop(partial);
+
+    if let Err(err) = fs::rename(&partial_path, path).await {
+        let _ = fs::remove_file(&partial_path).await;
+        return Err(err.into());
+    }
+    debug!(path = %path.display(), actions = actions.len(), "Wrote the report");
+    Ok(())
+}
@@ -0,0 +1,134 @@
+//! Copying the covers and metadata files that sit alongside some books.
+
+use {
+    crate::{
+        copy::{is_outdated, partial_path_for, preserve_modified_time, CopyOptions},
+        kepub::{is_kepub, plain_path_for_kepub},
+        stats::Statistic,
+    },
+    anyhow::Result,
+    std::{
+        ffi::OsString,
+        path::{Path, PathBuf},
+    },
+    tokio::{fs, sync::mpsc::Sender},
+    tracing::{debug, info, warn},
+};
+
+/// A book's name without its extension, counting the whole of a KEPUB's double extension.
+fn stem_of(path: &Path) -> Option<OsString> {
+    let plain = if is_kepub(path) {
+        plain_path_for_kepub(path)?
+    } else {
+        path.to_path_buf()
+    };
+    plain.file_stem().map(ToOwned::to_owned)
+}
+
+/// The files that might sit alongside a book, such as `a.jpg`, `a.opf`, and `a.pdf.jpg` for
+/// `a.pdf`, each paired with where it goes alongside the book's destination, which may be named
+/// differently.
+fn sidecars_of(src_path: &Path, dest_path: &Path) -> Vec<(PathBuf, PathBuf)> {
+    let (Some(src_stem), Some(dest_stem)) = (stem_of(src_path), stem_of(dest_path)) else {
+        return vec![];
+    };
+    let (Some(src_name), Some(dest_name)) = (src_path.file_name(), dest_path.file_name()) else {
+        return vec![];
+    };
+
+    let named = |base: &OsString, suffix: &str| {
+        let mut name = base.clone();
+        name.push(suffix);
+        name
+    };
+    [
+        (named(&src_stem, ".jpg"), named(&dest_stem, ".jpg")),
+        (named(&src_stem, ".opf"), named(&dest_stem, ".opf")),
+        (
+            named(&src_name.to_owned(), ".jpg"),
+            named(&dest_name.to_owned(), ".jpg"),
+        ),
+    ]
+    .into_iter()
+    .map(|(src, dest)| (src_path.with_file_name(src), dest_path.with_file_name(dest)))
+    .collect()
+}
+
+/// Copy a sidecar through a partial file, like a book, but without the retries and the like, as
+/// sidecars are small.
+async fn copy_sidecar(src_path: &Path, dest_path: &Path, preserve_times: bool) -> Result<()> {
+    let partial_path = partial_path_for(dest_path);
+    if let Err(err) = fs::copy(src_path, &partial_path).await {
+        let _ = fs::remove_file(&partial_path).await;
+        return Err(err.into());
+    }
+    fs::rename(&partial_path, dest_path).await?;
+    if preserve_times {
+        preserve_modified_time(src_path, dest_path).await?;
+    }
+    Ok(())
+}
+
+/// Copy the sidecars of a book that was just copied to alongside it on the destination. Like
+/// books, sidecars already on the destination are left alone, unless updating outdated ones.
+pub(crate) async fn copy_sidecars(
+    src_path: &Path,
+    dest_path: &Path,
+    &CopyOptions {
+        dry_run,
+        update,
+        preserve_times,
+        ..
+    }: &CopyOptions,
+    stats: &Sender<Statistic>,
+) -> Result<()> {
+    for (src, dest) in sidecars_of(src_path, dest_path) {
+        let is_file = fs::metadata(&src)
+            .await
+            .map(|metadata| metadata.is_file())
+            .unwrap_or(false);
+        if !is_file {
+            continue;
+        }
+
+        let (src_str, dest_str) = (src.display(), dest.display());
+        if fs::try_exists(&dest).await? && !(update && is_outdated(&src, &dest).await?) {
+            debug!(
+                path = %src_str,
+                dest = %dest_str,
+                "The sidecar {dest_str} already exists on the destination"
+            );
+            stats.send(Statistic::SidecarSkippedExisting).await?;
+            continue;
+        }
+
+        if dry_run {
+            info!(
+                path = %src_str,
+                dest = %dest_str,
+                "Dry-running; would otherwise copy the sidecar {src_str} to {dest_str}"
+            );
+            stats.send(Statistic::SidecarCopied).await?;
+            continue;
+        }
+        match copy_sidecar(&src, &dest, preserve_times).await {
+            Ok(()) => {
+                info!(
+                    path = %src_str,
+                    dest = %dest_str,
+                    "Copied the sidecar {src_str} to {dest_str}"
+                );
+                stats.send(Statistic::SidecarCopied).await?;
+            }
+            Err(err) => {
+                warn!(
+                    path = %src_str,
+                    dest = %dest_str,
+                    "Failed to copy the sidecar {src_str} to {dest_str}: {err:#}"
+                );
+                stats.send(Statistic::SidecarFailed).await?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,561 @@
+//! Counting what happened during a run.
+
+use {
+    crate::{
+        audit::AuditLog,
+        events::SyncEvent,
+        history::{History, SinceLastRun},
+        report::{Action, BookAction},
+        synchronise::Collision,
+        Run,
+    },
+    anyhow::Result,
+    serde::Serialize,
+    std::{
+        cmp::Reverse,
+        collections::{BinaryHeap, HashMap},
+        path::{Path, PathBuf},
+        time::{Duration, Instant},
+    },
+    tokio::sync::mpsc::{Receiver, Sender},
+};
+
+/// Something that happened during a run, to be counted in its summary. Sizes are in bytes.
+#[derive(Debug)]
+pub(crate) enum Statistic {
+    FoundSrcDocument(PathBuf, u64),
+    NotCopiedBecauseAlreadyExistedAtDest,
+
+    /// A book was compared with the one of the same name on the destination and found to be the
+    /// same, so it's skipped.
+    ComparedIdentical,
+
+    /// A book was compared with the one of the same name on the destination and found to differ,
+    /// so it's copied over it.
+    ComparedDifferent,
+
+    Copied(u64),
+
+    /// A book would have been copied, but the run is dry.
+    WouldCopy(u64),
+
+    /// A book would have been updated, but the run is dry.
+    WouldUpdate(u64),
+
+    /// A book would have been skipped as it's already on the destination, had the run not been
+    /// dry.
+    WouldSkipExisting,
+
+    CopyFailed,
+
+    /// A book failed to convert to an EPUB, so wasn't copied.
+    ConversionFailed,
+
+    Deleted,
+    Updated(u64),
+    VerificationFailed,
+    Excluded,
+    ExcludedBySize,
+    ExcludedAsTooOld,
+
+    /// A book wasn't looked for on the destination, as it's unchanged since the last run.
+    UnchangedSinceLastSync,
+
+    SkippedInvalid,
+    Duplicate,
+
+    /// A book wasn't synchronised, as another format of it was preferred.
+    Superseded,
+
+    /// How many files that weren't books were walked past.
+    ScannedNonMatching(usize),
+
+    /// How many directories were walked.
+    TraversedDirectories(usize),
+
+    /// A directory wasn't walked, as it was deeper than the maximum depth.
+    PrunedByDepth,
+
+    /// A file or directory couldn't be read while walking the documents directories.
+    WalkFailed,
+
+    /// Every book in the documents directories has been found, though more may be when watching.
+    FindingFinished,
+
+    /// Every copy has finished, whether it succeeded or not.
+    CopyingFinished,
+
+    NotCopiedBecauseItWouldNotFit,
+
+    /// A book was left for a later run, as copying it would go over the most bytes to copy.
+    Deferred,
+
+    OutOfSpace,
+    TimedOut,
+    Declined,
+    Pulled(u64),
+    Collided(Collision),
+
+    /// A copy was abandoned part way through, as another book failed when failing fast.
+    Cancelled,
+
+    SidecarCopied,
+    SidecarSkippedExisting,
+    SidecarFailed,
+
+    /// A book was verified to be on the destination intact.
+    VerifiedHealthy,
+
+    /// A book was verified to be missing from the destination.
+    VerifiedMissing,
+
+    /// A book was verified to be on the destination, but with a different size.
+    VerifiedMismatched,
+
+    /// A book was verified to be on the destination with the same size, but with different
+    /// contents, or couldn't be read back.
+    VerifiedCorrupted,
+
+    /// A book started being copied. Only its event matters, so it isn't counted.
+    CopyStarted {
+        source: PathBuf,
+        destination: PathBuf,
+    },
+
+    /// How much of a book has been copied so far, out of its total size, which is only sent when
+    /// events are wanted.
+    CopyProgressed {
+        source: PathBuf,
+        destination: PathBuf,
+        bytes: u64,
+        total: u64,
+    },
+
+    /// What was done with a book, for the report.
+    Acted(BookAction),
+}
+
+impl Statistic {
+    /// The statistic for a book skipped as it's already on the destination, which dry runs count
+    /// apart from real ones.
+    pub(crate) fn skipped_existing(dry_run: bool) -> Statistic {
+        if dry_run {
+            Statistic::WouldSkipExisting
+        } else {
+            Statistic::NotCopiedBecauseAlreadyExistedAtDest
+        }
+    }
+}
+
+/// The counters accumulated from the statistics of a run.
+#[derive(Debug, Default, Serialize)]
+pub struct Counters {
+    pub found: usize,
+    pub skipped_existing: usize,
+    pub compared_identical: usize,
+    pub compared_different: usize,
+    pub copied: usize,
+    pub would_copy: usize,
+    pub would_update: usize,
+    pub would_skip_existing: usize,
+    pub updated: usize,
+    pub failed: usize,
+    pub conversions_failed: usize,
+    pub verification_failed: usize,
+    pub deleted: usize,
+    pub excluded: usize,
+    pub excluded_by_size: usize,
+    pub excluded_as_too_old: usize,
+    pub unchanged_since_last_sync: usize,
+    pub skipped_invalid: usize,
+    pub duplicates: usize,
+    pub superseded: usize,
+    pub scanned_non_matching: usize,
+    pub directories_traversed: usize,
+    pub pruned_by_depth: usize,
+    pub walk_failed: usize,
+    pub wont_fit: usize,
+    pub deferred: usize,
+    pub out_of_space: usize,
+    pub timed_out: usize,
+    pub declined: usize,
+    pub pulled: usize,
+    pub cancelled: usize,
+    pub sidecars_copied: usize,
+    pub sidecars_skipped_existing: usize,
+    pub sidecars_failed: usize,
+    pub healthy: usize,
+    pub missing: usize,
+    pub mismatched: usize,
+    pub corrupted: usize,
+    pub bytes_found: u64,
+    pub bytes_copied: u64,
+    pub bytes_would_copy: u64,
+    pub bytes_pulled: u64,
+
+    /// The total size of the books found that were already on the destination, going by their
+    /// sizes in the documents directories.
+    pub bytes_found_on_destination: u64,
+
+    /// The largest books found, largest first.
+    pub largest_found: Vec<LargeBook>,
+
+    pub collisions: Vec<Collision>,
+
+    /// Whether the device was ejected, if that was asked for.
+    pub ejected: Option<bool>,
+
+    /// What changed since the last run to the same destination, if it's remembered.
+    pub since_last_run: Option<SinceLastRun>,
+}
+
+/// One of the largest books found in the documents directories, and its size in bytes.
+#[derive(Clone, Debug, Serialize)]
+pub struct LargeBook {
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+/// What happened during a run.
+#[derive(Debug, Default, Serialize)]
+pub struct SyncReport {
+    #[serde(flatten)]
+    pub counters: Counters,
+
+    /// How quickly books were copied, or zero if none were.
+    pub bytes_per_second: u64,
+
+    /// What was done with each book considered, in the order it was done.
+    #[serde(skip)]
+    pub actions: Vec<BookAction>,
+
+    /// Whether the run was interrupted before it could finish.
+    pub interrupted: bool,
+
+    pub timings: Timings,
+}
+
+/// How long the phases of a run took, in milliseconds. Copies start while books are still being
+/// found, so the copying phase is only the time spent waiting for them after the last book was
+/// found; a run bound by copying spends longer in it than one bound by finding books.
+#[derive(Debug, Default, Serialize)]
+pub struct Timings {
+    /// From the start of the run until every book was found.
+    pub finding_ms: u64,
+
+    /// From every book being found until every copy finished.
+    pub copying_ms: u64,
+
+    /// The whole run, including anything done after copying, such as deleting stale books.
+    pub total_ms: u64,
+
+    /// How long each book copied or updated took to copy on average, or zero if none were.
+    pub average_copy_ms: u64,
+}
+
+impl SyncReport {
+    /// The number of books that failed to convert, copy, or verify, and of files and directories that
+    /// couldn't be read while finding books, which might have held some.
+    pub fn failures(&self) -> usize {
+        let Counters {
+            failed,
+            conversions_failed,
+            verification_failed,
+            out_of_space,
+            timed_out,
+            walk_failed,
+            ..
+        } = self.counters;
+        failed + conversions_failed + verification_failed + out_of_space + timed_out + walk_failed
+    }
+}
+
+/// How many of the largest books found are kept for the summary.
+const LARGEST_FOUND: usize = 5;
+
+/// How often the progress of finding books is passed along as an event.
+const SCAN_EVENT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Collect the statistics of a run into its report, passing along the events they amount to if
+/// anything is listening for them and appending them to the audit log if there is one. The books
+/// found are compared with those of the last run in the history, if there is one. When failing
+/// fast, the first failure counted stops the run.
+pub(crate) async fn collect_stats(
+    dry_run: bool,
+    fails_fast: bool,
+    mut stats: Receiver<Statistic>,
+    events: Option<Sender<SyncEvent>>,
+    mut audit: Option<AuditLog>,
+    history: Option<History>,
+    run: Run,
+) -> Result<SyncReport> {
+    let mut counters = Counters::default();
+    let mut actions = vec![];
+    let started = Instant::now();
+    let mut last_copied = None;
+    let mut finding = true;
+    let mut last_scan_event = started;
+    let mut found_all = None;
+    let mut copied_all = None;
+    let mut found_books = HashMap::new();
+
+    // The smallest of the largest books found so far is on top, ready to be displaced.
+    let mut largest_found = BinaryHeap::with_capacity(LARGEST_FOUND + 1);
+    let mut found_every_book = false;
+
+    while let Some(stat) = stats.recv().await {
+        use Statistic::*;
+
+        if let Some(events) = &events {
+            if let Some(event) = SyncEvent::of(&stat) {
+                // Whatever was listening may have stopped, but that's no reason to stop the run.
+                let _ = events.send(event).await;
+            }
+        }
+
+        let is_scan_progress = matches!(
+            stat,
+            FoundSrcDocument(..)
+                | ScannedNonMatching(_)
+                | Excluded
+                | ExcludedBySize
+                | ExcludedAsTooOld
+                | UnchangedSinceLastSync
+                | SkippedInvalid
+        );
+        let is_failure = matches!(
+            stat,
+            CopyFailed | ConversionFailed | VerificationFailed | OutOfSpace | TimedOut | WalkFailed
+        );
+        if fails_fast && is_failure && !run.has_failed_fast() {
+            run.fail_fast();
+        }
+
+        match stat {
+            FoundSrcDocument(path, len) => {
+                counters.found += 1;
+                counters.bytes_found += len;
+                largest_found.push(Reverse((len, path.clone())));
+                if LARGEST_FOUND < largest_found.len() {
+                    largest_found.pop();
+                }
+                found_books.insert(path, len);
+                if let Some(bar) = run.progress_bar() {
+                    bar.inc_length(1);
+                }
+            }
+            NotCopiedBecauseAlreadyExistedAtDest => {
+                counters.skipped_existing += 1;
+            }
+            ComparedIdentical => {
+                counters.compared_identical += 1;
+            }
+            ComparedDifferent => {
+                counters.compared_different += 1;
+            }
+            Copied(written) => {
+                counters.copied += 1;
+                counters.bytes_copied += written;
+                last_copied = Some(Instant::now());
+            }
+            WouldCopy(len) => {
+                counters.would_copy += 1;
+                counters.bytes_would_copy += len;
+            }
+            WouldUpdate(len) => {
+                counters.would_update += 1;
+                counters.bytes_would_copy += len;
+            }
+            WouldSkipExisting => {
+                counters.would_skip_existing += 1;
+            }
+            CopyFailed => {
+                counters.failed += 1;
+            }
+            ConversionFailed => {
+                counters.conversions_failed += 1;
+            }
+            Deleted => {
+                counters.deleted += 1;
+            }
+            Updated(written) => {
+                counters.updated += 1;
+                counters.bytes_copied += written;
+                last_copied = Some(Instant::now());
+            }
+            VerificationFailed => {
+                counters.verification_failed += 1;
+            }
+            Excluded => {
+                counters.excluded += 1;
+            }
+            ExcludedBySize => {
+                counters.excluded_by_size += 1;
+            }
+            ExcludedAsTooOld => {
+                counters.excluded_as_too_old += 1;
+            }
+            UnchangedSinceLastSync => {
+                counters.unchanged_since_last_sync += 1;
+            }
+            SkippedInvalid => {
+                counters.skipped_invalid += 1;
+            }
+            Duplicate => {
+                counters.duplicates += 1;
+            }
+            Superseded => {
+                counters.superseded += 1;
+            }
+            ScannedNonMatching(count) => {
+                counters.scanned_non_matching += count;
+            }
+            TraversedDirectories(count) => {
+                counters.directories_traversed += count;
+            }
+            PrunedByDepth => {
+                counters.pruned_by_depth += 1;
+            }
+            WalkFailed => {
+                counters.walk_failed += 1;
+            }
+            NotCopiedBecauseItWouldNotFit => {
+                counters.wont_fit += 1;
+            }
+            Deferred => {
+                counters.deferred += 1;
+            }
+            OutOfSpace => {
+                counters.out_of_space += 1;
+            }
+            TimedOut => {
+                counters.timed_out += 1;
+            }
+            Declined => {
+                counters.declined += 1;
+            }
+            Pulled(written) => {
+                counters.pulled += 1;
+                counters.bytes_pulled += written;
+            }
+            Collided(collision) => {
+                counters.collisions.push(collision);
+            }
+            Cancelled => {
+                counters.cancelled += 1;
+            }
+            SidecarCopied => {
+                counters.sidecars_copied += 1;
+            }
+            SidecarSkippedExisting => {
+                counters.sidecars_skipped_existing += 1;
+            }
+            SidecarFailed => {
+                counters.sidecars_failed += 1;
+            }
+            VerifiedHealthy => {
+                counters.healthy += 1;
+            }
+            VerifiedMissing => {
+                counters.missing += 1;
+            }
+            VerifiedMismatched => {
+                counters.mismatched += 1;
+            }
+            VerifiedCorrupted => {
+                counters.corrupted += 1;
+            }
+            Acted(action) => {
+                if action.action == Action::SkippedExisting {
+                    counters.bytes_found_on_destination +=
+                        found_books.get(&action.source).copied().unwrap_or(0);
+                }
+                if let Some(audit) = &mut audit {
+                    audit.record(&action).await;
+                }
+                actions.push(action);
+            }
+            CopyStarted { .. } | CopyProgressed { .. } => {}
+            CopyingFinished => {
+                copied_all = Some(Instant::now());
+            }
+            FindingFinished => {
+                found_all.get_or_insert_with(Instant::now);
+                finding = false;
+                found_every_book = !run.is_interrupted();
+                if let Some(events) = &events {
+                    let _ = events.send(SyncEvent::scanned(&counters, true)).await;
+                }
+            }
+        }
+
+        // The scan is reported now and then rather than for every file, as events for every file
+        // would swamp those for books.
+        if finding && is_scan_progress && last_scan_event.elapsed() >= SCAN_EVENT_INTERVAL {
+            if let Some(events) = &events {
+                let _ = events.send(SyncEvent::scanned(&counters, false)).await;
+            }
+            last_scan_event = Instant::now();
+        }
+    }
+
+    if let Some(bar) = run.progress_bar() {
+        bar.finish_and_clear();
+    }
+    counters.largest_found = largest_found
+        .into_sorted_vec()
+        .into_iter()
+        .map(|Reverse((bytes, path))| LargeBook { path, bytes })
+        .collect();
+    if let Some(history) = history {
+        let copied = actions
+            .iter()
+            .filter(|action| matches!(action.action, Action::Copied | Action::Updated))
+            .map(|action| action.source.as_path())
+            .collect::<Vec<&Path>>();
+        counters.since_last_run = history
+            .finish(found_books.keys(), &copied, found_every_book)
+            .await;
+    }
+    if let Some(audit) = audit {
+        audit.finish(&counters).await;
+    }
+
+    // Copies run concurrently, so throughput is measured over the wall-clock time until the last
+    // one finished rather than summed per copy.
+    // Nothing is actually copied when dry-running, so there is no throughput to speak of.
+    let bytes_per_second = match last_copied {
+        Some(last_copied) if !dry_run => {
+            let secs = last_copied.duration_since(started).as_secs_f64();
+            (counters.bytes_copied as f64 / secs.max(f64::EPSILON)) as u64
+        }
+        _ => 0,
+    };
+
+    let finished = Instant::now();
+    let found_all = found_all.unwrap_or(finished);
+    let copied_all = copied_all.unwrap_or(finished).max(found_all);
+    let copy_durations = actions
+        .iter()
+        .filter(|action| matches!(action.action, Action::Copied | Action::Updated))
+        .map(|action| action.duration_ms)
+        .collect::<Vec<_>>();
+    let timings = Timings {
+        finding_ms: found_all.duration_since(started).as_millis() as u64,
+        copying_ms: copied_all.duration_since(found_all).as_millis() as u64,
+        total_ms: finished.duration_since(started).as_millis() as u64,
+        average_copy_ms: copy_durations
+            .iter()
+            .sum::<u64>()
+            .checked_div(copy_durations.len() as u64)
+            .unwrap_or(0),
+    };
+
+    Ok(SyncReport {
+        counters,
+        bytes_per_second,
+        actions,
+        interrupted: false,
+        timings,
+    })
+}
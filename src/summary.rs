@@ -0,0 +1,767 @@
+//! Summarising what a run did, or would do, for people to read or as JSON for scripts.
+
+use {
+    crate::{
+        cli::OutputFormat, doctor::Diagnosis, Action, BookAction, Collision, Counters, Device,
+        LargeBook, Listing, Plan, PlannedCopy, PruneReport, SinceLastRun, SyncOptions, SyncReport,
+        Timings,
+    },
+    anstyle::AnsiColor,
+    anyhow::Result,
+    serde::Serialize,
+    std::path::{Path, PathBuf},
+};
+
+/// The machine-readable summary of a run, printed with `--output json`. Fields should only ever be
+/// added to this, so that scripts parsing it keep working.
+#[derive(Debug, Serialize)]
+struct Summary<'a> {
+    #[serde(flatten)]
+    report: &'a SyncReport,
+    documents_directories: &'a [PathBuf],
+    calibre_library: Option<&'a Path>,
+    destination_directory: &'a Path,
+    dry_run: bool,
+
+    /// The books that failed, each with why.
+    failures: Vec<&'a BookAction>,
+}
+
+/// The machine-readable result of verifying the device, printed with `--output json`.
+#[derive(Debug, Serialize)]
+struct VerificationSummary<'a> {
+    healthy: usize,
+    missing: usize,
+    mismatched: usize,
+    corrupted: usize,
+
+    /// Each book checked, with what was found.
+    books: &'a [BookAction],
+}
+
+/// Format a size for people to read, in binary units like "1.4 GiB", or just as a number of bytes
+/// with `raw`.
+pub fn format_bytes(bytes: u64, raw: bool) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    if raw || bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = UNITS[0];
+    for next_unit in &UNITS[1..] {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next_unit;
+    }
+    format!("{size:.1} {unit}")
+}
+
+/// Format a duration for people to read, like "850 ms" or "12.3 s".
+fn format_millis(ms: u64) -> String {
+    if ms < 1000 {
+        format!("{ms} ms")
+    } else {
+        format!("{:.1} s", ms as f64 / 1000.0)
+    }
+}
+
+/// Format a count with commas between the thousands, like "4,812".
+pub fn format_count(count: usize) -> String {
+    let digits = count.to_string();
+    let mut formatted = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        if i != 0 && (digits.len() - i).is_multiple_of(3) {
+            formatted.push(',');
+        }
+        formatted.push(digit);
+    }
+    formatted
+}
+
+/// A count of failures for the summary, in red if there were any and colouring.
+fn failure_count(count: usize, colors: bool) -> String {
+    if colors && 0 < count {
+        let style = AnsiColor::Red.on_default().bold();
+        format!("{}{count}{}", style.render(), style.render_reset())
+    } else {
+        count.to_string()
+    }
+}
+
+/// The summary of a run for people to read.
+fn text_summary(
+    options: &SyncOptions,
+    report: &SyncReport,
+    device: Device,
+    raw_bytes: bool,
+    colors: bool,
+) -> String {
+    let (src_dirs, calibre_library) = (options.sources(), options.calibre_library());
+    let dry_run = options.is_dry_run();
+    let device_name = device.name();
+    let SyncReport {
+        counters,
+        bytes_per_second,
+        timings:
+            Timings {
+                finding_ms,
+                copying_ms,
+                total_ms,
+                average_copy_ms,
+            },
+        ..
+    } = report;
+    let Counters {
+        found,
+        skipped_existing,
+        conversions_failed,
+        compared_identical,
+        compared_different,
+        copied,
+        would_copy,
+        would_update,
+        would_skip_existing,
+        updated,
+        failed,
+        verification_failed,
+        deleted,
+        excluded,
+        excluded_by_size,
+        excluded_as_too_old,
+        unchanged_since_last_sync,
+        skipped_invalid,
+        duplicates,
+        superseded,
+        scanned_non_matching,
+        directories_traversed,
+        pruned_by_depth,
+        walk_failed,
+        wont_fit,
+        deferred,
+        out_of_space,
+        timed_out,
+        declined,
+        pulled,
+        cancelled,
+        sidecars_copied,
+        sidecars_skipped_existing,
+        sidecars_failed,
+        bytes_found,
+        bytes_copied,
+        bytes_would_copy,
+        bytes_pulled,
+        bytes_found_on_destination,
+        largest_found,
+        collisions,
+        ejected,
+        since_last_run,
+        // Only verifying counts these, and it has a summary of its own.
+        healthy: _,
+        missing: _,
+        mismatched: _,
+        corrupted: _,
+    } = counters;
+
+    let bytes_found = format_bytes(*bytes_found, raw_bytes);
+    let bytes_copied = format_bytes(*bytes_copied, raw_bytes);
+    let bytes_would_copy = format_bytes(*bytes_would_copy, raw_bytes);
+    let bytes_pulled = format_bytes(*bytes_pulled, raw_bytes);
+    let bytes_found_on_destination = format_bytes(*bytes_found_on_destination, raw_bytes);
+    let throughput = format_bytes(*bytes_per_second, raw_bytes);
+    let finding = format_millis(*finding_ms);
+    let copying = format_millis(*copying_ms);
+    let total = format_millis(*total_ms);
+    let average_copy = format_millis(*average_copy_ms);
+    let walk_failed = failure_count(*walk_failed, colors);
+    let sidecars_failed = failure_count(*sidecars_failed, colors);
+    let failed = failure_count(*failed, colors);
+    let conversions_failed = failure_count(*conversions_failed, colors);
+    let out_of_space = failure_count(*out_of_space, colors);
+    let timed_out = failure_count(*timed_out, colors);
+    let verification_failed = failure_count(*verification_failed, colors);
+
+    let len = src_dirs.len();
+    let src_str: String = src_dirs
+        .iter()
+        .zip(1..)
+        .fold(String::new(), |mut s, (dir, i)| {
+            s.push_str(&dir.display().to_string());
+            if i < len {
+                s.push_str(" and ");
+            }
+            s
+        });
+
+    let found_in = match calibre_library {
+        None => format!("documents directory at {src_str}"),
+        Some(library) if src_dirs.is_empty() => {
+            format!("the Calibre library at {}", library.display())
+        }
+        Some(library) => format!(
+            "documents directory at {src_str} and the Calibre library at {}",
+            library.display()
+        ),
+    };
+
+    let copied_new = match since_last_run {
+        Some(SinceLastRun {
+            last_run,
+            copied_new,
+            ..
+        }) => {
+            let date = last_run.split('T').next().unwrap_or(last_run);
+            format!(" ({copied_new} new since the last sync on {date})")
+        }
+        None => String::new(),
+    };
+    let changes = match since_last_run {
+        Some(SinceLastRun {
+            new_sources,
+            disappeared_sources,
+            ..
+        }) => format!(
+            "Documents found that were not found by the last sync: {new_sources}\n\
+            Documents found by the last sync that are no longer found: {disappeared_sources}\n"
+        ),
+        None => String::new(),
+    };
+
+    let largest =
+        largest_found
+            .iter()
+            .fold(String::new(), |mut largest, LargeBook { path, bytes }| {
+                let bytes = format_bytes(*bytes, raw_bytes);
+                largest.push_str(&format!("  {} ({bytes})\n", path.display()));
+                largest
+            });
+    let largest = if largest.is_empty() {
+        largest
+    } else {
+        format!("Largest documents found:\n{largest}")
+    };
+
+    // Nothing is copied when dry-running, so the summary says what would have been instead.
+    let copies = if dry_run {
+        format!(
+            "Books that would not be copied because they already exist on the destination: \
+            {would_skip_existing}\n\
+            Books that would be copied: {would_copy}\n\
+            Books that would be updated: {would_update}\n\
+            Total size of the books that would be copied or updated: {bytes_would_copy}\n"
+        )
+    } else {
+        format!(
+            "Books not copied because they already exist on the destination {device_name}: \
+            {skipped_existing}\n\
+            Books copied: {copied}{copied_new}\n\
+            Total size of the books copied or updated: {bytes_copied}\n"
+        )
+    };
+    let has_copied = !dry_run && 0 < copied + updated;
+
+    // Lines for features that weren't used are left out unless they counted something anyway, as
+    // are those for failures that didn't happen.
+    let lines = [
+        (true, format!("Found documents in {found_in}: {found}\n")),
+        (
+            true,
+            format!("Total size of the found documents: {bytes_found}\n"),
+        ),
+        (
+            true,
+            format!(
+                "Total size of the found documents already on the destination: \
+                {bytes_found_on_destination}\n"
+            ),
+        ),
+        (true, largest),
+        (
+            true,
+            format!("Directories searched: {directories_traversed}\n"),
+        ),
+        (
+            options.limits_depth() || 0 < *pruned_by_depth,
+            format!(
+                "Directories not searched for being deeper than --max-depth: {pruned_by_depth}\n"
+            ),
+        ),
+        (
+            true,
+            format!(
+                "Other files skipped for not having a matching extension: {scanned_non_matching}\n"
+            ),
+        ),
+        (
+            0 < counters.walk_failed,
+            format!(
+                "Files and directories that could not be read while searching: {walk_failed}\n"
+            ),
+        ),
+        (
+            0 < *excluded,
+            format!(
+                "Documents excluded by an include or exclude pattern or a .syncignore file: \
+                {excluded}\n"
+            ),
+        ),
+        (
+            options.limits_size() || 0 < *excluded_by_size,
+            format!(
+                "Documents excluded for being larger than the maximum size: {excluded_by_size}\n"
+            ),
+        ),
+        (
+            options.has_cutoff() || 0 < *excluded_as_too_old,
+            format!(
+                "Documents excluded for being modified before the cutoff: {excluded_as_too_old}\n"
+            ),
+        ),
+        (
+            options.is_incremental() || 0 < *unchanged_since_last_sync,
+            format!(
+                "Documents skipped for being unchanged since the last run: \
+                {unchanged_since_last_sync}\n"
+            ),
+        ),
+        (
+            0 < *skipped_invalid,
+            format!("Documents skipped for being empty or corrupt: {skipped_invalid}\n"),
+        ),
+        (
+            options.dedupes_content() || 0 < *duplicates,
+            format!(
+                "Documents skipped as duplicates of others with the same contents: {duplicates}\n"
+            ),
+        ),
+        (
+            options.prefers_formats() || 0 < *superseded,
+            format!(
+                "Documents skipped as superseded by a preferred format of the same title: \
+                {superseded}\n"
+            ),
+        ),
+        (true, changes),
+        (true, copies),
+        (
+            has_copied,
+            format!("Average copy throughput: {throughput}/s\n"),
+        ),
+        (
+            !dry_run && 0 < *updated,
+            format!("Books updated because their source changed or they differed: {updated}\n"),
+        ),
+        (
+            0 < *compared_identical,
+            format!(
+                "Books on the destination compared and found identical, so skipped: \
+                {compared_identical}\n"
+            ),
+        ),
+        (
+            0 < *compared_different,
+            format!(
+                "Books on the destination compared and found to differ, so updated: \
+                {compared_different}\n"
+            ),
+        ),
+        (true, format!("Time spent finding books: {finding}\n")),
+        (
+            has_copied,
+            format!("Time spent waiting for copies after finding books: {copying}\n"),
+        ),
+        (
+            has_copied,
+            format!("Average time to copy a book: {average_copy}\n"),
+        ),
+        (true, format!("Total time taken: {total}\n")),
+        (
+            options.includes_sidecars() || 0 < *sidecars_copied,
+            format!("Covers and metadata files copied alongside books: {sidecars_copied}\n"),
+        ),
+        (
+            options.includes_sidecars() || 0 < *sidecars_skipped_existing,
+            format!(
+                "Covers and metadata files not copied because they already exist on the \
+                destination: {sidecars_skipped_existing}\n"
+            ),
+        ),
+        (
+            0 < counters.sidecars_failed,
+            format!("Covers and metadata files failed to copy: {sidecars_failed}\n"),
+        ),
+        (
+            0 < *wont_fit,
+            format!("Books not copied because they would not fit on the destination: {wont_fit}\n"),
+        ),
+        (
+            options.limits_total_bytes() || 0 < *deferred,
+            format!("Books deferred to a later run by --max-total-bytes: {deferred}\n"),
+        ),
+        (
+            0 < *declined,
+            format!("Books not copied because they were declined at the prompt: {declined}\n"),
+        ),
+        (true, format!("Books failed to copy: {failed}\n")),
+        (
+            options.converts() || 0 < counters.conversions_failed,
+            format!("Books failed to convert with ebook-convert: {conversions_failed}\n"),
+        ),
+        (
+            0 < counters.out_of_space,
+            format!(
+                "Books failed to copy because the destination ran out of space: {out_of_space}\n"
+            ),
+        ),
+        (
+            0 < counters.timed_out,
+            format!(
+                "Books failed to copy because they timed out, which suggests a bad connection: \
+                {timed_out}\n"
+            ),
+        ),
+        (
+            0 < counters.verification_failed,
+            format!(
+                "Books deleted because they failed verification after copying: \
+                {verification_failed}\n"
+            ),
+        ),
+        (
+            0 < *cancelled,
+            format!("Books whose copies were abandoned as another book failed: {cancelled}\n"),
+        ),
+        (
+            options.deletes() || 0 < *deleted,
+            format!(
+                "Books deleted because they no longer exist in the documents directories: \
+                {deleted}\n"
+            ),
+        ),
+        (
+            options.pulls() || 0 < *pulled,
+            format!(
+                "Books pulled from the destination because they only existed there: {pulled}\n"
+            ),
+        ),
+        (
+            options.pulls() || 0 < *pulled,
+            format!("Total size of the books pulled: {bytes_pulled}\n"),
+        ),
+        (
+            !collisions.is_empty(),
+            format!(
+                "Books synchronised to the same destination as another: {}\n",
+                collisions.len()
+            ),
+        ),
+    ];
+    let summary = lines
+        .into_iter()
+        .filter(|(shown, _)| *shown)
+        .fold("\n".to_owned(), |summary, (_, line)| summary + &line);
+    let summary = collisions.iter().fold(summary, |mut summary, collision| {
+        let Collision {
+            destination,
+            first,
+            second,
+        } = collision;
+        summary.push_str(&format!(
+            "  {}: {} and {}\n",
+            destination.display(),
+            first.display(),
+            second.display()
+        ));
+        summary
+    });
+    let failed_books = report
+        .actions
+        .iter()
+        .filter(|action| action.action == Action::Failed)
+        .collect::<Vec<_>>();
+    let summary = if failed_books.is_empty() {
+        summary
+    } else {
+        let heading = match failed_books.len() {
+            1 => "1 book failed to copy:\n".to_owned(),
+            failed => format!("{failed} books failed to copy:\n"),
+        };
+        failed_books
+            .into_iter()
+            .fold(summary + &heading, |mut summary, failed| {
+                summary.push_str(&format!(
+                    "  {} to {}\n",
+                    failed.source.display(),
+                    failed.destination.display()
+                ));
+                if let Some(error) = &failed.error {
+                    summary.push_str(&format!("    {error}\n"));
+                }
+                summary
+            })
+    };
+    match ejected {
+        Some(true) => summary + "The device was ejected, so it is safe to unplug.\n",
+        Some(false) => summary + "The device failed to eject; unmount it before unplugging.\n",
+        None => summary,
+    }
+}
+
+/// The summary of a run, in the requested format.
+pub fn format_summary(
+    options: &SyncOptions,
+    report: &SyncReport,
+    device: Device,
+    output: OutputFormat,
+    raw_bytes: bool,
+    colors: bool,
+) -> Result<String> {
+    match output {
+        OutputFormat::Text => Ok(text_summary(options, report, device, raw_bytes, colors)),
+        OutputFormat::Json => {
+            let summary = Summary {
+                report,
+                documents_directories: options.sources(),
+                calibre_library: options.calibre_library(),
+                destination_directory: options.destination(),
+                dry_run: options.is_dry_run(),
+                failures: report
+                    .actions
+                    .iter()
+                    .filter(|action| action.action == Action::Failed)
+                    .collect(),
+            };
+            let mut json = serde_json::to_string(&summary)?;
+            json.push('\n');
+            Ok(json)
+        }
+    }
+}
+
+/// A preview of what a run would do, grouped by what would be done with each book, in the requested
+/// format.
+pub fn format_plan(plan: &Plan, output: OutputFormat, raw_bytes: bool) -> Result<String> {
+    let printed = match output {
+        OutputFormat::Json => {
+            let mut json = serde_json::to_string(plan)?;
+            json.push('\n');
+            json
+        }
+        OutputFormat::Text => {
+            let (updates, new): (Vec<&PlannedCopy>, Vec<&PlannedCopy>) =
+                plan.copies.iter().partition(|copy| copy.update);
+
+            let mut printed = String::new();
+            for (heading, copies) in [
+                ("New books to copy", new),
+                ("Outdated books to update", updates),
+            ] {
+                let bytes = format_bytes(copies.iter().map(|copy| copy.bytes).sum(), raw_bytes);
+                printed.push_str(&format!("{heading}: {} ({bytes})\n", copies.len()));
+                for copy in copies {
+                    printed.push_str(&format!(
+                        "  {} to {}\n",
+                        copy.source.display(),
+                        copy.destination.display()
+                    ));
+                }
+            }
+            printed.push_str(&format!(
+                "Books already on the destination: {}\n",
+                plan.present.len()
+            ));
+            for path in &plan.present {
+                printed.push_str(&format!("  {}\n", path.display()));
+            }
+            printed.push_str(&format!("Name collisions: {}\n", plan.collisions.len()));
+            for Collision {
+                destination,
+                first,
+                second,
+            } in &plan.collisions
+            {
+                printed.push_str(&format!(
+                    "  {}: {} and {}\n",
+                    destination.display(),
+                    first.display(),
+                    second.display()
+                ));
+            }
+            printed
+        }
+    };
+    Ok(printed)
+}
+
+/// The books in both places and only in either, for people to read or, if `porcelain`, for scripts.
+pub fn format_listing(listing: &Listing, porcelain: bool) -> String {
+    let sections = [
+        (
+            '=',
+            "In both the documents directories and on the device",
+            &listing.both,
+        ),
+        (
+            '+',
+            "Only in the documents directories, so would be copied",
+            &listing.source_only,
+        ),
+        (
+            '-',
+            "Only on the device, so could be pulled or deleted",
+            &listing.destination_only,
+        ),
+    ];
+
+    let mut printed = String::new();
+    for (code, heading, paths) in sections {
+        if porcelain {
+            for path in paths {
+                printed.push_str(&format!("{code} {}\n", path.display()));
+            }
+        } else {
+            printed.push_str(&format!("{heading}: {}\n", paths.len()));
+            for path in paths {
+                printed.push_str(&format!("  {}\n", path.display()));
+            }
+        }
+    }
+    printed
+}
+
+/// How many duplicates pruning deleted, or would have, and the space freed, in the requested format.
+pub fn format_pruned(
+    report: &PruneReport,
+    dry_run: bool,
+    output: OutputFormat,
+    raw_bytes: bool,
+) -> Result<String> {
+    let printed = match output {
+        OutputFormat::Json => {
+            let mut json = serde_json::to_string(report)?;
+            json.push('\n');
+            json
+        }
+        OutputFormat::Text => format!(
+            "Duplicates {} from the device: {}\n\
+            Space freed: {}\n",
+            if dry_run {
+                "that would be deleted"
+            } else {
+                "deleted"
+            },
+            report.deleted.len(),
+            format_bytes(report.bytes_freed, raw_bytes)
+        ),
+    };
+    Ok(printed)
+}
+
+/// What verifying the device found, listing the books that are missing or differ, in the requested
+/// format.
+pub fn format_verification(
+    report: &SyncReport,
+    compared_digests: bool,
+    output: OutputFormat,
+    colors: bool,
+) -> Result<String> {
+    let Counters {
+        found,
+        healthy,
+        missing,
+        mismatched,
+        corrupted,
+        ..
+    } = report.counters;
+    let printed = match output {
+        OutputFormat::Json => {
+            let summary = VerificationSummary {
+                healthy,
+                missing,
+                mismatched,
+                corrupted,
+                books: &report.actions,
+            };
+            let mut json = serde_json::to_string(&summary)?;
+            json.push('\n');
+            json
+        }
+        OutputFormat::Text => {
+            let mut printed = format!(
+                "Books checked: {found}\n\
+                Books intact on the device: {healthy}\n\
+                Books missing from the device: {}\n\
+                Books on the device with a different size: {}\n",
+                failure_count(missing, colors),
+                failure_count(mismatched, colors),
+            );
+            if compared_digests {
+                printed.push_str(&format!(
+                    "Books on the device with different contents, or that could not be read: {}\n",
+                    failure_count(corrupted, colors)
+                ));
+            }
+            let problems = [
+                (Action::Missing, "Missing books"),
+                (Action::Mismatched, "Books with a different size"),
+                (Action::Corrupted, "Corrupted books"),
+            ];
+            for (problem, heading) in problems {
+                let books = report
+                    .actions
+                    .iter()
+                    .filter(|action| action.action == problem)
+                    .collect::<Vec<_>>();
+                if books.is_empty() {
+                    continue;
+                }
+                printed.push_str(&format!("{heading}:\n"));
+                for book in books {
+                    printed.push_str(&format!(
+                        "  {} at {}\n",
+                        book.source.display(),
+                        book.destination.display()
+                    ));
+                }
+            }
+            printed
+        }
+    };
+    Ok(printed)
+}
+
+/// Whether each of the doctor's checks passed, in the requested format.
+pub fn format_diagnoses(
+    diagnoses: &[Diagnosis],
+    output: OutputFormat,
+    colors: bool,
+) -> Result<String> {
+    let printed = match output {
+        OutputFormat::Json => {
+            let mut json = serde_json::to_string(&diagnoses)?;
+            json.push('\n');
+            json
+        }
+        OutputFormat::Text => {
+            let mut printed = String::new();
+            for Diagnosis { passed, message } in diagnoses {
+                let (verdict, colour) = if *passed {
+                    ("PASS", AnsiColor::Green)
+                } else {
+                    ("FAIL", AnsiColor::Red)
+                };
+                if colors {
+                    let style = colour.on_default().bold();
+                    let (style, reset) = (style.render(), style.render_reset());
+                    printed.push_str(&format!("{style}{verdict}{reset} {message}\n"));
+                } else {
+                    printed.push_str(&format!("{verdict} {message}\n"));
+                }
+            }
+            printed
+        }
+    };
+    Ok(printed)
+}
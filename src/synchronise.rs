@@ -0,0 +1,1633 @@
+//! Deciding what to copy, update, pull, and delete, and then doing it.
+
+use {
+    crate::{
+        convert::is_conversion,
+        copy::{
+            await_copy, copy_with_policy, hash_contents, hash_file, is_outdated, overwrites,
+            to_hex, CopyError, CopyKind, CopyOptions,
+        },
+        device::available_space,
+        find::{has_matching_extension, is_hidden, FoundBook},
+        hash_cache::HashCache,
+        kepub::{
+            copy_or_convert, is_convertible_to_kepub, is_epub, is_kepub, is_kepub_conversion,
+            kepub_path_for, plain_dest_for, plain_path_for_kepub, KEPUB_SUFFIX,
+        },
+        manifest::{manifest_entry_for, Manifest},
+        metadata::name_from_metadata,
+        progress_output,
+        report::{record_action, record_failure, Action},
+        sidecars::copy_sidecars,
+        stats::Statistic,
+        Run, RunFailure, FOUND_BOOKS_CHANNEL_BOUND,
+    },
+    anyhow::{anyhow, Result},
+    async_walkdir::{Filtering, WalkDir},
+    clap::ValueEnum,
+    serde::{Deserialize, Serialize},
+    std::{
+        borrow::Cow,
+        cmp::Ordering,
+        collections::{hash_map::Entry, HashMap, HashSet},
+        ffi::{OsStr, OsString},
+        io::Write,
+        path::{Path, PathBuf},
+        sync::Arc,
+        time::Duration,
+    },
+    tokio::{
+        fs, io,
+        sync::{
+            mpsc::{channel, Receiver, Sender, UnboundedReceiver},
+            Semaphore,
+        },
+        task::{spawn, spawn_blocking},
+        try_join,
+    },
+    tokio_stream::StreamExt,
+    tracing::{debug, error, info, warn},
+};
+
+/// Two books from different source paths that would be synchronised to the same destination.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Collision {
+    pub destination: PathBuf,
+    pub first: PathBuf,
+    pub second: PathBuf,
+}
+
+/// How the books in the documents directories compare with those on the destination, each sorted.
+#[derive(Debug, Default)]
+pub struct Listing {
+    /// The source paths of the books that are on the destination too.
+    pub both: Vec<PathBuf>,
+
+    /// The source paths of the books that aren't on the destination, so would be copied.
+    pub source_only: Vec<PathBuf>,
+
+    /// The destination paths of the books that are only on the destination, so could be pulled
+    /// or deleted.
+    pub destination_only: Vec<PathBuf>,
+}
+
+/// What to do when books from different source paths would be synchronised to the same
+/// destination, such as two `dune.epub` files in different documents directories.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum CollisionPolicy {
+    /// Keep whichever book was found first, warning about the others.
+    #[default]
+    Skip,
+
+    /// Keep the most recently modified book.
+    Newest,
+
+    /// Keep the largest book.
+    Largest,
+
+    /// Keep them all, naming the later ones with a short hash of their contents, e.g.
+    /// `dune-1a2b3c4d.epub`. Books whose names are already taken on the destination by different
+    /// books are named so too.
+    Suffix,
+}
+
+/// The order in which found books are copied. Anything but the order they were found in means
+/// waiting for every book to be found first, and holding them all in memory until then.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OrderBy {
+    /// Copy books as they're found, without waiting for the rest.
+    #[default]
+    Discovered,
+
+    /// By file name, alphabetically.
+    Name,
+
+    /// Smallest first.
+    SizeAsc,
+
+    /// Largest first.
+    SizeDesc,
+
+    /// The most recently modified first.
+    MtimeDesc,
+}
+
+/// How to tell whether a book already on the destination under the same name is the same book.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Compare {
+    /// Any book of the same name is the same book, unless updating outdated ones.
+    #[default]
+    Name,
+
+    /// Books of the same name but different sizes differ, such as truncated copies.
+    Size,
+
+    /// Books of the same name but different contents differ, which means hashing both.
+    Hash,
+}
+
+/// What to do when a book's destination already exists, before any updating or comparing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OverwritePolicy {
+    /// Leave it alone, as converted KEPUBs on the device may hold annotations.
+    #[default]
+    Never,
+
+    /// Overwrite it if the book was modified more recently.
+    IfNewer,
+
+    /// Overwrite it if its size or modification time differs from the book's.
+    IfDifferent,
+
+    /// Always overwrite it.
+    Always,
+}
+
+impl OverwritePolicy {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            OverwritePolicy::Never => "never",
+            OverwritePolicy::IfNewer => "if-newer",
+            OverwritePolicy::IfDifferent => "if-different",
+            OverwritePolicy::Always => "always",
+        }
+    }
+}
+
+/// Sort found books into the order they're to be copied in. Books whose metadata can't be read
+/// sort as if empty and unmodified, and otherwise equal books by their paths, so that the order
+/// is the same from run to run.
+async fn sort_books(books: Vec<FoundBook>, order_by: OrderBy) -> Vec<FoundBook> {
+    if order_by == OrderBy::Discovered {
+        return books;
+    }
+
+    let mut keyed = Vec::with_capacity(books.len());
+    for found in books {
+        let metadata = fs::metadata(&found.path).await.ok();
+        let len = metadata.as_ref().map_or(0, |metadata| metadata.len());
+        let modified = metadata.and_then(|metadata| metadata.modified().ok());
+        keyed.push((len, modified, found));
+    }
+
+    keyed.sort_by(|(a_len, a_modified, a), (b_len, b_modified, b)| {
+        let by_order = match order_by {
+            OrderBy::Discovered => Ordering::Equal,
+            OrderBy::Name => a.path.file_name().cmp(&b.path.file_name()),
+            OrderBy::SizeAsc => a_len.cmp(b_len),
+            OrderBy::SizeDesc => b_len.cmp(a_len),
+            OrderBy::MtimeDesc => b_modified.cmp(a_modified),
+        };
+        by_order.then_with(|| a.path.cmp(&b.path))
+    });
+    keyed.into_iter().map(|(_, _, found)| found).collect()
+}
+
+/// Send books that were held back, such as to sort them, out again to be copied.
+fn resend_books(books: Vec<FoundBook>) -> Receiver<FoundBook> {
+    let (books_tx, books_rx) = channel(FOUND_BOOKS_CHANNEL_BOUND);
+    spawn(async move {
+        for found in books {
+            if books_tx.send(found).await.is_err() {
+                break;
+            }
+        }
+    });
+    books_rx
+}
+
+/// An answer to the prompt asking whether to copy a book in interactive mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Confirmation {
+    Yes,
+    No,
+    All,
+    Quit,
+}
+
+/// Ask on stdin whether a book should be copied, until a valid answer is given. The read happens on
+/// the blocking threadpool so that copies already queued carry on in the meantime, and any progress
+/// bar is hidden while waiting so that it doesn't draw over the prompt.
+async fn confirm_copy(book: &Path, dest_dir: &Path, run: &Run) -> Result<Confirmation> {
+    let book_name = book.file_name().unwrap_or(book.as_os_str());
+    let dest_name = dest_dir.file_name().unwrap_or(dest_dir.as_os_str());
+    let prompt = format!(
+        "Copy {} to {}? [y/N/a/q] ",
+        Path::new(book_name).display(),
+        Path::new(dest_name).display(),
+    );
+
+    let ask = move || -> io::Result<Confirmation> {
+        use std::io::BufRead;
+
+        loop {
+            let mut out = progress_output();
+            out.write_all(prompt.as_bytes())?;
+            out.flush()?;
+
+            let mut line = String::new();
+            if std::io::stdin().lock().read_line(&mut line)? == 0 {
+                // With stdin closed, nobody can answer, so don't copy anything else.
+                return Ok(Confirmation::Quit);
+            }
+            match line.trim().to_lowercase().as_str() {
+                "y" | "yes" => return Ok(Confirmation::Yes),
+                "" | "n" | "no" => return Ok(Confirmation::No),
+                "a" | "all" => return Ok(Confirmation::All),
+                "q" | "quit" => return Ok(Confirmation::Quit),
+                _ => continue,
+            }
+        }
+    };
+    let bar = run.progress_bar().cloned();
+    let confirmation = spawn_blocking(move || match bar {
+        Some(bar) => bar.suspend(ask),
+        None => ask(),
+    })
+    .await??;
+    Ok(confirmation)
+}
+
+/// Whether a book would actually be copied or updated, and so is worth asking about.
+async fn would_copy(
+    book: &Path,
+    dest_path: &Path,
+    options: &CopyOptions,
+    hash_cache: Option<&HashCache>,
+) -> bool {
+    match fs::try_exists(dest_path).await {
+        Ok(true) => {
+            overwrites(book, dest_path, options.overwrite)
+                .await
+                .unwrap_or(false)
+                || (options.update && is_outdated(book, dest_path).await.unwrap_or(false))
+                || differs(book, dest_path, options.compare, hash_cache)
+                    .await
+                    .unwrap_or(false)
+        }
+        Ok(false) | Err(_) => true,
+    }
+}
+
+/// Whether a book differs from the book of the same name on the destination, as far as the
+/// comparison can tell. Books converted to KEPUBs or EPUBs always differ from their conversions,
+/// so they're never compared.
+async fn differs(
+    book: &Path,
+    dest_path: &Path,
+    compare: Compare,
+    hash_cache: Option<&HashCache>,
+) -> io::Result<bool> {
+    if compare == Compare::Name || is_conversion(book, dest_path) {
+        return Ok(false);
+    }
+
+    let (src_len, dest_len) = try_join!(fs::metadata(book), fs::metadata(dest_path))?;
+    if src_len.len() != dest_len.len() {
+        return Ok(true);
+    }
+    if compare == Compare::Size {
+        return Ok(false);
+    }
+    let (src_digest, dest_digest) = try_join!(
+        hash_file(book, hash_cache),
+        hash_file(dest_path, hash_cache)
+    )?;
+    Ok(src_digest != dest_digest)
+}
+
+/// Whether a book already on the destination should be copied over, either as it's outdated and
+/// updating or as it differs when comparing more than names. Books that can't be compared are
+/// left alone.
+async fn needs_replacing(
+    book: &Path,
+    dest_path: &Path,
+    &CopyOptions {
+        update, compare, ..
+    }: &CopyOptions,
+    stats: &Sender<Statistic>,
+    hash_cache: Option<&HashCache>,
+) -> Result<bool> {
+    if update && is_outdated(book, dest_path).await.unwrap_or(false) {
+        return Ok(true);
+    }
+    if compare == Compare::Name || is_conversion(book, dest_path) {
+        return Ok(false);
+    }
+
+    let (src_str, dest_str) = (book.display(), dest_path.display());
+    match differs(book, dest_path, compare, hash_cache).await {
+        Ok(true) => {
+            info!(
+                path = %src_str,
+                dest = %dest_str,
+                "{dest_str} differs from {src_str}, so it will be replaced"
+            );
+            stats.send(Statistic::ComparedDifferent).await?;
+            Ok(true)
+        }
+        Ok(false) => {
+            stats.send(Statistic::ComparedIdentical).await?;
+            Ok(false)
+        }
+        Err(err) => {
+            warn!(
+                path = %src_str,
+                dest = %dest_str,
+                "Failed to compare {src_str} with {dest_str}, so leaving it alone: {err}"
+            );
+            Ok(false)
+        }
+    }
+}
+
+/// Names that DOS devices reserve, with or without an extension, on FAT filesystems.
+const RESERVED_DOS_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Make a file or directory name valid on the FAT and exFAT filesystems that e-book readers use,
+/// by replacing the characters they reject, trimming the trailing dots and spaces they drop, and
+/// steering clear of reserved DOS device names. Valid names are kept exactly as they are. Names
+/// that aren't valid Unicode are kept too, as there's no telling what they were meant to be.
+fn fat_safe_name(name: &OsStr) -> Cow<'_, OsStr> {
+    let Some(name_str) = name.to_str() else {
+        return Cow::Borrowed(name);
+    };
+
+    let replaced: String = name_str
+        .chars()
+        .map(|c| match c {
+            '"' | '*' | '/' | ':' | '<' | '>' | '?' | '\\' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let mut safe = replaced.trim_end_matches(['.', ' ']).to_owned();
+    if safe.is_empty() {
+        safe.push('_');
+    }
+
+    let base = safe.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_DOS_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(base))
+    {
+        safe.insert(0, '_');
+    }
+
+    if safe == name_str {
+        Cow::Borrowed(name)
+    } else {
+        Cow::Owned(safe.into())
+    }
+}
+
+/// Work out where a book should be copied to on the destination. Books are flattened into the
+/// destination's root, or the subdirectory that their format is routed to, unless
+/// `mirror_structure` is set, in which case their path relative to their documents directory is
+/// kept beneath that. Names that the device's filesystem would reject are made safe for it, and
+/// EPUBs are named after their metadata if asked, and as KEPUBs if they're going to be converted.
+async fn dest_path_for(
+    dest_dir: &Path,
+    book: &FoundBook,
+    options: &CopyOptions,
+) -> Option<PathBuf> {
+    if let Some(planned) = options
+        .planned
+        .as_ref()
+        .and_then(|planned| planned.get(&book.path))
+    {
+        return Some(planned.clone());
+    }
+
+    let mut dest_path = PathBuf::new();
+    dest_path.push(dest_dir);
+
+    if let Some(ext) = book.path.extension() {
+        let routed = options
+            .routes
+            .iter()
+            .find(|(routed_ext, _)| ext.eq_ignore_ascii_case(routed_ext));
+        if let Some((_, subdir)) = routed {
+            dest_path.push(subdir);
+        }
+    }
+
+    if options.mirror_structure {
+        let relative = book.path.strip_prefix(&book.root).ok()?;
+        for component in relative.parent().into_iter().flatten() {
+            dest_path.push(fat_safe_name(component));
+        }
+    }
+    let name = book.path.file_name()?;
+    let renamed = match &book.name {
+        Some(name) => Some(name.clone()),
+        None => renamed_from_metadata(&book.path, options).await,
+    };
+    match renamed {
+        Some(renamed) => dest_path.push(fat_safe_name(&renamed)),
+        None => dest_path.push(fat_safe_name(name)),
+    }
+
+    if options.kepubify.is_some() && is_convertible_to_kepub(&book.path) {
+        dest_path = kepub_path_for(&dest_path);
+    }
+    if let Some(conversion) = &options.conversion {
+        if conversion.converts(&book.path) {
+            dest_path.set_extension("epub");
+        }
+    }
+    Some(dest_path)
+}
+
+/// The name of a book after its metadata, if it should be so named and its metadata can be read.
+async fn renamed_from_metadata(book: &Path, options: &CopyOptions) -> Option<OsString> {
+    if !options.rename_from_metadata || !is_epub(book) {
+        return None;
+    }
+    let book_str = book.display();
+    match name_from_metadata(book).await {
+        Ok(renamed) => {
+            let renamed_str = Path::new(&renamed).display();
+            debug!(path = %book_str, "Naming {book_str} {renamed_str}, after its metadata");
+            Some(renamed)
+        }
+        Err(err) => {
+            warn!(
+                path = %book_str,
+                "Keeping the name of {book_str}, as its metadata could not be read: {err:#}"
+            );
+            None
+        }
+    }
+}
+
+/// Wait for all books to be found, and then check that those needing to be copied fit on the
+/// destination. If they don't, either fail listing the books that won't fit or, with
+/// `fit_what_fits`, drop them and order the rest smallest first so that as many as possible are
+/// copied.
+async fn plan_for_free_space(
+    dest_dir: &Path,
+    options: &CopyOptions,
+    manifest: &Manifest,
+    mut books_to_sync: Receiver<FoundBook>,
+    stats: &Sender<Statistic>,
+    run: &Run,
+) -> Result<Vec<FoundBook>> {
+    let mut existing = vec![];
+    let mut to_copy = vec![];
+
+    while let Some(found) = books_to_sync.recv().await {
+        let already_exists = match dest_path_for(dest_dir, &found, options).await {
+            Some(dest_path) => {
+                let src = manifest_entry_for(&found.path, options).await;
+                manifest.is_current(dest_dir, &dest_path, src.as_ref())
+                    || fs::try_exists(&dest_path).await.unwrap_or(false)
+            }
+            None => true,
+        };
+        if already_exists {
+            existing.push(found);
+        } else {
+            let size = fs::metadata(&found.path)
+                .await
+                .map(|m| m.len())
+                .unwrap_or(0);
+            to_copy.push((size, found));
+        }
+    }
+
+    let available = available_space(dest_dir).await?;
+    let needed: u64 = to_copy.iter().map(|(size, _)| size).sum();
+    if needed <= available {
+        existing.extend(to_copy.into_iter().map(|(_, found)| found));
+        return Ok(existing);
+    }
+
+    to_copy.sort_by_key(|(size, _)| *size);
+    let mut remaining = available;
+    let split = to_copy
+        .iter()
+        .position(|(size, _)| match remaining.checked_sub(*size) {
+            Some(left) => {
+                remaining = left;
+                false
+            }
+            None => true,
+        })
+        .unwrap_or(to_copy.len());
+    let wont_fit = to_copy.split_off(split);
+
+    for (size, found) in &wont_fit {
+        let src_str = found.path.display();
+        warn!(
+            path = %src_str,
+            size, "Book {src_str} ({size} bytes) will not fit on the destination."
+        );
+    }
+
+    if !options.fit_what_fits {
+        return Err(anyhow!(
+            "the books to copy need {needed} bytes but the destination only has {available} \
+            bytes available; pass --fit-what-fits to copy as many as will fit"
+        ));
+    }
+
+    for _ in &wont_fit {
+        stats.send(Statistic::NotCopiedBecauseItWouldNotFit).await?;
+        run.advance_progress();
+    }
+    existing.extend(to_copy.into_iter().map(|(_, found)| found));
+    Ok(existing)
+}
+
+/// Compare the found books with those on the destination, working out where each would be
+/// synchronised to just as synchronising does, but without changing anything.
+pub(crate) async fn list_books(
+    dest_dir: &Path,
+    extensions_to_match: &HashSet<&OsStr>,
+    options: &CopyOptions,
+    mut found_books: Receiver<FoundBook>,
+) -> Result<Listing> {
+    let mut listing = Listing::default();
+    let mut accounted = HashSet::new();
+
+    while let Some(found) = found_books.recv().await {
+        let Some(dest_path) = dest_path_for(dest_dir, &found, options).await else {
+            continue;
+        };
+        // Like pulling, count a book as on the destination if its KEPUB is, and a KEPUB as there
+        // if the book failed to convert to it and was copied as-is.
+        let mut candidates = vec![dest_path.clone()];
+        if is_convertible_to_kepub(&found.path) && !is_kepub(&dest_path) {
+            candidates.push(kepub_path_for(&dest_path));
+        }
+        if is_kepub_conversion(&found.path, &dest_path) {
+            candidates.push(plain_dest_for(&found.path, &dest_path));
+        }
+
+        let mut present = false;
+        for candidate in candidates {
+            if fs::try_exists(&candidate).await? {
+                present = true;
+                accounted.insert(candidate);
+            }
+        }
+        if present {
+            listing.both.push(found.path);
+        } else {
+            listing.source_only.push(found.path);
+        }
+    }
+
+    let mut entries = WalkDir::new(dest_dir).filter(|entry| async move {
+        if is_hidden(&entry.file_name()) {
+            Filtering::IgnoreDir
+        } else {
+            Filtering::Continue
+        }
+    });
+    while let Some(entry) = entries.next().await {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type().await?.is_file()
+            && has_matching_extension(&path, extensions_to_match)
+            && !accounted.contains(&path)
+        {
+            listing.destination_only.push(path);
+        }
+    }
+
+    listing.both.sort();
+    listing.source_only.sort();
+    listing.destination_only.sort();
+    Ok(listing)
+}
+
+/// Check a copy of a book against the book, yielding what was found. Copies converted to KEPUBs or
+/// EPUBs can't be compared with their books, so they only need to be there. The copy is hashed afresh
+/// rather than from the hash cache, as its contents could have changed without its size or
+/// modification time changing.
+async fn verify_copy(
+    book: &Path,
+    dest_path: &Path,
+    compare_digests: bool,
+    hash_cache: Option<&HashCache>,
+) -> io::Result<Action> {
+    if is_conversion(book, dest_path) {
+        return Ok(Action::Healthy);
+    }
+    let (src_len, dest_len) = try_join!(fs::metadata(book), fs::metadata(dest_path))?;
+    if src_len.len() != dest_len.len() {
+        return Ok(Action::Mismatched);
+    }
+    if !compare_digests {
+        return Ok(Action::Healthy);
+    }
+    let (src_digest, dest_digest) =
+        try_join!(hash_file(book, hash_cache), hash_contents(dest_path))?;
+    if src_digest == dest_digest {
+        Ok(Action::Healthy)
+    } else {
+        Ok(Action::Corrupted)
+    }
+}
+
+/// Check that each book found is on the destination, where a run would have copied it, with the
+/// same size and, if comparing digests, the same contents, without changing anything.
+pub(crate) async fn verify_books(
+    dest_dir: &Path,
+    options: &CopyOptions,
+    compare_digests: bool,
+    mut found_books: Receiver<FoundBook>,
+    stats: Sender<Statistic>,
+    run: &Run,
+) -> Result<()> {
+    while let Some(found) = found_books.recv().await {
+        let Some(dest_path) = dest_path_for(dest_dir, &found, options).await else {
+            continue;
+        };
+        // Like listing, a book counts as on the destination if its KEPUB is, and a KEPUB as there
+        // if the book failed to convert to it and was copied as-is.
+        let mut candidates = vec![dest_path.clone()];
+        if is_convertible_to_kepub(&found.path) && !is_kepub(&dest_path) {
+            candidates.push(kepub_path_for(&dest_path));
+        }
+        if is_kepub_conversion(&found.path, &dest_path) {
+            candidates.push(plain_dest_for(&found.path, &dest_path));
+        }
+        let mut present = None;
+        for candidate in candidates {
+            if fs::try_exists(&candidate).await? {
+                present = Some(candidate);
+                break;
+            }
+        }
+
+        let (dest_path, action) = match present {
+            Some(present) => {
+                let action = verify_copy(&found.path, &present, compare_digests, run.hash_cache());
+                let action = match action.await {
+                    Ok(action) => action,
+                    Err(err) => {
+                        let (src_str, dest_str) = (found.path.display(), present.display());
+                        warn!(
+                            path = %src_str,
+                            dest = %dest_str,
+                            "Failed to verify {dest_str} against {src_str}, so counting it as \
+                            corrupted: {err}"
+                        );
+                        Action::Corrupted
+                    }
+                };
+                (present, action)
+            }
+            None => (dest_path, Action::Missing),
+        };
+        let statistic = match action {
+            Action::Healthy => Statistic::VerifiedHealthy,
+            Action::Missing => Statistic::VerifiedMissing,
+            Action::Mismatched => Statistic::VerifiedMismatched,
+            _ => Statistic::VerifiedCorrupted,
+        };
+        stats.send(statistic).await?;
+        record_action(&stats, &found.path, &dest_path, action, 0, Duration::ZERO).await?;
+        run.advance_progress();
+    }
+    Ok(())
+}
+
+/// Where to synchronise a book whose destination may already hold a different book with the same
+/// name, in which case it's named with a short hash of its contents instead. That name is the
+/// same on every run, so later runs find and skip the book under it. Nothing is yielded if the
+/// book couldn't be hashed, which is reported as a failure.
+async fn suffixed_past_different_book(
+    dest_dir: &Path,
+    found: &FoundBook,
+    dest_path: PathBuf,
+    manifest: &Manifest,
+    stats: &Sender<Statistic>,
+    hash_cache: Option<&HashCache>,
+) -> Result<Option<PathBuf>> {
+    let (src_str, dest_str) = (found.path.display(), dest_path.display());
+    let is_different = match holds_different_book(
+        &found.path,
+        &dest_path,
+        dest_dir,
+        manifest,
+        hash_cache,
+    )
+    .await
+    {
+        Ok(is_different) => is_different,
+        Err(err) => {
+            warn!(
+                path = %src_str,
+                dest = %dest_str,
+                "Failed to tell whether {dest_str} is the same book as {src_str}, so assuming it \
+                is: {err}"
+            );
+            false
+        }
+    };
+    if !is_different {
+        return Ok(Some(dest_path));
+    }
+
+    let digest = match hash_file(&found.path, hash_cache).await {
+        Ok(digest) => digest,
+        Err(err) => {
+            error!(path = %src_str, "Failed to hash {src_str}: {err}");
+            stats.send(Statistic::CopyFailed).await?;
+            let failed = format!("could not hash it: {err}");
+            record_failure(stats, &found.path, &dest_path, failed, Duration::ZERO).await?;
+            return Ok(None);
+        }
+    };
+    let suffixed = suffixed_path(&dest_path, &to_hex(&digest)[..8]);
+    let suffixed_str = suffixed.display();
+    warn!(
+        path = %src_str,
+        dest = %suffixed_str,
+        "{dest_str} is already a different book on the destination, so synchronising {src_str} \
+        to {suffixed_str} instead"
+    );
+    let collision = Collision {
+        destination: dest_path.clone(),
+        first: dest_path.clone(),
+        second: found.path.clone(),
+    };
+    stats.send(Statistic::Collided(collision)).await?;
+    Ok(Some(suffixed))
+}
+
+/// Name a book with a suffix before its extension, keeping KEPUBs' double extension intact.
+fn suffixed_path(path: &Path, suffix: &str) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let split = if is_kepub(path) {
+        name.len() - KEPUB_SUFFIX.len()
+    } else {
+        name.rfind('.').filter(|&i| 0 < i).unwrap_or(name.len())
+    };
+    let (stem, extension) = name.split_at(split);
+    path.with_file_name(format!("{stem}-{suffix}{extension}"))
+}
+
+/// Whether the destination of a book already holds a different book with the same name, such as
+/// one synchronised from another documents directory by an earlier run. The manifest says which
+/// book it was synchronised from, if it's recorded there. Otherwise, a destination with different
+/// contents is taken to be a different book, unless it's older than the book, in which case it's
+/// probably just an outdated copy of it.
+async fn holds_different_book(
+    book: &Path,
+    dest_path: &Path,
+    dest_dir: &Path,
+    manifest: &Manifest,
+    hash_cache: Option<&HashCache>,
+) -> Result<bool> {
+    if !fs::try_exists(dest_path).await? {
+        return Ok(false);
+    }
+    if let Some(source) = manifest.source_of(dest_dir, dest_path) {
+        return Ok(source != fs::canonicalize(book).await?);
+    }
+    // A conversion never has the same contents as the book it was converted from.
+    if is_conversion(book, dest_path) {
+        return Ok(false);
+    }
+
+    let (src, dest) = (fs::metadata(book).await?, fs::metadata(dest_path).await?);
+    if let (Ok(src_modified), Ok(dest_modified)) = (src.modified(), dest.modified()) {
+        if dest_modified < src_modified {
+            return Ok(false);
+        }
+    }
+    if src.len() != dest.len() {
+        return Ok(true);
+    }
+    Ok(hash_file(book, hash_cache).await? != hash_file(dest_path, hash_cache).await?)
+}
+
+/// Whether a book should replace another under a collision policy that picks between them. Ties,
+/// and books whose metadata can't be read, keep the book that's already there.
+async fn beats(challenger: &Path, incumbent: &Path, policy: CollisionPolicy) -> bool {
+    let (Ok(challenger), Ok(incumbent)) = (
+        fs::metadata(challenger).await,
+        fs::metadata(incumbent).await,
+    ) else {
+        return false;
+    };
+    match policy {
+        CollisionPolicy::Newest => match (challenger.modified(), incumbent.modified()) {
+            (Ok(challenger), Ok(incumbent)) => incumbent < challenger,
+            _ => false,
+        },
+        CollisionPolicy::Largest => incumbent.len() < challenger.len(),
+        CollisionPolicy::Skip | CollisionPolicy::Suffix => false,
+    }
+}
+
+/// Replace the books that lost a collision with the books that won it, once the losers' own copies
+/// have finished. A destination that was already there before this run is only replaced if it's
+/// outdated, as it may well be the winner copied by an earlier run.
+#[allow(clippy::too_many_arguments)]
+async fn replace_collided_books(
+    dest_dir: &Path,
+    winners: HashMap<PathBuf, FoundBook>,
+    copied_dests: &HashSet<PathBuf>,
+    options: &CopyOptions,
+    manifest: &mut Manifest,
+    synchronised: &mut HashMap<PathBuf, PathBuf>,
+    stats: &Sender<Statistic>,
+    run: &Run,
+) -> Result<()> {
+    let copy_permits = Arc::new(Semaphore::new(options.max_concurrent_copies.get()));
+    let mut copy_tasks = vec![];
+
+    for (dest_path, winner) in winners {
+        let (src_str, dest_str) = (winner.path.display(), dest_path.display());
+        if let Some(bar) = run.progress_bar() {
+            bar.inc_length(1);
+        }
+        let relative = winner
+            .path
+            .strip_prefix(&winner.root)
+            .unwrap_or(&winner.path);
+        synchronised.insert(dest_path.clone(), relative.to_path_buf());
+
+        if options.dry_run {
+            info!(
+                path = %src_str,
+                dest = %dest_str,
+                "Dry-running; would otherwise replace {dest_str} with {src_str}"
+            );
+            let len = fs::metadata(&winner.path).await?.len();
+            stats.send(Statistic::WouldUpdate(len)).await?;
+            let dry_run = Action::DryRun;
+            record_action(
+                stats,
+                &winner.path,
+                &dest_path,
+                dry_run,
+                len,
+                Duration::ZERO,
+            )
+            .await?;
+            run.advance_progress();
+            continue;
+        }
+
+        let exists = fs::try_exists(&dest_path).await?;
+        if exists
+            && !copied_dests.contains(&dest_path)
+            && !is_outdated(&winner.path, &dest_path).await?
+        {
+            debug!(path = %src_str, dest = %dest_str, "{dest_str} is already {src_str}");
+            run.advance_progress();
+            continue;
+        }
+        info!(
+            path = %src_str,
+            dest = %dest_str,
+            "Replacing {dest_str} with {src_str}, which won the collision"
+        );
+        let kind = if exists {
+            CopyKind::Update
+        } else {
+            CopyKind::New
+        };
+        let src_entry = manifest_entry_for(&winner.path, options).await;
+        let copy_task = copy_or_convert(
+            &winner.path,
+            &dest_path,
+            kind,
+            options,
+            &copy_permits,
+            stats,
+            run,
+        )
+        .await?;
+        copy_tasks.push((src_entry, winner.path, copy_task));
+    }
+
+    for (src_entry, book, task) in copy_tasks {
+        let copied = await_copy(task, stats, run).await?;
+        if let Some(copied) = copied.as_ref().filter(|_| options.include_sidecars) {
+            copy_sidecars(&book, &copied.dest_path, options, stats).await?;
+        }
+        if let (Some(mut entry), Some(copied)) = (src_entry, copied) {
+            entry.blake3 = copied.digest.as_ref().map(to_hex);
+            manifest.record(dest_dir, &copied.dest_path, entry);
+        }
+    }
+    Ok(())
+}
+
+/// Synchronise the found books to the destination, yielding the destination paths of every book
+/// found regardless of whether it needed copying, each mapped to the book's path relative to its
+/// documents directory.
+pub(crate) async fn sync_books(
+    dest_dir: &Path,
+    options: &CopyOptions,
+    manifest: &mut Manifest,
+    mut books_to_sync: Receiver<FoundBook>,
+    stats: Sender<Statistic>,
+    run: &Run,
+) -> Result<HashMap<PathBuf, PathBuf>> {
+    let &CopyOptions {
+        dry_run,
+        mirror_structure,
+        max_concurrent_copies,
+        check_free_space,
+        fit_what_fits,
+        interactive,
+        on_collision,
+        rename_from_metadata,
+        include_sidecars,
+        order_by,
+        max_total_bytes,
+        compare,
+        ..
+    } = options;
+
+    if check_free_space || fit_what_fits {
+        let planned =
+            plan_for_free_space(dest_dir, options, manifest, books_to_sync, &stats, run).await?;
+        books_to_sync = resend_books(sort_books(planned, order_by).await);
+    } else if order_by != OrderBy::Discovered {
+        let mut found = vec![];
+        while let Some(book) = books_to_sync.recv().await {
+            found.push(book);
+        }
+        books_to_sync = resend_books(sort_books(found, order_by).await);
+    }
+
+    let copy_permits = Arc::new(Semaphore::new(max_concurrent_copies.get()));
+    let mut copy_tasks = vec![];
+    let mut synchronised = HashMap::new();
+    let mut claimed: HashMap<PathBuf, PathBuf> = HashMap::new();
+    let mut created_dirs = HashSet::new();
+    let mut collision_winners: HashMap<PathBuf, FoundBook> = HashMap::new();
+    let mut confirmed_all = !interactive;
+    let mut quit = false;
+
+    // Once a book would go over the most bytes to copy, the rest are deferred too, even smaller
+    // ones that would still fit, so that the books copied are exactly the first in the order.
+    let mut queued_bytes = 0u64;
+    let mut deferring = false;
+
+    while let Some(found) = books_to_sync.recv().await {
+        // Books found before the interruption are still received, so that their finders aren't
+        // left waiting to send them, but no more are copied.
+        if run.is_interrupted() {
+            continue;
+        }
+        if let Some(mut dest_path) = dest_path_for(dest_dir, &found, options).await {
+            let relative = found.path.strip_prefix(&found.root).unwrap_or(&found.path);
+            let named_from_metadata = rename_from_metadata && is_epub(&found.path);
+            let renamed_for_dest = if named_from_metadata {
+                false
+            } else if mirror_structure {
+                relative.iter().any(|name| fat_safe_name(name) != name)
+            } else {
+                let name = found.path.file_name().unwrap_or_default();
+                fat_safe_name(name) != name
+            };
+
+            let first = claimed
+                .get(&dest_path)
+                .filter(|first| **first != found.path)
+                .cloned();
+            if let Some(first) = first {
+                let collision = Collision {
+                    destination: dest_path.clone(),
+                    first: first.clone(),
+                    second: found.path.clone(),
+                };
+                stats.send(Statistic::Collided(collision)).await?;
+
+                // Books named after their metadata collide for sharing a title and author, such as
+                // different editions of the same book, so they're all kept.
+                let policy = if named_from_metadata {
+                    CollisionPolicy::Suffix
+                } else {
+                    on_collision
+                };
+                let (src_str, first_str, dest_str) =
+                    (found.path.display(), first.display(), dest_path.display());
+                match policy {
+                    CollisionPolicy::Skip => {
+                        warn!(
+                            path = %src_str,
+                            dest = %dest_str,
+                            "Not synchronising {src_str}, as {first_str} is also synchronised to \
+                            {dest_str}"
+                        );
+                        run.advance_progress();
+                        continue;
+                    }
+                    CollisionPolicy::Newest | CollisionPolicy::Largest => {
+                        let incumbent = collision_winners
+                            .get(&dest_path)
+                            .map(|winner| winner.path.clone())
+                            .unwrap_or(first);
+                        let incumbent_str = incumbent.display();
+                        if beats(&found.path, &incumbent, policy).await {
+                            warn!(
+                                path = %src_str,
+                                dest = %dest_str,
+                                "{src_str} and {incumbent_str} are both synchronised to \
+                                {dest_str}; keeping {src_str}"
+                            );
+                            collision_winners.insert(dest_path, found);
+                        } else {
+                            warn!(
+                                path = %src_str,
+                                dest = %dest_str,
+                                "{src_str} and {incumbent_str} are both synchronised to \
+                                {dest_str}; keeping {incumbent_str}"
+                            );
+                        }
+                        run.advance_progress();
+                        continue;
+                    }
+                    CollisionPolicy::Suffix => {
+                        let digest = match hash_file(&found.path, run.hash_cache()).await {
+                            Ok(digest) => digest,
+                            Err(err) => {
+                                error!(path = %src_str, "Failed to hash {src_str}: {err}");
+                                stats.send(Statistic::CopyFailed).await?;
+                                let failed = format!("could not hash it: {err}");
+                                record_failure(
+                                    &stats,
+                                    &found.path,
+                                    &dest_path,
+                                    failed,
+                                    Duration::ZERO,
+                                )
+                                .await?;
+                                run.advance_progress();
+                                continue;
+                            }
+                        };
+                        let suffixed = suffixed_path(&dest_path, &to_hex(&digest)[..8]);
+                        let suffixed_str = suffixed.display();
+                        let is_identical_to_first =
+                            hash_file(&first, run.hash_cache()).await.ok() == Some(digest);
+                        let identical = if is_identical_to_first {
+                            Some(&first)
+                        } else {
+                            claimed.get(&suffixed)
+                        };
+                        if let Some(identical) = identical {
+                            let identical_str = identical.display();
+                            warn!(
+                                path = %src_str,
+                                dest = %suffixed_str,
+                                "Not synchronising {src_str}, as it's identical to \
+                                {identical_str}"
+                            );
+                            run.advance_progress();
+                            continue;
+                        }
+                        warn!(
+                            path = %src_str,
+                            dest = %suffixed_str,
+                            "{first_str} is also synchronised to {dest_str}, so synchronising \
+                            {src_str} to {suffixed_str} instead"
+                        );
+                        dest_path = suffixed;
+                    }
+                }
+            } else if on_collision == CollisionPolicy::Suffix && !claimed.contains_key(&dest_path) {
+                dest_path = match suffixed_past_different_book(
+                    dest_dir,
+                    &found,
+                    dest_path,
+                    manifest,
+                    &stats,
+                    run.hash_cache(),
+                )
+                .await?
+                {
+                    Some(dest_path) => dest_path,
+                    None => {
+                        run.advance_progress();
+                        continue;
+                    }
+                };
+            }
+
+            // A book that fails to convert is copied under its plain name instead, which mustn't
+            // then be deleted as stale.
+            if is_kepub_conversion(&found.path, &dest_path) {
+                let plain_dest = plain_dest_for(&found.path, &dest_path);
+                synchronised
+                    .entry(plain_dest)
+                    .or_insert_with(|| relative.to_path_buf());
+            }
+
+            // The same book may be found again while it's still mid-copy, such as when it's
+            // modified while watching, in which case the destination won't exist yet under its
+            // final name.
+            let queued_earlier = match claimed.entry(dest_path.clone()) {
+                Entry::Occupied(_) => true,
+                Entry::Vacant(entry) => {
+                    entry.insert(found.path.clone());
+                    false
+                }
+            };
+            synchronised
+                .entry(dest_path.clone())
+                .or_insert_with(|| relative.to_path_buf());
+            let book = found.path;
+
+            // The manifest only records what the source was like, so comparing by more than names
+            // or overwriting has to look at the destination itself.
+            let src_entry = manifest_entry_for(&book, options).await;
+            let trusts_manifest = !queued_earlier
+                && compare == Compare::Name
+                && options.overwrite == OverwritePolicy::Never;
+            if trusts_manifest && manifest.is_current(dest_dir, &dest_path, src_entry.as_ref()) {
+                let dest_str = dest_path.display();
+                debug!(
+                    path = %book.display(),
+                    dest = %dest_str,
+                    "The manifest records {dest_str} as already synchronised"
+                );
+                stats.send(Statistic::skipped_existing(dry_run)).await?;
+                let skipped = Action::SkippedExisting;
+                record_action(&stats, &book, &dest_path, skipped, 0, Duration::ZERO).await?;
+                run.advance_progress();
+                continue;
+            }
+
+            if let Some(max_total_bytes) = max_total_bytes.filter(|_| !queued_earlier) {
+                if would_copy(&book, &dest_path, options, run.hash_cache()).await {
+                    let len = fs::metadata(&book)
+                        .await
+                        .map_or(0, |metadata| metadata.len());
+                    deferring = deferring || queued_bytes.saturating_add(len) > max_total_bytes;
+                    if deferring {
+                        let src_str = book.display();
+                        info!(
+                            path = %src_str,
+                            "Deferring {src_str} to a later run, as copying it would go over the \
+                            {max_total_bytes} bytes to copy in this one"
+                        );
+                        stats.send(Statistic::Deferred).await?;
+                        let deferred = Action::Deferred;
+                        record_action(&stats, &book, &dest_path, deferred, len, Duration::ZERO)
+                            .await?;
+                        run.advance_progress();
+                        continue;
+                    }
+                    queued_bytes += len;
+                }
+            }
+
+            if !confirmed_all
+                && !queued_earlier
+                && would_copy(&book, &dest_path, options, run.hash_cache()).await
+            {
+                match confirm_copy(&book, dest_dir, run).await? {
+                    Confirmation::Yes => {}
+                    Confirmation::All => confirmed_all = true,
+                    Confirmation::No => {
+                        let src_str = book.display();
+                        info!(path = %src_str, "Not copying {src_str}, as it was declined.");
+                        stats.send(Statistic::Declined).await?;
+                        run.advance_progress();
+                        continue;
+                    }
+                    Confirmation::Quit => {
+                        quit = true;
+                        break;
+                    }
+                }
+            }
+
+            let parent = dest_path.parent().filter(|parent| *parent != dest_dir);
+            if let Some(parent) = parent.filter(|_| !dry_run) {
+                if created_dirs.insert(parent.to_path_buf()) {
+                    if let Err(err) = fs::create_dir_all(parent).await {
+                        let parent_str = parent.display();
+                        error!(
+                            path = %parent_str,
+                            "Failed to create directory {parent_str}: {err}"
+                        );
+                        stats.send(Statistic::CopyFailed).await?;
+                        let failed = format!("could not create {parent_str}: {err}");
+                        record_failure(&stats, &book, &dest_path, failed, Duration::ZERO).await?;
+                        run.advance_progress();
+                        continue;
+                    }
+                }
+            }
+
+            let copying = if queued_earlier {
+                Err(CopyError::AlreadyExists)
+            } else {
+                copy_with_policy(
+                    &book,
+                    &dest_path,
+                    CopyKind::New,
+                    options,
+                    &copy_permits,
+                    &stats,
+                    run,
+                )
+                .await
+            };
+            let replacing = match copying {
+                Err(CopyError::AlreadyExists) if !queued_earlier => {
+                    needs_replacing(&book, &dest_path, options, &stats, run.hash_cache()).await?
+                }
+                _ => false,
+            };
+            match copying {
+                Ok(copy_task) => {
+                    if renamed_for_dest {
+                        let (src_str, dest_str) = (book.display(), dest_path.display());
+                        info!(
+                            path = %src_str,
+                            dest = %dest_str,
+                            "{src_str} is named {dest_str} on the destination, as its name is \
+                            not valid there"
+                        );
+                    }
+                    copy_tasks.push((src_entry, book, dest_path, copy_task))
+                }
+                Err(CopyError::AlreadyExists) if replacing => {
+                    if dry_run {
+                        let (src_str, dest_str) = (book.display(), dest_path.display());
+                        info!(
+                            path = %src_str,
+                            dest = %dest_str,
+                            "Dry-running; would otherwise update {dest_str} from {src_str}"
+                        );
+                        let len = fs::metadata(&book).await?.len();
+                        stats.send(Statistic::WouldUpdate(len)).await?;
+                        let dry_run = Action::DryRun;
+                        record_action(&stats, &book, &dest_path, dry_run, len, Duration::ZERO)
+                            .await?;
+                        run.advance_progress();
+                        continue;
+                    }
+
+                    let overwriting = copy_or_convert(
+                        &book,
+                        &dest_path,
+                        CopyKind::Update,
+                        options,
+                        &copy_permits,
+                        &stats,
+                        run,
+                    );
+                    match overwriting.await {
+                        Ok(copy_task) => copy_tasks.push((src_entry, book, dest_path, copy_task)),
+                        Err(err) => {
+                            let (src_str, dest_str) = (book.display(), dest_path.display());
+                            error!(
+                                path = %src_str,
+                                dest = %dest_str,
+                                "Failed to update {dest_str} from {src_str}: {err:#}"
+                            );
+                            stats.send(Statistic::CopyFailed).await?;
+                            let failed = format!("{err:#}");
+                            record_failure(&stats, &book, &dest_path, failed, Duration::ZERO)
+                                .await?;
+                            run.advance_progress();
+                        }
+                    }
+                }
+                Err(CopyError::AlreadyExists) => {
+                    let dest_str = dest_path.display();
+                    if dry_run {
+                        info!(
+                            path = %book.display(),
+                            dest = %dest_str,
+                            outcome = "skipped",
+                            "Dry-running; {dest_str} already exists, so would skip it"
+                        );
+                    } else {
+                        info!(
+                            path = %book.display(),
+                            dest = %dest_str,
+                            outcome = "skipped",
+                            "Book {dest_str} already exists on the destination; will not copy \
+                            across."
+                        );
+
+                        // Only a destination that's up to date may be skipped by later runs; an
+                        // outdated one still needs to be found by `--update`.
+                        if let Some(src_entry) = src_entry {
+                            if !queued_earlier
+                                && !is_outdated(&book, &dest_path).await.unwrap_or(true)
+                            {
+                                manifest.record(dest_dir, &dest_path, src_entry);
+                            }
+                        }
+                    }
+                    stats.send(Statistic::skipped_existing(dry_run)).await?;
+                    let skipped = Action::SkippedExisting;
+                    record_action(&stats, &book, &dest_path, skipped, 0, Duration::ZERO).await?;
+                    run.advance_progress();
+                }
+                Err(CopyError::Failed(err)) => {
+                    let (src_str, dest_str) = (book.display(), dest_path.display());
+                    error!(
+                        path = %src_str,
+                        dest = %dest_str,
+                        "Failed to copy {src_str} to {dest_str}: {err:#}"
+                    );
+                    stats.send(Statistic::CopyFailed).await?;
+                    let failed = format!("{err:#}");
+                    record_failure(&stats, &book, &dest_path, failed, Duration::ZERO).await?;
+                    run.advance_progress();
+                }
+            }
+        } else {
+            run.advance_progress();
+        }
+    }
+
+    let mut copied_dests = HashSet::new();
+    for (src_entry, book, dest_path, task) in copy_tasks {
+        let copied = await_copy(task, &stats, run).await?;
+        // Dry-run copies don't yield anything, but their sidecars would be copied all the same.
+        if include_sidecars && (dry_run || copied.is_some()) {
+            let dest_path = copied
+                .as_ref()
+                .map_or(&dest_path, |copied| &copied.dest_path);
+            copy_sidecars(&book, dest_path, options, &stats).await?;
+        }
+        if let Some(copied) = copied {
+            if let Some(mut entry) = src_entry {
+                entry.blake3 = copied.digest.as_ref().map(to_hex);
+                manifest.record(dest_dir, &copied.dest_path, entry);
+            }
+            copied_dests.insert(copied.dest_path);
+        }
+    }
+
+    if !quit && !run.is_interrupted() {
+        replace_collided_books(
+            dest_dir,
+            collision_winners,
+            &copied_dests,
+            options,
+            manifest,
+            &mut synchronised,
+            &stats,
+            run,
+        )
+        .await?;
+    }
+
+    // The remaining books went unseen, so carrying on to, say, delete stale books would wrongly
+    // consider them stale.
+    if quit {
+        return Err(RunFailure::Interrupted(
+            "quit at the prompt; the remaining books were not copied".to_owned(),
+        )
+        .into());
+    }
+
+    Ok(synchronised)
+}
+
+/// Count the books that were found but filtered out as synchronised to wherever they would have
+/// been copied, so that deleting stale books leaves their copies alone. Nothing is copied.
+pub(crate) async fn keep_books(
+    dest_dir: &Path,
+    options: &CopyOptions,
+    mut kept: UnboundedReceiver<FoundBook>,
+    synchronised: &mut HashMap<PathBuf, PathBuf>,
+) {
+    while let Some(found) = kept.recv().await {
+        let Some(dest_path) = dest_path_for(dest_dir, &found, options).await else {
+            continue;
+        };
+        let relative = found.path.strip_prefix(&found.root).unwrap_or(&found.path);
+        // Like when synchronising, a book may be on the destination under its plain name if it
+        // failed to convert.
+        if is_kepub_conversion(&found.path, &dest_path) {
+            let plain_dest = plain_dest_for(&found.path, &dest_path);
+            synchronised
+                .entry(plain_dest)
+                .or_insert_with(|| relative.to_path_buf());
+        }
+        synchronised
+            .entry(dest_path)
+            .or_insert_with(|| relative.to_path_buf());
+    }
+}
+
+/// Copy books that are only on the destination, such as those sideloaded onto it from elsewhere,
+/// back into a local directory, keeping their paths relative to the destination. Like with
+/// deletion, hidden directories such as `.kobo` are never descended into. KEPUBs of books that are
+/// already local are left alone too, as they're just converted copies.
+pub(crate) async fn pull_books(
+    dest_dir: &Path,
+    pull_dir: &Path,
+    extensions_to_match: &HashSet<&OsStr>,
+    synchronised: &HashMap<PathBuf, PathBuf>,
+    options: &CopyOptions,
+    stats: &Sender<Statistic>,
+    run: &Run,
+) -> Result<()> {
+    let mut entries = WalkDir::new(dest_dir).filter(|entry| async move {
+        if is_hidden(&entry.file_name()) {
+            Filtering::IgnoreDir
+        } else {
+            Filtering::Continue
+        }
+    });
+
+    let copy_permits = Arc::new(Semaphore::new(options.max_concurrent_copies.get()));
+    let mut pull_tasks = vec![];
+
+    while let Some(entry) = entries.next().await {
+        let entry = entry?;
+        let path = entry.path();
+        if !entry.file_type().await?.is_file()
+            || !has_matching_extension(&path, extensions_to_match)
+            || synchronised.contains_key(&path)
+        {
+            continue;
+        }
+        if is_kepub(&path) {
+            if let Some(plain) = plain_path_for_kepub(&path) {
+                if synchronised.contains_key(&plain) {
+                    continue;
+                }
+            }
+        }
+
+        let Ok(relative) = path.strip_prefix(dest_dir) else {
+            continue;
+        };
+        let local_path = pull_dir.join(relative);
+        if let Some(bar) = run.progress_bar() {
+            bar.inc_length(1);
+        }
+
+        if !options.dry_run {
+            if let Some(parent) = local_path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+        }
+        let pulling = copy_with_policy(
+            &path,
+            &local_path,
+            CopyKind::Pull,
+            options,
+            &copy_permits,
+            stats,
+            run,
+        )
+        .await;
+        match pulling {
+            Ok(pull_task) => pull_tasks.push(pull_task),
+            Err(CopyError::AlreadyExists) => {
+                let (src_str, local_str) = (path.display(), local_path.display());
+                info!(
+                    path = %src_str,
+                    dest = %local_str,
+                    outcome = "skipped",
+                    "Not pulling {src_str}, as {local_str} already exists"
+                );
+                run.advance_progress();
+            }
+            Err(CopyError::Failed(err)) => {
+                let (src_str, local_str) = (path.display(), local_path.display());
+                error!(
+                    path = %src_str,
+                    dest = %local_str,
+                    "Failed to pull {src_str} to {local_str}: {err:#}"
+                );
+                stats.send(Statistic::CopyFailed).await?;
+                let failed = format!("{err:#}");
+                record_failure(stats, &path, &local_path, failed, Duration::ZERO).await?;
+                run.advance_progress();
+            }
+        }
+    }
+
+    for task in pull_tasks {
+        await_copy(task, stats, run).await?;
+    }
+    Ok(())
+}
+
+/// Delete books on the destination that no longer correspond to any found book. Only files with a
+/// synchronised extension are considered, and hidden directories such as `.kobo` are never
+/// descended into, so the device's own files are left alone.
+pub(crate) async fn delete_stale_books(
+    dest_dir: &Path,
+    extensions_to_match: &HashSet<&OsStr>,
+    synchronised: &HashMap<PathBuf, PathBuf>,
+    dry_run: bool,
+    mirror_structure: bool,
+    stats: Sender<Statistic>,
+) -> Result<()> {
+    let mut entries = WalkDir::new(dest_dir).filter(|entry| async move {
+        if is_hidden(&entry.file_name()) {
+            Filtering::IgnoreDir
+        } else {
+            Filtering::Continue
+        }
+    });
+
+    loop {
+        match entries.next().await {
+            Some(Ok(entry)) => {
+                let path = entry.path();
+                if !entry.file_type().await?.is_file()
+                    || !has_matching_extension(&path, extensions_to_match)
+                {
+                    continue;
+                }
+
+                let expected_path = if mirror_structure {
+                    path.clone()
+                } else {
+                    match path.file_name() {
+                        Some(name) => dest_dir.join(name),
+                        None => continue,
+                    }
+                };
+                if synchronised.contains_key(&path) || synchronised.contains_key(&expected_path) {
+                    continue;
+                }
+
+                let path_str = path.display();
+                if dry_run {
+                    info!(
+                        path = %path_str,
+                        "Dry-running; would otherwise delete {path_str}"
+                    );
+                } else {
+                    fs::remove_file(&path).await?;
+                    info!(path = %path_str, "Deleted {path_str}");
+                    stats.send(Statistic::Deleted).await?;
+                }
+            }
+            Some(Err(err)) => Err(anyhow!(err))?,
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn safe(name: &str) -> String {
+        fat_safe_name(OsStr::new(name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn keeps_valid_names_as_they_are() {
+        for name in [
+            "Dune.epub",
+            "dune - part 1.kepub.epub",
+            "CONSOLE.pdf",
+            "com10.epub",
+        ] {
+            assert!(
+                matches!(fat_safe_name(OsStr::new(name)), Cow::Borrowed(_)),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn steers_clear_of_reserved_names() {
+        assert_eq!(safe("CON"), "_CON");
+        assert_eq!(safe("con.epub"), "_con.epub");
+        assert_eq!(safe("Lpt9.tar.gz"), "_Lpt9.tar.gz");
+        assert_eq!(safe("NUL .pdf"), "_NUL .pdf");
+    }
+
+    #[test]
+    fn trims_trailing_dots_and_spaces() {
+        assert_eq!(safe("Dune."), "Dune");
+        assert_eq!(safe("Dune . . "), "Dune");
+        assert_eq!(safe("..."), "_");
+        assert_eq!(safe("aux. "), "_aux");
+    }
+
+    #[test]
+    fn replaces_illegal_characters() {
+        assert_eq!(safe("Dune: Messiah?.epub"), "Dune_ Messiah_.epub");
+        assert_eq!(safe(r#"a"b*c<d>e|f\g.pdf"#), "a_b_c_d_e_f_g.pdf");
+        assert_eq!(safe("tab\there.epub"), "tab_here.epub");
+    }
+}
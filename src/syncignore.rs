@@ -0,0 +1,150 @@
+//! Skipping what `.syncignore` files in the documents directories say to, like `.gitignore` files.
+
+use {
+    globset::{GlobBuilder, GlobMatcher},
+    std::{
+        collections::HashMap,
+        io,
+        path::{Path, PathBuf},
+        sync::Arc,
+    },
+    tokio::{fs, sync::Mutex},
+    tracing::warn,
+};
+
+const SYNCIGNORE_FILE_NAME: &str = ".syncignore";
+
+/// One line of a `.syncignore` file.
+struct Pattern {
+    matcher: GlobMatcher,
+
+    /// Whether the pattern starts with `!`, which includes what an earlier pattern excluded.
+    negated: bool,
+
+    /// Whether the pattern ends with `/`, which means it only matches directories.
+    dir_only: bool,
+}
+
+/// The patterns of a `.syncignore` file, which apply to everything beneath its directory.
+struct IgnoreFile {
+    patterns: Vec<Pattern>,
+}
+
+impl IgnoreFile {
+    /// Parse a `.syncignore` file, warning about and skipping any malformed patterns. As in
+    /// `.gitignore` files, blank lines and those starting with `#` are skipped, and patterns without
+    /// a slash in the middle match at any depth.
+    fn parse(path: &Path, contents: &str) -> IgnoreFile {
+        let mut patterns = vec![];
+        for (line, number) in contents.lines().zip(1..) {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (negated, line) = match line.strip_prefix('!') {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            let (dir_only, line) = match line.strip_suffix('/') {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            let glob = match line.strip_prefix('/') {
+                Some(anchored) => anchored.to_owned(),
+                None if line.contains('/') => line.to_owned(),
+                None => format!("**/{line}"),
+            };
+
+            match GlobBuilder::new(&glob).literal_separator(true).build() {
+                Ok(glob) => patterns.push(Pattern {
+                    matcher: glob.compile_matcher(),
+                    negated,
+                    dir_only,
+                }),
+                Err(err) => {
+                    let path_str = path.display();
+                    warn!(
+                        path = %path_str,
+                        line = number,
+                        "Skipping the pattern on line {number} of {path_str}: {err}"
+                    );
+                }
+            }
+        }
+        IgnoreFile { patterns }
+    }
+
+    /// Whether the file ignores a path relative to its directory, if it says either way. Later
+    /// patterns take precedence over earlier ones.
+    fn decide(&self, relative: &Path, is_dir: bool) -> Option<bool> {
+        self.patterns
+            .iter()
+            .rev()
+            .find(|pattern| (is_dir || !pattern.dir_only) && pattern.matcher.is_match(relative))
+            .map(|pattern| !pattern.negated)
+    }
+}
+
+/// The `.syncignore` files read while looking for books, each read only once.
+#[derive(Default)]
+pub(crate) struct SyncIgnore {
+    files: Mutex<HashMap<PathBuf, Option<Arc<IgnoreFile>>>>,
+}
+
+impl SyncIgnore {
+    /// The `.syncignore` file in a directory, if it has one.
+    async fn file_in(&self, dir: &Path) -> Option<Arc<IgnoreFile>> {
+        if let Some(file) = self.files.lock().await.get(dir) {
+            return file.clone();
+        }
+
+        let path = dir.join(SYNCIGNORE_FILE_NAME);
+        let file = match fs::read_to_string(&path).await {
+            Ok(contents) => Some(Arc::new(IgnoreFile::parse(&path, &contents))),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => {
+                let path_str = path.display();
+                warn!(path = %path_str, "Failed to read {path_str}, so it's ignored: {err}");
+                None
+            }
+        };
+        self.files
+            .lock()
+            .await
+            .insert(dir.to_path_buf(), file.clone());
+        file
+    }
+
+    /// Whether a path in a documents directory is ignored by the `.syncignore` files above it.
+    /// The deepest file to say either way takes precedence, and everything beneath an ignored
+    /// directory is ignored too.
+    pub(crate) async fn is_ignored(&self, root: &Path, path: &Path, is_dir: bool) -> bool {
+        let Ok(relative) = path.strip_prefix(root) else {
+            return false;
+        };
+        let components = relative.components().collect::<Vec<_>>();
+
+        let mut files = vec![];
+        let mut dir = root.to_path_buf();
+        for (i, component) in components.iter().enumerate() {
+            if let Some(file) = self.file_in(&dir).await {
+                files.push((dir.clone(), file));
+            }
+            let beneath = dir.join(component);
+            let is_beneath_dir = i + 1 < components.len() || is_dir;
+            let is_ignored = files
+                .iter()
+                .rev()
+                .find_map(|(base, file)| {
+                    let relative = beneath.strip_prefix(base).unwrap_or(&beneath);
+                    file.decide(relative, is_beneath_dir)
+                })
+                .unwrap_or(false);
+            if is_ignored {
+                return true;
+            }
+            dir = beneath;
+        }
+        false
+    }
+}
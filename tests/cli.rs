@@ -0,0 +1,237 @@
+//! Running the command line tool against a fake device and documents directory, both in temporary
+//! directories.
+
+use {
+    assert_cmd::Command,
+    predicates::prelude::*,
+    std::{fs, path::Path},
+    tempfile::TempDir,
+};
+
+/// A fake device's volume, with the directory that marks it as one.
+fn volume_with_marker(marker: &str) -> TempDir {
+    let volume = TempDir::new().unwrap();
+    fs::create_dir(volume.path().join(marker)).unwrap();
+    volume
+}
+
+/// The tool, kept away from the user's configuration, with `src` as its documents directory and
+/// `volume` as the device's.
+fn tool(volume: &Path, src: &Path) -> Command {
+    let mut command = Command::cargo_bin("sync-kobo-and-workstation").unwrap();
+    command
+        .args(["--no-config", "--no-progress"])
+        .arg("--kobo-directory")
+        .arg(volume)
+        .arg("--documents-directories")
+        .arg(src);
+    command
+}
+
+/// The tool synchronising without a subcommand, kept away from the user's history too.
+fn sync_kobo(volume: &Path, src: &Path) -> Command {
+    let mut command = tool(volume, src);
+    command.arg("--no-history");
+    command
+}
+
+#[test]
+fn synchronises_without_a_subcommand() {
+    let (volume, src) = (volume_with_marker(".kobo"), TempDir::new().unwrap());
+    fs::write(src.path().join("dune.pdf"), b"dune").unwrap();
+
+    sync_kobo(volume.path(), src.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Books copied: 1"));
+
+    assert_eq!(fs::read(volume.path().join("dune.pdf")).unwrap(), b"dune");
+}
+
+#[test]
+fn synchronises_the_same_with_the_sync_subcommand() {
+    let (volume, src) = (volume_with_marker(".kobo"), TempDir::new().unwrap());
+    fs::write(src.path().join("dune.pdf"), b"dune").unwrap();
+
+    tool(volume.path(), src.path())
+        .args(["sync", "--no-history", "--update"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Books copied: 1"));
+
+    assert_eq!(fs::read(volume.path().join("dune.pdf")).unwrap(), b"dune");
+}
+
+#[test]
+fn refuses_synchronising_options_before_the_sync_subcommand() {
+    let (volume, src) = (volume_with_marker(".kobo"), TempDir::new().unwrap());
+
+    tool(volume.path(), src.path())
+        .args(["--update", "sync"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains(
+            "`--update` must come after `sync`, not before it",
+        ));
+}
+
+#[test]
+fn shows_the_synchronising_options_and_subcommands_in_the_help() {
+    Command::cargo_bin("sync-kobo-and-workstation")
+        .unwrap()
+        .arg("--help")
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::contains("Usage: sync-kobo-and-workstation [OPTIONS] [COMMAND]")
+                .and(predicate::str::contains("--extensions"))
+                .and(predicate::str::contains("--update"))
+                .and(predicate::str::contains("  sync "))
+                .and(predicate::str::contains("  verify ")),
+        );
+}
+
+#[test]
+fn synchronises_the_given_extensions_whatever_their_case() {
+    let (volume, src) = (volume_with_marker(".kobo"), TempDir::new().unwrap());
+    for name in ["Dune.CBZ", "paper.Pdf", "emma.epub"] {
+        fs::write(src.path().join(name), name).unwrap();
+    }
+
+    sync_kobo(volume.path(), src.path())
+        .args(["--extensions", ".cbz,PDF"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Books copied: 2"));
+
+    assert!(volume.path().join("Dune.CBZ").exists());
+    assert!(volume.path().join("paper.Pdf").exists());
+    assert!(!volume.path().join("emma.epub").exists());
+}
+
+#[test]
+fn rejects_extensions_without_anything_but_dots() {
+    let (volume, src) = (volume_with_marker(".kobo"), TempDir::new().unwrap());
+
+    sync_kobo(volume.path(), src.path())
+        .args(["--extensions", "epub,."])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains(
+            "an extension must contain at least one non-dot character",
+        ));
+}
+
+#[test]
+fn synchronises_books_whatever_the_case_of_their_extensions() {
+    let (volume, src) = (volume_with_marker(".kobo"), TempDir::new().unwrap());
+    for name in ["Dune.EPUB", "emma.ePub", "paper.Pdf"] {
+        fs::write(src.path().join(name), name).unwrap();
+    }
+
+    sync_kobo(volume.path(), src.path())
+        .arg("--no-validate")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Books copied: 3"));
+}
+
+#[test]
+fn rejects_more_than_10_retries() {
+    let (volume, src) = (volume_with_marker(".kobo"), TempDir::new().unwrap());
+
+    sync_kobo(volume.path(), src.path())
+        .args(["--retries", "11"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("11 is not in 0..=10"));
+}
+
+#[test]
+fn exits_with_2_when_the_device_is_inaccessible() {
+    let (volume, src) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+
+    sync_kobo(&volume.path().join("unplugged"), src.path())
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("is not accessible"));
+}
+
+#[test]
+fn exits_with_2_when_a_documents_directory_is_inaccessible() {
+    let (volume, src) = (volume_with_marker(".kobo"), TempDir::new().unwrap());
+
+    sync_kobo(volume.path(), &src.path().join("missing"))
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("is not accessible"));
+}
+
+#[test]
+fn reports_books_renamed_to_be_valid_on_the_device() {
+    let (volume, src) = (volume_with_marker(".kobo"), TempDir::new().unwrap());
+    fs::write(src.path().join("What? Why: A Study <draft>.pdf"), b"why").unwrap();
+
+    sync_kobo(volume.path(), src.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "What_ Why_ A Study _draft_.pdf on the destination, as its name is not valid there",
+        ));
+
+    assert_eq!(
+        fs::read(volume.path().join("What_ Why_ A Study _draft_.pdf")).unwrap(),
+        b"why"
+    );
+}
+
+#[test]
+fn synchronises_into_a_kindles_documents_directory() {
+    let (volume, src) = (volume_with_marker("system"), TempDir::new().unwrap());
+    fs::create_dir(volume.path().join("documents")).unwrap();
+    fs::write(src.path().join("dune.pdf"), b"dune").unwrap();
+
+    sync_kobo(volume.path(), src.path())
+        .args(["--device", "kindle"])
+        .assert()
+        .success();
+
+    assert!(volume.path().join("documents/dune.pdf").exists());
+}
+
+#[test]
+fn summarises_runs_that_copy_nothing_without_copying_lines() {
+    let (volume, src) = (volume_with_marker("system"), TempDir::new().unwrap());
+    fs::create_dir(volume.path().join("documents")).unwrap();
+    fs::write(src.path().join("dune.pdf"), b"dune").unwrap();
+    fs::write(volume.path().join("documents/dune.pdf"), b"dune").unwrap();
+
+    sync_kobo(volume.path(), src.path())
+        .args(["--device", "kindle"])
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::contains("already exist on the destination Kindle: 1")
+                .and(predicate::str::contains("Average copy throughput").not())
+                .and(predicate::str::contains("Books updated").not())
+                .and(predicate::str::contains("Average time to copy a book").not())
+                .and(predicate::str::contains("waiting for copies").not()),
+        );
+}
+
+#[test]
+fn dry_runs_say_what_would_be_copied_without_copying_it() {
+    let (volume, src) = (volume_with_marker(".kobo"), TempDir::new().unwrap());
+    fs::write(src.path().join("dune.pdf"), b"dune").unwrap();
+
+    sync_kobo(volume.path(), src.path())
+        .arg("--dry-run")
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::contains("Books that would be copied: 1")
+                .and(predicate::str::contains("Books copied:").not()),
+        );
+
+    assert!(!volume.path().join("dune.pdf").exists());
+}
@@ -0,0 +1,318 @@
+//! Synchronising documents directories to destinations, both in temporary directories.
+
+use {
+    globset::{Glob, GlobSet, GlobSetBuilder},
+    std::{
+        fs,
+        path::Path,
+        time::{Duration, SystemTime},
+    },
+    sync_kobo_and_workstation::{sync, verify, Interrupter, SyncOptions},
+    tempfile::TempDir,
+};
+
+/// Write a book into a documents directory, creating any directories it's in.
+fn write_book(dir: &Path, relative: &str, contents: &[u8]) {
+    let path = dir.join(relative);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+}
+
+/// A set of glob patterns, as given to `--exclude` or `--include`.
+fn globs(patterns: &[&str]) -> GlobSet {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).unwrap());
+    }
+    builder.build().unwrap()
+}
+
+#[tokio::test]
+async fn copies_a_pdf() {
+    let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    write_book(src.path(), "dune.pdf", b"%PDF-1.7 dune");
+
+    let report = sync(SyncOptions::builder(dest.path()).source(src.path()).build())
+        .await
+        .unwrap();
+
+    assert_eq!(report.counters.copied, 1);
+    assert_eq!(
+        fs::read(dest.path().join("dune.pdf")).unwrap(),
+        b"%PDF-1.7 dune"
+    );
+}
+
+#[tokio::test]
+async fn deleting_keeps_excluded_books() {
+    let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    write_book(src.path(), "dune.pdf", b"dune");
+    write_book(src.path(), "emma.pdf", b"emma");
+    write_book(dest.path(), "emma.pdf", b"emma");
+    write_book(dest.path(), "gone.pdf", b"gone");
+
+    let options = SyncOptions::builder(dest.path())
+        .source(src.path())
+        .excludes(globs(&["emma.pdf"]))
+        .delete(true)
+        .build();
+    let report = sync(options).await.unwrap();
+
+    assert_eq!(report.counters.excluded, 1);
+    assert_eq!(report.counters.deleted, 1);
+    assert!(dest.path().join("dune.pdf").exists());
+    assert!(dest.path().join("emma.pdf").exists());
+    assert!(!dest.path().join("gone.pdf").exists());
+}
+
+#[tokio::test]
+async fn deleting_keeps_books_not_included() {
+    let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    write_book(src.path(), "fiction/dune.pdf", b"dune");
+    write_book(src.path(), "papers/notes.pdf", b"notes");
+    write_book(dest.path(), "notes.pdf", b"notes");
+    write_book(dest.path(), "gone.pdf", b"gone");
+
+    let options = SyncOptions::builder(dest.path())
+        .source(src.path())
+        .includes(Some(globs(&["fiction/**"])))
+        .delete(true)
+        .build();
+    let report = sync(options).await.unwrap();
+
+    assert_eq!(report.counters.excluded, 1);
+    assert_eq!(report.counters.deleted, 1);
+    assert!(dest.path().join("dune.pdf").exists());
+    assert!(dest.path().join("notes.pdf").exists());
+    assert!(!dest.path().join("gone.pdf").exists());
+}
+
+#[tokio::test]
+async fn resumes_a_partial_copy() {
+    let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    write_book(src.path(), "dune.pdf", b"the whole of dune");
+    // The partial file differs from the start of the book, to tell resuming from starting over.
+    write_book(dest.path(), "dune.pdf.sync-partial", b"THE WHOLE");
+
+    let options = SyncOptions::builder(dest.path())
+        .source(src.path())
+        .resume(true)
+        .build();
+    let report = sync(options).await.unwrap();
+
+    assert_eq!(report.counters.copied, 1);
+    assert_eq!(
+        fs::read(dest.path().join("dune.pdf")).unwrap(),
+        b"THE WHOLE of dune"
+    );
+    assert!(!dest.path().join("dune.pdf.sync-partial").exists());
+}
+
+#[tokio::test]
+async fn deleting_keeps_books_that_are_too_large() {
+    let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    write_book(src.path(), "dune.pdf", b"dune");
+    write_book(src.path(), "war-and-peace.pdf", b"war and peace");
+    write_book(dest.path(), "war-and-peace.pdf", b"war and peace");
+    write_book(dest.path(), "gone.pdf", b"gone");
+
+    let options = SyncOptions::builder(dest.path())
+        .source(src.path())
+        .max_size(Some(4))
+        .delete(true)
+        .build();
+    let report = sync(options).await.unwrap();
+
+    assert_eq!(report.counters.excluded_by_size, 1);
+    assert_eq!(report.counters.deleted, 1);
+    assert!(dest.path().join("dune.pdf").exists());
+    assert!(dest.path().join("war-and-peace.pdf").exists());
+    assert!(!dest.path().join("gone.pdf").exists());
+}
+
+#[tokio::test]
+async fn deleting_keeps_books_modified_before_the_cutoff() {
+    let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    write_book(src.path(), "dune.pdf", b"dune");
+    write_book(dest.path(), "dune.pdf", b"dune");
+    write_book(dest.path(), "gone.pdf", b"gone");
+
+    // Every book was modified before a cutoff in the future.
+    let since = SystemTime::now() + Duration::from_secs(60 * 60);
+    let options = SyncOptions::builder(dest.path())
+        .source(src.path())
+        .since(Some(since))
+        .delete(true)
+        .build();
+    let report = sync(options).await.unwrap();
+
+    assert_eq!(report.counters.excluded_as_too_old, 1);
+    assert_eq!(report.counters.deleted, 1);
+    assert!(dest.path().join("dune.pdf").exists());
+    assert!(!dest.path().join("gone.pdf").exists());
+}
+
+#[tokio::test]
+async fn interrupting_one_run_leaves_another_alone() {
+    let src = TempDir::new().unwrap();
+    let (interrupted_dest, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    write_book(src.path(), "dune.pdf", b"dune");
+
+    let interrupter = Interrupter::new();
+    interrupter.interrupt();
+    let interrupted = SyncOptions::builder(interrupted_dest.path())
+        .source(src.path())
+        .interrupter(interrupter)
+        .build();
+    let uninterrupted = SyncOptions::builder(dest.path()).source(src.path()).build();
+    let (interrupted, uninterrupted) = tokio::join!(sync(interrupted), sync(uninterrupted));
+
+    let (interrupted, uninterrupted) = (interrupted.unwrap(), uninterrupted.unwrap());
+    assert!(interrupted.interrupted);
+    assert_eq!(interrupted.counters.copied, 0);
+    assert!(!uninterrupted.interrupted);
+    assert_eq!(uninterrupted.counters.copied, 1);
+    assert!(dest.path().join("dune.pdf").exists());
+}
+
+#[tokio::test]
+async fn records_the_blake3_digest_of_verified_copies() {
+    let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    write_book(src.path(), "dune.pdf", b"dune");
+
+    let options = SyncOptions::builder(dest.path())
+        .source(src.path())
+        .verify(true)
+        .manifest(true)
+        .build();
+    let report = sync(options).await.unwrap();
+
+    assert_eq!(report.counters.copied, 1);
+    let manifest = fs::read(dest.path().join(".sync-manifest.json")).unwrap();
+    let manifest: serde_json::Value = serde_json::from_slice(&manifest).unwrap();
+    assert_eq!(
+        manifest["books"]["dune.pdf"]["blake3"],
+        blake3::hash(b"dune").to_hex().as_str()
+    );
+}
+
+/// Options to verify a book in a documents directory of its own against a copy of it, through a
+/// hash cache in `caches` named after it.
+fn verifying_through_a_hash_cache(name: &str, caches: &Path) -> (TempDir, TempDir, SyncOptions) {
+    let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    write_book(src.path(), name, name.as_bytes());
+    write_book(dest.path(), name, name.as_bytes());
+    let options = SyncOptions::builder(dest.path())
+        .source(src.path())
+        .hash_cache(Some(caches.join(format!("{name}.json"))))
+        .build();
+    (src, dest, options)
+}
+
+#[tokio::test]
+async fn runs_at_the_same_time_keep_their_own_hash_caches() {
+    let caches = TempDir::new().unwrap();
+    let (dune_src, _dune_dest, dune) = verifying_through_a_hash_cache("dune.pdf", caches.path());
+    let (emma_src, _emma_dest, emma) = verifying_through_a_hash_cache("emma.pdf", caches.path());
+
+    let (dune, emma) = tokio::join!(verify(dune, true), verify(emma, true));
+    dune.unwrap();
+    emma.unwrap();
+
+    for (src, name) in [(dune_src, "dune.pdf"), (emma_src, "emma.pdf")] {
+        let cache = fs::read(caches.path().join(format!("{name}.json"))).unwrap();
+        let cache: serde_json::Value = serde_json::from_slice(&cache).unwrap();
+        let book = fs::canonicalize(src.path().join(name)).unwrap();
+        let digests = cache["digests"].as_object().unwrap();
+        assert_eq!(digests.len(), 1);
+        assert!(digests.contains_key(book.to_str().unwrap()));
+    }
+}
+
+#[tokio::test]
+async fn dry_runs_count_what_would_be_done_rather_than_what_was() {
+    let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    write_book(src.path(), "dune.pdf", b"dune");
+    write_book(src.path(), "emma.pdf", b"emma");
+    write_book(dest.path(), "emma.pdf", b"emma");
+
+    let options = SyncOptions::builder(dest.path())
+        .source(src.path())
+        .dry_run(true)
+        .build();
+    let report = sync(options).await.unwrap();
+
+    assert_eq!(report.counters.would_copy, 1);
+    assert_eq!(report.counters.would_skip_existing, 1);
+    assert_eq!(report.counters.bytes_would_copy, 4);
+    assert_eq!(report.counters.copied, 0);
+    assert_eq!(report.counters.skipped_existing, 0);
+    assert_eq!(report.counters.bytes_copied, 0);
+    assert!(!dest.path().join("dune.pdf").exists());
+}
+
+#[tokio::test]
+async fn deleting_keeps_books_deeper_than_the_maximum_depth() {
+    let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    write_book(src.path(), "dune.pdf", b"dune");
+    write_book(src.path(), "series/emma.pdf", b"emma");
+    write_book(dest.path(), "emma.pdf", b"emma");
+    write_book(dest.path(), "gone.pdf", b"gone");
+
+    let options = SyncOptions::builder(dest.path())
+        .source(src.path())
+        .max_depth(Some(0))
+        .delete(true)
+        .build();
+    let report = sync(options).await.unwrap();
+
+    assert_eq!(report.counters.pruned_by_depth, 1);
+    assert_eq!(report.counters.deleted, 1);
+    assert!(dest.path().join("dune.pdf").exists());
+    assert!(dest.path().join("emma.pdf").exists());
+    assert!(!dest.path().join("gone.pdf").exists());
+}
+
+#[tokio::test]
+async fn deleting_keeps_books_ignored_by_a_syncignore_file() {
+    let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    write_book(src.path(), ".syncignore", b"drafts/\nnotes.pdf\n");
+    write_book(src.path(), "dune.pdf", b"dune");
+    write_book(src.path(), "drafts/emma.pdf", b"emma");
+    write_book(src.path(), "notes.pdf", b"notes");
+    write_book(dest.path(), "emma.pdf", b"emma");
+    write_book(dest.path(), "notes.pdf", b"notes");
+    write_book(dest.path(), "gone.pdf", b"gone");
+
+    let options = SyncOptions::builder(dest.path())
+        .source(src.path())
+        .delete(true)
+        .build();
+    let report = sync(options).await.unwrap();
+
+    assert_eq!(report.counters.deleted, 1);
+    assert!(dest.path().join("dune.pdf").exists());
+    assert!(dest.path().join("emma.pdf").exists());
+    assert!(dest.path().join("notes.pdf").exists());
+    assert!(!dest.path().join("gone.pdf").exists());
+}
+
+#[tokio::test]
+async fn deleting_keeps_hidden_books() {
+    let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    write_book(src.path(), "dune.pdf", b"dune");
+    write_book(src.path(), ".hidden/emma.pdf", b"emma");
+    write_book(dest.path(), "emma.pdf", b"emma");
+    write_book(dest.path(), "gone.pdf", b"gone");
+
+    let options = SyncOptions::builder(dest.path())
+        .source(src.path())
+        .delete(true)
+        .build();
+    let report = sync(options).await.unwrap();
+
+    assert_eq!(report.counters.deleted, 1);
+    assert!(dest.path().join("dune.pdf").exists());
+    assert!(dest.path().join("emma.pdf").exists());
+    assert!(!dest.path().join("gone.pdf").exists());
+}